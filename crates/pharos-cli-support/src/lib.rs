@@ -258,12 +258,12 @@ pub async fn execute_with_interactive_setup(
         } else {
             return Err(err);
         }
-    } else if is_auth_failure_error(&err) {
-        if let Ok(key_path) = default_personal_key_path() {
-            let pub_key_path = key_path.with_extension("pub");
-            if pub_key_path.exists() {
-                return offer_ssh_enrollment_and_retry(client, cmd_to_send, &pub_key_path, err).await;
-            }
+    } else if is_auth_failure_error(&err)
+        && let Ok(key_path) = default_personal_key_path()
+    {
+        let pub_key_path = key_path.with_extension("pub");
+        if pub_key_path.exists() {
+            return offer_ssh_enrollment_and_retry(client, cmd_to_send, &pub_key_path, err).await;
         }
     }
 
@@ -347,18 +347,18 @@ pub fn enforce_add_record_type(cmd_str: &str, expected_type: &str, cli_name: &st
 
     let mut type_found = false;
     for token in tokens.iter_mut().skip(1) {
-        if let Some((k, v)) = token.split_once('=') {
-            if k.eq_ignore_ascii_case("type") {
-                let unquoted = v.trim_matches('"');
-                if !unquoted.eq_ignore_ascii_case(expected_type) {
-                    eprintln!(
-                        "Note: {} always registers {}-type records; overriding type={} to type={}.",
-                        cli_name, expected_type, unquoted, expected_type
-                    );
-                }
-                *token = format!("type={}", expected_type);
-                type_found = true;
+        if let Some((k, v)) = token.split_once('=')
+            && k.eq_ignore_ascii_case("type")
+        {
+            let unquoted = v.trim_matches('"');
+            if !unquoted.eq_ignore_ascii_case(expected_type) {
+                eprintln!(
+                    "Note: {} always registers {}-type records; overriding type={} to type={}.",
+                    cli_name, expected_type, unquoted, expected_type
+                );
             }
+            *token = format!("type={}", expected_type);
+            type_found = true;
         }
     }
 
@@ -40,6 +40,59 @@ pub struct PharosRecord {
     pub fields: Vec<PharosField>,
 }
 
+/// A field as described by the server's FIELDS command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PharosFieldInfo {
+    pub id: i32,
+    pub name: String,
+    pub max_len: usize,
+    pub public: bool,
+    pub required: bool,
+    pub multi: bool,
+    /// `string`, `int`, `bool`, `timestamp`, `ip`, `mac` or `enum`.
+    pub kind: String,
+    /// The accepted values of an `enum` field.
+    pub values: Vec<String>,
+    pub description: String,
+}
+
+impl PharosFieldInfo {
+    /// Parses one FIELDS entry: its attribute line (`max 64 Public Required Integer`) followed by
+    /// its description. Attribute words this client doesn't know are ignored.
+    pub fn from_record(record: &PharosRecord) -> Option<Self> {
+        let attributes = record.fields.first()?;
+        let mut info = PharosFieldInfo {
+            id: record.id,
+            name: attributes.key.clone(),
+            kind: "string".to_string(),
+            description: record.fields.get(1).map(|f| f.value.clone()).unwrap_or_default(),
+            ..Default::default()
+        };
+
+        let mut words = attributes.value.split_whitespace();
+        while let Some(word) = words.next() {
+            match word {
+                "max" => info.max_len = words.next().and_then(|n| n.parse().ok()).unwrap_or(0),
+                "Public" => info.public = true,
+                "Private" => info.public = false,
+                "Required" => info.required = true,
+                "Multiple" => info.multi = true,
+                "Integer" => info.kind = "int".to_string(),
+                "Boolean" => info.kind = "bool".to_string(),
+                "Timestamp" => info.kind = "timestamp".to_string(),
+                "IP" => info.kind = "ip".to_string(),
+                "MAC" => info.kind = "mac".to_string(),
+                w if w.starts_with("Enum(") && w.ends_with(')') => {
+                    info.kind = "enum".to_string();
+                    info.values = w["Enum(".len()..w.len() - 1].split('|').map(str::to_string).collect();
+                }
+                _ => {}
+            }
+        }
+        Some(info)
+    }
+}
+
 /// Represents the possible outcomes of a Pharos query.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PharosResponse {
@@ -197,13 +250,25 @@ impl PharosClient {
         self.parse_response().await
     }
 
+    /// Describes the server's fields, as they apply to `record_type` if one is given.
+    pub async fn fields(&mut self, record_type: Option<&str>) -> Result<Vec<PharosFieldInfo>> {
+        let command = match record_type {
+            Some(t) => format!("fields {}", join_wire_args(&[format!("type={}", t)])),
+            None => "fields".to_string(),
+        };
+        match self.execute(&command).await? {
+            PharosResponse::Matches { records, .. } => Ok(records.iter().filter_map(PharosFieldInfo::from_record).collect()),
+            PharosResponse::Error { code, message } => Err(anyhow!("Server rejected fields command ({}): {}", code, message)),
+            _ => Ok(Vec::new()),
+        }
+    }
+
     /// Explicitly authenticates the session using the configured client ID.
     pub async fn authenticate(&mut self) -> Result<()> {
         self.send_line(&format!("login {}", self.client_id)).await?;
         let resp = self.read_line().await?;
         
-        if resp.starts_with("301:") {
-            let challenge = &resp[4..];
+        if let Some(challenge) = resp.strip_prefix("301:") {
             let (pub_key_ssh, sig_b64) = Self::sign_message_async(challenge).await?;
             
             self.send_line(&format!("auth \"{}\" \"{}\"", pub_key_ssh, sig_b64)).await?;
@@ -221,10 +286,8 @@ impl PharosClient {
 
     async fn send_line(&mut self, line: &str) -> Result<()> {
         let mut cmd = line.to_string();
-        if !cmd.ends_with("
-") {
-            cmd.push_str("
-");
+        if !cmd.ends_with('\n') {
+            cmd.push('\n');
         }
         self.stream.write_all(cmd.as_bytes()).await
             .context("Failed to write to stream")?;
@@ -483,6 +546,37 @@ mod tests {
         assert_eq!(join_wire_args(&args), "filter=a=b");
     }
 
+    #[test]
+    fn test_should_parse_field_attributes_and_description() {
+        let record = PharosRecord {
+            id: 3,
+            fields: vec![
+                PharosField { key: "env".to_string(), value: "max 16 Private Required Enum(prod|staging)".to_string() },
+                PharosField { key: "env".to_string(), value: "Deployment environment.".to_string() },
+            ],
+        };
+        let info = PharosFieldInfo::from_record(&record).unwrap();
+        assert_eq!(info, PharosFieldInfo {
+            id: 3,
+            name: "env".to_string(),
+            max_len: 16,
+            public: false,
+            required: true,
+            multi: false,
+            kind: "enum".to_string(),
+            values: vec!["prod".to_string(), "staging".to_string()],
+            description: "Deployment environment.".to_string(),
+        });
+
+        let record = PharosRecord {
+            id: 5,
+            fields: vec![PharosField { key: "ip_addr".to_string(), value: "max 64 Public Multiple IP".to_string() }],
+        };
+        let info = PharosFieldInfo::from_record(&record).unwrap();
+        assert!(info.public && info.multi);
+        assert_eq!(info.kind, "ip");
+    }
+
     #[test]
     fn test_should_list_all_attempted_paths_when_personal_key_not_used() {
         let tried = PharosClient::describe_attempted_paths(
@@ -13,7 +13,7 @@
  * Related to Task 22.4 (Issue #141), implements human-readable flags.
  * ======================================================================== */
 
-use pharos_client::{PharosClient, PharosResponse};
+use pharos_client::{PharosClient, PharosRecord, PharosResponse};
 use std::process;
 use std::io::{self, IsTerminal};
 use anyhow::{Result, Context};
@@ -50,6 +50,24 @@ enum Commands {
         #[command(subcommand)]
         sub: AuthCommands,
     },
+    /// Show the revision timeline of a machine: who changed which field, when
+    History {
+        /// Hostname to look up, or an explicit selection such as 'alias=gw'
+        host: String,
+    },
+    /// Show what is linked to a machine (its VMs, services, ...) as a tree
+    Tree {
+        /// Hostname to start from, or an explicit selection such as 'alias=gw'
+        host: String,
+        /// Walk up instead: what the machine runs on, depends on, is owned by
+        #[arg(long)]
+        parents: bool,
+    },
+    /// List machines that look like the same box: a shared MAC, IP, uuid or serial number
+    Dupes {
+        /// Only look among these records, e.g. 'ip_addr=10.0.0.*' (default: every machine)
+        selection: Option<String>,
+    },
 }
 
 #[derive(Subcommand)]
@@ -97,7 +115,13 @@ async fn main() -> Result<()> {
     }
 
     // Legacy fallback/Direct query support
-    let query_string = if cli.command.is_some() {
+    let query_string = if let Some(Commands::History { host }) = &cli.command {
+        history_command(host)
+    } else if let Some(Commands::Tree { host, parents }) = &cli.command {
+        tree_command(host, *parents)
+    } else if let Some(Commands::Dupes { selection }) = &cli.command {
+        dupes_command(selection.as_deref())
+    } else if cli.command.is_some() {
         // If it was a recognized subcommand that didn't exit (none yet except auth)
         String::new() 
     } else if !cli.query.is_empty() {
@@ -128,7 +152,7 @@ async fn main() -> Result<()> {
     } else {
         let first_word = lower_cmd.split_whitespace().next().unwrap_or("");
         match first_word {
-            "add" | "change" | "delete" | "status" | "siteinfo" | "quit" => query_string,
+            "add" | "change" | "delete" | "undelete" | "tombstones" | "history" | "links" | "dupes" | "merge" | "snapshot" | "status" | "siteinfo" | "quit" => query_string,
             _ => format!("query {}", query_string),
         }
     };
@@ -145,7 +169,11 @@ async fn main() -> Result<()> {
         eprintln!("[DEBUG] Response: {:?}", resp);
     }
 
-    handle_response(resp, cli.human)?;
+    match (&cli.command, resp) {
+        (Some(Commands::Tree { .. }), PharosResponse::Matches { records, .. }) => println!("{}", render_tree(&records)),
+        (Some(Commands::Dupes { .. }), PharosResponse::Matches { records, .. }) => println!("{}", render_dupes(&records)),
+        (_, resp) => handle_response(resp, cli.human)?,
+    }
 
     let _ = client.quit().await;
     Ok(())
@@ -170,6 +198,95 @@ fn handle_response(resp: PharosResponse, human: bool) -> Result<()> {
     Ok(())
 }
 
+/// Builds the wire command for `mdb history`: a bare host is a hostname selection.
+fn history_command(host: &str) -> String {
+    let selection = if host.contains('=') { host.to_string() } else { format!("hostname={}", host) };
+    format!("history {}", pharos_client::join_wire_args(&[selection]))
+}
+
+/// Builds the wire command for `mdb tree`: every link followed from the host, down or up.
+fn tree_command(host: &str, parents: bool) -> String {
+    let selection = if host.contains('=') { host.to_string() } else { format!("hostname={}", host) };
+    let direction = if parents { "parents" } else { "children" };
+    format!("links {} {} all", pharos_client::join_wire_args(&[selection]), direction)
+}
+
+/// Draws the records a `links` walk returned as a tree: each under the record it was reached
+/// from, with the link field that connects them.
+fn render_tree(records: &[PharosRecord]) -> String {
+    let field = |record: &PharosRecord, key: &str| record.fields.iter().find(|f| f.key == key).map(|f| f.value.clone());
+    let label = |record: &PharosRecord| field(record, "hostname").or_else(|| field(record, "alias")).unwrap_or_else(|| format!("#{}", record.id));
+
+    fn draw(node: usize, prefix: &str, children: &[Vec<usize>], labels: &[String], lines: &mut Vec<String>) {
+        let count = children[node].len();
+        for (i, &child) in children[node].iter().enumerate() {
+            let last = i + 1 == count;
+            lines.push(format!("{}{}{}", prefix, if last { "└── " } else { "├── " }, labels[child]));
+            draw(child, &format!("{}{}", prefix, if last { "    " } else { "│   " }), children, labels, lines);
+        }
+    }
+
+    let names: Vec<String> = records.iter().map(label).collect();
+    let labels: Vec<String> = records
+        .iter()
+        .zip(&names)
+        .map(|(record, name)| match field(record, "link_via") {
+            Some(via) => format!("{} ({})", name, via),
+            None => name.clone(),
+        })
+        .collect();
+    let mut children = vec![Vec::new(); records.len()];
+    let mut roots = Vec::new();
+    for (i, record) in records.iter().enumerate() {
+        match field(record, "link_from").and_then(|from| names.iter().position(|n| *n == from)) {
+            Some(parent) if parent != i => children[parent].push(i),
+            _ => roots.push(i),
+        }
+    }
+
+    let mut lines = Vec::new();
+    for root in roots {
+        lines.push(labels[root].clone());
+        draw(root, "", &children, &labels, &mut lines);
+    }
+    lines.join("\n")
+}
+
+/// Builds the wire command for `mdb dupes`: machines only, unless a selection says otherwise.
+fn dupes_command(selection: Option<&str>) -> String {
+    let selection = selection.unwrap_or("type=machine");
+    format!("dupes {}", pharos_client::join_wire_args(&[selection.to_string()]))
+}
+
+/// Lists the records a `dupes` command returned group by group, each with the values it shares
+/// with the rest of its group, and how to fold them together.
+fn render_dupes(records: &[PharosRecord]) -> String {
+    let field = |record: &PharosRecord, key: &str| record.fields.iter().find(|f| f.key == key).map(|f| f.value.clone());
+    let label = |record: &PharosRecord| match (field(record, "hostname"), field(record, "alias")) {
+        (Some(hostname), _) => format!("hostname={}", hostname),
+        (None, Some(alias)) => format!("alias={}", alias),
+        (None, None) => format!("#{}", record.id),
+    };
+
+    let width = records.iter().map(|r| label(r).len()).max().unwrap_or(0);
+    let mut lines = Vec::new();
+    let mut group = None;
+    for record in records {
+        let this = field(record, "dupe_group");
+        if this != group {
+            if group.is_some() {
+                lines.push(String::new());
+            }
+            lines.push(format!("Group {}", this.clone().unwrap_or_default()));
+            group = this;
+        }
+        lines.push(format!("  {:<width$}  {}", label(record), field(record, "dupe_shared").unwrap_or_default(), width = width));
+    }
+    lines.push(String::new());
+    lines.push("Fold one into another with: mdb merge <selection> into <selection>".to_string());
+    lines.join("\n")
+}
+
 /// Formats raw protocol values into human-readable strings.
 fn format_human(key: &str, value: &str) -> String {
     let lower_key = key.to_lowercase();
@@ -183,17 +300,17 @@ fn format_human(key: &str, value: &str) -> String {
         if let Ok(bytes) = value.parse::<f64>() {
             return format_bytes(bytes);
         }
-    } else if lower_key.ends_with("_mb") {
-        if let Ok(mb) = value.parse::<f64>() {
-            return format_bytes(mb * 1024.0 * 1024.0);
-        }
+    } else if lower_key.ends_with("_mb")
+        && let Ok(mb) = value.parse::<f64>()
+    {
+        return format_bytes(mb * 1024.0 * 1024.0);
     }
 
     // 2. Timestamp conversions
-    if lower_key.ends_with("_at") || lower_key == "created" || lower_key == "updated" {
-        if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
-            return dt.format("%Y-%m-%d %H:%M:%S").to_string();
-        }
+    if (lower_key.ends_with("_at") || lower_key == "created" || lower_key == "updated")
+        && let Ok(dt) = DateTime::parse_from_rfc3339(value)
+    {
+        return dt.format("%Y-%m-%d %H:%M:%S").to_string();
     }
 
     value.to_string()
@@ -245,6 +362,63 @@ mod tests {
         assert_eq!(result, "pharos-main");
     }
 
+    #[test]
+    fn test_should_build_history_command_from_host_or_selection() {
+        assert_eq!(history_command("web01"), "history hostname=web01");
+        assert_eq!(history_command("alias=gw"), "history alias=gw");
+    }
+
+    #[test]
+    fn test_should_draw_a_links_walk_as_a_tree() {
+        assert_eq!(tree_command("pve01", false), "links hostname=pve01 children all");
+        assert_eq!(tree_command("alias=gw", true), "links alias=gw parents all");
+
+        let record = |id, pairs: &[(&str, &str)]| PharosRecord {
+            id,
+            fields: pairs.iter().map(|(k, v)| pharos_client::PharosField { key: k.to_string(), value: v.to_string() }).collect(),
+        };
+        let records = [
+            record(1, &[("hostname", "pve01"), ("link_depth", "0")]),
+            record(2, &[("hostname", "vm-web"), ("link_depth", "1"), ("link_from", "pve01"), ("link_via", "runs_on")]),
+            record(3, &[("hostname", "vm-db"), ("link_depth", "1"), ("link_from", "pve01"), ("link_via", "runs_on")]),
+            record(4, &[("hostname", "svc-api"), ("link_depth", "2"), ("link_from", "vm-web"), ("link_via", "depends_on")]),
+        ];
+        assert_eq!(render_tree(&records), [
+            "pve01",
+            "├── vm-web (runs_on)",
+            "│   └── svc-api (depends_on)",
+            "└── vm-db (runs_on)",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_should_list_duplicates_by_group() {
+        assert_eq!(dupes_command(None), "dupes type=machine");
+        assert_eq!(dupes_command(Some("ip_addr=10.0.0.*")), "dupes ip_addr=10.0.0.*");
+
+        let record = |id, pairs: &[(&str, &str)]| PharosRecord {
+            id,
+            fields: pairs.iter().map(|(k, v)| pharos_client::PharosField { key: k.to_string(), value: v.to_string() }).collect(),
+        };
+        let records = [
+            record(1, &[("alias", "scan-ab12"), ("dupe_group", "1"), ("dupe_shared", "mac_addr=aa:bb:cc:00:00:01")]),
+            record(2, &[("hostname", "web01"), ("dupe_group", "1"), ("dupe_shared", "mac_addr=aa:bb:cc:00:00:01")]),
+            record(3, &[("hostname", "db01"), ("dupe_group", "2"), ("dupe_shared", "uuid=4c4c-02")]),
+            record(4, &[("hostname", "db01-old"), ("dupe_group", "2"), ("dupe_shared", "uuid=4c4c-02")]),
+        ];
+        assert_eq!(render_dupes(&records), [
+            "Group 1",
+            "  alias=scan-ab12    mac_addr=aa:bb:cc:00:00:01",
+            "  hostname=web01     mac_addr=aa:bb:cc:00:00:01",
+            "",
+            "Group 2",
+            "  hostname=db01      uuid=4c4c-02",
+            "  hostname=db01-old  uuid=4c4c-02",
+            "",
+            "Fold one into another with: mdb merge <selection> into <selection>",
+        ].join("\n"));
+    }
+
     #[test]
     fn test_should_handle_invalid_numeric_values_gracefully() {
         let result = format_human("mem_total_kb", "invalid");
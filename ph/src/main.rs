@@ -113,7 +113,7 @@ async fn main() -> Result<()> {
     }
 
     // Legacy fallback/Direct query support
-    let query_string = if let Some(_) = &cli.command {
+    let query_string = if cli.command.is_some() {
         String::new() 
     } else if !cli.query.is_empty() {
         pharos_client::join_wire_args(&cli.query)
@@ -102,7 +102,7 @@ mod tests {
         assert_eq!(nodes.len(), 1);
         assert!(nodes[0].ports.is_empty());
         assert!(nodes[0].role.is_none());
-        assert_eq!(nodes[0].is_existing, false);
+        assert!(!nodes[0].is_existing);
     }
 
     #[tokio::test]
@@ -35,10 +35,10 @@ impl OUIResolver {
             .collect::<String>()
             .to_uppercase();
         for &len in &[9usize, 7, 6] {
-            if normalized.len() >= len {
-                if let Some(org) = self.prefixes.get(&normalized[..len]) {
-                    return Some(org.clone());
-                }
+            if normalized.len() >= len
+                && let Some(org) = self.prefixes.get(&normalized[..len])
+            {
+                return Some(org.clone());
             }
         }
         None
@@ -116,15 +116,15 @@ pub async fn sync_discovered_device(
         "add type=\"machine\" {}=\"{}\" ip_addr=\"{}\"",
         field_name, identifier, node.ip
     );
-    if let Some(ref mac) = node.mac {
-        if !mac.trim().is_empty() {
-            add_cmd.push_str(&format!(" mac_addr=\"{}\"", mac));
-        }
+    if let Some(ref mac) = node.mac
+        && !mac.trim().is_empty()
+    {
+        add_cmd.push_str(&format!(" mac_addr=\"{}\"", mac));
     }
-    if let Some(ref manufacturer) = node.manufacturer {
-        if !manufacturer.trim().is_empty() {
-            add_cmd.push_str(&format!(" manufacturer=\"{}\"", manufacturer));
-        }
+    if let Some(ref manufacturer) = node.manufacturer
+        && !manufacturer.trim().is_empty()
+    {
+        add_cmd.push_str(&format!(" manufacturer=\"{}\"", manufacturer));
     }
 
     let write_resp = match client.execute_authenticated(&add_cmd).await {
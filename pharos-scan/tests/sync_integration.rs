@@ -23,7 +23,7 @@ use pharos_server::middleware::{MiddlewareChain, SecurityTierMiddleware};
 use pharos_server::storage::{MemoryStorage, Storage};
 
 use std::path::PathBuf;
-use std::sync::{Arc, OnceLock, RwLock};
+use std::sync::{Arc, OnceLock};
 use tempfile::tempdir;
 use tokio::net::TcpListener;
 use tokio_rustls::rustls::pki_types::PrivateKeyDer;
@@ -117,7 +117,7 @@ fn get_test_auth_assets() -> (PathBuf, String) {
     (assets.keys_dir.clone(), assets.priv_key_path.clone())
 }
 
-async fn setup_test_server() -> (String, Arc<RwLock<dyn Storage>>) {
+async fn setup_test_server() -> (String, Arc<dyn Storage>) {
     setup_test_server_with_tier(SecurityTier::Open).await
 }
 
@@ -131,7 +131,7 @@ async fn setup_test_server() -> (String, Arc<RwLock<dyn Storage>>) {
 // for both steps. This parameterized variant exists so a regression test can reproduce
 // that exact real-world scenario instead of only ever exercising the tier that happened
 // to mask the bug.
-async fn setup_test_server_with_tier(tier: SecurityTier) -> (String, Arc<RwLock<dyn Storage>>) {
+async fn setup_test_server_with_tier(tier: SecurityTier) -> (String, Arc<dyn Storage>) {
     let (acceptor, ca_path) = get_test_tls();
     let (keys_dir, priv_key_path) = get_test_auth_assets();
 
@@ -140,7 +140,7 @@ async fn setup_test_server_with_tier(tier: SecurityTier) -> (String, Arc<RwLock<
         std::env::set_var("PHAROS_PRIVATE_KEY", &priv_key_path);
     }
 
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(MemoryStorage::new()));
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
     let auth_manager = Arc::new(AuthManager::new(&keys_dir, tier));
 
     let mut chain = MiddlewareChain::new();
@@ -15,7 +15,7 @@
 use crate::storage::{Record, RecordType, Storage};
 use chrono::{DateTime, Utc};
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use tracing::{info, warn};
 
 /// Tracks which (hostname, last_seen_at) pairs have already triggered an alert, so a
@@ -71,7 +71,7 @@ pub fn find_newly_stale<'a>(
 /// Strips a single leading 'v'/'V' if present, so "v1.10.15" and "1.10.15"
 /// compare equal. Used only for comparison — never changes what's stored
 /// or displayed.
-fn normalize_version(v: &str) -> &str {
+pub(crate) fn normalize_version(v: &str) -> &str {
     v.strip_prefix(['v', 'V']).unwrap_or(v)
 }
 
@@ -156,7 +156,7 @@ pub fn fire_script(script_path: &str, hostname: &str, last_seen_at: &str) {
 /// fires configured alerts (detached, never blocking this function's caller), and updates
 /// `alert_state`. Does nothing if both `webhook_url` and `script_path` are `None`.
 pub async fn check_presence(
-    storage: &Arc<RwLock<dyn Storage>>,
+    storage: &Arc<dyn Storage>,
     alert_state: &mut AlertState,
     threshold_secs: i64,
     webhook_url: Option<&str>,
@@ -166,14 +166,11 @@ pub async fn check_presence(
         return;
     }
 
-    let records = {
-        let Ok(lock) = storage.read() else { return; };
-        match lock.query(&[], Some(RecordType::Machine)) {
-            Ok(r) => r,
-            Err(e) => {
-                warn!("Presence check: failed to query machine records: {}", e);
-                return;
-            }
+    let records = match storage.query(&[], Some(RecordType::Machine)).await {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("Presence check: failed to query machine records: {}", e);
+            return;
         }
     };
 
@@ -252,7 +249,7 @@ pub async fn fire_version_mismatch_webhook(
 /// Called once per health-monitor tick. Queries machine records, finds version mismatches,
 /// fires configured alerts, and updates `alert_state`.
 pub async fn check_version_mismatches(
-    storage: &Arc<RwLock<dyn Storage>>,
+    storage: &Arc<dyn Storage>,
     alert_state: &mut AlertState,
     webhook_url: Option<&str>,
     script_path: Option<&str>,
@@ -261,14 +258,11 @@ pub async fn check_version_mismatches(
         return;
     }
 
-    let records = {
-        let Ok(lock) = storage.read() else { return; };
-        match lock.query(&[], Some(RecordType::Machine)) {
-            Ok(r) => r,
-            Err(e) => {
-                warn!("Version mismatch check: failed to query machine records: {}", e);
-                return;
-            }
+    let records = match storage.query(&[], Some(RecordType::Machine)).await {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("Version mismatch check: failed to query machine records: {}", e);
+            return;
         }
     };
 
@@ -321,6 +315,9 @@ mod tests {
             multi_fields: StdHashMap::new(),
             owner_fingerprint: None,
             owner_team: None,
+            history: Vec::new(),
+            deleted_at: None,
+            revision: 0,
         }
     }
 
@@ -403,6 +400,9 @@ mod tests {
             multi_fields: StdHashMap::new(),
             owner_fingerprint: None,
             owner_team: None,
+            history: Vec::new(),
+            deleted_at: None,
+            revision: 0,
         };
 
         let alert_state = AlertState::default();
@@ -433,6 +433,9 @@ mod tests {
             multi_fields: StdHashMap::new(),
             owner_fingerprint: None,
             owner_team: None,
+            history: Vec::new(),
+            deleted_at: None,
+            revision: 0,
         };
 
         let alert_state = AlertState::default();
@@ -454,6 +457,9 @@ mod tests {
             multi_fields: StdHashMap::new(),
             owner_fingerprint: None,
             owner_team: None,
+            history: Vec::new(),
+            deleted_at: None,
+            revision: 0,
         };
 
         let alert_state = AlertState::default();
@@ -473,8 +479,8 @@ mod tests {
         fields2.insert("expected_version".to_string(), "v1.0.0".to_string());
 
         let records = vec![
-            Record { id: 1, record_type: Some(RecordType::Machine), fields: fields1, multi_fields: StdHashMap::new(), owner_fingerprint: None, owner_team: None },
-            Record { id: 2, record_type: Some(RecordType::Machine), fields: fields2, multi_fields: StdHashMap::new(), owner_fingerprint: None, owner_team: None },
+            Record { id: 1, record_type: Some(RecordType::Machine), fields: fields1, multi_fields: StdHashMap::new(), owner_fingerprint: None, owner_team: None, history: Vec::new(), deleted_at: None, revision: 0 },
+            Record { id: 2, record_type: Some(RecordType::Machine), fields: fields2, multi_fields: StdHashMap::new(), owner_fingerprint: None, owner_team: None, history: Vec::new(), deleted_at: None, revision: 0 },
         ];
 
         let alert_state = AlertState::default();
@@ -495,6 +501,9 @@ mod tests {
             multi_fields: StdHashMap::new(),
             owner_fingerprint: None,
             owner_team: None,
+            history: Vec::new(),
+            deleted_at: None,
+            revision: 0,
         };
 
         let mut alert_state = AlertState::default();
@@ -521,6 +530,9 @@ mod tests {
             multi_fields: StdHashMap::new(),
             owner_fingerprint: None,
             owner_team: None,
+            history: Vec::new(),
+            deleted_at: None,
+            revision: 0,
         };
 
         let mut alert_state = AlertState::default();
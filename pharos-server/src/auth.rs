@@ -18,7 +18,7 @@ use base64::{Engine as _, engine::general_purpose::STANDARD};
 use std::fs;
 use std::path::{Path, PathBuf};
 use tracing::{info, warn, error};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::sync::RwLock;
 use std::time::{Instant, Duration};
 use rand::rngs::OsRng;
@@ -132,6 +132,16 @@ fn log_load_summary(keys_dir: &Path, result: &LoadResult) {
     }
 }
 
+/// The SHA-256 fingerprints of the keys in `keys_dir` and every team they belong to: who a
+/// record can be owned by. Reads the directory only - unlike `AuthManager::new`, it never
+/// generates an admin key - so offline tools can check ownership against a stopped server's keys.
+pub fn known_owners(keys_dir: &Path) -> (BTreeSet<String>, BTreeSet<String>) {
+    let store = load_keys_from_dir(keys_dir).store;
+    let fingerprints = store.authorized_keys.iter().map(|k| k.fingerprint(ssh_key::HashAlg::Sha256).to_string()).collect();
+    let teams = store.key_teams.into_values().flatten().collect();
+    (fingerprints, teams)
+}
+
 pub struct AuthManager {
     keys_dir: PathBuf,
     store: RwLock<KeyStore>,
@@ -274,7 +284,7 @@ impl AuthManager {
         let mut roles = Vec::new();
         let mut teams = Vec::new();
         if let Some(filename) = path.file_stem().and_then(|s| s.to_str()) {
-            let tokens: Vec<&str> = filename.split(|c: char| c == '-' || c == '_').collect();
+            let tokens: Vec<&str> = filename.split(['-', '_']).collect();
 
             if tokens.contains(&"admin") {
                 roles.push("admin".to_string());
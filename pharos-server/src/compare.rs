@@ -0,0 +1,360 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/compare.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Selections used to be word/wildcard matches only, so "machines with at
+ * least 16 GB" or "not seen since October" could not be asked. A selection
+ * can now compare a field with `>`, `<`, `>=`, `<=` or `!=`. How two values
+ * compare depends on the field's value kind in the field schema: integers
+ * numerically, timestamps as points in time (including `now-2h` style
+ * relative times), versions as semver, enums in declaration order.
+ * * Traceability:
+ * Backs comparison selections in query/ph/mdb, change, delete and history.
+ * ======================================================================== */
+
+use crate::alerting::normalize_version;
+use crate::schema::{FieldDef, ValueKind};
+use crate::storage::StorageError;
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc};
+use std::cmp::Ordering;
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Operator spellings, two-character ones first so `>=` is never read as `>`.
+const OPERATORS: [(&str, Op); 5] = [("!=", Op::Ne), (">=", Op::Ge), ("<=", Op::Le), (">", Op::Gt), ("<", Op::Lt)];
+
+impl Op {
+    pub fn symbol(self) -> &'static str {
+        OPERATORS.iter().find(|(_, op)| *op == self).map_or("", |(symbol, _)| symbol)
+    }
+
+    fn accepts(self, ordering: Ordering) -> bool {
+        match self {
+            Op::Ne => ordering != Ordering::Equal,
+            Op::Lt => ordering == Ordering::Less,
+            Op::Le => ordering != Ordering::Greater,
+            Op::Gt => ordering == Ordering::Greater,
+            Op::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+/// Splits a query token into a `(field, value)` selection, or `None` for a bare word. A
+/// comparison keeps its operator on the field (`cpu_cores>8` becomes `("cpu_cores>", "8")`),
+/// so selections keep their shape everywhere and only the matcher needs to look closer. Only
+/// a field name (ASCII letters, digits and `_`) can start a comparison, so a word such as
+/// `web-01>lb` stays a bare word.
+pub fn parse_selection(token: &str) -> Option<(String, String)> {
+    let pos = token.find(['=', '<', '>', '!'])?;
+    if is_field_name(&token[..pos])
+        && let Some((symbol, _)) = OPERATORS.iter().find(|(symbol, _)| token[pos..].starts_with(symbol))
+    {
+        let end = pos + symbol.len();
+        return Some((token[..end].to_string(), token[end..].to_string()));
+    }
+    let (key, value) = token.split_once('=')?;
+    Some((key.to_string(), value.to_string()))
+}
+
+/// The field and operator of a comparison selection's key, or `None` for a plain `field=value`.
+pub fn split_key(key: &str) -> Option<(&str, Op)> {
+    OPERATORS.iter().find_map(|(symbol, op)| {
+        key.strip_suffix(symbol).filter(|field| is_field_name(field)).map(|field| (field, *op))
+    })
+}
+
+fn is_field_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+/// Whether a selection key is a comparison, which the word indexes and pushed-down filters
+/// can't answer.
+pub fn is_comparison(key: Option<&str>) -> bool {
+    key.and_then(split_key).is_some()
+}
+
+/// A dotted version with an optional pre-release, compared the semver way: numerically part by
+/// part (missing parts count as 0), and a pre-release before its release. A leading `v` and any
+/// `+build` suffix are ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    parts: Vec<u64>,
+    pre: Option<String>,
+}
+
+impl Version {
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = normalize_version(value.trim());
+        let value = value.split_once('+').map_or(value, |(v, _)| v);
+        let (core, pre) = match value.split_once('-') {
+            Some((core, pre)) if !pre.is_empty() => (core, Some(pre.to_string())),
+            Some(_) => return None,
+            None => (value, None),
+        };
+        let parts = core.split('.').map(|p| p.parse::<u64>().ok()).collect::<Option<Vec<_>>>()?;
+        Some(Self { parts, pre })
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let len = self.parts.len().max(other.parts.len());
+        let part = |v: &Self, i: usize| v.parts.get(i).copied().unwrap_or(0);
+        (0..len)
+            .map(|i| part(self, i).cmp(&part(other, i)))
+            .find(|o| o.is_ne())
+            .unwrap_or_else(|| match (&self.pre, &other.pre) {
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(_), None) => Ordering::Less,
+                (Some(a), Some(b)) => compare_pre_release(a, b),
+            })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Semver pre-release precedence: dot-separated identifiers, numeric ones compared as numbers
+/// and ranked below alphanumeric ones, a shorter list first when all else is equal.
+fn compare_pre_release(a: &str, b: &str) -> Ordering {
+    let mut a_ids = a.split('.');
+    let mut b_ids = b.split('.');
+    loop {
+        let ordering = match (a_ids.next(), b_ids.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => match (x.parse::<u64>(), y.parse::<u64>()) {
+                (Ok(x), Ok(y)) => x.cmp(&y),
+                (Ok(_), Err(_)) => Ordering::Less,
+                (Err(_), Ok(_)) => Ordering::Greater,
+                (Err(_), Err(_)) => x.cmp(y),
+            },
+        };
+        if ordering.is_ne() {
+            return ordering;
+        }
+    }
+}
+
+/// Reads a point in time: RFC 3339, a date-time without offset or a bare date (both taken as
+/// UTC), or `now` optionally followed by `+`/`-` and an amount in s, m, h, d or w (`now-2h`).
+pub fn parse_time(value: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    if let Some(offset) = value.strip_prefix("now") {
+        if offset.is_empty() {
+            return Some(now);
+        }
+        let (sign, amount) = match offset.split_at(1) {
+            ("+", amount) => (1, amount),
+            ("-", amount) => (-1, amount),
+            _ => return None,
+        };
+        let (count, unit) = amount.split_at(amount.len().checked_sub(1)?);
+        let count: i64 = count.parse().ok()?;
+        let step = match unit {
+            "s" => Duration::try_seconds(count),
+            "m" => Duration::try_minutes(count),
+            "h" => Duration::try_hours(count),
+            "d" => Duration::try_days(count),
+            "w" => Duration::try_weeks(count),
+            _ => None,
+        }?;
+        return if sign > 0 { now.checked_add_signed(step) } else { now.checked_sub_signed(step) };
+    }
+
+    if let Ok(t) = DateTime::parse_from_rfc3339(value) {
+        return Some(t.with_timezone(&Utc));
+    }
+    if let Ok(t) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S") {
+        return Some(t.and_utc());
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d").ok().and_then(|d| d.and_hms_opt(0, 0, 0)).map(|t| t.and_utc())
+}
+
+/// The operand of a comparison, read once as whatever the field's kind compares as.
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Int(i64),
+    Number(f64),
+    Time(DateTime<Utc>),
+    Version(Version),
+    Ip(IpAddr),
+    Bool(bool),
+    /// Position in an enum field's declared values.
+    Rank(usize),
+    Text(String),
+}
+
+/// One comparison selection, ready to test stored values against.
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    op: Op,
+    operand: Operand,
+    values: Vec<String>,
+}
+
+impl Comparison {
+    /// Reads `operand` for a field declared as `def`. A field without a declaration (or a plain
+    /// string field) compares as a version when the operand is dotted (`1.10`), else as a
+    /// number, a time or a version when the operand reads as one, and as case-insensitive text
+    /// otherwise. An operand the declared kind can't read is an
+    /// error rather than a query that silently matches nothing.
+    pub fn new(field: &str, def: Option<&FieldDef>, op: Op, operand: &str, now: DateTime<Utc>) -> Result<Self, StorageError> {
+        let kind = def.map_or(ValueKind::String, |d| d.kind);
+        let values = def.map(|d| d.values.clone()).unwrap_or_default();
+        let raw = operand.trim();
+        let illegal = |expected: &str| {
+            StorageError::InvalidArgument(format!(
+                "cannot compare field '{}' {} '{}': expected {}",
+                field, op.symbol(), operand, expected
+            ))
+        };
+
+        let operand = match kind {
+            ValueKind::Int => Operand::Int(raw.parse().map_err(|_| illegal("an integer"))?),
+            ValueKind::Timestamp => Operand::Time(parse_time(raw, now).ok_or_else(|| illegal("a timestamp, date or now-<n><s|m|h|d|w>"))?),
+            ValueKind::Version => Operand::Version(Version::parse(raw).ok_or_else(|| illegal("a version"))?),
+            ValueKind::Ip => Operand::Ip(raw.parse().map_err(|_| illegal("an IP address"))?),
+            ValueKind::Bool => Operand::Bool(parse_bool(raw).ok_or_else(|| illegal("true or false"))?),
+            ValueKind::Enum => Operand::Rank(values.iter().position(|v| v == raw).ok_or_else(|| illegal(&format!("one of {}", values.join(", "))))?),
+            ValueKind::Mac | ValueKind::Link => Operand::Text(raw.to_lowercase()),
+            ValueKind::String => infer(raw, now),
+        };
+        Ok(Self { op, operand, values })
+    }
+
+    /// Whether a stored value satisfies the comparison. A value that can't be read the way the
+    /// operand was never matches, whatever the operator.
+    pub fn matches(&self, stored: &str, now: DateTime<Utc>) -> bool {
+        let stored = stored.trim();
+        let ordering = match &self.operand {
+            Operand::Int(operand) => stored.parse::<i64>().ok().map(|v| v.cmp(operand)),
+            Operand::Number(operand) => stored.parse::<f64>().ok().and_then(|v| v.partial_cmp(operand)),
+            Operand::Time(operand) => parse_time(stored, now).map(|v| v.cmp(operand)),
+            Operand::Version(operand) => Version::parse(stored).map(|v| v.cmp(operand)),
+            Operand::Ip(operand) => stored.parse::<IpAddr>().ok().map(|v| v.cmp(operand)),
+            Operand::Bool(operand) => parse_bool(stored).map(|v| v.cmp(operand)),
+            Operand::Rank(operand) => self.values.iter().position(|v| v == stored).map(|v| v.cmp(operand)),
+            Operand::Text(operand) => Some(stored.to_lowercase().cmp(operand)),
+        };
+        ordering.is_some_and(|o| self.op.accepts(o))
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    ["false", "true"].iter().position(|b| value.eq_ignore_ascii_case(b)).map(|i| i == 1)
+}
+
+fn infer(raw: &str, now: DateTime<Utc>) -> Operand {
+    // A dotted operand such as `1.10` reads as a version before a number: as a number it would
+    // sort below `1.9`.
+    let dotted = normalize_version(raw).split(['-', '+']).next().is_some_and(|core| core.contains('.'));
+    if dotted && let Some(v) = Version::parse(raw) {
+        return Operand::Version(v);
+    }
+    if let Ok(n) = raw.parse::<f64>() {
+        return Operand::Number(n);
+    }
+    if let Some(t) = parse_time(raw, now) {
+        return Operand::Time(t);
+    }
+    match Version::parse(raw) {
+        Some(v) => Operand::Version(v),
+        None => Operand::Text(raw.to_lowercase()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        parse_time("2026-10-17T12:00:00Z", Utc::now()).unwrap()
+    }
+
+    fn holds(def: Option<&FieldDef>, stored: &str, op: Op, operand: &str) -> bool {
+        Comparison::new("f", def, op, operand, now()).unwrap().matches(stored, now())
+    }
+
+    #[test]
+    fn test_should_parse_operators_onto_the_selection_key() {
+        let parsed = |t: &str| parse_selection(t).unwrap();
+        assert_eq!(parsed("cpu_cores>8"), ("cpu_cores>".to_string(), "8".to_string()));
+        assert_eq!(parsed("mem>=16"), ("mem>=".to_string(), "16".to_string()));
+        assert_eq!(parsed("status!=active"), ("status!=".to_string(), "active".to_string()));
+        assert_eq!(parsed("name=a<b"), ("name".to_string(), "a<b".to_string()));
+        assert_eq!(parse_selection("plainword"), None);
+        assert_eq!(parse_selection("web-01>lb"), None, "only a field name starts a comparison");
+        // With `=` in it, such a word is the plain `field=value` it always was.
+        assert_eq!(parsed("x.y>=3"), ("x.y>".to_string(), "3".to_string()));
+        assert_eq!(split_key("x.y>"), None);
+
+        assert_eq!(split_key("mem>="), Some(("mem", Op::Ge)));
+        assert_eq!(split_key("last_seen_at<"), Some(("last_seen_at", Op::Lt)));
+        assert_eq!(split_key("hostname"), None);
+        assert_eq!(split_key(">"), None);
+    }
+
+    #[test]
+    fn test_should_compare_by_declared_kind() {
+        let int = FieldDef { kind: ValueKind::Int, ..schema_def() };
+        assert!(holds(Some(&int), "16", Op::Gt, "8"));
+        assert!(!holds(Some(&int), "9", Op::Gt, "10"), "numeric, not lexicographic");
+        assert!(!holds(Some(&int), "lots", Op::Gt, "8"));
+        assert!(Comparison::new("f", Some(&int), Op::Gt, "eight", now()).is_err());
+
+        let version = FieldDef { kind: ValueKind::Version, ..schema_def() };
+        assert!(holds(Some(&version), "v1.10.0", Op::Gt, "1.9.3"));
+        assert!(holds(Some(&version), "2.0.0-rc.1", Op::Lt, "2.0.0"));
+        assert!(holds(Some(&version), "2.0.0-rc.2", Op::Gt, "2.0.0-rc.1"));
+        assert!(holds(Some(&version), "1.2", Op::Ge, "v1.2.0"));
+
+        let env = FieldDef { kind: ValueKind::Enum, values: vec!["dev".into(), "staging".into(), "prod".into()], ..schema_def() };
+        assert!(holds(Some(&env), "prod", Op::Gt, "staging"));
+        assert!(Comparison::new("f", Some(&env), Op::Gt, "qa", now()).is_err());
+    }
+
+    #[test]
+    fn test_should_compare_timestamps_including_relative_times() {
+        let ts = FieldDef { kind: ValueKind::Timestamp, ..schema_def() };
+        assert!(holds(Some(&ts), "2026-09-30T23:59:59+00:00", Op::Lt, "2026-10-01"));
+        assert!(holds(Some(&ts), "2026-10-17T11:00:00Z", Op::Lt, "now-30m"));
+        assert!(!holds(Some(&ts), "2026-10-17T11:00:00Z", Op::Lt, "now-2h"));
+        assert!(holds(Some(&ts), "2026-10-17T11:00:00Z", Op::Ge, "now-1d"));
+        assert!(Comparison::new("f", Some(&ts), Op::Lt, "now-2x", now()).is_err());
+    }
+
+    #[test]
+    fn test_should_infer_how_to_compare_undeclared_fields() {
+        assert!(holds(None, "16384000", Op::Ge, "16000000"));
+        assert!(holds(None, "2026-01-01T00:00:00Z", Op::Lt, "now"));
+        assert!(holds(None, "1.10.2", Op::Gt, "1.9.0"));
+        assert!(holds(None, "1.10", Op::Ge, "1.9"), "a two-part version is not a decimal");
+        assert!(!holds(None, "1.9", Op::Ge, "1.10"));
+        assert!(holds(None, "v1.10", Op::Gt, "1.9"));
+        assert!(holds(None, "2026-10-17T11:59:59.5Z", Op::Lt, "2026-10-17T12:00:00.5Z"));
+        assert!(holds(None, "Active", Op::Ne, "offline"));
+        assert!(!holds(None, "Active", Op::Ne, "active"));
+        assert!(!holds(None, "n/a", Op::Lt, "100"), "a non-number never compares with a number");
+    }
+
+    fn schema_def() -> FieldDef {
+        toml::from_str("").unwrap()
+    }
+}
@@ -0,0 +1,284 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/expiry.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * The scanner files every phone, guest laptop and DHCP lease it sees, and
+ * nothing ever removed them. A record can now expire: a per-source TTL
+ * (`PHAROS_TTL_BY_SOURCE`, e.g. scan records 14 days after `last_seen_at`)
+ * or its own `ttl` field says how long it lives without being seen again,
+ * and the reaper deletes whatever has run out - leaving a tombstone,
+ * replicating the delete and notifying like any other `delete`.
+ * * Traceability:
+ * `reap` runs in main.rs's monitor loop; counted by `pharos_records_reaped_total`.
+ * ======================================================================== */
+
+use crate::index;
+use crate::protocol::{self, Command};
+use crate::storage::{Record, RecordGuard, Storage, StorageError};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+
+/// The per-record field that overrides its source's TTL; `never` keeps the record for good.
+pub const TTL_FIELD: &str = "ttl";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExpiryConfig {
+    /// How long a record from each source lives after it was last seen.
+    pub by_source: BTreeMap<String, Duration>,
+}
+
+impl ExpiryConfig {
+    /// Reads `PHAROS_TTL_BY_SOURCE`, comma-separated `source=ttl` pairs such as
+    /// `pharos-scan=14d`. A pair that doesn't parse is skipped with a warning rather than
+    /// refusing to start.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Ok(raw) = std::env::var("PHAROS_TTL_BY_SOURCE") {
+            for pair in raw.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+                match pair.split_once('=').and_then(|(source, ttl)| Some((source.trim(), parse_ttl(ttl)?))) {
+                    Some((source, ttl)) if !source.is_empty() => {
+                        config.by_source.insert(source.to_string(), ttl);
+                    }
+                    _ => warn!("Ignoring invalid PHAROS_TTL_BY_SOURCE entry '{}'", pair),
+                }
+            }
+        }
+        config
+    }
+
+    /// How long `record` lives after it was last seen: its own `ttl` field if it has one, else
+    /// its source's. `None` when it never expires, including when its `ttl` doesn't parse.
+    pub fn ttl_of(&self, record: &Record) -> Option<Duration> {
+        if let Some(ttl) = record.fields.get(TTL_FIELD) {
+            return parse_ttl(ttl);
+        }
+        record.fields.get("source").and_then(|source| self.by_source.get(source)).copied()
+    }
+
+    /// When `record` expires: its TTL after `last_seen_at`, or after `created_at` if it was
+    /// never seen. A record it can't date doesn't expire - deleting is the one thing the reaper
+    /// does, so it only acts on what it can date.
+    pub fn expires_at(&self, record: &Record) -> Option<DateTime<Utc>> {
+        let ttl = self.ttl_of(record)?;
+        let seen = record.fields.get("last_seen_at").or_else(|| record.fields.get("created_at"))?;
+        let seen = DateTime::parse_from_rfc3339(seen).ok()?.with_timezone(&Utc);
+        Some(seen + ttl)
+    }
+
+    /// Whether `record` is live and has expired by `now`.
+    pub fn has_expired(&self, record: &Record, now: DateTime<Utc>) -> bool {
+        record.deleted_at.is_none() && self.expires_at(record).is_some_and(|at| at <= now)
+    }
+
+    /// The live records in `records` that have expired by `now`.
+    pub fn find_expired<'a>(&self, records: &'a [Record], now: DateTime<Utc>) -> Vec<&'a Record> {
+        records.iter().filter(|r| self.has_expired(r, now)).collect()
+    }
+
+    /// The selections that find every record that could expire: those with a `ttl` of their
+    /// own, and those from each source with one.
+    fn candidate_selections(&self) -> Vec<(Option<String>, String)> {
+        std::iter::once((Some(TTL_FIELD.to_string()), "*".to_string()))
+            .chain(self.by_source.keys().map(|source| (Some("source".to_string()), source.clone())))
+            .collect()
+    }
+}
+
+/// Parses a TTL such as `14d`, `12h`, `30m` or `90s`; `never` (and anything else) is `None`.
+pub fn parse_ttl(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let unit = value.chars().last()?;
+    let count: i64 = value[..value.len() - unit.len_utf8()].parse().ok().filter(|n| *n > 0)?;
+    match unit {
+        'd' => Duration::try_days(count),
+        'h' => Duration::try_hours(count),
+        'm' => Duration::try_minutes(count),
+        's' => Duration::try_seconds(count),
+        _ => None,
+    }
+}
+
+/// The selection a reaped record is deleted by - its hostname, else its alias - provided it picks
+/// out that record alone.
+async fn exact_selection(storage: &Arc<dyn Storage>, record: &Record) -> Option<(Option<String>, String)> {
+    let (key, value) = ["hostname", "alias"].into_iter().find_map(|k| record.fields.get(k).map(|v| (k, v)))?;
+    let selection = (Some(key.to_string()), value.clone());
+    match storage.query(std::slice::from_ref(&selection), None).await {
+        Ok(matched) if matched.len() == 1 && matched[0].id == record.id => Some(selection),
+        _ => None,
+    }
+}
+
+/// The live records that could expire, each once - not the whole directory, which with nothing
+/// configured and no record carrying a `ttl` comes to nothing at all.
+async fn candidates(storage: &Arc<dyn Storage>, config: &ExpiryConfig) -> Result<Vec<Record>, StorageError> {
+    let mut found = BTreeMap::new();
+    for selection in config.candidate_selections() {
+        for record in storage.query(std::slice::from_ref(&selection), None).await? {
+            found.insert(record.id, record);
+        }
+    }
+    Ok(found.into_values().collect())
+}
+
+/// Deletes every live record that has expired by `now`, as its owner would, so each leaves a
+/// tombstone. Expiry is checked again as the delete runs, so a record seen in the meantime (a
+/// heartbeat bumping `last_seen_at`, a new `ttl`) is left alone. Each delete is replicated to
+/// peers (when `my_addr` is set and its selection means the same there) and notified like a
+/// `delete` command. Returns how many records were reaped.
+pub async fn reap(storage: &Arc<dyn Storage>, config: &ExpiryConfig, now: DateTime<Utc>, my_addr: &str) -> usize {
+    let records = match candidates(storage, config).await {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("Expiry: failed to query records: {}", e);
+            return 0;
+        }
+    };
+    let expired = config.find_expired(&records, now);
+    if expired.is_empty() {
+        return 0;
+    }
+
+    let schema = storage.field_schema().await;
+    let shared = Arc::new(config.clone());
+    let mut reaped = 0;
+    for record in expired {
+        let Some(selection) = exact_selection(storage, record).await else {
+            warn!("Expiry: record #{} has expired but no hostname or alias selects it alone; leaving it", record.id);
+            continue;
+        };
+        let teams: Vec<String> = record.owner_team.iter().cloned().collect();
+        let selections = vec![selection];
+        let (config, id) = (Arc::clone(&shared), record.id);
+        let still: RecordGuard = Arc::new(move |r: &Record| r.id == id && config.has_expired(r, now));
+        match storage.delete_record_if(&selections, record.owner_fingerprint.clone(), &teams, still).await {
+            Ok(0) => debug!("Expiry: record #{} changed before it could be reaped; leaving it", record.id),
+            Ok(count) => {
+                reaped += count;
+                let source = record.fields.get("source").map(String::as_str).unwrap_or("unknown");
+                crate::metrics::RECORDS_REAPED_TOTAL.with_label_values(&[source]).inc_by(count as u64);
+                let (key, value) = &selections[0];
+                info!("Expiry: reaped {}={}", key.as_deref().unwrap_or_default(), value);
+
+                if !my_addr.is_empty() {
+                    match replicable_delete(&selections[0]) {
+                        Some(command) => {
+                            let storage = Arc::clone(storage);
+                            let my_addr = my_addr.to_string();
+                            tokio::spawn(async move {
+                                crate::sync::replicate_command(storage, command, my_addr).await;
+                            });
+                        }
+                        None => warn!(
+                            "Expiry: {}={} would select differently on a peer; not replicating its delete",
+                            key.as_deref().unwrap_or_default(),
+                            value
+                        ),
+                    }
+                }
+                crate::notifications::notify(crate::notifications::NotificationEvent::Delete { selections, count }.redacted(&schema));
+            }
+            Err(e) => warn!("Expiry: failed to delete record #{}: {}", record.id, e),
+        }
+    }
+    reaped
+}
+
+/// The `delete` line that selects on a peer exactly what `selection` selects here, or `None`
+/// when its value wouldn't survive the trip as one literal word: whitespace would split it into
+/// several selections and a wildcard character would make it a pattern.
+fn replicable_delete(selection: &(Option<String>, String)) -> Option<String> {
+    let (key, value) = selection;
+    if value.is_empty() || index::is_wildcard_word(value) || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        return None;
+    }
+    let command = format!("delete {}={}", key.as_deref()?, value);
+    match protocol::parse_command(&command) {
+        Ok(Command::Delete(parsed)) if parsed == [selection.clone()] => Some(command),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn record(id: usize, fields: &[(&str, &str)]) -> Record {
+        Record {
+            id,
+            fields: fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn scan_config() -> ExpiryConfig {
+        ExpiryConfig { by_source: BTreeMap::from([("pharos-scan".to_string(), Duration::days(14))]) }
+    }
+
+    #[test]
+    fn test_should_parse_ttls_with_a_unit() {
+        assert_eq!(parse_ttl("14d"), Some(Duration::days(14)));
+        assert_eq!(parse_ttl(" 12h "), Some(Duration::hours(12)));
+        assert_eq!(parse_ttl("30m"), Some(Duration::minutes(30)));
+        assert_eq!(parse_ttl("90s"), Some(Duration::seconds(90)));
+        for bad in ["never", "14", "0d", "-1d", "d", "", "14w"] {
+            assert_eq!(parse_ttl(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_should_expire_by_source_unless_the_record_sets_its_own_ttl() {
+        let config = scan_config();
+        let now = DateTime::parse_from_rfc3339("2026-06-30T00:00:00+00:00").unwrap().with_timezone(&Utc);
+        let records = vec![
+            record(1, &[("alias", "scan-old"), ("source", "pharos-scan"), ("last_seen_at", "2026-06-01T00:00:00+00:00")]),
+            record(2, &[("alias", "scan-new"), ("source", "pharos-scan"), ("last_seen_at", "2026-06-20T00:00:00+00:00")]),
+            record(3, &[("alias", "scan-kept"), ("source", "pharos-scan"), ("last_seen_at", "2026-06-01T00:00:00+00:00"), ("ttl", "never")]),
+            record(4, &[("hostname", "web01"), ("source", "mdb"), ("last_seen_at", "2025-01-01T00:00:00+00:00")]),
+            record(5, &[("hostname", "guest"), ("source", "mdb"), ("created_at", "2026-06-29T00:00:00+00:00"), ("ttl", "12h")]),
+            record(6, &[("alias", "scan-undated"), ("source", "pharos-scan"), ("last_seen_at", "yesterday")]),
+        ];
+        let expired: Vec<usize> = config.find_expired(&records, now).iter().map(|r| r.id).collect();
+        assert_eq!(expired, vec![1, 5]);
+    }
+
+    #[test]
+    fn test_should_replicate_a_reaped_delete_only_when_its_value_is_one_literal_word() {
+        let selection = |key: &str, value: &str| (Some(key.to_string()), value.to_string());
+        assert_eq!(replicable_delete(&selection("alias", "scan-phone")), Some("delete alias=scan-phone".to_string()));
+        for value in ["web*", "web?1", "guest laptop", "a\"b", "c:\\temp", ""] {
+            assert_eq!(replicable_delete(&selection("hostname", value)), None, "{:?}", value);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_should_reap_expired_records_to_tombstones() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        for (alias, owner) in [("scan-phone", Some("SHA256:scanner")), ("scan-printer", None)] {
+            let fields = vec![
+                ("type".to_string(), "machine".to_string()),
+                ("alias".to_string(), alias.to_string()),
+                ("source".to_string(), "pharos-scan".to_string()),
+            ];
+            storage.add_record(fields, owner.map(str::to_string), None).await.unwrap();
+        }
+        storage
+            .add_record(vec![("type".to_string(), "machine".to_string()), ("hostname".to_string(), "web01".to_string())], None, None)
+            .await
+            .unwrap();
+
+        assert_eq!(reap(&storage, &scan_config(), Utc::now(), "").await, 0, "just seen");
+        let later = Utc::now() + Duration::days(15);
+        assert_eq!(reap(&storage, &scan_config(), later, "").await, 2);
+        let left: Vec<String> = storage.query(&[], None).await.unwrap().iter().filter_map(|r| r.fields.get("hostname").cloned()).collect();
+        assert_eq!(left, vec!["web01"]);
+        assert_eq!(storage.tombstones(&[]).await.unwrap().len(), 2);
+        assert_eq!(reap(&storage, &scan_config(), later, "").await, 0);
+    }
+}
@@ -0,0 +1,261 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/export.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Getting records out of Pharos meant a `ph` query per type and a script to
+ * scrape the output, and getting them in meant one `mdb add` per record.
+ * Records now export to and import from two formats: JSON Lines, one whole
+ * record per line - id, ownership, history and tombstones included - for
+ * backups and moving a directory between servers, and CSV, one live record
+ * per row with a column per field, for spreadsheets and other inventories.
+ * * Traceability:
+ * Called from offline.rs (`pharos-server export` and `import`).
+ * ======================================================================== */
+
+use crate::storage::Record;
+use anyhow::{Context, bail};
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::Path;
+
+/// Separates the values of a multi-valued field inside one CSV cell. Values never hold a line
+/// break - the protocol is line-based - so splitting on it is lossless.
+const MULTI_VALUE_SEPARATOR: &str = "\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One JSON record per line, exactly as stored.
+    JsonLines,
+    /// A header of field names, then one live record per row.
+    Csv,
+}
+
+impl Format {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "jsonl" | "json-lines" | "ndjson" => Some(Format::JsonLines),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+
+    /// The format a file name's extension names, if any.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension().and_then(|ext| Self::parse(&ext.to_string_lossy()))
+    }
+}
+
+/// Writes every record in `records`, tombstones included, as JSON Lines in id order. Returns how
+/// many were written.
+pub fn write_jsonl(records: &[Record], out: &mut dyn Write) -> anyhow::Result<usize> {
+    let mut sorted: Vec<&Record> = records.iter().collect();
+    sorted.sort_by_key(|r| r.id);
+    for record in &sorted {
+        serde_json::to_writer(&mut *out, record)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(sorted.len())
+}
+
+/// Parses JSON Lines `text`; blank lines are skipped. The first line that isn't a record is an
+/// error naming it.
+pub fn read_jsonl(text: &str) -> anyhow::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for (index, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        records.push(serde_json::from_str(line).with_context(|| format!("line {} is not a record", index + 1))?);
+    }
+    Ok(records)
+}
+
+/// Writes the live records in `records` as CSV in id order: an `id` and a `type` column, then
+/// one per field any of them has, alphabetically. A multi-valued field's values share one cell,
+/// a line apiece. Returns how many rows were written.
+pub fn write_csv(records: &[Record], out: &mut dyn Write) -> anyhow::Result<usize> {
+    let mut live: Vec<&Record> = records.iter().filter(|r| r.deleted_at.is_none()).collect();
+    live.sort_by_key(|r| r.id);
+    let names: BTreeSet<&str> = live
+        .iter()
+        .flat_map(|r| r.fields.keys().chain(r.multi_fields.keys()))
+        .map(String::as_str)
+        .filter(|name| *name != "type")
+        .collect();
+    let header: Vec<&str> = ["id", "type"].into_iter().chain(names.iter().copied()).collect();
+    write_row(out, header.iter().copied())?;
+
+    for record in &live {
+        let cells: Vec<String> = header
+            .iter()
+            .map(|name| match *name {
+                "id" => record.id.to_string(),
+                name => match (record.fields.get(name), record.multi_fields.get(name)) {
+                    (Some(value), _) => value.clone(),
+                    (None, Some(values)) => values.join(MULTI_VALUE_SEPARATOR),
+                    (None, None) => String::new(),
+                },
+            })
+            .collect();
+        write_row(out, cells.iter().map(String::as_str))?;
+    }
+    Ok(live.len())
+}
+
+fn write_row<'a>(out: &mut dyn Write, cells: impl Iterator<Item = &'a str>) -> std::io::Result<()> {
+    let row: Vec<String> = cells.map(quote).collect();
+    out.write_all(row.join(",").as_bytes())?;
+    out.write_all(b"\r\n")
+}
+
+/// A CSV cell per RFC 4180: quoted, with quotes doubled, when it holds a comma, quote or line break.
+fn quote(cell: &str) -> String {
+    if cell.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", cell.replace('"', "\"\""))
+    } else {
+        cell.to_string()
+    }
+}
+
+/// Parses CSV `text` into one list of `(field, value)` pairs per row, ready to upsert: the `id`
+/// column is dropped (ids are the target's to hand out), empty cells are skipped, and a cell
+/// holding several lines gives one pair per line, as a multi-valued field's values.
+pub fn read_csv(text: &str) -> anyhow::Result<Vec<Vec<(String, String)>>> {
+    let mut rows = parse_csv(text)?.into_iter();
+    let Some(header) = rows.next() else {
+        return Ok(Vec::new());
+    };
+    if !header.iter().any(|name| name == "type") {
+        bail!("the header has no type column");
+    }
+    let mut records = Vec::new();
+    for (index, row) in rows.enumerate() {
+        if row.len() != header.len() {
+            bail!("row {} has {} cells but the header has {}", index + 2, row.len(), header.len());
+        }
+        let fields: Vec<(String, String)> = header
+            .iter()
+            .zip(&row)
+            .filter(|(name, _)| *name != "id")
+            .flat_map(|(name, cell)| cell.split(MULTI_VALUE_SEPARATOR).map(move |value| (name.clone(), value.trim_end_matches('\r').to_string())))
+            .filter(|(_, value)| !value.is_empty())
+            .collect();
+        if !fields.is_empty() {
+            records.push(fields);
+        }
+    }
+    Ok(records)
+}
+
+/// Splits RFC 4180 CSV into rows of cells. Rows end at CRLF or a bare LF; blank lines are skipped.
+fn parse_csv(text: &str) -> anyhow::Result<Vec<Vec<String>>> {
+    let mut rows = Vec::new();
+    let (mut row, mut cell) = (Vec::new(), String::new());
+    let (mut quoted, mut was_quoted) = (false, false);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                cell.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, c) => cell.push(c),
+            (false, '"') if cell.is_empty() && !was_quoted => (quoted, was_quoted) = (true, true),
+            (false, ',') => {
+                row.push(std::mem::take(&mut cell));
+                was_quoted = false;
+            }
+            (false, '\r') if chars.peek() == Some(&'\n') => {}
+            (false, '\n') => {
+                row.push(std::mem::take(&mut cell));
+                if row.len() > 1 || !row[0].is_empty() || was_quoted {
+                    rows.push(std::mem::take(&mut row));
+                }
+                row.clear();
+                was_quoted = false;
+            }
+            (false, c) => cell.push(c),
+        }
+    }
+    if quoted {
+        bail!("a quoted cell never closes");
+    }
+    if !cell.is_empty() || !row.is_empty() || was_quoted {
+        row.push(cell);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn record(id: usize, fields: &[(&str, &str)], multi: &[(&str, &[&str])]) -> Record {
+        Record {
+            id,
+            fields: fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            multi_fields: multi.iter().map(|(k, vs)| (k.to_string(), vs.iter().map(|v| v.to_string()).collect())).collect::<HashMap<_, _>>(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_should_round_trip_whole_records_through_json_lines() {
+        let mut gone = record(2, &[("type", "machine"), ("hostname", "old01")], &[]);
+        gone.deleted_at = Some("2026-10-01T00:00:00+00:00".to_string());
+        gone.owner_fingerprint = Some("SHA256:abc".to_string());
+        let records = vec![gone, record(1, &[("type", "machine"), ("hostname", "web01")], &[("ip_addr", &["10.0.0.5", "10.0.1.5"])])];
+
+        let mut out = Vec::new();
+        assert_eq!(write_jsonl(&records, &mut out).unwrap(), 2);
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(text.lines().count(), 2);
+
+        let read = read_jsonl(&format!("{}\n", text)).unwrap();
+        assert_eq!(read.iter().map(|r| r.id).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(read[0].multi_fields["ip_addr"], vec!["10.0.0.5", "10.0.1.5"]);
+        assert_eq!(read[1].owner_fingerprint.as_deref(), Some("SHA256:abc"));
+        assert!(read[1].deleted_at.is_some());
+
+        let first = text.lines().next().unwrap();
+        let err = read_jsonl(&format!("{}\nnot json\n", first)).unwrap_err().to_string();
+        assert!(err.contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn test_should_write_live_records_as_csv_and_read_them_back_as_fields() {
+        let mut gone = record(3, &[("type", "machine"), ("hostname", "old01")], &[]);
+        gone.deleted_at = Some("2026-10-01T00:00:00+00:00".to_string());
+        let records = vec![
+            record(1, &[("type", "machine"), ("hostname", "web01"), ("notes", "rack 4, \"top\" shelf")], &[("ip_addr", &["10.0.0.5", "10.0.1.5"])]),
+            record(2, &[("type", "person"), ("alias", "rdavis")], &[]),
+            gone,
+        ];
+
+        let mut out = Vec::new();
+        assert_eq!(write_csv(&records, &mut out).unwrap(), 2);
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("id,type,alias,hostname,ip_addr,notes\r\n"), "{}", text);
+        assert!(text.contains("\"10.0.0.5\n10.0.1.5\",\"rack 4, \"\"top\"\" shelf\""), "{}", text);
+
+        let rows = read_csv(&text).unwrap();
+        assert_eq!(
+            rows[0],
+            vec![
+                ("type".to_string(), "machine".to_string()),
+                ("hostname".to_string(), "web01".to_string()),
+                ("ip_addr".to_string(), "10.0.0.5".to_string()),
+                ("ip_addr".to_string(), "10.0.1.5".to_string()),
+                ("notes".to_string(), "rack 4, \"top\" shelf".to_string()),
+            ]
+        );
+        assert_eq!(rows[1], vec![("type".to_string(), "person".to_string()), ("alias".to_string(), "rdavis".to_string())]);
+
+        assert!(read_csv("hostname\nweb01\n").is_err(), "no type column");
+        assert!(read_csv("type,hostname\nmachine\n").is_err(), "short row");
+        assert!(read_csv("type,hostname\nmachine,\"web01\n").is_err(), "unclosed quote");
+    }
+}
@@ -0,0 +1,153 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/file_cipher.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Sealing encrypted fields still leaves every hostname, address and owner
+ * readable to anyone who can copy the data directory or a backup of it.
+ * With a storage key, FileStorage seals whole files with AES-256-GCM: the
+ * data file and named snapshots as one sealed value each, the journal line
+ * by line so appends stay appends. Files written under a previous key (or
+ * before encryption was turned on) still open, and are rewritten under the
+ * current key the next time FileStorage persists them. A file sealed with a
+ * key that isn't configured is a startup error, not an empty directory.
+ * * Traceability:
+ * Used by FileStorage, journal.rs and snapshot.rs; keys from
+ * `PHAROS_STORAGE_KEY` and `PHAROS_STORAGE_PREVIOUS_KEY`.
+ * ======================================================================== */
+
+use crate::sealing::{self, SealingKey};
+use anyhow::{anyhow, bail};
+use std::borrow::Cow;
+use std::sync::Arc;
+
+/// Authenticated with a sealed data file or named snapshot, so one can't pass for the other kind.
+pub const RECORDS: &str = "pharos-records";
+
+/// Authenticated with each sealed journal line.
+pub const JOURNAL_ENTRY: &str = "pharos-journal-entry";
+
+/// How FileStorage's files are written and read back. The default seals nothing and reads only
+/// files in the clear.
+#[derive(Debug, Clone, Default)]
+pub struct FileCipher {
+    /// What files are sealed with; `None` writes them in the clear.
+    key: Option<Arc<SealingKey>>,
+    /// Keys files may still be sealed with, read but never written.
+    previous: Vec<Arc<SealingKey>>,
+}
+
+impl FileCipher {
+    pub fn new(key: Option<SealingKey>) -> Self {
+        Self { key: key.map(Arc::new), previous: Vec::new() }
+    }
+
+    /// Also reads files sealed with `key`, so they can be rewritten under the current one.
+    pub fn with_previous(mut self, key: SealingKey) -> Self {
+        self.previous.push(Arc::new(key));
+        self
+    }
+
+    /// The storage key in `PHAROS_STORAGE_KEY` (or the file named by `PHAROS_STORAGE_KEY_FILE`),
+    /// and the key it replaces in `PHAROS_STORAGE_PREVIOUS_KEY` (or `..._PREVIOUS_KEY_FILE`).
+    /// A previous key alone turns encryption off: files are read with it and written in the clear.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let cipher = Self::new(SealingKey::from_env_vars("PHAROS_STORAGE_KEY", "PHAROS_STORAGE_KEY_FILE")?);
+        match SealingKey::from_env_vars("PHAROS_STORAGE_PREVIOUS_KEY", "PHAROS_STORAGE_PREVIOUS_KEY_FILE")? {
+            Some(previous) if cipher.key_id() == Some(previous.id()) => {
+                bail!("PHAROS_STORAGE_PREVIOUS_KEY is the same key as PHAROS_STORAGE_KEY")
+            }
+            Some(previous) => Ok(cipher.with_previous(previous)),
+            None => Ok(cipher),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.key.is_some()
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.key.is_some() || !self.previous.is_empty()
+    }
+
+    pub fn key_id(&self) -> Option<&str> {
+        self.key.as_deref().map(SealingKey::id)
+    }
+
+    /// `text` as it is written to disk: sealed for `context` when there's a key.
+    pub fn seal(&self, context: &str, text: String) -> String {
+        match &self.key {
+            Some(key) => key.seal(context, &text),
+            None => text,
+        }
+    }
+
+    /// The key `text` was sealed with; `None` when it's in the clear. A key that isn't
+    /// configured is an error naming both keys.
+    pub fn key_for(&self, text: &str) -> anyhow::Result<Option<&SealingKey>> {
+        let Some(id) = sealing::key_id_of(text) else {
+            return Ok(None);
+        };
+        if let Some(key) = self.key.iter().chain(&self.previous).find(|k| k.id() == id) {
+            return Ok(Some(key));
+        }
+        Err(match self.key_id() {
+            Some(current) => anyhow!("encrypted with storage key {}, but the configured storage key is {}", id, current),
+            None => anyhow!("encrypted with storage key {}, but no storage key is configured (PHAROS_STORAGE_KEY)", id),
+        })
+    }
+
+    /// `text` as read from disk, opened if it's sealed.
+    pub fn open<'a>(&self, context: &str, text: &'a str) -> anyhow::Result<Cow<'a, str>> {
+        match self.key_for(text)? {
+            Some(key) => key.open(context, text).map(Cow::Owned),
+            None => Ok(Cow::Borrowed(text)),
+        }
+    }
+
+    /// Whether `text` as read from disk isn't what `seal` writes now - in the clear while there's
+    /// a key, sealed with a previous key, or sealed while encryption is being turned off.
+    pub fn is_stale(&self, text: &str) -> bool {
+        sealing::key_id_of(text) != self.key_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: &str) -> SealingKey {
+        SealingKey::parse(&byte.repeat(32)).unwrap()
+    }
+
+    #[test]
+    fn test_should_pass_text_through_without_a_key() {
+        let cipher = FileCipher::default();
+        assert_eq!(cipher.seal(RECORDS, "[]".to_string()), "[]");
+        assert_eq!(cipher.open(RECORDS, "[]").unwrap(), "[]");
+        assert!(!cipher.is_stale("[]"));
+
+        let sealed = FileCipher::new(Some(key("11"))).seal(RECORDS, "[]".to_string());
+        let err = cipher.open(RECORDS, &sealed).unwrap_err().to_string();
+        assert!(err.contains("no storage key is configured"), "{}", err);
+    }
+
+    #[test]
+    fn test_should_open_files_sealed_with_the_previous_key_and_mark_them_stale() {
+        let (old, new) = (key("11"), key("22"));
+        let sealed_old = FileCipher::new(Some(key("11"))).seal(RECORDS, "[{\"id\":1}]".to_string());
+
+        let rotated = FileCipher::new(Some(new)).with_previous(old);
+        assert_eq!(rotated.open(RECORDS, &sealed_old).unwrap(), "[{\"id\":1}]");
+        assert!(rotated.is_stale(&sealed_old));
+        assert!(rotated.is_stale("[]"), "files in the clear are rewritten once there's a key");
+        let sealed_new = rotated.seal(RECORDS, "[]".to_string());
+        assert!(!rotated.is_stale(&sealed_new));
+        assert!(rotated.open(JOURNAL_ENTRY, &sealed_new).is_err(), "sealed for another kind of file");
+
+        let err = FileCipher::new(Some(key("33"))).open(RECORDS, &sealed_new).unwrap_err().to_string();
+        assert!(err.contains(rotated.key_id().unwrap()), "{}", err);
+    }
+}
@@ -0,0 +1,416 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/fsck.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * The only maintenance a data file ever got was the self-heal and ip/mac
+ * migration FileStorage runs as it loads, silently and only for the two
+ * problems it knows. `pharos-server fsck` checks a stopped server's records
+ * for everything that leaves the directory quietly wrong - two live records
+ * claiming one identity, records with no type, values left in legacy
+ * single-valued fields, owners whose keys are gone, addresses that don't
+ * parse - and with `--repair` fixes what can be fixed without guessing,
+ * logging each fix in the record's history like any other write.
+ * * Traceability:
+ * Called from offline.rs (`pharos-server fsck`).
+ * ======================================================================== */
+
+use crate::history::{self, Author};
+use crate::links;
+use crate::merge;
+use crate::schema::{self, FieldSchema, ValueKind};
+use crate::storage::{self, Record, RecordType};
+use crate::tombstone;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// The source fsck's repairs are logged under.
+pub const SOURCE: &str = "fsck";
+
+/// Something wrong with a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Problem {
+    /// Another live record of the same type holds one of its identity values; an upsert can't
+    /// tell them apart.
+    DuplicateIdentity,
+    /// No `type` field, so it's invisible to mdb and ph queries.
+    MissingType,
+    /// Its `record_type` disagrees with its `type` field.
+    StaleType,
+    /// A single value in a field the schema declares multi-valued, where queries shadow its list.
+    LegacyField,
+    /// Owned by a key or team the keys directory no longer has, so nobody can modify it.
+    DanglingOwner,
+    /// An IP or MAC address that doesn't parse.
+    InvalidAddress,
+}
+
+impl Problem {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Problem::DuplicateIdentity => "duplicate-identity",
+            Problem::MissingType => "missing-type",
+            Problem::StaleType => "stale-type",
+            Problem::LegacyField => "legacy-field",
+            Problem::DanglingOwner => "dangling-owner",
+            Problem::InvalidAddress => "invalid-address",
+        }
+    }
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A problem `check` found on one record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub id: usize,
+    /// The record's hostname, else its alias, else `#id`.
+    pub label: String,
+    pub problem: Problem,
+    pub detail: String,
+    /// Whether `repair` fixes it.
+    pub repairable: bool,
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{} {}: {}: {}", self.id, self.label, self.problem, self.detail)?;
+        if !self.repairable {
+            write!(f, " (not repairable)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Who records can be owned by: the fingerprints of the keys in the keys directory and the
+/// teams they belong to (see `auth::known_owners`). With no keys at all ownership isn't checked,
+/// since that's a keys directory that wasn't found rather than every owner gone.
+#[derive(Debug, Clone, Default)]
+pub struct Owners {
+    pub fingerprints: BTreeSet<String>,
+    pub teams: BTreeSet<String>,
+}
+
+/// Every problem with `records`, in record id order.
+pub fn check(records: &[Record], schema: &FieldSchema, owners: &Owners) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut sorted: Vec<&Record> = records.iter().collect();
+    sorted.sort_by_key(|r| r.id);
+    let finding = |record: &Record, problem, detail: String, repairable| Finding { id: record.id, label: links::label(record), problem, detail, repairable };
+
+    for record in &sorted {
+        match (record.fields.get("type"), &record.record_type) {
+            (None, Some(record_type)) => findings.push(finding(record, Problem::MissingType, format!("no type field; record_type says {}", record_type.as_str()), true)),
+            (None, None) => findings.push(finding(record, Problem::MissingType, "no type field or record_type".to_string(), false)),
+            (Some(declared), record_type) if record_type.as_ref() != Some(&RecordType::from(declared.as_str())) => {
+                let stored = record_type.as_ref().map_or("missing", RecordType::as_str);
+                findings.push(finding(record, Problem::StaleType, format!("record_type is {} but type is {}", stored, declared), true));
+            }
+            _ => {}
+        }
+
+        let record_type = schema::type_of(record);
+        let mut legacy: Vec<&String> = record.fields.keys().filter(|name| schema.is_multi(record_type, name)).collect();
+        legacy.sort();
+        for name in legacy {
+            findings.push(finding(record, Problem::LegacyField, format!("single value in {}, which is multi-valued", name), true));
+        }
+
+        for (name, value) in values(record) {
+            let Some(kind) = address_kind(schema, record_type, name) else {
+                continue;
+            };
+            if is_valid_address(kind, value) {
+                continue;
+            }
+            let detail = format!("{} '{}' is not a valid {}", name, value, if kind == ValueKind::Ip { "IP address" } else { "MAC address" });
+            match normalize_address(kind, value) {
+                Some(fixed) => findings.push(finding(record, Problem::InvalidAddress, format!("{}; repairs to {}", detail, fixed), true)),
+                None => findings.push(finding(record, Problem::InvalidAddress, detail, false)),
+            }
+        }
+
+        if record.deleted_at.is_none() && !owners.fingerprints.is_empty() {
+            if let Some(fingerprint) = record.owner_fingerprint.as_ref().filter(|f| !owners.fingerprints.contains(*f)) {
+                findings.push(finding(record, Problem::DanglingOwner, format!("owned by key {}, which is not in the keys directory", fingerprint), false));
+            }
+            if let Some(team) = record.owner_team.as_ref().filter(|t| !owners.teams.contains(*t)) {
+                findings.push(finding(record, Problem::DanglingOwner, format!("owned by team {}, which no key belongs to", team), false));
+            }
+        }
+    }
+
+    for (identity, group) in duplicate_groups(records, schema) {
+        let oldest = &records[group[0]];
+        for &i in &group[1..] {
+            let detail = format!("shares {} with {} (#{})", identity, links::label(oldest), oldest.id);
+            findings.push(finding(&records[i], Problem::DuplicateIdentity, detail, true));
+        }
+    }
+    findings.sort_by_key(|f| (f.id, f.problem));
+    findings
+}
+
+/// Fixes every repairable problem in `records`, returning how many records changed: a missing
+/// `type` is restored from `record_type` and a stale `record_type` from `type`, legacy single
+/// values move into their lists, addresses that only need tidying are normalized, and each group
+/// of duplicates is merged into its oldest record (see `merge::combine`) with the others left as
+/// tombstones and links to them pointed at the survivor. Dangling owners and addresses that
+/// can't be read are left alone for a person to decide.
+pub fn repair(records: &mut [Record], schema: &FieldSchema, precedence: &[String], history_limit: usize, now: DateTime<Utc>) -> usize {
+    let author = Author { source: Some(SOURCE.to_string()), ..Default::default() };
+    let mut changed = BTreeSet::new();
+
+    for record in records.iter_mut() {
+        let before = record.clone();
+        let mut fixed = false;
+        if !record.fields.contains_key("type")
+            && let Some(record_type) = &record.record_type
+        {
+            record.fields.insert("type".to_string(), record_type.as_str().to_string());
+            fixed = true;
+        }
+        if let Some(declared) = record.fields.get("type") {
+            let declared = RecordType::from(declared.as_str());
+            if record.record_type.as_ref() != Some(&declared) {
+                record.record_type = Some(declared);
+                fixed = true;
+            }
+        }
+        fixed |= schema.migrate_multi_values(record);
+        fixed |= normalize_addresses(schema, record);
+        if fixed {
+            history::record_revision(Some(&before), record, &author, history_limit);
+            changed.insert(record.id);
+        }
+    }
+
+    let mut merged_into: HashMap<usize, usize> = HashMap::new();
+    let survivor = |merged_into: &HashMap<usize, usize>, mut i: usize| {
+        while let Some(&next) = merged_into.get(&i) {
+            i = next;
+        }
+        i
+    };
+    for (_, group) in duplicate_groups(records, schema) {
+        let mut members: Vec<usize> = group.iter().map(|&i| survivor(&merged_into, i)).collect();
+        members.sort_by_key(|&i| records[i].id);
+        members.dedup();
+        let keep = members[0];
+        for &lose in &members[1..] {
+            let Ok(merged) = merge::combine(&records[keep], &records[lose], precedence) else {
+                continue;
+            };
+            let before = std::mem::replace(&mut records[keep], merged);
+            history::record_revision(Some(&before), &mut records[keep], &author, history_limit);
+            tombstone::mark_deleted(&mut records[lose], now.to_rfc3339(), &author, history_limit);
+            merged_into.insert(lose, keep);
+            changed.extend([records[keep].id, records[lose].id]);
+
+            let identities: BTreeSet<String> = links::identities(&records[lose]).map(str::to_string).collect();
+            let replacement = links::identities(&records[keep]).next().map(str::to_string);
+            for record in records.iter_mut().filter(|r| r.deleted_at.is_none()) {
+                let before = record.clone();
+                if links::unlink(schema, record, &identities, replacement.as_deref()) {
+                    history::record_revision(Some(&before), record, &author, history_limit);
+                    changed.insert(record.id);
+                }
+            }
+        }
+    }
+    changed.len()
+}
+
+/// Every single and multi value `record` holds, as (field, value) pairs.
+fn values(record: &Record) -> Vec<(&str, &str)> {
+    let single = record.fields.iter().map(|(name, value)| (name.as_str(), value.as_str()));
+    let multi = record.multi_fields.iter().flat_map(|(name, values)| values.iter().map(move |v| (name.as_str(), v.as_str())));
+    let mut values: Vec<(&str, &str)> = single.chain(multi).collect();
+    values.sort();
+    values
+}
+
+/// Whether `name` holds IP or MAC addresses: the legacy `ip` and `mac` fields, `ip_addr` and
+/// `mac_addr`, and any field the schema declares of either kind.
+fn address_kind(schema: &FieldSchema, record_type: &str, name: &str) -> Option<ValueKind> {
+    match name {
+        "ip" | "ip_addr" => Some(ValueKind::Ip),
+        "mac" | "mac_addr" => Some(ValueKind::Mac),
+        _ => schema.field(Some(record_type), name).map(|def| def.kind).filter(|kind| matches!(kind, ValueKind::Ip | ValueKind::Mac)),
+    }
+}
+
+fn is_valid_address(kind: ValueKind, value: &str) -> bool {
+    match kind {
+        ValueKind::Ip => value.parse::<std::net::IpAddr>().is_ok(),
+        _ => storage::is_valid_mac_address(value),
+    }
+}
+
+/// `value` tidied into a valid address, if that's all it needs: surrounding whitespace trimmed,
+/// and a MAC address of 12 hex digits in any grouping (`AABB.CCDD.EEFF`, `aabbccddeeff`)
+/// rewritten as lowercase colon-separated pairs.
+fn normalize_address(kind: ValueKind, value: &str) -> Option<String> {
+    let trimmed = value.trim();
+    match kind {
+        ValueKind::Ip => trimmed.parse::<std::net::IpAddr>().is_ok().then(|| trimmed.to_string()),
+        _ => {
+            let digits: String = trimmed.chars().filter(|c| !matches!(c, ':' | '-' | '.')).collect();
+            if digits.len() != 12 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            let lower = digits.to_ascii_lowercase();
+            Some((0..6).map(|i| &lower[i * 2..i * 2 + 2]).collect::<Vec<_>>().join(":"))
+        }
+    }
+}
+
+/// Normalizes every invalid address on `record` that `normalize_address` can fix, dropping a
+/// value from a list that already holds it. Returns whether anything changed.
+fn normalize_addresses(schema: &FieldSchema, record: &mut Record) -> bool {
+    let record_type = schema::type_of(record).to_string();
+    let fix = |name: &str, value: &str| {
+        let kind = address_kind(schema, &record_type, name)?;
+        if is_valid_address(kind, value) { None } else { normalize_address(kind, value) }
+    };
+    let mut changed = false;
+    for (name, value) in record.fields.iter_mut() {
+        if let Some(fixed) = fix(name, value) {
+            *value = fixed;
+            changed = true;
+        }
+    }
+    for (name, values) in record.multi_fields.iter_mut() {
+        if !values.iter().any(|v| fix(name, v).is_some()) {
+            continue;
+        }
+        let mut kept: Vec<String> = Vec::new();
+        for value in values.drain(..) {
+            let value = fix(name, &value).unwrap_or(value);
+            if !kept.contains(&value) {
+                kept.push(value);
+            }
+        }
+        *values = kept;
+        changed = true;
+    }
+    changed
+}
+
+/// Groups of live records of one type sharing a value of one of that type's identity keys, as
+/// upserts match them (a hostname and an alias name a record alike; MAC addresses ignore case),
+/// each with the `key=value` they share and their positions in `records`, oldest first.
+fn duplicate_groups(records: &[Record], schema: &FieldSchema) -> Vec<(String, Vec<usize>)> {
+    let mut holders: BTreeMap<(String, String, String), BTreeSet<(usize, usize)>> = BTreeMap::new();
+    for (i, record) in records.iter().enumerate().filter(|(_, r)| r.deleted_at.is_none()) {
+        let record_type = schema::type_of(record);
+        if record_type.is_empty() {
+            continue;
+        }
+        for key in schema.identity_keys(record_type) {
+            for (name, value) in values(record).into_iter().filter(|(name, value)| *name == key && !value.trim().is_empty()) {
+                let (class, value) = match name {
+                    "hostname" | "alias" => ("hostname/alias", value.to_string()),
+                    _ if address_kind(schema, record_type, name) == Some(ValueKind::Mac) => (name, value.trim().to_ascii_lowercase()),
+                    _ => (name, value.to_string()),
+                };
+                holders.entry((record_type.to_lowercase(), class.to_string(), value)).or_default().insert((record.id, i));
+            }
+        }
+    }
+    holders
+        .into_iter()
+        .filter(|(_, held)| held.len() > 1)
+        .map(|((_, class, value), held)| {
+            let name = class.split('/').next().unwrap_or_default().to_string();
+            (format!("{}={}", name, value), held.into_iter().map(|(_, i)| i).collect())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: usize, fields: &[(&str, &str)], multi: &[(&str, &[&str])]) -> Record {
+        let fields: HashMap<String, String> = fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        Record {
+            id,
+            record_type: fields.get("type").map(|t| RecordType::from(t.as_str())),
+            fields,
+            multi_fields: multi.iter().map(|(k, vs)| (k.to_string(), vs.iter().map(|v| v.to_string()).collect())).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn problems(findings: &[Finding]) -> Vec<(usize, &str, bool)> {
+        findings.iter().map(|f| (f.id, f.problem.name(), f.repairable)).collect()
+    }
+
+    #[test]
+    fn test_should_find_every_kind_of_problem() {
+        let mut untyped = record(3, &[("hostname", "db01")], &[]);
+        untyped.record_type = Some(RecordType::Machine);
+        let mut stale = record(4, &[("type", "person"), ("alias", "rdavis")], &[]);
+        stale.record_type = Some(RecordType::Machine);
+        let mut orphan = record(5, &[("type", "machine"), ("hostname", "app01")], &[]);
+        orphan.owner_fingerprint = Some("SHA256:gone".to_string());
+        let records = vec![
+            record(1, &[("type", "machine"), ("hostname", "web01")], &[("mac_addr", &["AABB.CCDD.EE01"])]),
+            record(2, &[("type", "machine"), ("hostname", "web01"), ("ip_addr", "10.0.0.5")], &[]),
+            untyped,
+            stale,
+            orphan,
+            record(6, &[("alias", "nobody")], &[("ip_addr", &["10.0.0.300"])]),
+        ];
+        let owners = Owners { fingerprints: BTreeSet::from(["SHA256:admin".to_string()]), teams: BTreeSet::new() };
+
+        let findings = check(&records, &FieldSchema::default(), &owners);
+        assert_eq!(
+            problems(&findings),
+            vec![
+                (1, "invalid-address", true),
+                (2, "duplicate-identity", true),
+                (2, "legacy-field", true),
+                (3, "missing-type", true),
+                (4, "stale-type", true),
+                (5, "dangling-owner", false),
+                (6, "missing-type", false),
+                (6, "invalid-address", false),
+            ]
+        );
+        assert!(findings[0].detail.ends_with("repairs to aa:bb:cc:dd:ee:01"), "{}", findings[0]);
+        assert!(findings[1].detail.contains("hostname=web01 with web01 (#1)"), "{}", findings[1]);
+
+        let unchecked = check(&records, &FieldSchema::default(), &Owners::default());
+        assert!(!unchecked.iter().any(|f| f.problem == Problem::DanglingOwner), "no keys, no ownership check");
+    }
+
+    #[test]
+    fn test_should_repair_what_it_can_and_leave_the_rest() {
+        let mut records = vec![
+            record(1, &[("type", "machine"), ("hostname", "web01"), ("source", "pharos-scan"), ("os", "linux")], &[("mac_addr", &["AABB.CCDD.EE01"])]),
+            record(2, &[("type", "machine"), ("hostname", "web01"), ("source", "mdb"), ("os", "debian13"), ("ip_addr", " 10.0.0.5")], &[]),
+            record(3, &[("type", "machine"), ("hostname", "lb01"), ("backend", "web01")], &[("ip_addr", &["10.0.0.300"])]),
+        ];
+        let schema = FieldSchema::default();
+        let now = Utc::now();
+
+        assert_eq!(repair(&mut records, &schema, &merge::MergeConfig::default().precedence, 10, now), 2);
+        assert_eq!(records[0].multi_fields["mac_addr"], vec!["aa:bb:cc:dd:ee:01"]);
+        assert_eq!(records[0].multi_fields["ip_addr"], vec!["10.0.0.5"]);
+        assert_eq!(records[0].fields["os"], "debian13", "mdb outranks the scanner");
+        assert_eq!(records[0].history.last().unwrap().source.as_deref(), Some(SOURCE));
+        assert_eq!(records[1].deleted_at, Some(now.to_rfc3339()));
+
+        let left = check(&records, &schema, &Owners::default());
+        assert_eq!(problems(&left), vec![(3, "invalid-address", false)]);
+        assert_eq!(repair(&mut records, &schema, &[], 10, now), 0);
+    }
+}
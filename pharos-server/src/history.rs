@@ -0,0 +1,238 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/history.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * A `change` or pulse upsert overwrites fields in place. This module keeps a
+ * bounded per-record revision log next to the fields - who made each write
+ * (fingerprint, team, client source), when, and which fields went from what
+ * to what - so "when did this box's IP change and who did it" has an answer.
+ * The log travels inside the Record, so every backend that persists records
+ * persists their history with them.
+ * * Traceability:
+ * Backs the `history` protocol command and `mdb history <host>`.
+ * ======================================================================== */
+
+use crate::storage::Record;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use tracing::warn;
+
+/// Revisions kept per record unless overridden by `PHAROS_HISTORY_LIMIT`.
+pub const DEFAULT_HISTORY_LIMIT: usize = 50;
+
+/// Server-maintained timestamps. `last_seen_at` moves on every heartbeat, so tracking it would
+/// push every meaningful revision out of the log within the hour.
+const UNTRACKED_FIELDS: [&str; 2] = ["created_at", "last_seen_at"];
+
+/// One field's value before and after a write. `None` means the field was absent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+impl FieldChange {
+    /// `old -> new`, as shown by the `history` command.
+    pub fn summary(&self) -> String {
+        format!(
+            "{} -> {}",
+            self.from.as_deref().unwrap_or("(none)"),
+            self.to.as_deref().unwrap_or("(none)")
+        )
+    }
+}
+
+/// One write to a record. Revision numbers count up from 1 for the record's creation and keep
+/// counting after the oldest revisions are trimmed away.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Revision {
+    pub revision: u64,
+    pub at: String,
+    pub fingerprint: Option<String>,
+    pub team: Option<String>,
+    pub source: Option<String>,
+    pub changes: Vec<FieldChange>,
+}
+
+/// Who made a write: the session's key fingerprint, the team it acted for and the normalized
+/// client source (`mdb`, `pharos-pulse`, ...).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Author {
+    pub fingerprint: Option<String>,
+    pub team: Option<String>,
+    pub source: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryConfig {
+    /// Revisions kept per record; 0 turns history off.
+    pub limit: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self { limit: DEFAULT_HISTORY_LIMIT }
+    }
+}
+
+impl HistoryConfig {
+    /// Reads `PHAROS_HISTORY_LIMIT`, falling back to the default (with a warning) on an
+    /// unparseable value rather than refusing to start.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Ok(raw) = std::env::var("PHAROS_HISTORY_LIMIT") {
+            match raw.trim().parse::<usize>() {
+                Ok(n) => config.limit = n,
+                Err(_) => warn!("Ignoring invalid PHAROS_HISTORY_LIMIT value '{}'", raw),
+            }
+        }
+        config
+    }
+}
+
+/// Pseudo-field under which a delete or undelete shows up in the log (see tombstone.rs).
+const DELETED_AT: &str = "deleted_at";
+
+/// A field's value for comparison. Multi-valued fields compare as their whole list.
+fn value_of(record: &Record, field: &str) -> Option<String> {
+    if field == DELETED_AT {
+        return record.deleted_at.clone();
+    }
+    record
+        .fields
+        .get(field)
+        .cloned()
+        .or_else(|| record.multi_fields.get(field).map(|values| values.join(", ")))
+}
+
+/// Every tracked field whose value differs between `before` (`None` for a new record) and
+/// `after`, in field-name order.
+pub fn diff(before: Option<&Record>, after: &Record) -> Vec<FieldChange> {
+    let mut names: BTreeSet<&str> = BTreeSet::new();
+    for record in before.into_iter().chain(std::iter::once(after)) {
+        names.extend(record.fields.keys().map(String::as_str));
+        names.extend(record.multi_fields.keys().map(String::as_str));
+        if record.deleted_at.is_some() {
+            names.insert(DELETED_AT);
+        }
+    }
+
+    names
+        .into_iter()
+        .filter(|name| !UNTRACKED_FIELDS.contains(name))
+        .filter_map(|name| {
+            let from = before.and_then(|r| value_of(r, name));
+            let to = value_of(after, name);
+            (from != to).then(|| FieldChange { field: name.to_string(), from, to })
+        })
+        .collect()
+}
+
+/// Bumps `record.revision` for the write that turned `before` into `record`, unless it changed
+/// no tracked field, and logs it unless history is off, trimming the log to the newest `limit`
+/// revisions. Records stored before revisions were counted pick up from their log.
+pub fn record_revision(before: Option<&Record>, record: &mut Record, author: &Author, limit: usize) {
+    let changes = diff(before, record);
+    if changes.is_empty() {
+        return;
+    }
+
+    let logged = record.history.last().map_or(0, |last| last.revision);
+    record.revision = record.revision.max(logged) + 1;
+    if limit == 0 {
+        return;
+    }
+    record.history.push(Revision {
+        revision: record.revision,
+        at: chrono::Utc::now().to_rfc3339(),
+        fingerprint: author.fingerprint.clone(),
+        team: author.team.clone(),
+        source: author.source.clone(),
+        changes,
+    });
+    if record.history.len() > limit {
+        let excess = record.history.len() - limit;
+        record.history.drain(..excess);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn machine(fields: &[(&str, &str)], ips: &[&str]) -> Record {
+        Record {
+            id: 1,
+            fields: fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            multi_fields: HashMap::from([("ip_addr".to_string(), ips.iter().map(|ip| ip.to_string()).collect())]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_should_diff_fields_and_multi_values_but_not_timestamps() {
+        let before = machine(&[("hostname", "web01"), ("status", "online"), ("last_seen_at", "t1")], &["10.0.0.1"]);
+        let after = machine(&[("hostname", "web01"), ("os_name", "Debian"), ("last_seen_at", "t2")], &["10.0.0.1", "10.0.0.2"]);
+
+        let changes = diff(Some(&before), &after);
+        assert_eq!(changes, vec![
+            FieldChange { field: "ip_addr".to_string(), from: Some("10.0.0.1".to_string()), to: Some("10.0.0.1, 10.0.0.2".to_string()) },
+            FieldChange { field: "os_name".to_string(), from: None, to: Some("Debian".to_string()) },
+            FieldChange { field: "status".to_string(), from: Some("online".to_string()), to: None },
+        ]);
+        assert_eq!(changes[2].summary(), "online -> (none)");
+    }
+
+    #[test]
+    fn test_should_number_revisions_and_keep_only_the_newest() {
+        let author = Author { fingerprint: Some("SHA256:abc".to_string()), team: None, source: Some("mdb".to_string()) };
+        let mut record = machine(&[("hostname", "web01")], &[]);
+        record_revision(None, &mut record, &author, 3);
+
+        for status in ["a", "b", "c", "d"] {
+            let before = record.clone();
+            record.fields.insert("status".to_string(), status.to_string());
+            record_revision(Some(&before), &mut record, &author, 3);
+        }
+
+        let numbers: Vec<u64> = record.history.iter().map(|r| r.revision).collect();
+        assert_eq!(numbers, vec![3, 4, 5]);
+        assert_eq!(record.history[2].changes[0].summary(), "c -> d");
+        assert_eq!(record.history[2].source.as_deref(), Some("mdb"));
+    }
+
+    #[test]
+    fn test_should_skip_revisions_that_only_touch_timestamps_or_when_disabled() {
+        let author = Author::default();
+        let mut record = machine(&[("hostname", "web01"), ("last_seen_at", "t1")], &[]);
+        let before = record.clone();
+        record.fields.insert("last_seen_at".to_string(), "t2".to_string());
+        record_revision(Some(&before), &mut record, &author, 10);
+        assert!(record.history.is_empty());
+
+        record_revision(None, &mut record, &author, 0);
+        assert!(record.history.is_empty());
+    }
+
+    #[test]
+    fn test_should_count_revisions_with_history_off_and_resume_from_an_older_log() {
+        let author = Author::default();
+        let mut record = machine(&[("hostname", "web01")], &[]);
+        record_revision(None, &mut record, &author, 0);
+        assert_eq!(record.revision, 1);
+
+        // Stored before revisions were counted: the log already reached revision 7.
+        let mut legacy = machine(&[("hostname", "web02")], &[]);
+        legacy.history.push(Revision { revision: 7, ..Default::default() });
+        let before = legacy.clone();
+        legacy.fields.insert("status".to_string(), "online".to_string());
+        record_revision(Some(&before), &mut legacy, &author, 10);
+        assert_eq!(legacy.revision, 8);
+        assert_eq!(legacy.history.last().unwrap().revision, 8);
+    }
+}
@@ -0,0 +1,182 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/index.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * MemoryStorage used to answer every query and upsert with a linear scan,
+ * running the wildcard matcher over every field of every record. This module
+ * keeps inverted indexes from lowercased words to record ids - one per
+ * identity field plus one across all fields - so exact selections only visit
+ * the handful of records that can possibly match.
+ * * Traceability:
+ * Supports RFC 2378 Section 3 (query) word-by-word matching.
+ * ======================================================================== */
+
+use std::collections::{BTreeSet, HashMap};
+use crate::storage::Record;
+
+/// Fields that get a dedicated word index. Identity fields are what upsert and most `ph`/`mdb`
+/// lookups select on; ip_addr/mac_addr are the address fields the scanner syncs against.
+pub const INDEXED_FIELDS: [&str; 6] = ["hostname", "alias", "uuid", "serial_number", "ip_addr", "mac_addr"];
+
+/// Splits an already-lowercased field value into the words queries are matched against.
+/// Must stay in step with `MemoryStorage::matches`, which uses it for the same purpose.
+pub fn field_words(value: &str) -> impl Iterator<Item = &str> {
+    value.split(|c: char| c.is_whitespace() || c == ',' || c == ';' || c == ':')
+}
+
+/// A query word containing any of these is a pattern and can't be looked up directly.
+pub fn is_wildcard_word(word: &str) -> bool {
+    word.contains(['*', '?', '+', '[', ']'])
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RecordIndex {
+    by_field: HashMap<&'static str, HashMap<String, BTreeSet<usize>>>,
+    by_token: HashMap<String, BTreeSet<usize>>,
+}
+
+impl RecordIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, record: &Record) {
+        for (field, value) in Self::values(record) {
+            for word in Self::words(value) {
+                if let Some(field) = Self::indexed_field(field) {
+                    self.by_field.entry(field).or_default().entry(word.clone()).or_default().insert(record.id);
+                }
+                self.by_token.entry(word).or_default().insert(record.id);
+            }
+        }
+    }
+
+    /// Must be called with the record exactly as it was indexed, i.e. before it is mutated.
+    pub fn remove(&mut self, record: &Record) {
+        for (field, value) in Self::values(record) {
+            for word in Self::words(value) {
+                if let Some(field) = Self::indexed_field(field)
+                    && let Some(words) = self.by_field.get_mut(field)
+                {
+                    Self::remove_id(words, &word, record.id);
+                }
+                Self::remove_id(&mut self.by_token, &word, record.id);
+            }
+        }
+    }
+
+    /// Ids of every record that could match the selection, or `None` if the selection can't be
+    /// answered from the index (unindexed field, pure wildcard pattern or empty value) and the
+    /// caller has to scan. The result is a superset: candidates still go through the full matcher.
+    pub fn candidates(&self, field: Option<&str>, value: &str) -> Option<BTreeSet<usize>> {
+        let postings = match field {
+            Some(name) => self.by_field.get(Self::indexed_field(name)?),
+            None => Some(&self.by_token),
+        };
+
+        let value = value.to_lowercase();
+        let mut result: Option<BTreeSet<usize>> = None;
+        for word in value.split_whitespace().filter(|w| !is_wildcard_word(w)) {
+            let ids = postings.and_then(|p| p.get(word));
+            let Some(ids) = ids else {
+                return Some(BTreeSet::new());
+            };
+            result = Some(match result {
+                Some(acc) => acc.intersection(ids).copied().collect(),
+                None => ids.clone(),
+            });
+        }
+        result
+    }
+
+    fn indexed_field(name: &str) -> Option<&'static str> {
+        INDEXED_FIELDS.iter().copied().find(|f| *f == name)
+    }
+
+    fn values(record: &Record) -> impl Iterator<Item = (&str, &str)> {
+        let single = record.fields.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+        let multi = record
+            .multi_fields
+            .iter()
+            .flat_map(|(k, list)| list.iter().map(move |v| (k.as_str(), v.as_str())));
+        single.chain(multi)
+    }
+
+    fn words(value: &str) -> BTreeSet<String> {
+        let lower = value.to_lowercase();
+        field_words(&lower).filter(|w| !w.is_empty()).map(str::to_string).collect()
+    }
+
+    fn remove_id(postings: &mut HashMap<String, BTreeSet<usize>>, word: &str, id: usize) {
+        if let Some(ids) = postings.get_mut(word) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                postings.remove(word);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: usize, fields: &[(&str, &str)], ips: &[&str]) -> Record {
+        let mut r = Record {
+            id,
+            fields: fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            ..Default::default()
+        };
+        if !ips.is_empty() {
+            r.multi_fields.insert("ip_addr".to_string(), ips.iter().map(|s| s.to_string()).collect());
+        }
+        r
+    }
+
+    #[test]
+    fn test_should_return_ids_for_exact_identity_word_case_insensitively() {
+        let mut index = RecordIndex::new();
+        index.insert(&record(1, &[("hostname", "Web01")], &[]));
+        index.insert(&record(2, &[("hostname", "db01")], &[]));
+
+        let ids = index.candidates(Some("hostname"), "web01").unwrap();
+        assert_eq!(ids.into_iter().collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn test_should_fall_back_to_scan_for_unindexed_field_or_wildcard() {
+        let mut index = RecordIndex::new();
+        index.insert(&record(1, &[("hostname", "web01"), ("location", "rack1")], &[]));
+
+        assert!(index.candidates(Some("location"), "rack1").is_none());
+        assert!(index.candidates(Some("hostname"), "web*").is_none());
+        assert!(index.candidates(None, "").is_none());
+    }
+
+    #[test]
+    fn test_should_intersect_words_across_fields_for_fieldless_selection() {
+        let mut index = RecordIndex::new();
+        index.insert(&record(1, &[("name", "Alice Smith")], &["10.0.0.1"]));
+        index.insert(&record(2, &[("name", "Bob Smith")], &[]));
+
+        assert_eq!(index.candidates(None, "smith").unwrap().len(), 2);
+        assert_eq!(index.candidates(None, "alice smith").unwrap().into_iter().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(index.candidates(None, "10.0.0.1").unwrap().into_iter().collect::<Vec<_>>(), vec![1]);
+        assert!(index.candidates(None, "carol").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_should_forget_record_words_on_remove() {
+        let mut index = RecordIndex::new();
+        let r = record(1, &[("hostname", "web01")], &["10.0.0.1"]);
+        index.insert(&r);
+        index.remove(&r);
+
+        assert!(index.candidates(Some("hostname"), "web01").unwrap().is_empty());
+        assert!(index.candidates(Some("ip_addr"), "10.0.0.1").unwrap().is_empty());
+        assert!(index.by_token.is_empty());
+    }
+}
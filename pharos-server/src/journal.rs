@@ -0,0 +1,510 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/journal.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * This module implements the append-only write-ahead journal behind
+ * FileStorage. Each add/change/delete appends one line per touched record
+ * instead of rewriting the whole data file; the data file itself becomes a
+ * periodic snapshot, and compaction folds the journal back into it.
+ * * Traceability:
+ * Replaces the full-file rewrite in FileStorage::persist_to_disk_atomic.
+ * ======================================================================== */
+
+use crate::file_cipher::{self, FileCipher};
+use crate::storage::Record;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+/// Number of journal entries after which the persistence worker folds the journal back into a
+/// fresh snapshot, unless overridden by `PHAROS_JOURNAL_COMPACT_EVERY`.
+pub const DEFAULT_COMPACT_EVERY: usize = 1000;
+
+/// One record-level mutation. Entries carry the whole post-mutation record rather than a field
+/// diff, so replay is idempotent: re-applying an entry that already made it into the snapshot
+/// (a crash between snapshot rename and journal truncation) converges to the same state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum JournalEntry {
+    Put { record: Box<Record> },
+    Remove { id: usize },
+}
+
+/// The data file as written: the id the next new record gets, then every record. The id is a
+/// high-water mark rather than one past the highest id present, so a record purged for good
+/// never has its id handed out again after a restart.
+#[derive(Serialize)]
+struct DataFile<'a> {
+    next_id: usize,
+    records: &'a [&'a Record],
+}
+
+#[derive(Deserialize)]
+struct StoredDataFile {
+    next_id: usize,
+    records: Vec<Record>,
+}
+
+/// One past the highest id in `records`, or `next_id` if that is higher.
+pub fn high_water_mark<'a>(records: impl IntoIterator<Item = &'a Record>, next_id: usize) -> usize {
+    records.into_iter().map(|r| r.id + 1).fold(next_id.max(1), usize::max)
+}
+
+/// Parses a data file (or named snapshot) into its records and next id. A bare array of records,
+/// as written before the next id was kept, is read too; its next id is one past its highest id.
+pub fn parse_data_file(data: &str) -> serde_json::Result<(Vec<Record>, usize)> {
+    if data.trim_start().starts_with('[') {
+        let records: Vec<Record> = serde_json::from_str(data)?;
+        let next_id = high_water_mark(&records, 1);
+        return Ok((records, next_id));
+    }
+    let stored: StoredDataFile = serde_json::from_str(data)?;
+    let next_id = high_water_mark(&stored.records, stored.next_id);
+    Ok((stored.records, next_id))
+}
+
+/// When the persistence worker calls `fsync` on the journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FsyncPolicy {
+    /// After every appended entry - no acknowledged write is ever lost, slowest.
+    Always,
+    /// Once per drained batch of queued writes - a burst of pulse heartbeats costs one fsync.
+    #[default]
+    Batch,
+    /// Never explicitly; the OS flushes page cache on its own schedule.
+    Never,
+}
+
+impl FsyncPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "always" => Some(FsyncPolicy::Always),
+            "batch" => Some(FsyncPolicy::Batch),
+            "never" | "off" => Some(FsyncPolicy::Never),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JournalConfig {
+    pub fsync: FsyncPolicy,
+    pub compact_every: usize,
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self {
+            fsync: FsyncPolicy::default(),
+            compact_every: DEFAULT_COMPACT_EVERY,
+        }
+    }
+}
+
+impl JournalConfig {
+    /// Reads `PHAROS_JOURNAL_FSYNC` and `PHAROS_JOURNAL_COMPACT_EVERY`, falling back to the
+    /// defaults (with a warning) on unrecognized values rather than refusing to start.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Ok(raw) = std::env::var("PHAROS_JOURNAL_FSYNC") {
+            match FsyncPolicy::parse(&raw) {
+                Some(policy) => config.fsync = policy,
+                None => warn!("Ignoring unrecognized PHAROS_JOURNAL_FSYNC value '{}' (expected always, batch or never)", raw),
+            }
+        }
+        if let Ok(raw) = std::env::var("PHAROS_JOURNAL_COMPACT_EVERY") {
+            match raw.trim().parse::<usize>() {
+                Ok(n) if n > 0 => config.compact_every = n,
+                _ => warn!("Ignoring invalid PHAROS_JOURNAL_COMPACT_EVERY value '{}'", raw),
+            }
+        }
+        config
+    }
+}
+
+/// The journal lives next to the snapshot: `data.json` journals to `data.journal`.
+pub fn journal_path(data_path: &Path) -> PathBuf {
+    data_path.with_extension("journal")
+}
+
+/// Result of reading a journal back from disk.
+#[derive(Debug, Default)]
+pub struct Replay {
+    pub entries: Vec<JournalEntry>,
+    /// Set when reading stopped at a line that could not be read back - normally the torn tail
+    /// of an append that was interrupted by a crash.
+    pub truncated_at_line: Option<usize>,
+    /// Set when that line wasn't the last one, which no interrupted append explains.
+    pub damage: Option<JournalDamage>,
+}
+
+/// A journal line other than the last that didn't read back: a damaged line, or one that fails
+/// authentication under the key it names.
+#[derive(Debug)]
+pub struct JournalDamage {
+    pub error: String,
+    /// The numbers of the lines dropped with it, its own first.
+    pub dropped: Vec<usize>,
+}
+
+/// Reads every complete entry from the journal. A missing journal is an empty one. Reading stops
+/// at the first line that doesn't read back, since nothing after it can be trusted to be in
+/// order: the last line is taken for a torn append and dropped with a warning, any other line
+/// is reported as `damage` for the caller to handle as corruption. A line sealed with a key
+/// `cipher` doesn't have is an error, not a torn write.
+pub fn read_journal(path: &Path, cipher: &FileCipher) -> anyhow::Result<Replay> {
+    let mut replay = Replay::default();
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(replay),
+        Err(e) => return Err(e.into()),
+    };
+
+    let lines: Vec<(usize, &[u8])> = bytes
+        .split(|b| *b == b'\n')
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.strip_suffix(b"\r").unwrap_or(line)))
+        .filter(|(_, line)| !line.trim_ascii().is_empty())
+        .collect();
+    for (position, (number, line)) in lines.iter().enumerate() {
+        let error = match read_entry(line, cipher).map_err(|e| e.context(format!("journal {:?} line {}", path, number)))? {
+            Ok(entry) => {
+                replay.entries.push(entry);
+                continue;
+            }
+            Err(error) => error,
+        };
+        replay.truncated_at_line = Some(*number);
+        if position + 1 == lines.len() {
+            warn!("Journal {:?} line {} is torn ({}); dropping it", path, number, error);
+        } else {
+            let dropped: Vec<usize> = lines[position..].iter().map(|(n, _)| *n).collect();
+            warn!("Journal {:?} line {} is damaged ({}); dropping it and the {} lines after it", path, number, error, dropped.len() - 1);
+            replay.damage = Some(JournalDamage { error, dropped });
+        }
+        break;
+    }
+    Ok(replay)
+}
+
+/// One journal line, or why it doesn't read back. Only a key `cipher` doesn't have is an error.
+fn read_entry(line: &[u8], cipher: &FileCipher) -> anyhow::Result<Result<JournalEntry, String>> {
+    let Ok(line) = std::str::from_utf8(line) else {
+        return Ok(Err("not valid UTF-8".to_string()));
+    };
+    let line = match cipher.key_for(line)? {
+        Some(key) => match key.open(file_cipher::JOURNAL_ENTRY, line) {
+            Ok(opened) => Cow::Owned(opened),
+            Err(e) => return Ok(Err(format!("does not decrypt with storage key {}: {}", key.id(), e))),
+        },
+        None => Cow::Borrowed(line),
+    };
+    Ok(serde_json::from_str(&line).map_err(|e| e.to_string()))
+}
+
+/// Applies entries over a snapshot, keyed by record id. Returns one past the highest id an entry
+/// named, removals included, so a replay keeps the next id past records it purged.
+pub fn apply_entries(records: &mut BTreeMap<usize, Record>, entries: impl IntoIterator<Item = JournalEntry>) -> usize {
+    let mut next_id = 1;
+    for entry in entries {
+        match entry {
+            JournalEntry::Put { record } => {
+                next_id = next_id.max(record.id + 1);
+                records.insert(record.id, *record);
+            }
+            JournalEntry::Remove { id } => {
+                next_id = next_id.max(id + 1);
+                records.remove(&id);
+            }
+        }
+    }
+    next_id
+}
+
+/// Atomically replaces the snapshot using a temporary file and rename, then syncs the parent
+/// directory so the rename itself survives a power loss. With a storage key the whole file is
+/// one sealed value.
+pub fn write_snapshot_atomic(path: &Path, records: &[&Record], next_id: usize, cipher: &FileCipher) -> anyhow::Result<()> {
+    debug!("Starting atomic snapshot to {:?}", path);
+    let next_id = high_water_mark(records.iter().copied(), next_id);
+    let data = cipher.seal(file_cipher::RECORDS, serde_json::to_string_pretty(&DataFile { next_id, records })?);
+
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(data.as_bytes())?;
+        file.sync_all()?;
+    }
+
+    std::fs::rename(tmp_path, path)?;
+    sync_parent_dir(path);
+    debug!("Atomic snapshot completed successfully for {:?}", path);
+    Ok(())
+}
+
+fn sync_parent_dir(path: &Path) {
+    #[cfg(unix)]
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty())
+        && let Err(e) = File::open(parent).and_then(|dir| dir.sync_all())
+    {
+        debug!("Could not fsync directory {:?}: {}", parent, e);
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+/// Owned by the FileStorage persistence worker. Keeps its own copy of the record set (updated
+/// entry by entry, never cloned wholesale) so compaction can write a snapshot without asking the
+/// storage for one.
+pub struct JournalWriter {
+    data_path: PathBuf,
+    journal_path: PathBuf,
+    file: File,
+    config: JournalConfig,
+    cipher: FileCipher,
+    records: BTreeMap<usize, Record>,
+    next_id: usize,
+    entries_since_compaction: usize,
+}
+
+impl JournalWriter {
+    pub fn open(data_path: &Path, records: Vec<Record>, next_id: usize, config: JournalConfig, cipher: FileCipher) -> std::io::Result<Self> {
+        let journal_path = journal_path(data_path);
+        let file = OpenOptions::new().create(true).append(true).open(&journal_path)?;
+        Ok(Self {
+            data_path: data_path.to_path_buf(),
+            journal_path,
+            file,
+            config,
+            cipher,
+            records: records.into_iter().map(|r| (r.id, r)).collect(),
+            next_id,
+            entries_since_compaction: 0,
+        })
+    }
+
+    /// Appends a batch of entries as JSON Lines in a single write, each line sealed on its own
+    /// when there's a storage key.
+    pub fn append(&mut self, entries: Vec<JournalEntry>) -> anyhow::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let mut buf = String::new();
+        for entry in &entries {
+            buf.push_str(&self.cipher.seal(file_cipher::JOURNAL_ENTRY, serde_json::to_string(entry)?));
+            buf.push('\n');
+        }
+        self.file.write_all(buf.as_bytes())?;
+        if self.config.fsync == FsyncPolicy::Always {
+            self.file.sync_data()?;
+        }
+        self.entries_since_compaction += entries.len();
+        self.next_id = self.next_id.max(apply_entries(&mut self.records, entries));
+        Ok(())
+    }
+
+    /// Flushes appended entries to stable storage when the policy is `Batch`.
+    pub fn end_batch(&mut self) -> std::io::Result<()> {
+        if self.config.fsync == FsyncPolicy::Batch {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.entries_since_compaction >= self.config.compact_every
+    }
+
+    /// Writes a fresh snapshot of the current record set and truncates the journal. The snapshot
+    /// is durable before the journal is cut, so a crash in between only causes a harmless replay.
+    pub fn compact(&mut self) -> anyhow::Result<()> {
+        let records: Vec<&Record> = self.records.values().collect();
+        write_snapshot_atomic(&self.data_path, &records, self.next_id, &self.cipher)?;
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        info!(
+            "Compacted {} journal entries into snapshot {:?} ({} records)",
+            self.entries_since_compaction, self.data_path, self.records.len()
+        );
+        self.entries_since_compaction = 0;
+        Ok(())
+    }
+
+    /// Swaps in a whole new record set (a restored backup) and compacts, so the data file holds
+    /// exactly `records` and the journal is empty. The next id never goes down. On failure the
+    /// previous set is kept.
+    pub fn replace(&mut self, records: Vec<Record>) -> anyhow::Result<()> {
+        let previous = std::mem::replace(&mut self.records, records.into_iter().map(|r| (r.id, r)).collect());
+        let previous_next_id = self.next_id;
+        self.next_id = high_water_mark(self.records.values(), self.next_id);
+        if let Err(e) = self.compact() {
+            self.records = previous;
+            self.next_id = previous_next_id;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn journal_path(&self) -> &Path {
+        &self.journal_path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tempfile::tempdir;
+
+    fn record(id: usize, hostname: &str) -> Record {
+        let mut fields = HashMap::new();
+        fields.insert("hostname".to_string(), hostname.to_string());
+        fields.insert("type".to_string(), "machine".to_string());
+        Record { id, fields, ..Default::default() }
+    }
+
+    #[test]
+    fn test_should_parse_fsync_policy_case_insensitively() {
+        assert_eq!(FsyncPolicy::parse("Always"), Some(FsyncPolicy::Always));
+        assert_eq!(FsyncPolicy::parse("batch"), Some(FsyncPolicy::Batch));
+        assert_eq!(FsyncPolicy::parse("never"), Some(FsyncPolicy::Never));
+        assert_eq!(FsyncPolicy::parse("sometimes"), None);
+    }
+
+    #[test]
+    fn test_should_derive_journal_path_from_data_path() {
+        assert_eq!(journal_path(Path::new("/var/lib/pharos/data.json")), PathBuf::from("/var/lib/pharos/data.journal"));
+    }
+
+    #[test]
+    fn test_should_round_trip_appended_entries() {
+        let dir = tempdir().unwrap();
+        let data_path = dir.path().join("data.json");
+        let mut writer = JournalWriter::open(&data_path, Vec::new(), 1, JournalConfig::default(), FileCipher::default()).unwrap();
+        writer.append(vec![
+            JournalEntry::Put { record: Box::new(record(1, "a")) },
+            JournalEntry::Put { record: Box::new(record(2, "b")) },
+        ]).unwrap();
+        writer.append(vec![JournalEntry::Remove { id: 1 }]).unwrap();
+        writer.end_batch().unwrap();
+
+        let replay = read_journal(writer.journal_path(), &FileCipher::default()).unwrap();
+        assert_eq!(replay.entries.len(), 3);
+        assert_eq!(replay.truncated_at_line, None);
+
+        let mut records = BTreeMap::new();
+        assert_eq!(apply_entries(&mut records, replay.entries), 3);
+        assert_eq!(records.keys().copied().collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn test_should_ignore_torn_tail_when_replaying() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data.journal");
+        let good = serde_json::to_string(&JournalEntry::Put { record: Box::new(record(1, "a")) }).unwrap();
+        std::fs::write(&path, format!("{}\n{{\"op\":\"put\",\"record\":{{\"id\":2,\"fie", good)).unwrap();
+
+        let replay = read_journal(&path, &FileCipher::default()).unwrap();
+        assert_eq!(replay.entries.len(), 1);
+        assert_eq!(replay.truncated_at_line, Some(2));
+        assert!(replay.damage.is_none(), "a torn last line is not damage");
+    }
+
+    #[test]
+    fn test_should_seal_each_line_and_refuse_a_key_it_does_not_have() {
+        let dir = tempdir().unwrap();
+        let data_path = dir.path().join("data.json");
+        let cipher = FileCipher::new(Some(crate::sealing::SealingKey::parse(&"42".repeat(32)).unwrap()));
+        let mut writer = JournalWriter::open(&data_path, Vec::new(), 1, JournalConfig::default(), cipher.clone()).unwrap();
+        writer.append(vec![
+            JournalEntry::Put { record: Box::new(record(1, "secret-host")) },
+            JournalEntry::Put { record: Box::new(record(2, "b")) },
+        ]).unwrap();
+
+        let on_disk = std::fs::read_to_string(writer.journal_path()).unwrap();
+        assert!(!on_disk.contains("secret-host"));
+        assert_eq!(on_disk.lines().count(), 2);
+        assert_eq!(read_journal(writer.journal_path(), &cipher).unwrap().entries.len(), 2);
+
+        // A torn sealed line ends the replay like any torn line...
+        let torn = format!("{}\n{}", on_disk.lines().next().unwrap(), &on_disk.lines().nth(1).unwrap()[..40]);
+        std::fs::write(writer.journal_path(), torn).unwrap();
+        let replay = read_journal(writer.journal_path(), &cipher).unwrap();
+        assert_eq!((replay.entries.len(), replay.truncated_at_line), (1, Some(2)));
+        // ...but a line sealed with another key is a configuration error.
+        assert!(read_journal(writer.journal_path(), &FileCipher::default()).is_err());
+    }
+
+    #[test]
+    fn test_should_report_a_damaged_line_that_is_not_the_tail() {
+        let dir = tempdir().unwrap();
+        let data_path = dir.path().join("data.json");
+        let cipher = FileCipher::new(Some(crate::sealing::SealingKey::parse(&"42".repeat(32)).unwrap()));
+        let mut writer = JournalWriter::open(&data_path, Vec::new(), 1, JournalConfig::default(), cipher.clone()).unwrap();
+        writer.append((1..=3).map(|id| JournalEntry::Put { record: Box::new(record(id, "a")) }).collect()).unwrap();
+
+        // Flip a character inside the second line's ciphertext: it still names the key, but fails authentication.
+        let on_disk = std::fs::read_to_string(writer.journal_path()).unwrap();
+        let mut lines: Vec<String> = on_disk.lines().map(str::to_string).collect();
+        let at = lines[1].len() - 10;
+        let flipped = if &lines[1][at..at + 1] == "A" { "B" } else { "A" };
+        lines[1].replace_range(at..at + 1, flipped);
+        std::fs::write(writer.journal_path(), lines.join("\n") + "\n").unwrap();
+
+        let replay = read_journal(writer.journal_path(), &cipher).unwrap();
+        assert_eq!((replay.entries.len(), replay.truncated_at_line), (1, Some(2)));
+        let damage = replay.damage.unwrap();
+        assert_eq!(damage.dropped, vec![2, 3]);
+        assert!(damage.error.contains("does not decrypt"), "{}", damage.error);
+    }
+
+    #[test]
+    fn test_should_treat_missing_journal_as_empty() {
+        let dir = tempdir().unwrap();
+        let replay = read_journal(&dir.path().join("absent.journal"), &FileCipher::default()).unwrap();
+        assert!(replay.entries.is_empty());
+    }
+
+    #[test]
+    fn test_should_write_snapshot_and_truncate_journal_on_compaction() {
+        let dir = tempdir().unwrap();
+        let data_path = dir.path().join("data.json");
+        let config = JournalConfig { fsync: FsyncPolicy::Never, compact_every: 2 };
+        let mut writer = JournalWriter::open(&data_path, vec![record(1, "a")], 2, config, FileCipher::default()).unwrap();
+
+        writer.append(vec![JournalEntry::Put { record: Box::new(record(2, "b")) }]).unwrap();
+        assert!(!writer.needs_compaction());
+        writer.append(vec![JournalEntry::Put { record: Box::new(record(3, "c")) }]).unwrap();
+        assert!(writer.needs_compaction());
+
+        writer.compact().unwrap();
+        assert!(!writer.needs_compaction());
+        assert_eq!(std::fs::metadata(writer.journal_path()).unwrap().len(), 0);
+
+        let (snapshot, next_id) = parse_data_file(&std::fs::read_to_string(&data_path).unwrap()).unwrap();
+        assert_eq!(snapshot.iter().map(|r| r.id).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(next_id, 4);
+    }
+
+    #[test]
+    fn test_should_keep_the_next_id_past_records_removed_before_compaction() {
+        let dir = tempdir().unwrap();
+        let data_path = dir.path().join("data.json");
+        let mut writer = JournalWriter::open(&data_path, vec![record(1, "a"), record(2, "b")], 3, JournalConfig::default(), FileCipher::default()).unwrap();
+        writer.append(vec![JournalEntry::Put { record: Box::new(record(3, "c")) }, JournalEntry::Remove { id: 3 }, JournalEntry::Remove { id: 2 }]).unwrap();
+        writer.compact().unwrap();
+        writer.replace(vec![record(1, "a")]).unwrap();
+
+        let (records, next_id) = parse_data_file(&std::fs::read_to_string(&data_path).unwrap()).unwrap();
+        assert_eq!((records.len(), next_id), (1, 4));
+        let legacy = serde_json::to_string_pretty(&[record(1, "a"), record(5, "e")]).unwrap();
+        assert_eq!(parse_data_file(&legacy).unwrap().1, 6, "a bare array's next id is one past its highest");
+    }
+}
@@ -0,0 +1,1382 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/ldap.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * The Enterprise storage tier: Ph records backed by an existing LDAP
+ * directory. Queries are translated into LDAP search filters; writes create
+ * and modify `ipHost`/`inetOrgPerson` entries under a configurable DN
+ * template per record type, with Pharos ownership (fingerprint/team) kept in
+ * configurable attributes so the same authorization rules apply as for the
+ * local tiers. Directory result codes are translated into StorageError.
+ * * Traceability:
+ * Implements RFC 2378 Section 3 (query) and Section 4 (add/change/delete)
+ * over RFC 4511 (LDAP), RFC 4514 (DN strings) and RFC 4515 (filters).
+ * ======================================================================== */
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use ldap3::{LdapError, Mod, SearchEntry};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tracing::{instrument, debug, info, error, warn};
+use crate::compare;
+use crate::index;
+use crate::ldap_pool::{LdapPool, LdapPoolConfig};
+use crate::links;
+use crate::schema::{self, FieldSchema};
+use crate::storage::{self, Edit, Record, RecordType, Storage, StorageError, UpsertOutcome};
+
+pub const DEFAULT_OWNER_FINGERPRINT_ATTR: &str = "pharosOwnerFingerprint";
+pub const DEFAULT_OWNER_TEAM_ATTR: &str = "pharosOwnerTeam";
+
+/// Fields Pharos maintains itself that have no place in a directory entry: the type is carried
+/// by objectClass, and the directory keeps its own create/modify timestamps.
+const UNMAPPED_FIELDS: [&str; 3] = ["type", "created_at", "last_seen_at"];
+
+fn default_owner_fingerprint_attr() -> String {
+    DEFAULT_OWNER_FINGERPRINT_ATTR.to_string()
+}
+
+fn default_owner_team_attr() -> String {
+    DEFAULT_OWNER_TEAM_ATTR.to_string()
+}
+
+/// How one Ph record type is represented in the directory.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TypeMapping {
+    /// The objectClass that identifies entries of this type in searches.
+    pub object_class: String,
+    /// Every objectClass written on add. Defaults to `top` plus `object_class`.
+    #[serde(default)]
+    pub object_classes: Vec<String>,
+    /// Search and naming base for this type. Defaults to `PHAROS_LDAP_BASE_DN`.
+    #[serde(default)]
+    pub base_dn: Option<String>,
+    /// DN of new entries. `{base_dn}` is replaced by the type's base DN, any other `{field}`
+    /// by that record field, escaped as an RFC 4514 attribute value.
+    #[serde(default)]
+    pub dn_template: Option<String>,
+    /// Ph field -> LDAP attribute, consulted before the shared map.
+    #[serde(default)]
+    pub attributes: BTreeMap<String, String>,
+    /// Attributes read into `multi_fields` (one value each) instead of a joined field.
+    #[serde(default)]
+    pub multi_valued: Vec<String>,
+    /// Attributes whose values form the record id. An empty list uses the entry's DN.
+    #[serde(default)]
+    pub id_attributes: Vec<String>,
+}
+
+impl TypeMapping {
+    fn object_classes(&self) -> Vec<String> {
+        if self.object_classes.is_empty() {
+            vec!["top".to_string(), self.object_class.clone()]
+        } else {
+            self.object_classes.clone()
+        }
+    }
+
+    fn is_multi_valued(&self, attr: &str) -> bool {
+        self.multi_valued.iter().any(|a| a.eq_ignore_ascii_case(attr))
+    }
+}
+
+/// The directory schema mapping: which objectClass, attributes and DN each record type uses,
+/// and where Pharos ownership is kept. Loaded from `PHAROS_LDAP_SCHEMA_PATH` (TOML).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LdapSchema {
+    #[serde(default = "default_owner_fingerprint_attr")]
+    pub owner_fingerprint_attr: String,
+    #[serde(default = "default_owner_team_attr")]
+    pub owner_team_attr: String,
+    /// Ph field -> LDAP attribute for every type.
+    #[serde(default)]
+    pub attributes: BTreeMap<String, String>,
+    /// Keyed by lowercased record type.
+    #[serde(default)]
+    pub types: BTreeMap<String, TypeMapping>,
+}
+
+impl Default for LdapSchema {
+    fn default() -> Self {
+        let owned = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+        let strings = |items: &[&str]| -> Vec<String> { items.iter().map(|s| s.to_string()).collect() };
+
+        let mut types = BTreeMap::new();
+        types.insert("machine".to_string(), TypeMapping {
+            object_class: "ipHost".to_string(),
+            object_classes: strings(&["top", "device", "ipHost", "ieee802Device"]),
+            dn_template: Some("cn={hostname},ou=hosts,{base_dn}".to_string()),
+            attributes: owned(&[("hostname", "cn"), ("ip_addr", "ipHostNumber"), ("mac_addr", "macAddress")]),
+            multi_valued: strings(&["ipHostNumber", "macAddress"]),
+            ..Default::default()
+        });
+        types.insert("person".to_string(), TypeMapping {
+            object_class: "inetOrgPerson".to_string(),
+            object_classes: strings(&["top", "person", "organizationalPerson", "inetOrgPerson"]),
+            dn_template: Some("cn={name},ou=people,{base_dn}".to_string()),
+            attributes: owned(&[("name", "cn")]),
+            ..Default::default()
+        });
+
+        Self {
+            owner_fingerprint_attr: default_owner_fingerprint_attr(),
+            owner_team_attr: default_owner_team_attr(),
+            attributes: owned(&[
+                ("name", "cn"),
+                ("email", "mail"),
+                ("phone", "telephoneNumber"),
+                ("hostname", "cn"),
+                ("ip", "ipHostNumber"),
+            ]),
+            types,
+        }
+    }
+}
+
+impl LdapSchema {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path)?;
+        let mut schema: LdapSchema = toml::from_str(&raw)?;
+        schema.types = std::mem::take(&mut schema.types)
+            .into_iter()
+            .map(|(name, mapping)| (name.to_lowercase(), mapping))
+            .collect();
+        for (name, mapping) in &schema.types {
+            if mapping.object_class.trim().is_empty() {
+                anyhow::bail!("type '{}' in {:?} has an empty object_class", name, path);
+            }
+        }
+        Ok(schema)
+    }
+
+    /// The mapping file at `PHAROS_LDAP_SCHEMA_PATH` (or the built-in mapping), with
+    /// `PHAROS_LDAP_DN_TEMPLATE_<TYPE>` and the owner attribute variables applied on top.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut schema = match std::env::var("PHAROS_LDAP_SCHEMA_PATH") {
+            Ok(path) => Self::load(Path::new(&path))?,
+            Err(_) => Self::default(),
+        };
+        for (key, value) in std::env::vars() {
+            if let Some(record_type) = key.strip_prefix("PHAROS_LDAP_DN_TEMPLATE_") {
+                let record_type = record_type.to_lowercase();
+                let mapping = schema.types.entry(record_type.clone()).or_insert_with(|| TypeMapping {
+                    object_class: record_type,
+                    ..Default::default()
+                });
+                mapping.dn_template = Some(value);
+            }
+        }
+        if let Ok(attr) = std::env::var("PHAROS_LDAP_OWNER_FINGERPRINT_ATTR") {
+            schema.owner_fingerprint_attr = attr;
+        }
+        if let Ok(attr) = std::env::var("PHAROS_LDAP_OWNER_TEAM_ATTR") {
+            schema.owner_team_attr = attr;
+        }
+        Ok(schema)
+    }
+
+    fn mapping(&self, record_type: &str) -> Option<&TypeMapping> {
+        self.types.get(&record_type.to_lowercase())
+    }
+
+    /// The attribute a Ph field is stored in: the type's own map, then the shared map, then
+    /// (for searches across all types) any type that maps it, else the field name itself.
+    fn attr_for(&self, record_type: Option<&str>, field: &str) -> String {
+        if let Some(attr) = record_type.and_then(|rt| self.mapping(rt)).and_then(|m| m.attributes.get(field)) {
+            return attr.clone();
+        }
+        if let Some(attr) = self.attributes.get(field) {
+            return attr.clone();
+        }
+        if record_type.is_none()
+            && let Some(attr) = self.types.values().find_map(|m| m.attributes.get(field))
+        {
+            return attr.clone();
+        }
+        field.to_string()
+    }
+
+    /// Attributes a field-less selection is matched against: every attribute the mapping
+    /// knows for the type (or for any type), plus the shared ones.
+    fn search_attributes(&self, record_type: Option<&str>) -> Vec<String> {
+        let mut attrs: Vec<String> = match record_type.and_then(|rt| self.mapping(rt)) {
+            Some(mapping) => mapping.attributes.values().cloned().collect(),
+            None => self.types.values().flat_map(|m| m.attributes.values().cloned()).collect(),
+        };
+        attrs.extend(self.attributes.values().cloned());
+        attrs.sort_by_key(|a| a.to_lowercase());
+        attrs.dedup_by(|a, b| a.eq_ignore_ascii_case(b));
+        attrs
+    }
+
+    /// The Ph field an attribute is read into, for an entry of the given type.
+    fn field_for(&self, mapping: Option<&TypeMapping>, attr: &str) -> String {
+        let find = |map: &BTreeMap<String, String>| {
+            map.iter().find(|(_, a)| a.eq_ignore_ascii_case(attr)).map(|(f, _)| f.clone())
+        };
+        mapping
+            .and_then(|m| find(&m.attributes))
+            .or_else(|| find(&self.attributes))
+            .unwrap_or_else(|| attr.to_string())
+    }
+
+    /// The record type of an entry, from the first mapping whose objectClass it carries.
+    fn type_of(&self, entry: &SearchEntry) -> Option<(&String, &TypeMapping)> {
+        let classes = entry.attrs.iter().find(|(a, _)| a.eq_ignore_ascii_case("objectClass")).map(|(_, v)| v)?;
+        self.types
+            .iter()
+            .find(|(_, m)| classes.iter().any(|c| c.eq_ignore_ascii_case(&m.object_class)))
+    }
+}
+
+/// Shared, swappable handle on the active schema mapping so it can be reloaded (on SIGHUP)
+/// while LdapStorage sits behind the storage lock. Each operation works on one snapshot.
+#[derive(Clone)]
+pub struct LdapSchemaHandle {
+    schema: Arc<RwLock<Arc<LdapSchema>>>,
+}
+
+impl LdapSchemaHandle {
+    pub fn new(schema: LdapSchema) -> Self {
+        Self { schema: Arc::new(RwLock::new(Arc::new(schema))) }
+    }
+
+    pub fn current(&self) -> Arc<LdapSchema> {
+        match self.schema.read() {
+            Ok(guard) => Arc::clone(&guard),
+            Err(poisoned) => Arc::clone(&poisoned.into_inner()),
+        }
+    }
+
+    /// Re-reads the mapping from the environment/file. A mapping that fails to load is logged
+    /// and the previous one stays active.
+    pub fn reload(&self) {
+        self.swap(LdapSchema::from_env());
+    }
+
+    fn swap(&self, loaded: anyhow::Result<LdapSchema>) {
+        match loaded {
+            Ok(schema) => match self.schema.write() {
+                Ok(mut guard) => {
+                    *guard = Arc::new(schema);
+                    info!("LDAP schema mapping reloaded successfully.");
+                }
+                Err(e) => error!("Failed to acquire write lock while reloading LDAP schema mapping: {}", e),
+            },
+            Err(e) => error!(
+                "Failed to reload LDAP schema mapping ({}); continuing with the previous mapping.",
+                e
+            ),
+        }
+    }
+}
+
+/// FNV-1a, so a record's id is stable across queries, restarts and builds.
+fn stable_id(parts: &[&str]) -> usize {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for byte in part.to_lowercase().bytes().chain(std::iter::once(0)) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash as usize
+}
+
+/// Escapes a value for use inside a DN (RFC 4514 Section 2.4).
+pub fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '\\' | ',' | '+' | '"' | '<' | '>' | ';' | '=' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\0' => escaped.push_str("\\00"),
+            '#' if i == 0 => escaped.push_str("\\#"),
+            ' ' if i == 0 || i == last => escaped.push_str("\\ "),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escapes a value for use inside a search filter (RFC 4515 Section 3).
+pub fn escape_filter_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '*' => escaped.push_str("\\2a"),
+            '(' => escaped.push_str("\\28"),
+            ')' => escaped.push_str("\\29"),
+            '\\' => escaped.push_str("\\5c"),
+            '\0' => escaped.push_str("\\00"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The literal runs of a Pharos wildcard word, i.e. what's left between `*`, `+`, `?` and
+/// `[set]` tokens: `web?[0-9]*prod` -> `["web", "prod"]`.
+fn literal_runs(pattern: &str) -> Vec<String> {
+    let mut runs = vec![String::new()];
+    let mut in_set = false;
+    for c in pattern.chars() {
+        match c {
+            '[' => in_set = true,
+            ']' => in_set = false,
+            '*' | '+' | '?' => {}
+            _ if !in_set => {
+                runs.last_mut().unwrap().push(c);
+                continue;
+            }
+            _ => continue,
+        }
+        if !runs.last().unwrap().is_empty() {
+            runs.push(String::new());
+        }
+    }
+    runs.retain(|r| !r.is_empty());
+    runs
+}
+
+/// Translates one query word into an assertion on `attr` that every entry the word matches
+/// (word-by-word, as in the local tiers) satisfies. The word may sit anywhere in a
+/// multi-word value, so literals become `*word*` and patterns keep their literal runs in
+/// order (`web?[0-9]*` -> `*web*`); `?`, `+` and `[set]` can't be expressed in a substring
+/// filter, so the entries returned are re-checked with `wildcard_match`.
+fn word_assertion(attr: &str, word: &str) -> Result<String, StorageError> {
+    if !index::is_wildcard_word(word) {
+        return Ok(format!("({}=*{}*)", attr, escape_filter_value(word)));
+    }
+    // Surfaces malformed patterns (unclosed or empty sets) as InvalidArgument, as the local tiers do.
+    storage::wildcard_match("", word)?;
+    let runs = literal_runs(word);
+    if runs.is_empty() {
+        return Ok(format!("({}=*)", attr));
+    }
+    let runs: Vec<String> = runs.iter().map(|r| escape_filter_value(r)).collect();
+    Ok(format!("({}=*{}*)", attr, runs.join("*")))
+}
+
+/// Whether `attr` is a plain attribute description (RFC 4512 Section 2.5), so it can't carry
+/// filter syntax into the search.
+fn is_attribute_description(attr: &str) -> bool {
+    !attr.is_empty() && attr.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == ';' || c == '.')
+}
+
+/// ANDs assertions, without wrapping a single one.
+fn and_filter(mut filters: Vec<String>) -> String {
+    match filters.len() {
+        0 => "(objectClass=*)".to_string(),
+        1 => filters.remove(0),
+        _ => format!("(&{})", filters.concat()),
+    }
+}
+
+/// Maps a failed directory operation onto the storage error the protocol layer already knows
+/// how to report (RFC 4511 Appendix A result codes).
+pub fn translate_ldap_error(operation: &str, err: LdapError) -> StorageError {
+    match err {
+        LdapError::LdapResult { result } => match result.rc {
+            // entryAlreadyExists
+            68 => StorageError::Collision,
+            // strongerAuthRequired, insufficientAccessRights
+            8 | 50 => StorageError::Unauthorized,
+            // referral (we are talking to a read-only replica), unwillingToPerform
+            10 | 53 => StorageError::ReadOnly,
+            // noSuchAttribute, undefinedAttributeType, constraintViolation, invalidAttributeSyntax,
+            // noSuchObject, invalidDNSyntax, namingViolation, objectClassViolation,
+            // notAllowedOnRDN, objectClassModsProhibited
+            16 | 17 | 19 | 21 | 32 | 34 | 64 | 65 | 67 | 69 => {
+                StorageError::InvalidArgument(format!("directory rejected {}: {}", operation, result))
+            }
+            // busy, unavailable
+            51 | 52 => StorageError::Unavailable(format!("LDAP {} failed: {}", operation, result)),
+            _ => StorageError::Internal(format!("LDAP {} failed: {}", operation, result)),
+        },
+        LdapError::Io { .. } | LdapError::OpSend { .. } | LdapError::ResultRecv { .. } | LdapError::EndOfStream | LdapError::Timeout { .. } => {
+            StorageError::Unavailable(format!("LDAP {} failed: {}", operation, err))
+        }
+        other => StorageError::Internal(format!("LDAP {} failed: {}", operation, other)),
+    }
+}
+
+/// How long a directory-wide count is reused before `record_count` searches again.
+const COUNT_CACHE_TTL: Duration = Duration::from_secs(30);
+
+pub struct LdapStorage {
+    base_dn: String,
+    pool: LdapPool,
+    count_cache: Mutex<Option<(Instant, usize)>>,
+
+    // Schema mapping (Ph field <-> LDAP attribute, objectClass and DN per type)
+    schema: LdapSchemaHandle,
+    field_schema: Arc<FieldSchema>,
+}
+
+impl LdapStorage {
+    pub fn new(url: String, bind_dn: String, bind_pw: String, base_dn: String) -> Self {
+        Self::with_pool_config(LdapPoolConfig::from_env(url, bind_dn, bind_pw), base_dn)
+    }
+
+    pub fn with_pool_config(config: LdapPoolConfig, base_dn: String) -> Self {
+        Self {
+            base_dn,
+            pool: LdapPool::new(config),
+            count_cache: Mutex::new(None),
+            schema: LdapSchemaHandle::new(LdapSchema::default()),
+            field_schema: Arc::new(FieldSchema::default()),
+        }
+    }
+
+    pub fn with_schema(mut self, schema: LdapSchema) -> Self {
+        self.schema = LdapSchemaHandle::new(schema);
+        self
+    }
+
+    pub fn with_field_schema(mut self, schema: FieldSchema) -> Self {
+        self.field_schema = Arc::new(schema);
+        self
+    }
+
+    /// Handle for reloading the schema mapping without going through the storage lock.
+    pub fn schema_handle(&self) -> LdapSchemaHandle {
+        self.schema.clone()
+    }
+
+    fn base_for<'a>(&'a self, mapping: Option<&'a TypeMapping>) -> &'a str {
+        mapping.and_then(|m| m.base_dn.as_deref()).unwrap_or(&self.base_dn)
+    }
+
+    /// Translates Ph selections into an RFC 4515 search filter. The filter selects a superset of
+    /// the matching entries; `select_records` narrows them down to exactly what the local tiers
+    /// would return. Without an explicit `type` selection, `default_type` restricts the search to
+    /// that type's objectClass. Fields are mapped with the explicit type's attributes when it
+    /// names a single type, and with `default_type`'s otherwise.
+    fn build_filter(&self, schema: &LdapSchema, selections: &[(Option<String>, String)], default_type: Option<&RecordType>) -> Result<String, StorageError> {
+        let mut filters = Vec::new();
+        let object_class = |record_type: &str| {
+            schema.mapping(record_type).map(|m| m.object_class.clone()).unwrap_or_else(|| record_type.to_string())
+        };
+
+        let explicit_type = selections.iter().any(|(f, _)| f.as_deref() == Some("type"));
+        if let Some(dt) = default_type
+            && !explicit_type
+        {
+            filters.push(format!("(objectClass={})", escape_filter_value(&object_class(dt.as_str()))));
+        }
+
+        let selected_type = selections.iter().find_map(|(f, v)| {
+            let mut words = v.split_whitespace();
+            match (f.as_deref(), words.next(), words.next()) {
+                (Some("type"), Some(word), None) if !index::is_wildcard_word(word) => Some(word),
+                _ => None,
+            }
+        });
+        let record_type = selected_type.or(default_type.map(RecordType::as_str));
+        for (field_opt, val) in selections {
+            // LDAP ordering rules don't know the field schema's value kinds, so a comparison
+            // only asks for the attribute to be present and is decided by `select_records`.
+            if let Some((field_name, _)) = field_opt.as_deref().and_then(compare::split_key) {
+                let attr = schema.attr_for(record_type, field_name);
+                if field_name != "type" && is_attribute_description(&attr) {
+                    filters.push(format!("({}=*)", attr));
+                }
+                continue;
+            }
+            for word in val.split_whitespace() {
+                match field_opt.as_deref() {
+                    // The type is carried by objectClass rather than an attribute.
+                    Some("type") => {
+                        if !index::is_wildcard_word(word) {
+                            filters.push(format!("(objectClass={})", escape_filter_value(&object_class(word))));
+                        }
+                    }
+                    Some(field_name) => {
+                        let attr = schema.attr_for(record_type, field_name);
+                        if !is_attribute_description(&attr) {
+                            return Err(StorageError::InvalidArgument(format!("'{}' is not a valid LDAP attribute name", attr)));
+                        }
+                        filters.push(word_assertion(&attr, word)?);
+                    }
+                    None => {
+                        let any: Vec<String> = schema
+                            .search_attributes(record_type)
+                            .iter()
+                            .filter(|a| is_attribute_description(a))
+                            .map(|a| word_assertion(a, word))
+                            .collect::<Result<_, _>>()?;
+                        filters.push(format!("(|{})", any.concat()));
+                    }
+                }
+            }
+        }
+
+        Ok(and_filter(filters))
+    }
+
+    /// Converts the entries a search returned and keeps those the selections match with the
+    /// local tiers' word-by-word and wildcard semantics.
+    fn select_records(schema: &LdapSchema, field_schema: &FieldSchema, entries: Vec<SearchEntry>, selections: &[(Option<String>, String)]) -> Result<Vec<(SearchEntry, Record)>, StorageError> {
+        let mut selected = Vec::new();
+        for entry in entries {
+            let record = Self::entry_to_record(schema, entry.clone());
+            if storage::record_matches_selections(&record, selections, field_schema)? {
+                selected.push((entry, record));
+            }
+        }
+        Ok(selected)
+    }
+
+    /// Renders the DN template configured for the record's type.
+    fn render_dn(&self, schema: &LdapSchema, record: &Record) -> Result<String, StorageError> {
+        let record_type = record.record_type.as_ref().map(RecordType::as_str).unwrap_or("");
+        let mapping = schema.mapping(record_type);
+        let template = mapping.and_then(|m| m.dn_template.as_ref()).ok_or_else(|| {
+            StorageError::InvalidArgument(format!(
+                "no LDAP DN template configured for type '{}' (set dn_template in the schema mapping or PHAROS_LDAP_DN_TEMPLATE_{})",
+                record_type,
+                record_type.to_uppercase()
+            ))
+        })?;
+
+        let mut dn = String::new();
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find('{') {
+            dn.push_str(&rest[..start]);
+            let end = rest[start..].find('}').map(|e| start + e).ok_or_else(|| {
+                StorageError::Internal(format!("unterminated placeholder in LDAP DN template '{}'", template))
+            })?;
+            let placeholder = &rest[start + 1..end];
+            if placeholder == "base_dn" {
+                dn.push_str(self.base_for(mapping));
+            } else {
+                let value = record
+                    .fields
+                    .get(placeholder)
+                    .or_else(|| record.multi_fields.get(placeholder).and_then(|v| v.first()))
+                    .ok_or_else(|| {
+                        StorageError::InvalidArgument(format!(
+                            "a '{}' field is required to name a type={} entry in the directory",
+                            placeholder, record_type
+                        ))
+                    })?;
+                dn.push_str(&escape_dn_value(value));
+            }
+            rest = &rest[end + 1..];
+        }
+        dn.push_str(rest);
+        Ok(dn)
+    }
+
+    /// The full attribute set of a new directory entry for `record`.
+    fn entry_attributes(schema: &LdapSchema, record: &Record) -> Vec<(String, HashSet<String>)> {
+        let record_type = record.record_type.as_ref().map(RecordType::as_str);
+        let mut attrs: HashMap<String, HashSet<String>> = HashMap::new();
+        if let Some(rt) = record_type {
+            let object_classes = match schema.mapping(rt) {
+                Some(mapping) => mapping.object_classes(),
+                None => vec!["top".to_string(), rt.to_string()],
+            };
+            attrs.insert("objectClass".to_string(), object_classes.into_iter().collect());
+        }
+        for (field, value) in &record.fields {
+            if UNMAPPED_FIELDS.contains(&field.as_str()) {
+                continue;
+            }
+            attrs.entry(schema.attr_for(record_type, field)).or_default().insert(value.clone());
+        }
+        for (field, values) in &record.multi_fields {
+            attrs.entry(schema.attr_for(record_type, field)).or_default().extend(values.iter().cloned());
+        }
+        if let Some(ref fp) = record.owner_fingerprint {
+            attrs.insert(schema.owner_fingerprint_attr.clone(), HashSet::from([fp.clone()]));
+        }
+        if let Some(ref team) = record.owner_team {
+            attrs.insert(schema.owner_team_attr.clone(), HashSet::from([team.clone()]));
+        }
+        attrs.into_iter().collect()
+    }
+
+    /// Modifications that apply `fields` to an existing entry with change/upsert semantics:
+    /// single-valued fields are replaced, values of fields the field schema declares
+    /// multi-valued (or that map to a multi-valued attribute) are appended unless present.
+    /// Unsetting, removing one value and replacing every value (see `storage::Edit`) become
+    /// deletes and replaces of the attribute.
+    fn field_mods(schema: &LdapSchema, field_schema: &FieldSchema, entry: &SearchEntry, fields: &[(String, String)]) -> Vec<Mod<String>> {
+        let typed = schema.type_of(entry);
+        let record_type = typed.map(|(name, _)| name.as_str());
+        let mut replace: HashMap<String, HashSet<String>> = HashMap::new();
+        let mut append: HashMap<String, HashSet<String>> = HashMap::new();
+        let mut delete: HashMap<String, HashSet<String>> = HashMap::new();
+        for (key, value) in fields {
+            let (field, edit) = storage::parse_modification(key, value);
+            if UNMAPPED_FIELDS.contains(&field) {
+                continue;
+            }
+            let attr = schema.attr_for(record_type, field);
+            let existing = entry.attrs.get(&attr);
+            let multi = field_schema.is_multi(record_type.unwrap_or(""), field)
+                || typed.is_some_and(|(_, mapping)| mapping.is_multi_valued(&attr));
+            match edit {
+                Edit::Set(value) if multi => {
+                    if !existing.is_some_and(|vals| vals.iter().any(|v| v.eq_ignore_ascii_case(value))) {
+                        append.entry(attr).or_default().insert(value.to_string());
+                    }
+                }
+                Edit::Set(value) => {
+                    replace.insert(attr, HashSet::from([value.to_string()]));
+                }
+                Edit::Replace(value) => {
+                    let values = replace.entry(attr).or_default();
+                    if !multi {
+                        values.clear();
+                    }
+                    values.insert(value.to_string());
+                }
+                Edit::Unset => {
+                    if existing.is_some() {
+                        delete.insert(attr, HashSet::new());
+                    }
+                }
+                Edit::Remove(value) => {
+                    // The directory deletes a value by its stored spelling.
+                    if let Some(stored) = existing.and_then(|vals| vals.iter().find(|v| v.eq_ignore_ascii_case(value))) {
+                        delete.entry(attr).or_default().insert(stored.clone());
+                    }
+                }
+            }
+        }
+        let mut mods: Vec<Mod<String>> = delete.into_iter().map(|(a, v)| Mod::Delete(a, v)).collect();
+        mods.extend(replace.into_iter().map(|(a, v)| Mod::Replace(a, v)));
+        mods.extend(append.into_iter().map(|(a, v)| Mod::Add(a, v)));
+        mods
+    }
+
+    /// Converts a directory entry into a Ph record using the mapping of the type its
+    /// objectClass identifies. The ownership attributes become the record's owner, the
+    /// type is exposed as the `type` field, and the id is derived from the id attributes.
+    fn entry_to_record(schema: &LdapSchema, entry: SearchEntry) -> Record {
+        let typed = schema.type_of(&entry);
+        let mapping = typed.map(|(_, m)| m);
+
+        let id_parts: Vec<&str> = match mapping {
+            Some(m) if !m.id_attributes.is_empty() => m
+                .id_attributes
+                .iter()
+                .flat_map(|a| entry.attrs.iter().filter(move |(k, _)| k.eq_ignore_ascii_case(a)))
+                .flat_map(|(_, vals)| vals.iter().map(String::as_str))
+                .collect(),
+            _ => vec![entry.dn.as_str()],
+        };
+        let id = stable_id(&id_parts);
+
+        let mut fields = HashMap::new();
+        let mut multi_fields = HashMap::new();
+        let mut owner_fingerprint = None;
+        let mut owner_team = None;
+
+        for (attr, vals) in entry.attrs {
+            if vals.is_empty() {
+                continue;
+            }
+            if attr.eq_ignore_ascii_case(&schema.owner_fingerprint_attr) {
+                owner_fingerprint = vals.into_iter().next();
+                continue;
+            }
+            if attr.eq_ignore_ascii_case(&schema.owner_team_attr) {
+                owner_team = vals.into_iter().next();
+                continue;
+            }
+            let ph_field = schema.field_for(mapping, &attr);
+            if mapping.is_some_and(|m| m.is_multi_valued(&attr)) {
+                multi_fields.insert(ph_field, vals);
+            } else {
+                fields.insert(ph_field, vals.join(", "));
+            }
+        }
+
+        let record_type = typed.map(|(name, _)| RecordType::from(name.as_str()));
+        if let Some(ref rt) = record_type {
+            fields.entry("type".to_string()).or_insert_with(|| rt.as_str().to_string());
+        }
+
+        Record {
+            id,
+            record_type,
+            fields,
+            multi_fields,
+            owner_fingerprint,
+            owner_team,
+            // The directory is the system of record here; revision history and numbers are only
+            // kept by the local tiers.
+            history: Vec::new(),
+            deleted_at: None,
+            revision: 0,
+        }
+    }
+
+    async fn search(&self, base: &str, filter: &str) -> Result<Vec<SearchEntry>, StorageError> {
+        debug!("LDAP Filter: {}", filter);
+        self.pool.search(base, filter, &["*"]).await
+    }
+
+    /// Entries matched by a delete/change, failing the whole operation if any of them isn't the
+    /// caller's to modify.
+    async fn authorized_entries(&self, schema: &LdapSchema, selections: &[(Option<String>, String)], fingerprint: Option<&String>, teams: &[String]) -> Result<Vec<SearchEntry>, StorageError> {
+        let filter = self.build_filter(schema, selections, None)?;
+        let selected = Self::select_records(schema, &self.field_schema, self.search(&self.base_dn, &filter).await?, selections)?;
+        for (entry, record) in &selected {
+            if !storage::is_authorized(record, fingerprint, teams) {
+                warn!("Refusing to modify {}: not owned by the caller", entry.dn);
+                return Err(StorageError::Unauthorized);
+            }
+        }
+        Ok(selected.into_iter().map(|(entry, _)| entry).collect())
+    }
+
+    /// The entry whose hostname or alias is exactly `id_val`, as attributes of `record_type`.
+    async fn find_identity(&self, schema: &LdapSchema, record_type: Option<&str>, id_val: &str) -> Result<Option<SearchEntry>, StorageError> {
+        let value = escape_filter_value(id_val);
+        let filter = format!(
+            "(|({}={})({}={}))",
+            schema.attr_for(record_type, "hostname"),
+            value,
+            schema.attr_for(record_type, "alias"),
+            value
+        );
+        Ok(self.search(&self.base_dn, &filter).await?.into_iter().next())
+    }
+
+    /// The entry an upsert of `fields` updates, if its identity values name one.
+    async fn resolve_upsert(&self, schema: &LdapSchema, fields: &[(String, String)]) -> Result<Option<SearchEntry>, StorageError> {
+        let record_type = fields.iter().find(|(k, _)| k == "type").map(|(_, v)| v.as_str());
+        let mut entries: Vec<SearchEntry> = Vec::new();
+        let mut found = Vec::new();
+        for (key, value) in storage::upsert_identity(&self.field_schema, fields) {
+            let escaped = escape_filter_value(value);
+            let filter = match key {
+                "hostname" | "alias" => format!(
+                    "(|({}={})({}={}))",
+                    schema.attr_for(record_type, "hostname"),
+                    escaped,
+                    schema.attr_for(record_type, "alias"),
+                    escaped
+                ),
+                _ => format!("({}={})", schema.attr_for(record_type, key), escaped),
+            };
+            for entry in self.search(&self.base_dn, &filter).await? {
+                let record = Self::entry_to_record(schema, entry.clone());
+                if !storage::holds_identity(&self.field_schema, &record, key, value) {
+                    continue;
+                }
+                let slot = match entries.iter().position(|e| e.dn == entry.dn) {
+                    Some(slot) => slot,
+                    None => {
+                        entries.push(entry);
+                        entries.len() - 1
+                    }
+                };
+                found.push((key, value, slot, links::label(&record)));
+            }
+        }
+        Ok(storage::resolve_identity(&found)?.map(|slot| entries.swap_remove(slot)))
+    }
+
+    /// Checks the links a write sets on a record of `record_type` against the directory. Deletes
+    /// can come from outside Pharos, so links aren't cleaned up here; `links` skips targets that
+    /// are gone.
+    async fn check_links(&self, schema: &LdapSchema, record_type: &str, fields: &[(String, String)]) -> Result<(), StorageError> {
+        let mut found = HashMap::new();
+        for (_, value) in fields.iter().filter(|(name, _)| self.field_schema.is_link(record_type, name)) {
+            let target = self.find_identity(schema, None, value.trim()).await?;
+            found.insert(value.trim(), target.map(|entry| schema::type_of(&Self::entry_to_record(schema, entry)).to_string()));
+        }
+        links::check_links(&self.field_schema, record_type, fields, |identity| Ok(found.get(identity).cloned().flatten()))
+    }
+
+    async fn add_entry(&self, schema: &LdapSchema, record: &Record) -> Result<(), StorageError> {
+        let dn = self.render_dn(schema, record)?;
+        info!("Adding LDAP entry {}", dn);
+        self.pool.add(&dn, Self::entry_attributes(schema, record)).await
+    }
+
+    async fn modify_entry(&self, dn: &str, mods: Vec<Mod<String>>) -> Result<(), StorageError> {
+        if mods.is_empty() {
+            return Ok(());
+        }
+        self.pool.modify(dn, mods).await
+    }
+
+    /// One count per mapped type, each under that type's base: the searches `query` would
+    /// run for it without selections.
+    fn count_searches(&self, schema: &LdapSchema) -> Vec<(String, String)> {
+        schema
+            .types
+            .values()
+            .map(|m| (self.base_for(Some(m)).to_string(), format!("(objectClass={})", escape_filter_value(&m.object_class))))
+            .collect()
+    }
+
+    async fn count_records(&self) -> Result<usize, StorageError> {
+        let mut total = 0;
+        for (base, filter) in self.count_searches(&self.schema.current()) {
+            total += self.pool.count(&base, &filter).await?;
+        }
+        Ok(total)
+    }
+
+    fn cached_count(&self) -> Option<(Instant, usize)> {
+        match self.count_cache.lock() {
+            Ok(guard) => *guard,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for LdapStorage {
+    #[instrument(skip(self))]
+    async fn record_count(&self) -> usize {
+        if let Some((at, count)) = self.cached_count()
+            && at.elapsed() < COUNT_CACHE_TTL
+        {
+            return count;
+        }
+        match self.count_records().await {
+            Ok(count) => {
+                if let Ok(mut cache) = self.count_cache.lock() {
+                    *cache = Some((Instant::now(), count));
+                }
+                count
+            }
+            Err(e) => {
+                warn!("Failed to count LDAP entries: {}", e);
+                self.cached_count().map(|(_, count)| count).unwrap_or(0)
+            }
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn add_record(&self, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<(), StorageError> {
+        let schema = self.schema.current();
+        self.check_links(&schema, storage::declared_type(&fields), &fields).await?;
+        let record = storage::new_record(0, fields, fingerprint, team, &self.field_schema)?;
+        self.add_entry(&schema, &record).await
+    }
+
+    #[instrument(skip(self))]
+    async fn query(&self, selections: &[(Option<String>, String)], default_type: Option<RecordType>) -> Result<Vec<Record>, StorageError> {
+        info!("Executing LDAP query...");
+
+        let schema = self.schema.current();
+        let explicit_type = selections.iter().any(|(f, _)| f.as_deref() == Some("type"));
+        let base = match default_type {
+            Some(ref dt) if !explicit_type => self.base_for(schema.mapping(dt.as_str())),
+            _ => &self.base_dn,
+        };
+        let filter = self.build_filter(&schema, selections, default_type.as_ref())?;
+        let entries = self.search(base, &filter).await.inspect_err(|e| error!("LDAP query failed: {}", e))?;
+
+        Ok(Self::select_records(&schema, &self.field_schema, entries, selections)?
+            .into_iter()
+            .map(|(_, record)| record)
+            .collect())
+    }
+
+    #[instrument(skip(self))]
+    async fn upsert_record(&self, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<UpsertOutcome, StorageError> {
+        for (k, v) in &fields {
+            storage::validate_ip_mac_field(k, v)?;
+        }
+
+        let schema = self.schema.current();
+        if let Some(entry) = self.resolve_upsert(&schema, &fields).await? {
+            let existing = Self::entry_to_record(&schema, entry.clone());
+            storage::check_upsert(&existing, &fields, fingerprint.as_ref(), team.as_ref(), &self.field_schema)?;
+            self.check_links(&schema, schema::type_of(&existing), &fields).await?;
+
+            // source describes a record's provenance - once set it is never overwritten.
+            let fields: Vec<(String, String)> = fields
+                .into_iter()
+                .filter(|(k, _)| !(k == "source" && existing.fields.contains_key("source")))
+                .collect();
+            let mut mods = Self::field_mods(&schema, &self.field_schema, &entry, &fields);
+            if existing.owner_fingerprint.is_none()
+                && let Some(fp) = fingerprint
+            {
+                mods.push(Mod::Replace(schema.owner_fingerprint_attr.clone(), HashSet::from([fp])));
+            }
+            if existing.owner_team.is_none()
+                && let Some(team) = team
+            {
+                mods.push(Mod::Replace(schema.owner_team_attr.clone(), HashSet::from([team])));
+            }
+            self.modify_entry(&entry.dn, mods).await?;
+            return Ok(UpsertOutcome::Updated);
+        }
+
+        self.check_links(&schema, storage::declared_type(&fields), &fields).await?;
+        let record = storage::new_record(0, fields, fingerprint, team, &self.field_schema)?;
+        self.add_entry(&schema, &record).await?;
+        Ok(UpsertOutcome::Created)
+    }
+
+    #[instrument(skip(self))]
+    async fn delete_record(&self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String]) -> Result<usize, StorageError> {
+        let schema = self.schema.current();
+        let entries = self.authorized_entries(&schema, selections, fingerprint.as_ref(), teams).await?;
+        for entry in &entries {
+            info!("Deleting LDAP entry {}", entry.dn);
+            self.pool.delete(&entry.dn).await?;
+        }
+        Ok(entries.len())
+    }
+
+    /// Purpose: Applies change modifications to every matched entry the caller owns, with the
+    /// same replace/append semantics as the local tiers. `source` is unused: the directory
+    /// keeps no Pharos revision history.
+    #[instrument(skip(self))]
+    async fn change_record(&self, selections: &[(Option<String>, String)], modifications: &[(String, String)], fingerprint: Option<String>, teams: &[String], _source: Option<String>, force: bool) -> Result<usize, StorageError> {
+        storage::validate_modifications(modifications)?;
+
+        let schema = self.schema.current();
+        let entries = self.authorized_entries(&schema, selections, fingerprint.as_ref(), teams).await?;
+        let records: Vec<Record> = entries.iter().map(|entry| Self::entry_to_record(&schema, entry.clone())).collect();
+        storage::check_change(&self.field_schema, &records, modifications, force)?;
+        let written = storage::written_values(modifications);
+        for record in &records {
+            self.check_links(&schema, schema::type_of(record), &written).await?;
+        }
+        for entry in &entries {
+            let mods = Self::field_mods(&schema, &self.field_schema, entry, modifications);
+            self.modify_entry(&entry.dn, mods).await?;
+        }
+        Ok(entries.len())
+    }
+
+    /// The directory is the system of record and deletes entries outright, so it holds no
+    /// tombstones; restoring a deleted entry is left to the directory's own backups.
+    async fn tombstones(&self, _selections: &[(Option<String>, String)]) -> Result<Vec<Record>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    async fn undelete_record(&self, _selections: &[(Option<String>, String)], _fingerprint: Option<String>, _teams: &[String], _admin: bool) -> Result<usize, StorageError> {
+        Err(StorageError::InvalidArgument(
+            "the LDAP backend deletes entries permanently, so there is nothing to undelete".to_string(),
+        ))
+    }
+
+    async fn purge_tombstones(&self, _cutoff: DateTime<Utc>) -> Result<usize, StorageError> {
+        Ok(0)
+    }
+
+    /// Peers sharing a directory see each other's deletes directly; nothing to import.
+    async fn import_tombstone(&self, _identity: &str, _deleted_at: &str) -> Result<bool, StorageError> {
+        Ok(false)
+    }
+
+    async fn field_schema(&self) -> Arc<FieldSchema> {
+        Arc::clone(&self.field_schema)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ldap3::LdapResult;
+
+    fn storage() -> LdapStorage {
+        LdapStorage::new(
+            "ldap://localhost:389".to_string(),
+            "cn=admin,dc=example,dc=org".to_string(),
+            "admin".to_string(),
+            "dc=example,dc=org".to_string(),
+        )
+    }
+
+    fn machine(hostname: &str) -> Record {
+        storage::new_record(
+            0,
+            vec![
+                ("type".to_string(), "machine".to_string()),
+                ("hostname".to_string(), hostname.to_string()),
+                ("ip_addr".to_string(), "10.0.0.1".to_string()),
+                ("ip_addr".to_string(), "10.0.0.2".to_string()),
+            ],
+            Some("SHA256:abc".to_string()),
+            Some("ops".to_string()),
+            &FieldSchema::default(),
+        )
+        .unwrap()
+    }
+
+    fn entry(dn: &str, attrs: &[(&str, &[&str])]) -> SearchEntry {
+        SearchEntry {
+            dn: dn.to_string(),
+            attrs: attrs
+                .iter()
+                .map(|(a, vals)| (a.to_string(), vals.iter().map(|v| v.to_string()).collect()))
+                .collect(),
+            bin_attrs: HashMap::new(),
+        }
+    }
+
+    fn ldap_result(rc: u32) -> LdapError {
+        LdapError::LdapResult {
+            result: LdapResult { rc, matched: String::new(), text: String::new(), refs: vec![], ctrls: vec![] },
+        }
+    }
+
+    const CUSTOM_SCHEMA: &str = r#"
+        owner_fingerprint_attr = "acmeOwnerKey"
+
+        [attributes]
+        email = "acmeMail"
+
+        [types.machine]
+        object_class = "acmeServer"
+        base_dn = "ou=servers,o=acme"
+        dn_template = "acmeHost={hostname},{base_dn}"
+        id_attributes = ["acmeAssetTag"]
+        multi_valued = ["acmeAddress"]
+
+        [types.machine.attributes]
+        hostname = "acmeHost"
+        ip_addr = "acmeAddress"
+    "#;
+
+    fn custom_schema() -> LdapSchema {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ldap-schema.toml");
+        std::fs::write(&path, CUSTOM_SCHEMA).unwrap();
+        LdapSchema::load(&path).unwrap()
+    }
+
+    #[test]
+    fn test_should_render_dn_from_type_template_with_escaping() {
+        let ldap = storage();
+        let schema = LdapSchema::default();
+        assert_eq!(ldap.render_dn(&schema, &machine("web01")).unwrap(), "cn=web01,ou=hosts,dc=example,dc=org");
+        assert_eq!(ldap.render_dn(&schema, &machine("a,b+c")).unwrap(), "cn=a\\,b\\+c,ou=hosts,dc=example,dc=org");
+        assert_eq!(escape_dn_value(" #lead trail "), "\\ #lead trail\\ ");
+        assert_eq!(escape_dn_value("#x"), "\\#x");
+    }
+
+    #[test]
+    fn test_should_reject_write_without_template_or_naming_field() {
+        let ldap = storage();
+        let mut schema = LdapSchema::default();
+        let mut record = machine("web01");
+        record.record_type = Some(RecordType::Other("printer".to_string()));
+        assert!(matches!(ldap.render_dn(&schema, &record), Err(StorageError::InvalidArgument(_))));
+
+        let mut unnamed = machine("web01");
+        unnamed.fields.remove("hostname");
+        assert!(matches!(ldap.render_dn(&schema, &unnamed), Err(StorageError::InvalidArgument(_))));
+
+        schema.types.insert("printer".to_string(), TypeMapping {
+            object_class: "printer".to_string(),
+            dn_template: Some("cn={hostname},ou=printers,{base_dn}".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(ldap.render_dn(&schema, &record).unwrap(), "cn=web01,ou=printers,dc=example,dc=org");
+    }
+
+    #[test]
+    fn test_should_map_fields_and_ownership_to_entry_attributes() {
+        let schema = LdapSchema::default();
+        let attrs: HashMap<String, HashSet<String>> = LdapStorage::entry_attributes(&schema, &machine("web01")).into_iter().collect();
+
+        assert!(attrs["objectClass"].contains("ipHost"));
+        assert_eq!(attrs["cn"], HashSet::from(["web01".to_string()]));
+        assert_eq!(attrs["ipHostNumber"], HashSet::from(["10.0.0.1".to_string(), "10.0.0.2".to_string()]));
+        assert_eq!(attrs[DEFAULT_OWNER_FINGERPRINT_ATTR], HashSet::from(["SHA256:abc".to_string()]));
+        assert_eq!(attrs[DEFAULT_OWNER_TEAM_ATTR], HashSet::from(["ops".to_string()]));
+        assert!(!attrs.contains_key("type"));
+        assert!(!attrs.contains_key("created_at"));
+        assert!(!attrs.contains_key("last_seen_at"));
+    }
+
+    #[test]
+    fn test_should_read_ownership_type_and_multi_values_back_from_entry() {
+        let schema = LdapSchema::default();
+        let entry = entry("cn=web01,ou=hosts,dc=example,dc=org", &[
+            ("objectClass", &["device", "ipHost"]),
+            ("cn", &["web01"]),
+            ("ipHostNumber", &["10.0.0.1", "10.0.0.2"]),
+            (DEFAULT_OWNER_FINGERPRINT_ATTR, &["SHA256:abc"]),
+            (DEFAULT_OWNER_TEAM_ATTR, &["ops"]),
+        ]);
+
+        let record = LdapStorage::entry_to_record(&schema, entry);
+        assert_eq!(record.record_type, Some(RecordType::Machine));
+        assert_eq!(record.fields.get("type").unwrap(), "machine");
+        assert_eq!(record.fields.get("hostname").unwrap(), "web01");
+        assert_eq!(record.multi_fields.get("ip_addr").unwrap(), &vec!["10.0.0.1".to_string(), "10.0.0.2".to_string()]);
+        assert_eq!(record.owner_fingerprint.as_deref(), Some("SHA256:abc"));
+        assert_eq!(record.owner_team.as_deref(), Some("ops"));
+        assert!(!record.fields.contains_key(DEFAULT_OWNER_TEAM_ATTR));
+        assert!(!storage::is_authorized(&record, Some(&"SHA256:other".to_string()), &["dev".to_string()]));
+        assert!(storage::is_authorized(&record, None, &["ops".to_string()]));
+    }
+
+    #[test]
+    fn test_should_replace_single_fields_and_append_new_addresses_only() {
+        let schema = LdapSchema::default();
+        let entry = entry("cn=web01,ou=hosts,dc=example,dc=org", &[
+            ("objectClass", &["ipHost"]),
+            ("ipHostNumber", &["10.0.0.1"]),
+        ]);
+        let mods = LdapStorage::field_mods(&schema, &FieldSchema::default(), &entry, &[
+            ("ip_addr".to_string(), "10.0.0.1".to_string()),
+            ("ip_addr".to_string(), "10.0.0.3".to_string()),
+            ("email".to_string(), "ops@example.org".to_string()),
+            ("last_seen_at".to_string(), "2026-01-01T00:00:00Z".to_string()),
+        ]);
+
+        assert_eq!(mods.len(), 2);
+        assert!(mods.iter().any(|m| matches!(m, Mod::Add(a, v) if a == "ipHostNumber" && *v == HashSet::from(["10.0.0.3".to_string()]))));
+        assert!(mods.iter().any(|m| matches!(m, Mod::Replace(a, v) if a == "mail" && v.contains("ops@example.org"))));
+    }
+
+    #[test]
+    fn test_should_delete_and_replace_attributes_for_removal_edits() {
+        let schema = LdapSchema::default();
+        let entry = entry("cn=web01,ou=hosts,dc=example,dc=org", &[
+            ("objectClass", &["ipHost"]),
+            ("ipHostNumber", &["10.0.0.1", "10.0.0.2"]),
+            ("macAddress", &["AA:BB:CC:DD:EE:01"]),
+            ("mail", &["ops@example.org"]),
+        ]);
+        let mods = LdapStorage::field_mods(&schema, &FieldSchema::default(), &entry, &[
+            ("email".to_string(), String::new()),
+            ("ip_addr-".to_string(), "10.0.0.1".to_string()),
+            ("mac_addr-".to_string(), "aa:bb:cc:dd:ee:01".to_string()),
+            ("description".to_string(), String::new()),
+        ]);
+
+        assert_eq!(mods.len(), 3, "unsetting an absent attribute is a no-op: {:?}", mods);
+        assert!(mods.iter().any(|m| matches!(m, Mod::Delete(a, v) if a == "mail" && v.is_empty())));
+        assert!(mods.iter().any(|m| matches!(m, Mod::Delete(a, v) if a == "ipHostNumber" && *v == HashSet::from(["10.0.0.1".to_string()]))));
+        assert!(mods.iter().any(|m| matches!(m, Mod::Delete(a, v) if a == "macAddress" && v.contains("AA:BB:CC:DD:EE:01"))));
+
+        let mods = LdapStorage::field_mods(&schema, &FieldSchema::default(), &entry, &[
+            ("ip_addr:".to_string(), "10.0.0.3".to_string()),
+            ("ip_addr:".to_string(), "10.0.0.4".to_string()),
+        ]);
+        assert_eq!(mods.len(), 1);
+        assert!(matches!(&mods[0], Mod::Replace(a, v) if a == "ipHostNumber" && v.len() == 2));
+    }
+
+    #[test]
+    fn test_should_translate_ldap_result_codes_to_storage_errors() {
+        assert!(matches!(translate_ldap_error("add", ldap_result(68)), StorageError::Collision));
+        assert!(matches!(translate_ldap_error("modify", ldap_result(50)), StorageError::Unauthorized));
+        assert!(matches!(translate_ldap_error("modify", ldap_result(53)), StorageError::ReadOnly));
+        assert!(matches!(translate_ldap_error("add", ldap_result(65)), StorageError::InvalidArgument(_)));
+        assert!(matches!(translate_ldap_error("bind", ldap_result(49)), StorageError::Internal(_)));
+        assert!(matches!(translate_ldap_error("search", ldap_result(52)), StorageError::Unavailable(_)));
+        assert!(matches!(translate_ldap_error("search", LdapError::EndOfStream), StorageError::Unavailable(_)));
+        assert!(matches!(translate_ldap_error("search", LdapError::UnknownScheme("x".to_string())), StorageError::Internal(_)));
+    }
+
+    #[test]
+    fn test_should_count_each_mapped_type_under_its_own_base() {
+        let ldap = storage();
+        let mut schema = custom_schema();
+        schema.types.insert("person".to_string(), LdapSchema::default().types["person"].clone());
+        let mut searches = ldap.count_searches(&schema);
+        searches.sort();
+        assert_eq!(searches, vec![
+            ("dc=example,dc=org".to_string(), "(objectClass=inetOrgPerson)".to_string()),
+            ("ou=servers,o=acme".to_string(), "(objectClass=acmeServer)".to_string()),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_should_surface_unreachable_directory_instead_of_empty_results() {
+        let config = LdapPoolConfig::new("ldap://127.0.0.1:1".to_string(), String::new(), String::new());
+        let ldap = LdapStorage::with_pool_config(config, "dc=example,dc=org".to_string());
+
+        let query = ldap.query(&selection(Some("hostname"), "web01"), Some(RecordType::Machine)).await;
+        assert!(matches!(query, Err(StorageError::Unavailable(_))), "{:?}", query);
+        let delete = ldap.delete_record(&selection(Some("hostname"), "web01"), None, &[]).await;
+        assert!(matches!(delete, Err(StorageError::Unavailable(_))), "{:?}", delete);
+        assert_eq!(ldap.record_count().await, 0);
+    }
+
+    #[test]
+    fn test_should_escape_filter_metacharacters() {
+        assert_eq!(escape_filter_value("a*(b)\\c"), "a\\2a\\28b\\29\\5cc");
+    }
+
+    fn selection(field: Option<&str>, value: &str) -> Vec<(Option<String>, String)> {
+        vec![(field.map(str::to_string), value.to_string())]
+    }
+
+    #[test]
+    fn test_should_escape_literal_words_in_substring_assertions() {
+        let ldap = storage();
+        let schema = LdapSchema::default();
+        let filter = |sel: &[(Option<String>, String)]| ldap.build_filter(&schema, sel, None).unwrap();
+
+        assert_eq!(filter(&selection(Some("hostname"), "web01")), "(cn=*web01*)");
+        assert_eq!(filter(&selection(Some("hostname"), "a)(objectClass=\\00")), "(cn=*a\\29\\28objectClass=\\5c00*)");
+        assert_eq!(filter(&selection(Some("email"), "ops team")), "(&(mail=*ops*)(mail=*team*))");
+        assert!(matches!(
+            ldap.build_filter(&schema, &selection(Some("cn)(x"), "y"), None),
+            Err(StorageError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_should_translate_wildcards_to_substring_filters() {
+        let ldap = storage();
+        let schema = LdapSchema::default();
+        let filter = |value: &str| ldap.build_filter(&schema, &selection(Some("hostname"), value), None);
+
+        assert_eq!(filter("web*").unwrap(), "(cn=*web*)");
+        assert_eq!(filter("*db*prod").unwrap(), "(cn=*db*prod*)");
+        assert_eq!(filter("web?[0-9]+x").unwrap(), "(cn=*web*x*)");
+        assert_eq!(filter("*").unwrap(), "(cn=*)");
+        assert_eq!(filter("[ab]?").unwrap(), "(cn=*)");
+        assert!(matches!(filter("web[0-9"), Err(StorageError::InvalidArgument(_))));
+        assert!(matches!(filter("web]"), Err(StorageError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_should_map_type_and_field_less_selections() {
+        let ldap = storage();
+        let schema = LdapSchema::default();
+
+        assert_eq!(
+            ldap.build_filter(&schema, &selection(None, "web01"), Some(&RecordType::Machine)).unwrap(),
+            "(&(objectClass=ipHost)(|(cn=*web01*)(ipHostNumber=*web01*)(macAddress=*web01*)(mail=*web01*)(telephoneNumber=*web01*)))"
+        );
+        // An explicit type selection replaces the default type's objectClass.
+        assert_eq!(
+            ldap.build_filter(&schema, &selection(Some("type"), "person"), Some(&RecordType::Machine)).unwrap(),
+            "(objectClass=inetOrgPerson)"
+        );
+        assert_eq!(ldap.build_filter(&schema, &selection(Some("type"), "pers*"), None).unwrap(), "(objectClass=*)");
+        // ... and its attribute mapping replaces the default type's (no machine-only macAddress).
+        let person = vec![(Some("type".to_string()), "person".to_string()), (Some("name".to_string()), "ann".to_string()), (None, "ops".to_string())];
+        assert_eq!(
+            ldap.build_filter(&schema, &person, Some(&RecordType::Machine)).unwrap(),
+            "(&(objectClass=inetOrgPerson)(cn=*ann*)(|(cn=*ops*)(ipHostNumber=*ops*)(mail=*ops*)(telephoneNumber=*ops*)))"
+        );
+    }
+
+    #[test]
+    fn test_should_post_filter_entries_with_local_tier_semantics() {
+        let schema = LdapSchema::default();
+        let entries = || {
+            vec![
+                entry("cn=web01,ou=hosts,dc=example,dc=org", &[("objectClass", &["ipHost"]), ("cn", &["web01"])]),
+                entry("cn=web1,ou=hosts,dc=example,dc=org", &[("objectClass", &["ipHost"]), ("cn", &["web1"])]),
+                entry("cn=oldweb01,ou=hosts,dc=example,dc=org", &[("objectClass", &["ipHost"]), ("cn", &["oldweb01"])]),
+                entry("cn=db,ou=hosts,dc=example,dc=org", &[("objectClass", &["ipHost"]), ("cn", &["db"]), ("description", &["web01 replica"])]),
+            ]
+        };
+        let hostnames = |sel: &[(Option<String>, String)]| -> Vec<String> {
+            LdapStorage::select_records(&schema, &FieldSchema::default(), entries(), sel)
+                .unwrap()
+                .into_iter()
+                .map(|(_, r)| r.fields["hostname"].clone())
+                .collect()
+        };
+
+        // A substring filter would return oldweb01 too; word matching must not.
+        assert_eq!(hostnames(&selection(Some("hostname"), "web01")), vec!["web01"]);
+        assert_eq!(hostnames(&selection(Some("hostname"), "web?1")), vec!["web01"]);
+        assert_eq!(hostnames(&selection(Some("hostname"), "web[12]")), vec!["web1"]);
+        assert_eq!(hostnames(&selection(Some("hostname"), "web*")), vec!["web01", "web1"]);
+        assert_eq!(hostnames(&selection(None, "WEB01")), vec!["web01", "db"]);
+        assert_eq!(hostnames(&selection(Some("description"), "replica web01")), vec!["db"]);
+    }
+
+    #[test]
+    fn test_should_apply_custom_schema_mapping_from_file() {
+        let ldap = storage();
+        let schema = custom_schema();
+
+        let filter = ldap.build_filter(&schema, &[(Some("hostname".to_string()), "web01".to_string())], Some(&RecordType::Machine));
+        assert_eq!(filter.unwrap(), "(&(objectClass=acmeServer)(acmeHost=*web01*))");
+        assert_eq!(ldap.base_for(schema.mapping("machine")), "ou=servers,o=acme");
+        assert_eq!(ldap.render_dn(&schema, &machine("web01")).unwrap(), "acmeHost=web01,ou=servers,o=acme");
+
+        let attrs: HashMap<String, HashSet<String>> = LdapStorage::entry_attributes(&schema, &machine("web01")).into_iter().collect();
+        assert_eq!(attrs["objectClass"], HashSet::from(["top".to_string(), "acmeServer".to_string()]));
+        assert!(attrs.contains_key("acmeAddress"));
+        assert!(attrs.contains_key("acmeOwnerKey"));
+
+        let first = entry("acmeHost=web01,ou=servers,o=acme", &[
+            ("objectClass", &["acmeServer"]),
+            ("acmeHost", &["web01"]),
+            ("acmeAssetTag", &["A-1001"]),
+            ("acmeAddress", &["10.0.0.1", "10.0.0.2"]),
+            ("acmeMail", &["ops@acme.example"]),
+        ]);
+        let mut renamed = first.clone();
+        renamed.dn = "acmeHost=web01-new,ou=servers,o=acme".to_string();
+
+        let record = LdapStorage::entry_to_record(&schema, first);
+        assert_eq!(record.record_type, Some(RecordType::Machine));
+        assert_eq!(record.fields.get("hostname").unwrap(), "web01");
+        assert_eq!(record.fields.get("email").unwrap(), "ops@acme.example");
+        assert_eq!(record.multi_fields.get("ip_addr").unwrap().len(), 2);
+        // The id follows the asset tag, not the DN.
+        assert_eq!(record.id, LdapStorage::entry_to_record(&schema, renamed).id);
+    }
+
+    #[test]
+    fn test_should_parse_example_schema_file_as_built_in_mapping() {
+        let example: LdapSchema = toml::from_str(include_str!("../../docs/ldap-schema.example.toml")).unwrap();
+        let built_in = LdapSchema::default();
+        assert_eq!(example.attributes, built_in.attributes);
+        assert_eq!(example.types.keys().collect::<Vec<_>>(), built_in.types.keys().collect::<Vec<_>>());
+        for (name, mapping) in &built_in.types {
+            let parsed = &example.types[name];
+            assert_eq!(parsed.object_classes(), mapping.object_classes());
+            assert_eq!(parsed.dn_template, mapping.dn_template);
+            assert_eq!(parsed.attributes, mapping.attributes);
+            assert_eq!(parsed.multi_valued, mapping.multi_valued);
+        }
+    }
+
+    #[test]
+    fn test_should_reject_unknown_keys_in_schema_file_and_keep_previous_on_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ldap-schema.toml");
+        std::fs::write(&path, "[types.machine]\nobject_class = \"ipHost\"\nobjectclasses = [\"x\"]\n").unwrap();
+        assert!(LdapSchema::load(&path).is_err());
+
+        std::fs::write(&path, "[types.machine]\nobject_class = \"\"\n").unwrap();
+        assert!(LdapSchema::load(&path).is_err());
+
+        let handle = LdapSchemaHandle::new(custom_schema());
+        let snapshot = handle.current();
+        handle.swap(LdapSchema::load(&path));
+        assert_eq!(handle.current().owner_fingerprint_attr, "acmeOwnerKey");
+
+        handle.swap(Ok(LdapSchema::default()));
+        assert_eq!(handle.current().owner_fingerprint_attr, DEFAULT_OWNER_FINGERPRINT_ATTR);
+        // An operation already holding a snapshot keeps seeing the mapping it started with.
+        assert_eq!(snapshot.owner_fingerprint_attr, "acmeOwnerKey");
+    }
+}
@@ -0,0 +1,329 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/ldap_pool.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Pooled, asynchronous access to the Enterprise tier's directory. Connections
+ * are bound once and reused, every request is bounded by a timeout, large
+ * result sets are fetched in pages (RFC 2696), and an unreachable server is
+ * retried with exponential backoff instead of on every query.
+ * * Traceability:
+ * Related to Task 2.3 (Storage Tiering), LdapStorage.
+ * ======================================================================== */
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use ldap3::adapters::{Adapter, EntriesOnly, PagedResults};
+use ldap3::{drive, Ldap, LdapConnAsync, LdapConnSettings, LdapError, Mod, Scope, SearchEntry};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::{info, warn};
+use crate::ldap::translate_ldap_error;
+use crate::storage::StorageError;
+
+const DEFAULT_POOL_SIZE: usize = 4;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_PAGE_SIZE: i32 = 500;
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+#[derive(Clone)]
+pub struct LdapPoolConfig {
+    pub url: String,
+    pub bind_dn: String,
+    pub bind_pw: String,
+    /// Upper bound on open connections, and so on concurrent directory requests.
+    pub size: usize,
+    /// Connect timeout, and the longest the server may take to answer one request (or page).
+    pub timeout: Duration,
+    /// Entries per page of a search.
+    pub page_size: i32,
+    /// Longest wait between reconnect attempts while the server is unreachable.
+    pub max_backoff: Duration,
+}
+
+impl LdapPoolConfig {
+    pub fn new(url: String, bind_dn: String, bind_pw: String) -> Self {
+        Self {
+            url,
+            bind_dn,
+            bind_pw,
+            size: DEFAULT_POOL_SIZE,
+            timeout: DEFAULT_TIMEOUT,
+            page_size: DEFAULT_PAGE_SIZE,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        }
+    }
+
+    /// Reads `PHAROS_LDAP_POOL_SIZE`, `PHAROS_LDAP_TIMEOUT_SECS`, `PHAROS_LDAP_PAGE_SIZE` and
+    /// `PHAROS_LDAP_MAX_BACKOFF_SECS`, falling back to the defaults (with a warning) on invalid values.
+    pub fn from_env(url: String, bind_dn: String, bind_pw: String) -> Self {
+        let mut config = Self::new(url, bind_dn, bind_pw);
+        if let Some(n) = positive_env("PHAROS_LDAP_POOL_SIZE") {
+            config.size = n as usize;
+        }
+        if let Some(secs) = positive_env("PHAROS_LDAP_TIMEOUT_SECS") {
+            config.timeout = Duration::from_secs(secs);
+        }
+        if let Some(n) = positive_env("PHAROS_LDAP_PAGE_SIZE") {
+            config.page_size = n.min(i32::MAX as u64) as i32;
+        }
+        if let Some(secs) = positive_env("PHAROS_LDAP_MAX_BACKOFF_SECS") {
+            config.max_backoff = Duration::from_secs(secs);
+        }
+        config
+    }
+}
+
+fn positive_env(name: &str) -> Option<u64> {
+    let raw = std::env::var(name).ok()?;
+    match raw.trim().parse::<u64>() {
+        Ok(n) if n > 0 => Some(n),
+        _ => {
+            warn!("Ignoring invalid {} value '{}'", name, raw);
+            None
+        }
+    }
+}
+
+/// Reconnect schedule after failed connection attempts: 0.5s, 1s, 2s, ... up to `max`.
+#[derive(Debug, Default)]
+struct Backoff {
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Backoff {
+    /// Time left before another connection attempt is allowed.
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.retry_at.filter(|at| *at > now).map(|at| at - now)
+    }
+
+    fn record_failure(&mut self, now: Instant, max: Duration) -> Duration {
+        let delay = INITIAL_BACKOFF.saturating_mul(1 << self.failures.min(16)).min(max);
+        self.failures = self.failures.saturating_add(1);
+        self.retry_at = Some(now + delay);
+        delay
+    }
+
+    fn record_success(&mut self) {
+        self.failures = 0;
+        self.retry_at = None;
+    }
+}
+
+/// Failures of the connection itself rather than of the request: the connection is dropped and,
+/// for a connection that sat idle in the pool, the request is retried once on a fresh one.
+fn is_connection_error(err: &LdapError) -> bool {
+    matches!(
+        err,
+        LdapError::Io { .. } | LdapError::OpSend { .. } | LdapError::ResultRecv { .. } | LdapError::EndOfStream | LdapError::Timeout { .. }
+    )
+}
+
+pub struct LdapPool {
+    config: LdapPoolConfig,
+    idle: Mutex<Vec<Ldap>>,
+    permits: Semaphore,
+    backoff: Mutex<Backoff>,
+}
+
+/// A connection checked out of the pool. It goes back to the pool only through `release`;
+/// dropping it closes the connection.
+struct PooledConn<'a> {
+    ldap: Ldap,
+    reused: bool,
+    pool: &'a LdapPool,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl PooledConn<'_> {
+    fn release(self) {
+        if let Ok(mut idle) = self.pool.idle.lock() {
+            idle.push(self.ldap);
+        }
+    }
+}
+
+impl LdapPool {
+    pub fn new(config: LdapPoolConfig) -> Self {
+        let size = config.size.max(1);
+        Self {
+            config,
+            idle: Mutex::new(Vec::new()),
+            permits: Semaphore::new(size),
+            backoff: Mutex::new(Backoff::default()),
+        }
+    }
+
+    /// Opens and binds a new connection. The bind is kept for the connection's lifetime, so
+    /// pooled connections never bind again.
+    async fn connect(&self) -> Result<Ldap, StorageError> {
+        if let Some(wait) = self.backoff.lock().map_err(|_| poisoned())?.remaining(Instant::now()) {
+            return Err(StorageError::Unavailable(format!(
+                "LDAP server unreachable, next connection attempt in {}ms",
+                wait.as_millis()
+            )));
+        }
+
+        let settings = LdapConnSettings::new().set_conn_timeout(self.config.timeout);
+        let result = async {
+            let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url).await?;
+            drive!(conn);
+            ldap.with_timeout(self.config.timeout)
+                .simple_bind(&self.config.bind_dn, &self.config.bind_pw)
+                .await?
+                .success()?;
+            Ok::<Ldap, LdapError>(ldap)
+        }
+        .await;
+
+        let mut backoff = self.backoff.lock().map_err(|_| poisoned())?;
+        match result {
+            Ok(ldap) => {
+                if backoff.failures > 0 {
+                    info!("Reconnected to LDAP server at {}", self.config.url);
+                }
+                backoff.record_success();
+                Ok(ldap)
+            }
+            Err(e) => {
+                let delay = backoff.record_failure(Instant::now(), self.config.max_backoff);
+                warn!("LDAP connection to {} failed ({}); retrying in {}ms", self.config.url, e, delay.as_millis());
+                Err(translate_ldap_error("bind", e))
+            }
+        }
+    }
+
+    async fn acquire(&self) -> Result<PooledConn<'_>, StorageError> {
+        let permit = self.permits.acquire().await.map_err(|_| StorageError::Internal("LDAP pool closed".to_string()))?;
+        loop {
+            let idle = self.idle.lock().map_err(|_| poisoned())?.pop();
+            let Some(mut ldap) = idle else { break };
+            if !ldap.is_closed() {
+                return Ok(PooledConn { ldap, reused: true, pool: self, _permit: permit });
+            }
+        }
+        let ldap = self.connect().await?;
+        Ok(PooledConn { ldap, reused: false, pool: self, _permit: permit })
+    }
+
+    /// Runs `op` on a pooled connection. A pooled connection that turns out to be dead is
+    /// discarded and the request retried once on a fresh connection.
+    async fn with_conn<T, F, Fut>(&self, operation: &str, op: F) -> Result<T, StorageError>
+    where
+        F: Fn(Ldap) -> Fut,
+        Fut: Future<Output = Result<T, LdapError>>,
+    {
+        loop {
+            let conn = self.acquire().await?;
+            let mut ldap = conn.ldap.clone();
+            ldap.with_timeout(self.config.timeout);
+            match op(ldap).await {
+                Ok(value) => {
+                    conn.release();
+                    return Ok(value);
+                }
+                Err(e) if is_connection_error(&e) => {
+                    if conn.reused {
+                        warn!("Pooled LDAP connection failed during {} ({}); retrying on a new connection", operation, e);
+                        continue;
+                    }
+                    return Err(translate_ldap_error(operation, e));
+                }
+                Err(e) => {
+                    conn.release();
+                    return Err(translate_ldap_error(operation, e));
+                }
+            }
+        }
+    }
+
+    /// Subtree search, fetched page by page.
+    pub async fn search(&self, base: &str, filter: &str, attrs: &[&str]) -> Result<Vec<SearchEntry>, StorageError> {
+        let page_size = self.config.page_size;
+        self.with_conn("search", |mut ldap| async move {
+            let adapters: Vec<Box<dyn Adapter<_, _>>> = vec![Box::new(EntriesOnly::new()), Box::new(PagedResults::new(page_size))];
+            let mut stream = ldap.streaming_search_with(adapters, base, Scope::Subtree, filter, attrs.to_vec()).await?;
+            let mut entries = Vec::new();
+            while let Some(entry) = stream.next().await? {
+                entries.push(SearchEntry::construct(entry));
+            }
+            stream.finish().await.success()?;
+            Ok(entries)
+        })
+        .await
+    }
+
+    /// Number of entries a subtree search matches, without transferring their attributes.
+    pub async fn count(&self, base: &str, filter: &str) -> Result<usize, StorageError> {
+        // "1.1" requests no attributes (RFC 4511 Section 4.5.1.8).
+        Ok(self.search(base, filter, &["1.1"]).await?.len())
+    }
+
+    pub async fn add(&self, dn: &str, attrs: Vec<(String, std::collections::HashSet<String>)>) -> Result<(), StorageError> {
+        self.with_conn("add", |mut ldap| {
+            let attrs = attrs.clone();
+            async move { ldap.add(dn, attrs).await?.success().map(|_| ()) }
+        })
+        .await
+    }
+
+    pub async fn modify(&self, dn: &str, mods: Vec<Mod<String>>) -> Result<(), StorageError> {
+        self.with_conn("modify", |mut ldap| {
+            let mods = mods.clone();
+            async move { ldap.modify(dn, mods).await?.success().map(|_| ()) }
+        })
+        .await
+    }
+
+    pub async fn delete(&self, dn: &str) -> Result<(), StorageError> {
+        self.with_conn("delete", |mut ldap| async move { ldap.delete(dn).await?.success().map(|_| ()) })
+            .await
+    }
+}
+
+fn poisoned() -> StorageError {
+    StorageError::Internal("LDAP pool lock poisoned".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_back_off_exponentially_up_to_the_cap() {
+        let mut backoff = Backoff::default();
+        let now = Instant::now();
+        let max = Duration::from_secs(3);
+
+        assert_eq!(backoff.remaining(now), None);
+        assert_eq!(backoff.record_failure(now, max), Duration::from_millis(500));
+        assert_eq!(backoff.record_failure(now, max), Duration::from_secs(1));
+        assert_eq!(backoff.record_failure(now, max), Duration::from_secs(2));
+        assert_eq!(backoff.record_failure(now, max), max);
+        assert_eq!(backoff.record_failure(now, max), max);
+        assert_eq!(backoff.remaining(now), Some(max));
+        assert_eq!(backoff.remaining(now + max), None);
+
+        backoff.record_success();
+        assert_eq!(backoff.remaining(now), None);
+        assert_eq!(backoff.record_failure(now, max), Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn test_should_report_unreachable_server_as_unavailable_and_back_off() {
+        // Port 1 on loopback refuses connections immediately.
+        let pool = LdapPool::new(LdapPoolConfig::new("ldap://127.0.0.1:1".to_string(), String::new(), String::new()));
+
+        let first = pool.search("dc=example,dc=org", "(objectClass=*)", &["*"]).await;
+        assert!(matches!(first, Err(StorageError::Unavailable(_))), "{:?}", first);
+
+        // Inside the backoff window the pool fails fast without dialing again.
+        match pool.search("dc=example,dc=org", "(objectClass=*)", &["*"]).await {
+            Err(StorageError::Unavailable(msg)) => assert!(msg.contains("next connection attempt"), "{}", msg),
+            other => panic!("expected Unavailable, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,296 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/links.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Records used to be isolated bags of fields: "this VM runs on pve01" was a
+ * free-text value nothing checked, and it kept pointing at pve01 long after
+ * pve01 was deleted. A field the schema declares `kind = "link"` holds the
+ * hostname or alias of another record instead. Writes are refused unless the
+ * target is a live record (of the field's `target` type, if it has one),
+ * deleting a record drops the links that pointed at it, and `links` follows
+ * them to a record's children, its parents, or all of either.
+ * * Traceability:
+ * Backs the `links` protocol command and `mdb tree`.
+ * ======================================================================== */
+
+use crate::schema::{self, FieldSchema};
+use crate::storage::{self, Record, Storage, StorageError};
+use std::collections::{BTreeSet, HashSet, VecDeque};
+
+/// Which way `links` follows links from the records it selected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Direction {
+    /// Records that link to the selected ones: the VMs running on a hypervisor.
+    #[default]
+    Children,
+    /// Records the selected ones link to: the hypervisor a VM runs on.
+    Parents,
+}
+
+/// A record `links` reached, and how.
+#[derive(Debug, Clone)]
+pub struct Reached {
+    pub record: Record,
+    /// Links followed to get here; 0 for the selected records themselves.
+    pub depth: usize,
+    /// The record this one was reached from, by its label.
+    pub from: Option<String>,
+    /// The link field followed to get here.
+    pub via: Option<String>,
+}
+
+/// The hostname and alias a record can be linked to by.
+pub fn identities(record: &Record) -> impl Iterator<Item = &str> {
+    ["hostname", "alias"].into_iter().filter_map(|k| record.fields.get(k).map(String::as_str))
+}
+
+/// How a record is named in `links` output: its hostname, else its alias, else `#id`.
+pub fn label(record: &Record) -> String {
+    identities(record).next().map_or_else(|| format!("#{}", record.id), str::to_string)
+}
+
+/// The values `record` holds in its type's link fields, as (field, value) pairs.
+fn link_values<'a>(schema: &FieldSchema, record: &'a Record) -> Vec<(&'a str, &'a str)> {
+    let record_type = schema::type_of(record);
+    let single = record.fields.iter().map(|(name, value)| (name.as_str(), value.as_str()));
+    let multi = record.multi_fields.iter().flat_map(|(name, values)| values.iter().map(move |v| (name.as_str(), v.as_str())));
+    single.chain(multi).filter(|(name, _)| schema.is_link(record_type, name)).collect()
+}
+
+/// Checks the link values a write sets on a record of `record_type`. `lookup` returns the type
+/// of the live record whose hostname or alias is exactly the given value, if there is one.
+pub(crate) fn check_links(
+    schema: &FieldSchema,
+    record_type: &str,
+    fields: &[(String, String)],
+    mut lookup: impl FnMut(&str) -> Result<Option<String>, StorageError>,
+) -> Result<(), StorageError> {
+    for (name, value) in fields.iter().filter(|(name, _)| schema.is_link(record_type, name)) {
+        let target = schema.field(Some(record_type), name).and_then(|def| def.target.as_deref());
+        match lookup(value.trim())? {
+            None => {
+                return Err(StorageError::InvalidArgument(format!(
+                    "field '{}' links to '{}', which is not the hostname or alias of a live record",
+                    name, value
+                )));
+            }
+            Some(found) if target.is_some_and(|t| !t.eq_ignore_ascii_case(&found)) => {
+                return Err(StorageError::InvalidArgument(format!(
+                    "field '{}' must link to a {} record, but '{}' is a {}",
+                    name,
+                    target.unwrap_or_default(),
+                    value,
+                    found
+                )));
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Drops every link on `record` that points at one of `identities`, e.g. those of records
+/// just deleted, or points it at `replacement` instead, e.g. the record they were merged into.
+/// Returns whether anything changed.
+pub(crate) fn unlink(schema: &FieldSchema, record: &mut Record, identities: &BTreeSet<String>, replacement: Option<&str>) -> bool {
+    let record_type = schema::type_of(record).to_string();
+    let stale = |value: &String| identities.contains(value) && replacement != Some(value.as_str());
+    let mut changed = false;
+    record.fields.retain(|name, value| {
+        if !schema.is_link(&record_type, name) || !stale(value) {
+            return true;
+        }
+        changed = true;
+        match replacement {
+            Some(replacement) => {
+                *value = replacement.to_string();
+                true
+            }
+            None => false,
+        }
+    });
+    for (_, values) in record.multi_fields.iter_mut().filter(|(name, _)| schema.is_link(&record_type, name)) {
+        if !values.iter().any(stale) {
+            continue;
+        }
+        changed = true;
+        let mut kept: Vec<String> = Vec::new();
+        for value in values.drain(..) {
+            let value = match replacement {
+                Some(replacement) if stale(&value) => replacement.to_string(),
+                None if stale(&value) => continue,
+                _ => value,
+            };
+            if !kept.contains(&value) {
+                kept.push(value);
+            }
+        }
+        *values = kept;
+    }
+    record.multi_fields.retain(|_, values| !values.is_empty());
+    changed
+}
+
+/// Live records holding a link to `identity`, paired with the field that holds it.
+async fn children_of(storage: &dyn Storage, schema: &FieldSchema, identity: &str, via: Option<&str>) -> Result<Vec<(Record, String)>, StorageError> {
+    let mut found = Vec::new();
+    for name in schema.link_names().into_iter().filter(|name| via.is_none_or(|v| v == *name)) {
+        for record in storage.query(&[(Some(name.to_string()), identity.to_string())], None).await? {
+            // A query matches words; a link has to name the record exactly.
+            if link_values(schema, &record).iter().any(|(field, value)| *field == name && *value == identity) {
+                found.push((record, name.to_string()));
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// The live records `record` links to, paired with the field that links them.
+async fn parents_of(storage: &dyn Storage, schema: &FieldSchema, record: &Record, via: Option<&str>) -> Result<Vec<(Record, String)>, StorageError> {
+    let mut found = Vec::new();
+    for (name, value) in link_values(schema, record).into_iter().filter(|(name, _)| via.is_none_or(|v| v == *name)) {
+        for key in ["hostname", "alias"] {
+            let matches = storage.query(&[(Some(key.to_string()), value.to_string())], None).await?;
+            if let Some(target) = matches.into_iter().find(|r| storage::is_identity_match(r, value)) {
+                found.push((target, name.to_string()));
+                break;
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Follows links breadth-first from `start`, up to `max_depth` links away (`None` follows
+/// them all the way). Every record is reported once, at the depth it was first reached, so a
+/// cycle of links ends the walk instead of looping. `via` restricts the walk to one link field.
+pub async fn follow(storage: &dyn Storage, start: Vec<Record>, direction: Direction, max_depth: Option<usize>, via: Option<&str>) -> Result<Vec<Reached>, StorageError> {
+    let schema = storage.field_schema().await;
+    let mut seen: HashSet<usize> = start.iter().map(|r| r.id).collect();
+    let mut queue: VecDeque<Reached> = start.into_iter().map(|record| Reached { record, depth: 0, from: None, via: None }).collect();
+    let mut reached = Vec::new();
+
+    while let Some(node) = queue.pop_front() {
+        if max_depth.is_none_or(|max| node.depth < max) {
+            let next = match direction {
+                Direction::Children => {
+                    let mut children = Vec::new();
+                    for identity in identities(&node.record) {
+                        children.extend(children_of(storage, &schema, identity, via).await?);
+                    }
+                    children
+                }
+                Direction::Parents => parents_of(storage, &schema, &node.record, via).await?,
+            };
+            for (record, field) in next {
+                if seen.insert(record.id) {
+                    queue.push_back(Reached { record, depth: node.depth + 1, from: Some(label(&node.record)), via: Some(field) });
+                }
+            }
+        }
+        reached.push(node);
+    }
+    Ok(reached)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn link_schema() -> FieldSchema {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("field-schema.toml");
+        std::fs::write(&path, r#"
+            [fields.type]
+            required = true
+
+            [types.machine.fields.runs_on]
+            kind = "link"
+            target = "machine"
+
+            [types.machine.fields.owned_by]
+            kind = "link"
+            target = "person"
+
+            [types.machine.fields.depends_on]
+            kind = "link"
+            multi = true
+        "#).unwrap();
+        FieldSchema::load(&path).unwrap()
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_should_require_links_to_name_a_live_record_of_the_target_type() {
+        let schema = link_schema();
+        let lookup = |value: &str| Ok(match value {
+            "pve01" => Some("machine".to_string()),
+            "rdavis" => Some("person".to_string()),
+            _ => None,
+        });
+
+        assert!(check_links(&schema, "machine", &fields(&[("runs_on", "pve01"), ("owned_by", "rdavis"), ("notes", "x")]), lookup).is_ok());
+        for bad in [fields(&[("runs_on", "pve99")]), fields(&[("runs_on", "rdavis")]), fields(&[("owned_by", "pve01")])] {
+            assert!(matches!(check_links(&schema, "machine", &bad, lookup), Err(StorageError::InvalidArgument(_))), "{:?}", bad);
+        }
+        // A person's `runs_on` isn't declared a link, so it's free text.
+        assert!(check_links(&schema, "person", &fields(&[("runs_on", "anything")]), lookup).is_ok());
+    }
+
+    #[test]
+    fn test_should_unlink_or_repoint_only_links_to_the_given_identities() {
+        let schema = link_schema();
+        let mut record = Record {
+            fields: [("type", "machine"), ("runs_on", "pve01"), ("notes", "pve01")].iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            multi_fields: [("depends_on".to_string(), vec!["db01".to_string(), "pve01".to_string()])].into(),
+            ..Default::default()
+        };
+        assert!(unlink(&schema, &mut record, &BTreeSet::from(["pve01".to_string()]), None));
+        assert!(!record.fields.contains_key("runs_on"));
+        assert_eq!(record.fields["notes"], "pve01", "not a link field");
+        assert_eq!(record.multi_fields["depends_on"], vec!["db01"]);
+        assert!(!unlink(&schema, &mut record, &BTreeSet::from(["pve01".to_string()]), None));
+
+        record.multi_fields.insert("depends_on".to_string(), vec!["db01".to_string(), "scan-ab12".to_string()]);
+        record.fields.insert("runs_on".to_string(), "scan-ab12".to_string());
+        assert!(unlink(&schema, &mut record, &BTreeSet::from(["scan-ab12".to_string()]), Some("db01")));
+        assert_eq!(record.fields["runs_on"], "db01");
+        assert_eq!(record.multi_fields["depends_on"], vec!["db01"], "repointed, without a repeat");
+    }
+
+    #[tokio::test]
+    async fn test_should_follow_links_both_ways_and_stop_at_cycles() {
+        let storage = MemoryStorage::new().with_field_schema(link_schema());
+        for (hostname, extra) in [("pve01", vec![]), ("vm-web", vec![("runs_on", "pve01")]), ("vm-db", vec![("runs_on", "pve01")])] {
+            let mut record = fields(&[("type", "machine"), ("hostname", hostname)]);
+            record.extend(fields(&extra));
+            storage.add_record(record, None, None).await.unwrap();
+        }
+        storage.add_record(fields(&[("type", "machine"), ("hostname", "svc-api"), ("depends_on", "vm-db"), ("depends_on", "vm-web")]), None, None).await.unwrap();
+        // Close a cycle: the hypervisor depends on one of its own services.
+        storage.change_record(&[(Some("hostname".to_string()), "pve01".to_string())], &fields(&[("depends_on", "svc-api")]), None, &[], None, false).await.unwrap();
+
+        let start = storage.query(&[(Some("hostname".to_string()), "pve01".to_string())], None).await.unwrap();
+        let tree = follow(&storage, start.clone(), Direction::Children, None, Some("runs_on")).await.unwrap();
+        let labels: Vec<(String, usize)> = tree.iter().map(|r| (label(&r.record), r.depth)).collect();
+        assert_eq!(labels[0], ("pve01".to_string(), 0));
+        assert_eq!(labels.len(), 3);
+        assert!(tree[1..].iter().all(|r| r.from.as_deref() == Some("pve01") && r.via.as_deref() == Some("runs_on")));
+
+        let all = follow(&storage, start.clone(), Direction::Children, None, None).await.unwrap();
+        assert_eq!(all.len(), 4, "svc-api once, though it links to two children and pve01 links back to it");
+        assert_eq!(all[3].depth, 2);
+
+        let svc = storage.query(&[(Some("hostname".to_string()), "svc-api".to_string())], None).await.unwrap();
+        let parents = follow(&storage, svc, Direction::Parents, Some(1), None).await.unwrap();
+        let mut names: Vec<String> = parents.iter().skip(1).map(|r| label(&r.record)).collect();
+        names.sort();
+        assert_eq!(names, vec!["vm-db", "vm-web"]);
+    }
+}
@@ -11,15 +11,25 @@
  * Implements RFC 2378 Section 2.
  * ======================================================================== */
 
-use pharos_server::storage::{Storage, MemoryStorage, FileStorage, LdapStorage};
+use pharos_server::storage::{Storage, MemoryStorage, FileStorage};
+use pharos_server::sqlite::SqliteStorage;
+use pharos_server::ldap::{LdapSchema, LdapSchemaHandle, LdapStorage};
+use pharos_server::schema::FieldSchema;
+use pharos_server::sealing::SealingKey;
+use pharos_server::file_cipher::FileCipher;
+use pharos_server::journal::JournalConfig;
+use pharos_server::recovery::CorruptPolicy;
 use pharos_server::metrics::{CPU_USAGE, MEMORY_USAGE_BYTES, TOTAL_RECORDS, gather_metrics, check_health_thresholds};
 use pharos_server::auth::{AuthManager, SecurityTier};
 use pharos_server::middleware::{MiddlewareChain, LoggingMiddleware, ReadOnlyMiddleware, SecurityTierMiddleware};
 use pharos_server::handle_connection;
 use pharos_server::sync;
+use pharos_server::tombstone::TombstoneConfig;
+use pharos_server::expiry::{self, ExpiryConfig};
 use pharos_server::alerting::{self, AlertState};
+use pharos_server::offline;
 use tokio::net::TcpListener;
-use tracing::{info, error};
+use tracing::{info, warn, error};
 use std::sync::{Arc, RwLock};
 use sysinfo::System;
 use warp::Filter;
@@ -89,6 +99,20 @@ async fn main() -> anyhow::Result<()> {
     let args: Vec<String> = env::args().collect();
     let use_tui = args.contains(&"--tui".to_string());
 
+    // `pharos-server fsck|export|import|migrate` work on a stopped server's data and exit. Their
+    // report (or an export) goes to stdout, so logs go to stderr.
+    if args.get(1).is_some_and(|command| offline::SUBCOMMANDS.contains(&command.as_str())) {
+        tracing_subscriber::fmt()
+            .with_writer(std::io::stderr)
+            .with_env_filter(
+                tracing_subscriber::EnvFilter::builder()
+                    .with_default_directive(tracing_subscriber::filter::LevelFilter::INFO.into())
+                    .from_env_lossy(),
+            )
+            .init();
+        return offline::run(&args[1..], &mut std::io::stdout()).await;
+    }
+
     // Initialize tracing for observability only if TUI is not taking over stdout
     if !use_tui {
         // `from_default_env()` would default to ERROR-only when RUST_LOG is unset; use the
@@ -126,18 +150,55 @@ async fn main() -> anyhow::Result<()> {
     let tls_acceptor: Arc<RwLock<TlsAcceptor>> = Arc::new(RwLock::new(acceptor));
 
     // Determine storage backend based on environment variables
-    let storage: Arc<RwLock<dyn Storage>> = if let Ok(url) = env::var("PHAROS_LDAP_URL") {
+    let field_schema = FieldSchema::from_env()?;
+    let field_key = SealingKey::from_env()?;
+    let file_cipher = FileCipher::from_env()?;
+    let database_backed = env::var("PHAROS_LDAP_URL").is_ok() || env::var("PHAROS_SQLITE_PATH").is_ok();
+    let file_backed = !database_backed && env::var("PHAROS_STORAGE_PATH").is_ok();
+    if field_schema.has_encrypted_fields() {
+        if file_backed && field_key.is_none() {
+            anyhow::bail!("The field schema declares encrypted fields but no field key is configured; set PHAROS_FIELD_KEY or PHAROS_FIELD_KEY_FILE");
+        }
+        if database_backed {
+            warn!("Encrypted fields are only sealed at rest by FileStorage; SqliteStorage and LdapStorage store them as written");
+        }
+    }
+    if database_backed && file_cipher.is_configured() {
+        warn!("PHAROS_STORAGE_KEY only encrypts FileStorage's files; it is ignored with SqliteStorage and LdapStorage");
+    }
+    let mut ldap_schema: Option<LdapSchemaHandle> = None;
+    let storage: Arc<dyn Storage> = if let Ok(url) = env::var("PHAROS_LDAP_URL") {
         info!("Initializing LdapStorage at {}", url);
         let bind_dn = env::var("PHAROS_LDAP_BIND_DN").unwrap_or_default();
         let bind_pw = env::var("PHAROS_LDAP_BIND_PW").unwrap_or_default();
         let base_dn = env::var("PHAROS_LDAP_BASE_DN").unwrap_or_default();
-        Arc::new(RwLock::new(LdapStorage::new(url, bind_dn, bind_pw, base_dn)))
+        let ldap = LdapStorage::new(url, bind_dn, bind_pw, base_dn)
+            .with_schema(LdapSchema::from_env()?)
+            .with_field_schema(field_schema);
+        ldap_schema = Some(ldap.schema_handle());
+        Arc::new(ldap)
+    } else if let Ok(path) = env::var("PHAROS_SQLITE_PATH") {
+        info!("Initializing SqliteStorage at {:?}", path);
+        Arc::new(SqliteStorage::open_with_field_schema(Path::new(&path), field_schema)?)
     } else if let Ok(path) = env::var("PHAROS_STORAGE_PATH") {
         info!("Initializing FileStorage at {:?}", path);
-        Arc::new(RwLock::new(FileStorage::new(PathBuf::from(path))))
+        match file_cipher.key_id() {
+            Some(id) => info!("Encrypting FileStorage files with storage key {}", id),
+            None if file_cipher.is_configured() => warn!("Only a previous storage key is set; FileStorage files will be decrypted and written in the clear"),
+            None => {}
+        }
+        let file = FileStorage::open(PathBuf::from(path), JournalConfig::from_env(), file_cipher, CorruptPolicy::from_env())?.with_field_schema(field_schema);
+        let file = match field_key {
+            Some(key) => {
+                info!("Sealing encrypted fields with field key {}", key.id());
+                file.with_field_key(key)?
+            }
+            None => file,
+        };
+        Arc::new(file)
     } else {
         info!("Initializing in-memory storage (Development Tier)");
-        Arc::new(RwLock::new(MemoryStorage::new()))
+        Arc::new(MemoryStorage::new().with_field_schema(field_schema))
     };
 
     // --- Bootstrap & Self-Registration ---
@@ -173,6 +234,7 @@ async fn main() -> anyhow::Result<()> {
         let reload_tls_acceptor = Arc::clone(&tls_acceptor);
         let reload_cert_path = cert_path_str.clone();
         let reload_key_path = key_path_str.clone();
+        let reload_ldap_schema = ldap_schema.clone();
         let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
             .expect("failed to install SIGHUP handler");
         tokio::spawn(async move {
@@ -195,13 +257,18 @@ async fn main() -> anyhow::Result<()> {
                         e
                     ),
                 }
+
+                if let Some(ref schema) = reload_ldap_schema {
+                    info!("SIGHUP received, reloading LDAP schema mapping...");
+                    schema.reload();
+                }
             }
         });
     }
 
     // Initialize Middleware Chain
     let mut middleware_chain = MiddlewareChain::new();
-    middleware_chain.add(Arc::new(LoggingMiddleware));
+    middleware_chain.add(Arc::new(LoggingMiddleware { schema: storage.field_schema().await }));
 
     middleware_chain.add(Arc::new(SecurityTierMiddleware {
         default_tier: security_tier,
@@ -215,13 +282,14 @@ async fn main() -> anyhow::Result<()> {
     let middleware_chain = Arc::new(middleware_chain);
 
     // --- Metrics Scrape Server (Pull Method) ---
-    let storage_for_metrics: Arc<RwLock<dyn Storage>> = Arc::clone(&storage);
-    let metrics_route = warp::path("metrics").map(move || {
-        // Update storage count on scrape
-        if let Ok(lock) = storage_for_metrics.read() {
-            TOTAL_RECORDS.set(lock.record_count() as i64);
+    let storage_for_metrics: Arc<dyn Storage> = Arc::clone(&storage);
+    let metrics_route = warp::path("metrics").then(move || {
+        let storage = Arc::clone(&storage_for_metrics);
+        async move {
+            // Update storage count on scrape
+            TOTAL_RECORDS.set(storage.record_count().await as i64);
+            gather_metrics()
         }
-        gather_metrics()
     });
     
     tokio::spawn(async move {
@@ -230,7 +298,9 @@ async fn main() -> anyhow::Result<()> {
     });
 
     // --- Background Metrics Collection & Health Monitoring ---
-    let storage_for_monitor: Arc<RwLock<dyn Storage>> = Arc::clone(&storage);
+    let storage_for_monitor: Arc<dyn Storage> = Arc::clone(&storage);
+    let tombstone_config = TombstoneConfig::from_env();
+    let expiry_config = ExpiryConfig::from_env();
     tokio::spawn(async move {
         let mut sys = System::new_all();
         let pid = sysinfo::Pid::from_u32(std::process::id());
@@ -250,11 +320,19 @@ async fn main() -> anyhow::Result<()> {
                 MEMORY_USAGE_BYTES.set(used_mem as i64);
             }
 
-            // Record Storage Count
-            if let Ok(lock) = storage_for_monitor.read() {
-                TOTAL_RECORDS.set(lock.record_count() as i64);
+            // Purge tombstones past their retention window
+            match storage_for_monitor.purge_tombstones(tombstone_config.cutoff(chrono::Utc::now())).await {
+                Ok(0) => {}
+                Ok(purged) => info!("Purged {} expired tombstones", purged),
+                Err(e) => error!("Failed to purge tombstones: {}", e),
             }
 
+            // Delete records whose TTL has run out since they were last seen
+            expiry::reap(&storage_for_monitor, &expiry_config, chrono::Utc::now(), &my_addr).await;
+
+            // Record Storage Count
+            TOTAL_RECORDS.set(storage_for_monitor.record_count().await as i64);
+
             // Health Monitor Threshold Warnings
             let cpu_threshold = env::var("PHAROS_CPU_THRESHOLD")
                 .ok()
@@ -311,7 +389,7 @@ async fn main() -> anyhow::Result<()> {
             _ = async {
                 loop {
                     if let Ok((socket, peer_addr)) = listener.accept().await {
-                        let storage_ref: Arc<RwLock<dyn Storage>> = Arc::clone(&storage);
+                        let storage_ref: Arc<dyn Storage> = Arc::clone(&storage);
                         let auth_ref = Arc::clone(&auth_manager);
                         let middleware_ref = Arc::clone(&middleware_chain);
                         let acceptor = match tls_acceptor.read() {
@@ -352,7 +430,7 @@ async fn main() -> anyhow::Result<()> {
             _ = async {
                 loop {
                     let (socket, peer_addr) = listener.accept().await?;
-                    let storage_ref: Arc<RwLock<dyn Storage>> = Arc::clone(&storage);
+                    let storage_ref: Arc<dyn Storage> = Arc::clone(&storage);
                     let auth_ref = Arc::clone(&auth_manager);
                     let middleware_ref = Arc::clone(&middleware_chain);
                     let acceptor = match tls_acceptor.read() {
@@ -0,0 +1,270 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/merge.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * The same box routinely ends up in the directory two or three times: the
+ * scanner files it as `alias=scan-xxxx` by MAC, pulse adds it by hostname, and
+ * someone types it in by hand. `dupes` groups live records that share a MAC
+ * address, IP address, uuid or serial number, and `merge` folds one record
+ * into another: multi-valued fields are unioned, a field both records set
+ * keeps the value from the more trusted source, the earliest `created_at`
+ * survives, and the merged-away record is deleted.
+ * * Traceability:
+ * Backs the `dupes` and `merge` protocol commands and `mdb dupes`.
+ * ======================================================================== */
+
+use crate::history::Revision;
+use crate::links;
+use crate::schema;
+use crate::storage::{Record, StorageError};
+use chrono::DateTime;
+use std::collections::{BTreeMap, BTreeSet};
+use tracing::warn;
+
+/// Fields whose shared values mark two records as the same box.
+pub const DUPLICATE_KEYS: [&str; 4] = ["mac_addr", "ip_addr", "uuid", "serial_number"];
+
+/// Whose value wins a conflict, most trusted first: people over agents over the scanner.
+const DEFAULT_PRECEDENCE: [&str; 5] = ["mdb", "web-console", "ph", "pharos-pulse", "pharos-scan"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeConfig {
+    /// Record sources in the order their values win a merge conflict.
+    pub precedence: Vec<String>,
+}
+
+impl Default for MergeConfig {
+    fn default() -> Self {
+        Self { precedence: DEFAULT_PRECEDENCE.iter().map(|s| s.to_string()).collect() }
+    }
+}
+
+impl MergeConfig {
+    /// Reads `PHAROS_MERGE_PRECEDENCE`, a comma-separated list of sources, falling back to the
+    /// default (with a warning) when it names none.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Ok(raw) = std::env::var("PHAROS_MERGE_PRECEDENCE") {
+            let precedence: Vec<String> = raw.split(',').map(str::trim).filter(|s| !s.is_empty()).map(str::to_string).collect();
+            if precedence.is_empty() {
+                warn!("Ignoring empty PHAROS_MERGE_PRECEDENCE value '{}'", raw);
+            } else {
+                config.precedence = precedence;
+            }
+        }
+        config
+    }
+}
+
+/// A record `dupes` found, with the group of records it duplicates.
+#[derive(Debug, Clone)]
+pub struct Duplicate {
+    pub record: Record,
+    /// Numbered from 1, in order of each group's oldest record.
+    pub group: usize,
+    /// The `key=value` pairs this record shares with others in its group.
+    pub shared: Vec<String>,
+}
+
+/// How a duplicate key's value is compared: MAC addresses and uuids ignore case.
+fn normalize(key: &str, value: &str) -> String {
+    match key {
+        "mac_addr" | "uuid" => value.trim().to_ascii_lowercase(),
+        _ => value.trim().to_string(),
+    }
+}
+
+fn values_of<'a>(record: &'a Record, key: &str) -> impl Iterator<Item = &'a String> {
+    record.fields.get(key).into_iter().chain(record.multi_fields.get(key).into_iter().flatten())
+}
+
+/// Groups `records` that share a value of any duplicate key, directly or through each other.
+/// Records sharing nothing are left out.
+pub fn find_duplicates(mut records: Vec<Record>) -> Vec<Duplicate> {
+    records.sort_by_key(|r| r.id);
+    let mut holders: BTreeMap<(&str, String), BTreeSet<usize>> = BTreeMap::new();
+    for (i, record) in records.iter().enumerate() {
+        for key in DUPLICATE_KEYS {
+            for value in values_of(record, key).filter(|v| !v.trim().is_empty()) {
+                holders.entry((key, normalize(key, value))).or_default().insert(i);
+            }
+        }
+    }
+
+    // Union-find over record positions: every shared value joins its holders into one group.
+    let mut parent: Vec<usize> = (0..records.len()).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    let mut shared: Vec<BTreeSet<String>> = vec![BTreeSet::new(); records.len()];
+    for ((key, value), positions) in holders.iter().filter(|(_, positions)| positions.len() > 1) {
+        let first = *positions.iter().next().unwrap_or(&0);
+        for &i in positions {
+            shared[i].insert(format!("{}={}", key, value));
+            let (a, b) = (root(&mut parent, first), root(&mut parent, i));
+            parent[b.max(a)] = a.min(b);
+        }
+    }
+
+    let mut groups: BTreeMap<usize, usize> = BTreeMap::new();
+    let mut found = Vec::new();
+    for (i, record) in records.into_iter().enumerate() {
+        if shared[i].is_empty() {
+            continue;
+        }
+        let next = groups.len() + 1;
+        let group = *groups.entry(root(&mut parent, i)).or_insert(next);
+        found.push(Duplicate { record, group, shared: std::mem::take(&mut shared[i]).into_iter().collect() });
+    }
+    found.sort_by_key(|d| d.group);
+    found
+}
+
+/// The source that last set `field` on `record`: the newest revision that changed it, else the
+/// record's own `source`.
+fn field_source<'a>(record: &'a Record, field: &str) -> Option<&'a str> {
+    record
+        .history
+        .iter()
+        .rev()
+        .find(|rev: &&Revision| rev.changes.iter().any(|c| c.field == field))
+        .and_then(|rev| rev.source.as_deref())
+        .or_else(|| record.fields.get("source").map(String::as_str))
+}
+
+/// Where `source` ranks in `precedence`; unknown sources rank last.
+fn rank(precedence: &[String], source: Option<&str>) -> usize {
+    source.and_then(|s| precedence.iter().position(|p| p == s)).unwrap_or(precedence.len())
+}
+
+/// Compares timestamps in time order when both parse, else as text.
+fn earlier(a: &str, b: &str) -> bool {
+    match (DateTime::parse_from_rfc3339(a), DateTime::parse_from_rfc3339(b)) {
+        (Ok(a), Ok(b)) => a < b,
+        _ => a < b,
+    }
+}
+
+/// `keep` with `lose` folded into it. A field only `lose` sets is taken over; one both set keeps
+/// the value whose source ranks first in `precedence` (`keep`'s on a tie); multi-valued fields
+/// get every value either holds; `created_at` is the earlier and `last_seen_at` the later of the
+/// two. Its hostname, alias, `source` and ownership stay `keep`'s unless it has none.
+pub fn combine(keep: &Record, lose: &Record, precedence: &[String]) -> Result<Record, StorageError> {
+    let (keep_type, lose_type) = (schema::type_of(keep), schema::type_of(lose));
+    if !keep_type.eq_ignore_ascii_case(lose_type) {
+        return Err(StorageError::InvalidArgument(format!(
+            "can't merge {} (a {}) into {} (a {})",
+            links::label(lose),
+            lose_type,
+            links::label(keep),
+            keep_type
+        )));
+    }
+
+    let mut merged = keep.clone();
+    for (name, value) in &lose.fields {
+        let take = match merged.fields.get(name) {
+            None => true,
+            Some(current) if current == value => false,
+            Some(current) => match name.as_str() {
+                // The record merged into keeps its name, so links to it stay good.
+                "source" | "type" | "hostname" | "alias" => false,
+                "created_at" => earlier(value, current),
+                "last_seen_at" => earlier(current, value),
+                _ => rank(precedence, field_source(lose, name)) < rank(precedence, field_source(keep, name)),
+            },
+        };
+        if take {
+            merged.fields.insert(name.clone(), value.clone());
+        }
+    }
+    for (name, values) in &lose.multi_fields {
+        let held = merged.multi_fields.entry(name.clone()).or_default();
+        for value in values {
+            if !held.iter().any(|h| normalize(name, h) == normalize(name, value)) {
+                held.push(value.clone());
+            }
+        }
+    }
+    if merged.owner_fingerprint.is_none() && merged.owner_team.is_none() {
+        merged.owner_fingerprint = lose.owner_fingerprint.clone();
+        merged.owner_team = lose.owner_team.clone();
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::FieldChange;
+    use std::collections::HashMap;
+
+    fn record(id: usize, fields: &[(&str, &str)], multi: &[(&str, &[&str])]) -> Record {
+        Record {
+            id,
+            fields: fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            multi_fields: multi.iter().map(|(k, vs)| (k.to_string(), vs.iter().map(|v| v.to_string()).collect())).collect::<HashMap<_, _>>(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_should_group_records_sharing_a_key_through_each_other() {
+        let records = vec![
+            record(1, &[("type", "machine"), ("hostname", "web01"), ("uuid", "4C4C-01")], &[("ip_addr", &["10.0.0.5"])]),
+            record(2, &[("type", "machine"), ("alias", "scan-ab12")], &[("mac_addr", &["aa:bb:cc:00:00:01"]), ("ip_addr", &["10.0.0.5"])]),
+            record(3, &[("type", "machine"), ("hostname", "web01.lab"), ("uuid", "4c4c-01")], &[]),
+            record(4, &[("type", "machine"), ("hostname", "db01")], &[("mac_addr", &["AA:BB:CC:00:00:02"])]),
+            record(5, &[("type", "machine"), ("hostname", "db01-old")], &[("mac_addr", &["aa:bb:cc:00:00:02"])]),
+            record(6, &[("type", "machine"), ("hostname", "lonely")], &[("ip_addr", &["10.0.0.9"])]),
+        ];
+        let found = find_duplicates(records);
+        let groups: Vec<(usize, usize)> = found.iter().map(|d| (d.record.id, d.group)).collect();
+        assert_eq!(groups, vec![(1, 1), (2, 1), (3, 1), (4, 2), (5, 2)]);
+        assert_eq!(found[0].shared, vec!["ip_addr=10.0.0.5", "uuid=4c4c-01"]);
+        assert_eq!(found[3].shared, vec!["mac_addr=aa:bb:cc:00:00:02"]);
+    }
+
+    #[test]
+    fn test_should_resolve_conflicts_by_source_precedence() {
+        let precedence = MergeConfig::default().precedence;
+        let mut keep = record(
+            1,
+            &[("type", "machine"), ("alias", "scan-ab12"), ("source", "pharos-scan"), ("os", "linux"), ("created_at", "2026-03-01T00:00:00+00:00")],
+            &[("mac_addr", &["AA:BB:CC:00:00:01"])],
+        );
+        let lose = record(
+            2,
+            &[("type", "machine"), ("hostname", "web01"), ("source", "mdb"), ("os", "debian13"), ("created_at", "2026-01-01T00:00:00+00:00")],
+            &[("mac_addr", &["aa:bb:cc:00:00:01", "aa:bb:cc:00:00:02"])],
+        );
+        let merged = combine(&keep, &lose, &precedence).unwrap();
+        assert_eq!(merged.fields["os"], "debian13", "mdb outranks the scanner");
+        assert_eq!(merged.fields["hostname"], "web01");
+        assert_eq!(merged.fields["source"], "pharos-scan");
+        assert_eq!(merged.fields["created_at"], "2026-01-01T00:00:00+00:00");
+        assert_eq!(merged.multi_fields["mac_addr"], vec!["AA:BB:CC:00:00:01", "aa:bb:cc:00:00:02"]);
+
+        // A later edit through mdb makes the kept record's value the trusted one.
+        keep.history.push(Revision {
+            revision: 2,
+            at: "2026-04-01T00:00:00+00:00".to_string(),
+            fingerprint: None,
+            team: None,
+            source: Some("mdb".to_string()),
+            changes: vec![FieldChange { field: "os".to_string(), from: Some("linux".to_string()), to: Some("rocky9".to_string()) }],
+        });
+        keep.fields.insert("os".to_string(), "rocky9".to_string());
+        assert_eq!(combine(&keep, &lose, &precedence).unwrap().fields["os"], "rocky9");
+
+        let person = record(3, &[("type", "person"), ("alias", "rdavis")], &[]);
+        assert!(matches!(combine(&keep, &person, &precedence), Err(StorageError::InvalidArgument(_))));
+    }
+}
@@ -49,6 +49,11 @@ lazy_static! {
         Opts::new("pharos_records_deleted_total", "Total number of records deleted, labeled by source"),
         &["source"]
     ).expect("Failed to create records deleted counter");
+
+    pub static ref RECORDS_REAPED_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new("pharos_records_reaped_total", "Total number of records deleted on expiry of their TTL, labeled by the record's source"),
+        &["source"]
+    ).expect("Failed to create records reaped counter");
 }
 
 pub fn register_metrics() {
@@ -58,6 +63,7 @@ pub fn register_metrics() {
     REGISTRY.register(Box::new(RECORDS_ADDED_TOTAL.clone())).expect("Failed to register records added counter");
     REGISTRY.register(Box::new(RECORDS_UPDATED_TOTAL.clone())).expect("Failed to register records updated counter");
     REGISTRY.register(Box::new(RECORDS_DELETED_TOTAL.clone())).expect("Failed to register records deleted counter");
+    REGISTRY.register(Box::new(RECORDS_REAPED_TOTAL.clone())).expect("Failed to register records reaped counter");
 }
 
 pub fn gather_metrics() -> String {
@@ -151,6 +157,15 @@ mod tests {
         assert_eq!(after - before, 3);
     }
 
+    #[test]
+    fn test_should_increment_records_reaped_total_with_source_label() {
+        let _lock = TEST_MUTEX.lock().unwrap();
+        let before = RECORDS_REAPED_TOTAL.with_label_values(&["test-reap-source"]).get();
+        RECORDS_REAPED_TOTAL.with_label_values(&["test-reap-source"]).inc_by(2);
+        let after = RECORDS_REAPED_TOTAL.with_label_values(&["test-reap-source"]).get();
+        assert_eq!(after - before, 2);
+    }
+
     #[test]
     fn test_should_gather_records_metrics_in_prometheus_format() {
         let _lock = TEST_MUTEX.lock().unwrap();
@@ -14,6 +14,8 @@
 
 use crate::protocol::{Command, ProtocolError};
 use crate::auth::SecurityTier;
+use crate::schema::FieldSchema;
+use crate::storage::WriteLimits;
 use std::sync::Arc;
 use tracing::info;
 
@@ -42,6 +44,13 @@ impl Default for SessionOptions {
     }
 }
 
+impl SessionOptions {
+    /// The options storage enforces on change and delete (also inside a transaction).
+    pub fn write_limits(&self) -> WriteLimits {
+        WriteLimits { limit: self.limit, addonly: self.addonly }
+    }
+}
+
 /// Contextual information about the current client session.
 #[derive(Debug, Clone)]
 pub struct ClientContext {
@@ -127,12 +136,15 @@ impl MiddlewareChain {
 
 // --- Sample Middlewares ---
 
-/// Simple middleware that logs every command.
-pub struct LoggingMiddleware;
+/// Simple middleware that logs every command, with the values of the fields `schema` keeps
+/// private or encrypted redacted.
+pub struct LoggingMiddleware {
+    pub schema: Arc<FieldSchema>,
+}
 
 impl Middleware for LoggingMiddleware {
     fn pre_process(&self, command: &mut Command, context: &mut ClientContext) -> Result<MiddlewareAction, ProtocolError> {
-        info!(peer = %context.peer_addr, client_id = ?context.id, "Processing command: {:?}", command);
+        info!(peer = %context.peer_addr, client_id = ?context.id, "Processing command: {:?}", command.redacted(&self.schema));
         Ok(MiddlewareAction::Continue)
     }
 }
@@ -144,11 +156,7 @@ pub struct ReadOnlyMiddleware {
 
 impl Middleware for ReadOnlyMiddleware {
     fn pre_process(&self, command: &mut Command, context: &mut ClientContext) -> Result<MiddlewareAction, ProtocolError> {
-        let is_write_command = matches!(command, 
-            Command::Add(_) | Command::Delete(_) | Command::Change { .. }
-        );
-
-        if is_write_command {
+        if command.is_write() {
             let is_read_only = context.id.as_ref()
                 .map(|id| self.read_only_ids.contains(id))
                 .unwrap_or(false);
@@ -167,12 +175,8 @@ pub struct RbacMiddleware;
 
 impl Middleware for RbacMiddleware {
     fn pre_process(&self, command: &mut Command, context: &mut ClientContext) -> Result<MiddlewareAction, ProtocolError> {
-        let is_write_command = matches!(command, 
-            Command::Add(_) | Command::Delete(_) | Command::Change { .. }
-        );
-
         // Protocol Restriction: Strictly require authentication for INSERT, UPDATE, and DELETE.
-        if is_write_command && !context.authenticated {
+        if command.is_write() && !context.authenticated {
             return Ok(MiddlewareAction::ShortCircuit(
                 "506:Authentication required for write operations. Use 'login [alias]' to receive a challenge.\n".to_string()
             ));
@@ -205,11 +209,7 @@ impl Middleware for SecurityTierMiddleware {
             SecurityTier::Open => {
                 // Open tier: Read-only access is open, writes require auth
                 // (Already handled by RbacMiddleware if added, but keeping for logic isolation)
-                let is_write_command = matches!(command, 
-                    Command::Add(_) | Command::Delete(_) | Command::Change { .. }
-                );
-
-                if is_write_command && !context.authenticated {
+                if command.is_write() && !context.authenticated {
                     return Ok(MiddlewareAction::ShortCircuit(
                         "506:Authentication required. Use 'login [alias]' to receive a challenge.\n".to_string()
                     ));
@@ -233,11 +233,7 @@ impl Middleware for SecurityTierMiddleware {
                     ));
                 }
 
-                let is_write_command = matches!(command, 
-                    Command::Add(_) | Command::Delete(_) | Command::Change { .. }
-                );
-
-                if is_write_command && !context.roles.contains(&"admin".to_string()) {
+                if command.is_write() && !context.roles.contains(&"admin".to_string()) {
                     return Ok(MiddlewareAction::ShortCircuit("516:Forbidden: Admin role required for write operations\n".to_string()));
                 }
 
@@ -255,7 +251,7 @@ mod tests {
     #[test]
     fn test_should_continue_when_no_middleware_blocks() {
         let mut chain = MiddlewareChain::new();
-        chain.add(Arc::new(LoggingMiddleware));
+        chain.add(Arc::new(LoggingMiddleware { schema: Arc::default() }));
         
         let mut command = Command::Status;
         let mut context = ClientContext {
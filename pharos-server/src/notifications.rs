@@ -6,7 +6,7 @@
  * License: AGPL-3.0 (See LICENSE file for details)
  * * Purpose (The "Why"):
  * Implements the Webhook Notification Engine: fires a configurable webhook after
- * every successful add/change/delete, in a generic, Slack-compatible, or
+ * every successful add/change/delete/undelete/merge, in a generic, Slack-compatible, or
  * Discord-compatible payload format.
  * * Traceability:
  * Related to Task 15.2 (Issue #60).
@@ -16,6 +16,9 @@ use std::collections::HashMap;
 use std::env;
 use std::time::Duration;
 use tracing::{info, warn};
+use crate::compare;
+use crate::protocol;
+use crate::schema::FieldSchema;
 
 /// A record-modification event that may trigger a webhook notification.
 pub enum NotificationEvent {
@@ -31,6 +34,60 @@ pub enum NotificationEvent {
         selections: Vec<(Option<String>, String)>,
         count: usize,
     },
+    /// Tombstoned records matching `selections` were restored.
+    Undelete {
+        selections: Vec<(Option<String>, String)>,
+        count: usize,
+    },
+    /// The record `from` selected was folded into the one `into` selected and deleted.
+    Merge {
+        from: Vec<(Option<String>, String)>,
+        into: Vec<(Option<String>, String)>,
+    },
+    /// One committed transaction, in place of an event per command.
+    Batch {
+        commands: Vec<String>,
+        added: usize,
+        /// Staged `add`s that matched an existing record and updated it.
+        updated: usize,
+        changed: usize,
+        deleted: usize,
+    },
+}
+
+impl NotificationEvent {
+    /// The event with the values of fields `schema` keeps private or encrypted redacted: a
+    /// webhook reaches whoever is behind it, not only the owners of the records it names.
+    pub fn redacted(self, schema: &FieldSchema) -> Self {
+        match self {
+            NotificationEvent::Add { fields } => {
+                let fields: Vec<(String, String)> = fields.into_iter().collect();
+                NotificationEvent::Add { fields: protocol::redact_fields(&fields, schema).into_iter().collect() }
+            }
+            NotificationEvent::Change { selections, modifications, count } => NotificationEvent::Change {
+                selections: protocol::redact_selections(&selections, schema),
+                modifications: protocol::redact_fields(&modifications, schema),
+                count,
+            },
+            NotificationEvent::Delete { selections, count } => {
+                NotificationEvent::Delete { selections: protocol::redact_selections(&selections, schema), count }
+            }
+            NotificationEvent::Undelete { selections, count } => {
+                NotificationEvent::Undelete { selections: protocol::redact_selections(&selections, schema), count }
+            }
+            NotificationEvent::Merge { from, into } => NotificationEvent::Merge {
+                from: protocol::redact_selections(&from, schema),
+                into: protocol::redact_selections(&into, schema),
+            },
+            NotificationEvent::Batch { commands, added, updated, changed, deleted } => NotificationEvent::Batch {
+                commands: commands.iter().map(|c| protocol::redact_wire_values(c, schema)).collect(),
+                added,
+                updated,
+                changed,
+                deleted,
+            },
+        }
+    }
 }
 
 /// Human-readable one-line summary of an event, shared by the Slack and Discord payload formats.
@@ -38,7 +95,11 @@ fn summarize(event: &NotificationEvent) -> String {
     fn selections_to_string(selections: &[(Option<String>, String)]) -> String {
         selections
             .iter()
-            .map(|(k, v)| format!("{}={}", k.as_deref().unwrap_or("*"), v))
+            .map(|(k, v)| match k.as_deref() {
+                // A comparison key already ends in its operator (`cpu_cores>`).
+                Some(k) if compare::is_comparison(Some(k)) => format!("{}{}", k, v),
+                k => format!("{}={}", k.unwrap_or("*"), v),
+            })
             .collect::<Vec<_>>()
             .join(", ")
     }
@@ -69,6 +130,30 @@ fn summarize(event: &NotificationEvent) -> String {
                 selections_to_string(selections)
             )
         }
+        NotificationEvent::Undelete { selections, count } => {
+            format!(
+                "Pharos: {} record(s) matching [{}] restored",
+                count,
+                selections_to_string(selections)
+            )
+        }
+        NotificationEvent::Merge { from, into } => {
+            format!(
+                "Pharos: record matching [{}] merged into [{}] and deleted",
+                selections_to_string(from),
+                selections_to_string(into)
+            )
+        }
+        NotificationEvent::Batch { commands, added, updated, changed, deleted } => {
+            format!(
+                "Pharos: transaction of {} command(s) committed ({} added, {} updated, {} changed, {} deleted)",
+                commands.len(),
+                added,
+                updated,
+                changed,
+                deleted
+            )
+        }
     }
 }
 
@@ -76,7 +161,10 @@ fn selections_to_json(selections: &[(Option<String>, String)]) -> serde_json::Va
     serde_json::Value::Array(
         selections
             .iter()
-            .map(|(field, value)| serde_json::json!({ "field": field, "value": value }))
+            .map(|(field, value)| match field.as_deref().and_then(compare::split_key) {
+                Some((field, op)) => serde_json::json!({ "field": field, "op": op.symbol(), "value": value }),
+                None => serde_json::json!({ "field": field, "value": value }),
+            })
             .collect(),
     )
 }
@@ -103,6 +191,27 @@ fn generic_payload(event: &NotificationEvent) -> serde_json::Value {
             "count": count,
             "selections": selections_to_json(selections),
         }),
+        NotificationEvent::Undelete { selections, count } => serde_json::json!({
+            "event": "undelete",
+            "timestamp": timestamp,
+            "count": count,
+            "selections": selections_to_json(selections),
+        }),
+        NotificationEvent::Merge { from, into } => serde_json::json!({
+            "event": "merge",
+            "timestamp": timestamp,
+            "from": selections_to_json(from),
+            "into": selections_to_json(into),
+        }),
+        NotificationEvent::Batch { commands, added, updated, changed, deleted } => serde_json::json!({
+            "event": "batch",
+            "timestamp": timestamp,
+            "commands": commands,
+            "added": added,
+            "updated": updated,
+            "changed": changed,
+            "deleted": deleted,
+        }),
     }
 }
 
@@ -231,4 +340,83 @@ mod tests {
         let summary = summarize(&event);
         assert!(summary.contains("*=anything"));
     }
+
+    #[test]
+    fn test_should_render_comparison_selections_with_their_operator() {
+        let selections = vec![(Some("last_seen_at<".to_string()), "now-30d".to_string())];
+        let json = selections_to_json(&selections);
+        let event = NotificationEvent::Delete { selections, count: 2 };
+        assert!(summarize(&event).contains("[last_seen_at<now-30d]"));
+        assert_eq!(json[0], serde_json::json!({ "field": "last_seen_at", "op": "<", "value": "now-30d" }));
+    }
+
+    #[test]
+    fn test_should_report_restored_records_as_an_undelete_event() {
+        let event = NotificationEvent::Undelete {
+            selections: vec![(Some("hostname".to_string()), "vm1".to_string())],
+            count: 1,
+        };
+        assert!(summarize(&event).contains("1 record(s) matching [hostname=vm1] restored"));
+        let payload = build_payload(&event, "generic");
+        assert_eq!(payload["event"], "undelete");
+        assert_eq!(payload["count"], 1);
+        assert_eq!(payload["selections"][0]["value"], "vm1");
+    }
+
+    #[test]
+    fn test_should_name_both_sides_of_a_merge() {
+        let event = NotificationEvent::Merge {
+            from: vec![(Some("hostname".to_string()), "web01-old".to_string())],
+            into: vec![(Some("hostname".to_string()), "web01".to_string())],
+        };
+        assert!(summarize(&event).contains("[hostname=web01-old] merged into [hostname=web01] and deleted"));
+        let payload = build_payload(&event, "generic");
+        assert_eq!(payload["event"], "merge");
+        assert_eq!(payload["from"][0], serde_json::json!({ "field": "hostname", "value": "web01-old" }));
+        assert_eq!(payload["into"][0]["value"], "web01");
+    }
+
+    #[test]
+    fn test_should_aggregate_a_committed_transaction_into_one_event() {
+        let event = NotificationEvent::Batch {
+            commands: vec![
+                "change hostname=web01 make ip=10.0.0.2".to_string(),
+                "delete hostname=web02".to_string(),
+                "add type=machine hostname=web01 status=up".to_string(),
+            ],
+            added: 0,
+            updated: 1,
+            changed: 1,
+            deleted: 1,
+        };
+        assert!(summarize(&event).contains("transaction of 3 command(s) committed (0 added, 1 updated, 1 changed, 1 deleted)"));
+        let payload = build_payload(&event, "generic");
+        assert_eq!(payload["event"], "batch");
+        assert_eq!(payload["commands"][1], "delete hostname=web02");
+        assert_eq!((payload["added"].as_u64(), payload["updated"].as_u64()), (Some(0), Some(1)));
+        assert_eq!(payload["deleted"], 1);
+    }
+
+    #[test]
+    fn test_should_redact_encrypted_values_from_every_event() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("field-schema.toml");
+        std::fs::write(&path, "[types.machine.fields.bmc_password]\nencrypted = true").unwrap();
+        let schema = FieldSchema::load(&path).unwrap();
+        let bmc01 = || vec![(Some("hostname".to_string()), "bmc01".to_string())];
+        let events = [
+            NotificationEvent::Add { fields: HashMap::from([("bmc_password".to_string(), "calvin".to_string())]) },
+            NotificationEvent::Change { selections: bmc01(), modifications: vec![("bmc_password".to_string(), "calvin".to_string())], count: 1 },
+            NotificationEvent::Delete { selections: vec![(Some("bmc_password".to_string()), "calvin".to_string())], count: 1 },
+            NotificationEvent::Undelete { selections: vec![(Some("bmc_password".to_string()), "calvin".to_string())], count: 1 },
+            NotificationEvent::Merge { from: vec![(Some("bmc_password".to_string()), "calvin".to_string())], into: bmc01() },
+            NotificationEvent::Batch { commands: vec!["change hostname=bmc01 force bmc_password=calvin".to_string()], added: 0, updated: 0, changed: 1, deleted: 0 },
+        ];
+        for event in events {
+            let event = event.redacted(&schema);
+            assert!(!summarize(&event).contains("calvin"));
+            let payload = build_payload(&event, "generic").to_string();
+            assert!(!payload.contains("calvin") && payload.contains("<redacted>"), "{}", payload);
+        }
+    }
 }
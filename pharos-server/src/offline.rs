@@ -0,0 +1,343 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/offline.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Maintaining a FileStorage data file meant starting the server and hoping
+ * its load-time self-heal covered the problem, or editing JSON by hand.
+ * `pharos-server fsck`, `export`, `import` and `migrate` work directly on a
+ * stopped server's data file and journal, with the same keys, schema and
+ * corrupt-file policy the server would use: check (and repair) the records,
+ * dump them as JSON Lines or CSV, load them back in, or move them to SQLite
+ * or LDAP. Every write replaces the data file in one atomic rewrite, so an
+ * interrupted run leaves it as it was.
+ * * Traceability:
+ * Dispatched from main.rs before any server setup; see HOWTO "Offline
+ * Maintenance".
+ * ======================================================================== */
+
+use crate::auth;
+use crate::export::{self, Format};
+use crate::file_cipher::FileCipher;
+use crate::fsck::{self, Owners};
+use crate::history::HistoryConfig;
+use crate::journal::JournalConfig;
+use crate::ldap::{LdapSchema, LdapStorage};
+use crate::merge::MergeConfig;
+use crate::recovery::CorruptPolicy;
+use crate::schema::FieldSchema;
+use crate::sealing::{self, SealingKey};
+use crate::sqlite::SqliteStorage;
+use crate::storage::{self, FileStorage, MemoryStorage, Record, Storage};
+use anyhow::{Context, bail};
+use chrono::Utc;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// The first arguments that run an offline tool instead of the server.
+pub const SUBCOMMANDS: [&str; 4] = ["fsck", "export", "import", "migrate"];
+
+const USAGE: &str = "\
+usage: pharos-server fsck    [--data PATH] [--keys DIR] [--repair]
+       pharos-server export  [--data PATH] [--format jsonl|csv] [--output FILE]
+       pharos-server import  [--data PATH] [--format jsonl|csv] [--replace] FILE
+       pharos-server migrate [--data PATH] --from file --to sqlite|ldap [--to-path PATH]
+
+Run against a stopped server. --data defaults to PHAROS_STORAGE_PATH and --keys to
+PHAROS_KEYS_DIR; keys, schema and corrupt-file policy come from the same environment
+variables the server reads.";
+
+#[derive(Debug, Default)]
+struct Options {
+    data: Option<PathBuf>,
+    keys: Option<PathBuf>,
+    format: Option<Format>,
+    output: Option<PathBuf>,
+    repair: bool,
+    replace: bool,
+    from: Option<String>,
+    to: Option<String>,
+    to_path: Option<PathBuf>,
+    file: Option<PathBuf>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> anyhow::Result<Self> {
+        let mut options = Self::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().cloned().with_context(|| format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--data" => options.data = Some(PathBuf::from(value()?)),
+                "--keys" => options.keys = Some(PathBuf::from(value()?)),
+                "--format" => {
+                    let format = value()?;
+                    options.format = Some(Format::parse(&format).with_context(|| format!("unknown format '{}' (expected jsonl or csv)", format))?);
+                }
+                "--output" => options.output = Some(PathBuf::from(value()?)),
+                "--from" => options.from = Some(value()?),
+                "--to" => options.to = Some(value()?),
+                "--to-path" => options.to_path = Some(PathBuf::from(value()?)),
+                "--repair" => options.repair = true,
+                "--replace" => options.replace = true,
+                flag if flag.starts_with("--") => bail!("unknown option {}", flag),
+                file if options.file.is_none() => options.file = Some(PathBuf::from(file)),
+                extra => bail!("unexpected argument '{}'", extra),
+            }
+        }
+        Ok(options)
+    }
+
+    /// `--data`, else `PHAROS_STORAGE_PATH`.
+    fn data_path(&self) -> anyhow::Result<PathBuf> {
+        match &self.data {
+            Some(path) => Ok(path.clone()),
+            None => std::env::var("PHAROS_STORAGE_PATH").map(PathBuf::from).context("no data file: pass --data or set PHAROS_STORAGE_PATH"),
+        }
+    }
+}
+
+/// Runs offline tool `args[0]` with the rest of `args`, writing its report (or, for `export`
+/// without `--output`, the records) to `out`. An error means the tool failed or, for `fsck`,
+/// that problems are left.
+pub async fn run(args: &[String], out: &mut (dyn Write + Send)) -> anyhow::Result<()> {
+    let Some((command, rest)) = args.split_first() else {
+        bail!("{}", USAGE);
+    };
+    if rest.iter().any(|a| a == "--help" || a == "-h") {
+        writeln!(out, "{}", USAGE)?;
+        return Ok(());
+    }
+    let options = Options::parse(rest).map_err(|e| e.context(USAGE))?;
+    match command.as_str() {
+        "fsck" => fsck(&options, out).await,
+        "export" => export(&options, out),
+        "import" => import(&options, out).await,
+        "migrate" => migrate(&options, out).await,
+        other => bail!("unknown command '{}'\n{}", other, USAGE),
+    }
+}
+
+/// The records in the data file at `path` and its journal, as stored - not healed - with the
+/// fields `schema` declares encrypted opened. A data file that isn't there is an error, not an
+/// empty directory.
+fn read(path: &Path, schema: &FieldSchema) -> anyhow::Result<Vec<Record>> {
+    if !path.exists() {
+        bail!("no data file at {:?}", path);
+    }
+    let mut records = FileStorage::read_records(path.to_path_buf(), FileCipher::from_env()?, CorruptPolicy::from_env())?;
+    match SealingKey::from_env()? {
+        Some(key) => {
+            for record in records.iter_mut() {
+                sealing::open_record(schema, &key, record).with_context(|| format!("failed to open the encrypted fields in {:?} with field key {}", path, key.id()))?;
+            }
+        }
+        None if records.iter().any(|r| sealing::has_sealed_values(schema, r)) => {
+            bail!("{:?} holds encrypted fields but no field key is configured; set PHAROS_FIELD_KEY or PHAROS_FIELD_KEY_FILE", path)
+        }
+        None => {}
+    }
+    Ok(records)
+}
+
+/// FileStorage at `path`, set up as the server would set it up.
+fn open(path: &Path, schema: FieldSchema) -> anyhow::Result<FileStorage> {
+    let field_key = SealingKey::from_env()?;
+    if schema.has_encrypted_fields() && field_key.is_none() {
+        bail!("The field schema declares encrypted fields but no field key is configured; set PHAROS_FIELD_KEY or PHAROS_FIELD_KEY_FILE");
+    }
+    let file = FileStorage::open(path.to_path_buf(), JournalConfig::from_env(), FileCipher::from_env()?, CorruptPolicy::from_env())?.with_field_schema(schema);
+    match field_key {
+        Some(key) => file.with_field_key(key),
+        None => Ok(file),
+    }
+}
+
+/// Every record in `storage`, tombstones included.
+async fn all_records(storage: &dyn Storage) -> anyhow::Result<Vec<Record>> {
+    let mut records = storage.query(&[], None).await?;
+    records.extend(storage.tombstones(&[]).await?);
+    Ok(records)
+}
+
+async fn fsck(options: &Options, out: &mut (dyn Write + Send)) -> anyhow::Result<()> {
+    let path = options.data_path()?;
+    let schema = FieldSchema::from_env()?;
+    let mut records = read(&path, &schema)?;
+    let keys_dir = options.keys.clone().unwrap_or_else(|| PathBuf::from(std::env::var("PHAROS_KEYS_DIR").unwrap_or_else(|_| "./keys".to_string())));
+    let (fingerprints, teams) = auth::known_owners(&keys_dir);
+    if fingerprints.is_empty() {
+        warn!("No keys in {:?}; record ownership is not checked", keys_dir);
+    }
+    let owners = Owners { fingerprints, teams };
+
+    let findings = fsck::check(&records, &schema, &owners);
+    for finding in &findings {
+        writeln!(out, "{}", finding)?;
+    }
+    let affected: BTreeSet<usize> = findings.iter().map(|f| f.id).collect();
+    writeln!(out, "{} records checked, {} problems in {} records", records.len(), findings.len(), affected.len())?;
+    if findings.is_empty() {
+        return Ok(());
+    }
+    let repairable = findings.iter().filter(|f| f.repairable).count();
+    if !options.repair {
+        bail!("{} problems found ({} repairable with --repair)", findings.len(), repairable);
+    }
+
+    let changed = fsck::repair(&mut records, &schema, &MergeConfig::from_env().precedence, HistoryConfig::from_env().limit, Utc::now());
+    if changed > 0 {
+        let storage = open(&path, schema.clone())?;
+        storage.replace_records(records.clone()).await?;
+    }
+    let left = fsck::check(&records, &schema, &owners);
+    writeln!(out, "Repaired {} records; {} problems left", changed, left.len())?;
+    if !left.is_empty() {
+        bail!("{} problems need correcting by hand", left.len());
+    }
+    Ok(())
+}
+
+fn export(options: &Options, out: &mut (dyn Write + Send)) -> anyhow::Result<()> {
+    let path = options.data_path()?;
+    let records = read(&path, &FieldSchema::from_env()?)?;
+    let format = options.format.or_else(|| options.output.as_deref().and_then(Format::from_path)).unwrap_or(Format::JsonLines);
+
+    let mut file;
+    let target: &mut dyn Write = match &options.output {
+        Some(output) => {
+            file = std::io::BufWriter::new(std::fs::File::create(output).with_context(|| format!("failed to create {:?}", output))?);
+            &mut file
+        }
+        None => out,
+    };
+    let count = match format {
+        Format::JsonLines => export::write_jsonl(&records, target)?,
+        Format::Csv => export::write_csv(&records, target)?,
+    };
+    target.flush()?;
+    info!("Exported {} records from {:?}", count, path);
+    Ok(())
+}
+
+async fn import(options: &Options, out: &mut (dyn Write + Send)) -> anyhow::Result<()> {
+    let path = options.data_path()?;
+    let Some(file) = &options.file else {
+        bail!("import needs a file to read\n{}", USAGE);
+    };
+    let Some(format) = options.format.or_else(|| Format::from_path(file)) else {
+        bail!("can't tell the format of {:?}; pass --format jsonl or --format csv", file);
+    };
+    let text = std::fs::read_to_string(file).with_context(|| format!("failed to read {:?}", file))?;
+    let schema = FieldSchema::from_env()?;
+    let storage = open(&path, schema.clone())?;
+
+    // A JSON Lines file given with --replace becomes the data set exactly as exported.
+    if format == Format::JsonLines && options.replace {
+        let records = export::read_jsonl(&text)?;
+        let mut ids = BTreeSet::new();
+        if let Some(record) = records.iter().find(|r| !ids.insert(r.id)) {
+            bail!("{:?} holds record #{} more than once", file, record.id);
+        }
+        let live = storage.replace_records(records).await?;
+        writeln!(out, "Replaced the records in {:?} with {:?}: {} live", path, file, live)?;
+        return Ok(());
+    }
+
+    // Anything else is upserted, as an add would be, into a copy of what's there (or into
+    // nothing, with --replace); the copy is only written if every record went in.
+    let staged = MemoryStorage::new().with_field_schema(schema);
+    if !options.replace {
+        staged.replace_records(all_records(&storage).await?).await?;
+    }
+    let rows: Vec<Row> = match format {
+        Format::JsonLines => export::read_jsonl(&text)?
+            .into_iter()
+            .filter(|r| r.deleted_at.is_none())
+            .map(|r| (pairs(&r), r.owner_fingerprint, r.owner_team))
+            .collect(),
+        Format::Csv => export::read_csv(&text)?.into_iter().map(|fields| (fields, None, None)).collect(),
+    };
+    let mut failures = Vec::new();
+    for (index, (fields, fingerprint, team)) in rows.iter().enumerate() {
+        if let Err(e) = staged.upsert_record(fields.clone(), fingerprint.clone(), team.clone()).await {
+            failures.push(format!("record {} of {:?}: {}", index + 1, file, e));
+        }
+    }
+    if !failures.is_empty() {
+        for failure in &failures {
+            writeln!(out, "{}", failure)?;
+        }
+        bail!("{} of {} records failed to import; nothing was written", failures.len(), rows.len());
+    }
+    let live = storage.replace_records(all_records(&staged).await?).await?;
+    writeln!(out, "Imported {} records from {:?} into {:?}: {} live", rows.len(), file, path, live)?;
+    Ok(())
+}
+
+/// The fields of a record to upsert, with the key and team that own it.
+type Row = (Vec<(String, String)>, Option<String>, Option<String>);
+
+/// A record's fields as an add would give them, each value of a multi-valued field as its own pair.
+fn pairs(record: &Record) -> Vec<(String, String)> {
+    let single = record.fields.iter().map(|(k, v)| (k.clone(), v.clone()));
+    let multi = record.multi_fields.iter().flat_map(|(k, vs)| vs.iter().map(move |v| (k.clone(), v.clone())));
+    single.chain(multi).collect()
+}
+
+async fn migrate(options: &Options, out: &mut (dyn Write + Send)) -> anyhow::Result<()> {
+    match options.from.as_deref() {
+        Some("file") | None => {}
+        Some(other) => bail!("can't migrate from '{}'; only from file", other),
+    }
+    let path = options.data_path()?;
+    let schema = FieldSchema::from_env()?;
+    let mut records = read(&path, &schema)?;
+    storage::heal_records(&mut records, &schema);
+    if schema.has_encrypted_fields() {
+        warn!("Encrypted fields are only sealed at rest by FileStorage; the migrated copy holds them as written");
+    }
+
+    match options.to.as_deref() {
+        Some("sqlite") => {
+            let target = match &options.to_path {
+                Some(target) => target.clone(),
+                None => std::env::var("PHAROS_SQLITE_PATH").map(PathBuf::from).context("no SQLite database: pass --to-path or set PHAROS_SQLITE_PATH")?,
+            };
+            let sqlite = SqliteStorage::open_with_field_schema(&target, schema)?;
+            if !all_records(&sqlite).await?.is_empty() {
+                bail!("{:?} already holds records; migrate into an empty database", target);
+            }
+            let live = sqlite.replace_records(records.clone()).await?;
+            writeln!(out, "Migrated {} records ({} live) from {:?} to SQLite at {:?}", records.len(), live, path, target)?;
+            Ok(())
+        }
+        Some("ldap") => {
+            let url = std::env::var("PHAROS_LDAP_URL").context("no LDAP server: set PHAROS_LDAP_URL")?;
+            let env = |name: &str| std::env::var(name).unwrap_or_default();
+            let ldap = LdapStorage::new(url.clone(), env("PHAROS_LDAP_BIND_DN"), env("PHAROS_LDAP_BIND_PW"), env("PHAROS_LDAP_BASE_DN"))
+                .with_schema(LdapSchema::from_env()?)
+                .with_field_schema(schema);
+            // LDAP keeps neither history nor tombstones: only live records, as they are now.
+            let live: Vec<&Record> = records.iter().filter(|r| r.deleted_at.is_none()).collect();
+            let mut failed = 0;
+            for record in &live {
+                if let Err(e) = ldap.upsert_record(pairs(record), record.owner_fingerprint.clone(), record.owner_team.clone()).await {
+                    writeln!(out, "#{} {}: {}", record.id, crate::links::label(record), e)?;
+                    failed += 1;
+                }
+            }
+            writeln!(out, "Migrated {} of {} live records from {:?} to LDAP at {}", live.len() - failed, live.len(), path, url)?;
+            if failed > 0 {
+                bail!("{} records failed to migrate", failed);
+            }
+            Ok(())
+        }
+        Some(other) => bail!("can't migrate to '{}'; expected sqlite or ldap", other),
+        None => bail!("migrate needs --to sqlite or --to ldap\n{}", USAGE),
+    }
+}
@@ -12,9 +12,20 @@
  * Implements RFC 2378 Section 2.1 and Appendix C.
  * ======================================================================== */
 
+use crate::compare;
+use crate::links::Direction;
+use crate::schema::FieldSchema;
 use thiserror::Error;
 
-#[derive(PartialEq, Eq)]
+/// The admin-only `snapshot create <name>`, `snapshot list` and `snapshot restore <name>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotAction {
+    Create(String),
+    List,
+    Restore(String),
+}
+
+#[derive(Clone, PartialEq, Eq)]
 pub enum Command {
     Status,
     SiteInfo,
@@ -33,10 +44,34 @@ pub enum Command {
         returns: Vec<String>,
     },
     Delete(Vec<(Option<String>, String)>),
+    History(Vec<(Option<String>, String)>),
+    Undelete(Vec<(Option<String>, String)>),
+    Tombstones(Vec<(Option<String>, String)>),
+    Snapshot(SnapshotAction),
+    /// `links <selection> [children|parents] [all|depth=N] [via=<field>]`: the records linked to
+    /// (or from) the selected ones. `depth` is `None` for `all`.
+    Links {
+        selections: Vec<(Option<String>, String)>,
+        direction: Direction,
+        depth: Option<usize>,
+        via: Option<String>,
+    },
+    /// `dupes [selection]`: live records sharing a MAC, IP, uuid or serial number, grouped.
+    Dupes(Vec<(Option<String>, String)>),
+    /// `merge <selection> into <selection>`: folds the first record into the second.
+    Merge {
+        from: Vec<(Option<String>, String)>,
+        into: Vec<(Option<String>, String)>,
+    },
+    Begin,
+    Commit,
+    Rollback,
     Change {
         selections: Vec<(Option<String>, String)>,
         modifications: Vec<(String, String)>,
         force: bool,
+        /// `if revision=N`: only change records still at revision N.
+        if_revision: Option<u64>,
     },
     Help {
         target: Option<String>,
@@ -54,6 +89,49 @@ pub enum Command {
     Quit,
 }
 
+impl Command {
+    /// Whether the command changes records, and so needs the write permissions the
+    /// middleware enforces. `commit` doesn't count: each write it applies was checked
+    /// when it was staged.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Add(_)
+                | Command::Delete(_)
+                | Command::Undelete(_)
+                | Command::Merge { .. }
+                | Command::Change { .. }
+                | Command::Snapshot(SnapshotAction::Restore(_))
+        )
+    }
+
+    /// A copy for logs, with the values of fields `schema` keeps private or encrypted redacted
+    /// from its selections and from the fields it writes.
+    pub fn redacted(&self, schema: &FieldSchema) -> Command {
+        let selections = |s: &[(Option<String>, String)]| redact_selections(s, schema);
+        match self {
+            Command::Add(fields) => Command::Add(redact_fields(fields, schema)),
+            Command::Query { selections: s, returns } => Command::Query { selections: selections(s), returns: returns.clone() },
+            Command::Delete(s) => Command::Delete(selections(s)),
+            Command::History(s) => Command::History(selections(s)),
+            Command::Undelete(s) => Command::Undelete(selections(s)),
+            Command::Tombstones(s) => Command::Tombstones(selections(s)),
+            Command::Links { selections: s, direction, depth, via } => {
+                Command::Links { selections: selections(s), direction: *direction, depth: *depth, via: via.clone() }
+            }
+            Command::Dupes(s) => Command::Dupes(selections(s)),
+            Command::Merge { from, into } => Command::Merge { from: selections(from), into: selections(into) },
+            Command::Change { selections: s, modifications, force, if_revision } => Command::Change {
+                selections: selections(s),
+                modifications: redact_fields(modifications, schema),
+                force: *force,
+                if_revision: *if_revision,
+            },
+            other => other.clone(),
+        }
+    }
+}
+
 impl std::fmt::Debug for Command {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -75,11 +153,28 @@ impl std::fmt::Debug for Command {
                 .field("returns", returns)
                 .finish(),
             Command::Delete(v) => f.debug_tuple("Delete").field(v).finish(),
-            Command::Change { selections, modifications, force } => f
+            Command::History(v) => f.debug_tuple("History").field(v).finish(),
+            Command::Undelete(v) => f.debug_tuple("Undelete").field(v).finish(),
+            Command::Tombstones(v) => f.debug_tuple("Tombstones").field(v).finish(),
+            Command::Snapshot(v) => f.debug_tuple("Snapshot").field(v).finish(),
+            Command::Links { selections, direction, depth, via } => f
+                .debug_struct("Links")
+                .field("selections", selections)
+                .field("direction", direction)
+                .field("depth", depth)
+                .field("via", via)
+                .finish(),
+            Command::Dupes(v) => f.debug_tuple("Dupes").field(v).finish(),
+            Command::Merge { from, into } => f.debug_struct("Merge").field("from", from).field("into", into).finish(),
+            Command::Begin => write!(f, "Begin"),
+            Command::Commit => write!(f, "Commit"),
+            Command::Rollback => write!(f, "Rollback"),
+            Command::Change { selections, modifications, force, if_revision } => f
                 .debug_struct("Change")
                 .field("selections", selections)
                 .field("modifications", modifications)
                 .field("force", force)
+                .field("if_revision", if_revision)
                 .finish(),
             Command::Help { target, topics } => f
                 .debug_struct("Help")
@@ -149,6 +244,15 @@ pub fn parse_command(line: &str) -> Result<Command, ProtocolError> {
             })
         }
         "set" => Ok(Command::Set(tokens[1..].to_vec())),
+        "snapshot" => {
+            let action = match (tokens.get(1).map(|t| t.to_lowercase()).as_deref(), tokens.get(2), tokens.len()) {
+                (Some("create"), Some(name), 3) => SnapshotAction::Create(name.clone()),
+                (Some("list"), None, 2) => SnapshotAction::List,
+                (Some("restore"), Some(name), 3) => SnapshotAction::Restore(name.clone()),
+                _ => return Err(ProtocolError::SyntaxError),
+            };
+            Ok(Command::Snapshot(action))
+        }
         "login" => {
             if tokens.len() < 2 {
                 return Err(ProtocolError::SyntaxError);
@@ -206,55 +310,142 @@ pub fn parse_command(line: &str) -> Result<Command, ProtocolError> {
                 if in_returns {
                     returns.push(token.clone());
                 } else {
-                    if let Some((k, v)) = parse_attr_value(token) {
-                        selections.push((Some(k), v));
-                    } else {
-                        selections.push((None, token.clone()));
-                    }
+                    selections.push(parse_selection(token)?);
                 }
             }
             Ok(Command::Query { selections, returns })
         }
         "delete" => {
+            let selections = parse_selections(&tokens[1..])?;
+            Ok(Command::Delete(selections))
+        }
+        "history" => {
+            let selections = parse_selections(&tokens[1..])?;
+            if selections.is_empty() {
+                return Err(ProtocolError::SyntaxError);
+            }
+            Ok(Command::History(selections))
+        }
+        "undelete" => {
+            let selections = parse_selections(&tokens[1..])?;
+            if selections.is_empty() {
+                return Err(ProtocolError::SyntaxError);
+            }
+            Ok(Command::Undelete(selections))
+        }
+        "tombstones" => {
+            let selections = parse_selections(&tokens[1..])?;
+            Ok(Command::Tombstones(selections))
+        }
+        "links" => {
             let mut selections = Vec::new();
+            let mut direction = Direction::Children;
+            let mut depth = Some(1);
+            let mut via = None;
+            let mut in_options = false; // options end the selection, as `make` does for change
+
             for token in &tokens[1..] {
-                if let Some((k, v)) = parse_attr_value(token) {
-                    selections.push((Some(k), v));
-                } else {
-                    selections.push((None, token.clone()));
+                let lower = token.to_lowercase();
+                match lower.as_str() {
+                    "children" => direction = Direction::Children,
+                    "parents" => direction = Direction::Parents,
+                    "all" => depth = None,
+                    _ if lower.starts_with("depth=") => {
+                        let n = lower["depth=".len()..].parse::<usize>().ok().filter(|n| *n > 0);
+                        depth = Some(n.ok_or(ProtocolError::SyntaxError)?);
+                    }
+                    _ if lower.starts_with("via=") => {
+                        let field = &token["via=".len()..];
+                        if field.is_empty() {
+                            return Err(ProtocolError::SyntaxError);
+                        }
+                        via = Some(field.to_string());
+                    }
+                    _ if !in_options => {
+                        selections.push(parse_selection(token)?);
+                        continue;
+                    }
+                    _ => return Err(ProtocolError::SyntaxError),
                 }
+                in_options = true;
             }
-            Ok(Command::Delete(selections))
+            if selections.is_empty() {
+                return Err(ProtocolError::SyntaxError);
+            }
+            Ok(Command::Links { selections, direction, depth, via })
+        }
+        "dupes" => {
+            let selections = parse_selections(&tokens[1..])?;
+            Ok(Command::Dupes(selections))
+        }
+        "merge" => {
+            let mut from = Vec::new();
+            let mut into = Vec::new();
+            let mut seen_into = false;
+            for token in &tokens[1..] {
+                if token.eq_ignore_ascii_case("into") {
+                    if seen_into {
+                        return Err(ProtocolError::SyntaxError);
+                    }
+                    seen_into = true;
+                    continue;
+                }
+                let selection = parse_selection(token)?;
+                if seen_into { into.push(selection) } else { from.push(selection) }
+            }
+            if from.is_empty() || into.is_empty() {
+                return Err(ProtocolError::SyntaxError);
+            }
+            Ok(Command::Merge { from, into })
         }
         "change" => {
             let mut selections = Vec::new();
             let mut modifications = Vec::new();
             let mut force = false;
-            let mut phase = 0; // 0: selection, 1: make/force
+            let mut if_revision = None;
+            let mut phase = 0; // 0: selection, 1: make/force, 2: clear
+
+            // `if revision=N` is the only condition, and only as the last two words after a
+            // `make` or `force`; anywhere else `if` is an ordinary word.
+            let mut body = &tokens[1..];
+            if let [rest @ .., condition, revision] = body
+                && condition.eq_ignore_ascii_case("if")
+                && let Some(n) = revision.to_lowercase().strip_prefix("revision=").map(str::to_string)
+                && rest.iter().any(|t| t.eq_ignore_ascii_case("make") || t.eq_ignore_ascii_case("force"))
+            {
+                if_revision = Some(n.parse::<u64>().map_err(|_| ProtocolError::SyntaxError)?);
+                body = rest;
+            }
 
-            for token in &tokens[1..] {
+            for token in body {
                 let lower = token.to_lowercase();
                 if lower == "make" || lower == "force" {
                     force = lower == "force";
                     phase = 1;
                     continue;
                 }
+                // `clear` only starts a list of fields once the modifications have begun, so an
+                // older client's selection on the word `clear` still selects.
+                if lower == "clear" && phase > 0 {
+                    phase = 2;
+                    continue;
+                }
 
                 if phase == 0 {
-                    if let Some((k, v)) = parse_attr_value(token) {
-                        selections.push((Some(k), v));
-                    } else {
-                        selections.push((None, token.clone()));
-                    }
-                } else {
-                    if let Some((k, v)) = parse_attr_value(token) {
-                        modifications.push((k, v));
-                    } else {
+                    selections.push(parse_selection(token)?);
+                } else if phase == 2 {
+                    // `clear notes` is shorthand for `make notes=`; a value has no place here.
+                    if token.contains('=') {
                         return Err(ProtocolError::SyntaxError);
                     }
+                    modifications.push((token.clone(), String::new()));
+                } else if let Some((k, v)) = parse_attr_value(token) {
+                    modifications.push((k, v));
+                } else {
+                    return Err(ProtocolError::SyntaxError);
                 }
             }
-            Ok(Command::Change { selections, modifications, force })
+            Ok(Command::Change { selections, modifications, force, if_revision })
         }
         "help" => {
             let mut target = None;
@@ -270,11 +461,30 @@ pub fn parse_command(line: &str) -> Result<Command, ProtocolError> {
             }
             Ok(Command::Help { target, topics })
         }
+        "begin" => Ok(Command::Begin),
+        "commit" => Ok(Command::Commit),
+        "rollback" => Ok(Command::Rollback),
         "quit" | "exit" | "stop" => Ok(Command::Quit),
         _ => Err(ProtocolError::UnknownCommand),
     }
 }
 
+/// Parses selection tokens as `parse_selection` does, stopping at the first bad one.
+fn parse_selections(tokens: &[String]) -> Result<Vec<(Option<String>, String)>, ProtocolError> {
+    tokens.iter().map(|token| parse_selection(token)).collect()
+}
+
+/// One selection token: `field=value`, a comparison such as `cpu_cores>8`, or a bare word
+/// matched against the default fields. `=value`, with no field, is a syntax error rather than
+/// a search of a field nothing can have.
+fn parse_selection(token: &str) -> Result<(Option<String>, String), ProtocolError> {
+    match compare::parse_selection(token) {
+        Some((k, _)) if k.is_empty() => Err(ProtocolError::SyntaxError),
+        Some((k, v)) => Ok((Some(k), v)),
+        None => Ok((None, token.to_string())),
+    }
+}
+
 fn parse_attr_value(token: &str) -> Option<(String, String)> {
     if let Some(pos) = token.find('=') {
         let key = token[..pos].to_string();
@@ -330,18 +540,109 @@ fn tokenize(line: &str) -> Result<Vec<String>, ProtocolError> {
     Ok(tokens)
 }
 
+/// Stands in for a value kept out of logs and webhook notifications.
+pub const REDACTED: &str = "<redacted>";
+
 /// Redacts the argument portion of `auth`/`auth-check` wire lines for logging, so raw signature
-/// and challenge material is never written to logs verbatim. Uses a cheap first-word check
-/// (not full tokenization) so a malformed line (e.g. unclosed quotes) can never bypass redaction
-/// by failing to tokenize — this must be infallible.
-pub fn redact_wire_line_for_logging(line: &str) -> String {
+/// and challenge material is never written to logs verbatim, and the values of fields `schema`
+/// keeps private or encrypted from any other line. Uses a cheap first-word check (not full
+/// tokenization) so a malformed line (e.g. unclosed quotes) can never bypass redaction by
+/// failing to tokenize — this must be infallible.
+pub fn redact_wire_line_for_logging(line: &str, schema: &FieldSchema) -> String {
     let first_word = line.split_whitespace().next().unwrap_or("").to_lowercase();
     match first_word.as_str() {
-        "auth" | "auth-check" => format!("{} <redacted>", first_word),
+        "auth" | "auth-check" => format!("{} {}", first_word, REDACTED),
+        _ => redact_wire_values(line, schema),
+    }
+}
+
+/// `line` with the value of every `field=value` word whose field `schema` keeps private or
+/// encrypted replaced by `<redacted>`. Words are split on unquoted whitespace without
+/// unescaping, so this can't fail either: an unclosed quote makes the rest of the line one
+/// word, redacted along with the field it follows.
+pub fn redact_wire_values(line: &str, schema: &FieldSchema) -> String {
+    if !schema.has_private_fields() {
+        return line.to_string();
+    }
+    raw_words(line)
+        .into_iter()
+        .map(|word| match compare::parse_selection(word.strip_prefix('"').unwrap_or(word)) {
+            Some((key, value)) if is_hidden(schema, &key, &value) => format!("{}{}", &word[..word.len() - value.len()], REDACTED),
+            _ => word.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// `line` as it should be replicated to peers: a `change` loses its trailing `if revision=N`,
+/// since every node keeps its own revision counter and a peer would refuse it with `523`.
+/// Any other line is returned as typed.
+pub fn replication_line(line: &str) -> String {
+    match parse_command(line) {
+        Ok(Command::Change { if_revision: Some(_), .. }) => {
+            let words = raw_words(line);
+            words[..words.len() - 2].join(" ")
+        }
         _ => line.to_string(),
     }
 }
 
+/// `selections` with the values of fields `schema` keeps private or encrypted redacted.
+pub fn redact_selections(selections: &[(Option<String>, String)], schema: &FieldSchema) -> Vec<(Option<String>, String)> {
+    selections
+        .iter()
+        .map(|(key, value)| match key {
+            Some(k) if is_hidden(schema, k, value) => (key.clone(), REDACTED.to_string()),
+            _ => (key.clone(), value.clone()),
+        })
+        .collect()
+}
+
+/// `add` fields or `change` modifications with the values of fields `schema` keeps private or
+/// encrypted redacted. Unsetting one (`field=`) reveals nothing, so it is left as it is.
+pub fn redact_fields(fields: &[(String, String)], schema: &FieldSchema) -> Vec<(String, String)> {
+    fields
+        .iter()
+        .map(|(key, value)| match is_hidden(schema, key, value) {
+            true => (key.clone(), REDACTED.to_string()),
+            false => (key.clone(), value.clone()),
+        })
+        .collect()
+}
+
+/// Whether `value` under `key` - a selection, field or modification key, operator or suffix
+/// included (`cpu_cores>`, `bmc_password:`, `ip_addr-`) - belongs to a private or encrypted field.
+fn is_hidden(schema: &FieldSchema, key: &str, value: &str) -> bool {
+    let field = compare::split_key(key).map_or(key, |(field, _)| field);
+    let field = field.strip_suffix([':', '-']).filter(|f| !f.is_empty()).unwrap_or(field);
+    !value.is_empty() && schema.is_private_anywhere(field.trim_start_matches('"'))
+}
+
+/// The words of `line`, split the way `tokenize` splits them but left as typed.
+fn raw_words(line: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let (mut start, mut in_quotes, mut escaped) = (None, false, false);
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            in_quotes = !in_quotes;
+        } else if c.is_whitespace() && !in_quotes {
+            if let Some(s) = start.take() {
+                words.push(&line[s..i]);
+            }
+            continue;
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(s) = start {
+        words.push(&line[s..]);
+    }
+    words
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -363,16 +664,201 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_should_parse_comparison_selections() {
+        let cmd = parse_command("query cpu_cores>8 last_seen_at<now-2h status!=offline type=machine").unwrap();
+        if let Command::Query { selections, .. } = cmd {
+            assert_eq!(selections, vec![
+                (Some("cpu_cores>".to_string()), "8".to_string()),
+                (Some("last_seen_at<".to_string()), "now-2h".to_string()),
+                (Some("status!=".to_string()), "offline".to_string()),
+                (Some("type".to_string()), "machine".to_string()),
+            ]);
+        } else {
+            panic!("Expected Query command");
+        }
+    }
+
+    #[test]
+    fn test_should_reject_a_selection_without_a_field_in_every_command() {
+        for line in ["query =web01", "delete =web01", "history =web01", "undelete =web01", "tombstones =web01", "links =web01", "dupes =web01", "merge =web01 into web02", "change =web01 make os=x"] {
+            assert_eq!(parse_command(line), Err(ProtocolError::SyntaxError), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_should_classify_write_commands() {
+        for line in ["add type=machine hostname=web01", "delete web01", "undelete web01", "merge web01 into web02", "change web01 make os=x", "snapshot restore nightly"] {
+            assert!(parse_command(line).unwrap().is_write(), "{}", line);
+        }
+        for line in ["query web01", "history web01", "tombstones", "snapshot create nightly", "begin", "commit"] {
+            assert!(!parse_command(line).unwrap().is_write(), "{}", line);
+        }
+    }
+
     #[test]
     fn test_should_parse_change_command() {
         let cmd = parse_command("change alias=j-doe make fax=\"555-1212\"").unwrap();
-        if let Command::Change { selections, modifications, force } = cmd {
+        if let Command::Change { selections, modifications, force, if_revision } = cmd {
             assert_eq!(selections, vec![(Some("alias".to_string()), "j-doe".to_string())]);
             assert_eq!(modifications, vec![("fax".to_string(), "555-1212".to_string())]);
             assert!(!force);
+            assert_eq!(if_revision, None);
+        } else {
+            panic!("Expected Change command");
+        }
+    }
+
+    #[test]
+    fn test_should_parse_revision_condition_on_change() {
+        let cmd = parse_command("change hostname=web01 make ip_addr=10.0.0.2 if revision=17").unwrap();
+        if let Command::Change { modifications, if_revision, .. } = cmd {
+            assert_eq!(modifications, vec![("ip_addr".to_string(), "10.0.0.2".to_string())]);
+            assert_eq!(if_revision, Some(17));
+        } else {
+            panic!("Expected Change command");
+        }
+        for bad in [
+            "change hostname=web01 make status=up if",
+            "change hostname=web01 make status=up if revision=latest",
+            "change hostname=web01 make status=up if owner=me",
+            "change hostname=web01 make status=up if revision=1 revision=2",
+        ] {
+            assert_eq!(parse_command(bad), Err(ProtocolError::SyntaxError), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_should_parse_dupes_and_merge() {
+        assert_eq!(parse_command("dupes").unwrap(), Command::Dupes(vec![]));
+        assert_eq!(parse_command("dupes type=machine").unwrap(), Command::Dupes(vec![(Some("type".to_string()), "machine".to_string())]));
+        assert_eq!(
+            parse_command("merge alias=scan-ab12 INTO hostname=web01").unwrap(),
+            Command::Merge {
+                from: vec![(Some("alias".to_string()), "scan-ab12".to_string())],
+                into: vec![(Some("hostname".to_string()), "web01".to_string())],
+            }
+        );
+        for bad in ["merge alias=scan-ab12", "merge into hostname=web01", "merge alias=a into", "merge a into b into c"] {
+            assert!(matches!(parse_command(bad), Err(ProtocolError::SyntaxError)), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_should_parse_links_with_direction_depth_and_field() {
+        assert_eq!(
+            parse_command("links hostname=pve01").unwrap(),
+            Command::Links {
+                selections: vec![(Some("hostname".to_string()), "pve01".to_string())],
+                direction: Direction::Children,
+                depth: Some(1),
+                via: None,
+            }
+        );
+        assert_eq!(
+            parse_command("LINKS hostname=vm-web parents all via=runs_on").unwrap(),
+            Command::Links {
+                selections: vec![(Some("hostname".to_string()), "vm-web".to_string())],
+                direction: Direction::Parents,
+                depth: None,
+                via: Some("runs_on".to_string()),
+            }
+        );
+        assert!(matches!(parse_command("links hostname=pve01 depth=3"), Ok(Command::Links { depth: Some(3), .. })));
+        for bad in ["links", "links children", "links hostname=pve01 depth=0", "links hostname=pve01 all status=up", "links hostname=pve01 via="] {
+            assert_eq!(parse_command(bad), Err(ProtocolError::SyntaxError), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_should_parse_field_removal_in_change_command() {
+        let cmd = parse_command("change hostname=web01 make ip_addr-=10.0.0.1 mac_addr:=aa:bb:cc:dd:ee:ff clear notes").unwrap();
+        if let Command::Change { modifications, .. } = cmd {
+            assert_eq!(modifications, vec![
+                ("ip_addr-".to_string(), "10.0.0.1".to_string()),
+                ("mac_addr:".to_string(), "aa:bb:cc:dd:ee:ff".to_string()),
+                ("notes".to_string(), String::new()),
+            ]);
+        } else {
+            panic!("Expected Change command");
+        }
+        assert_eq!(parse_command("change hostname=web01 make notes"), Err(ProtocolError::SyntaxError));
+        assert_eq!(parse_command("change hostname=web01 make clear notes=foo"), Err(ProtocolError::SyntaxError));
+        assert_eq!(parse_command("change hostname=web01 force clear ip_addr-=10.0.0.1"), Err(ProtocolError::SyntaxError));
+    }
+
+    #[test]
+    fn test_should_replicate_a_conditional_change_without_its_revision_condition() {
+        assert_eq!(
+            replication_line("change hostname=web01 make notes=\"rack 4\" if revision=17"),
+            "change hostname=web01 make notes=\"rack 4\""
+        );
+        assert_eq!(
+            parse_command(&replication_line("change hostname=web01 make status=up IF Revision=3")),
+            parse_command("change hostname=web01 make status=up")
+        );
+        for line in ["change hostname=web01 make status=up", "change if make status=up", "add type=machine hostname=if"] {
+            assert_eq!(replication_line(line), line);
+        }
+    }
+
+    #[test]
+    fn test_should_read_clear_and_if_as_selection_words_before_make() {
+        let cmd = parse_command("change clear if make status=up").unwrap();
+        if let Command::Change { selections, modifications, if_revision, .. } = cmd {
+            assert_eq!(selections, vec![(None, "clear".to_string()), (None, "if".to_string())]);
+            assert_eq!(modifications, vec![("status".to_string(), "up".to_string())]);
+            assert_eq!(if_revision, None);
+        } else {
+            panic!("Expected Change command");
+        }
+
+        let cmd = parse_command("change if make status=up clear notes if revision=4").unwrap();
+        if let Command::Change { selections, modifications, if_revision, .. } = cmd {
+            assert_eq!(selections, vec![(None, "if".to_string())]);
+            assert_eq!(modifications, vec![("status".to_string(), "up".to_string()), ("notes".to_string(), String::new())]);
+            assert_eq!(if_revision, Some(4));
         } else {
             panic!("Expected Change command");
         }
+        assert_eq!(parse_command("change hostname=web01 make status=up if revision=4 notes=x"), Err(ProtocolError::SyntaxError));
+    }
+
+    #[test]
+    fn test_should_parse_history_command() {
+        assert_eq!(
+            parse_command("history hostname=web01").unwrap(),
+            Command::History(vec![(Some("hostname".to_string()), "web01".to_string())])
+        );
+        assert_eq!(parse_command("history"), Err(ProtocolError::SyntaxError));
+    }
+
+    #[test]
+    fn test_should_parse_snapshot_actions() {
+        assert_eq!(parse_command("snapshot create nightly").unwrap(), Command::Snapshot(SnapshotAction::Create("nightly".to_string())));
+        assert_eq!(parse_command("SNAPSHOT list").unwrap(), Command::Snapshot(SnapshotAction::List));
+        assert_eq!(parse_command("snapshot restore nightly").unwrap(), Command::Snapshot(SnapshotAction::Restore("nightly".to_string())));
+        for bad in ["snapshot", "snapshot create", "snapshot list extra", "snapshot drop nightly"] {
+            assert_eq!(parse_command(bad), Err(ProtocolError::SyntaxError), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_should_parse_transaction_commands() {
+        assert_eq!(parse_command("begin").unwrap(), Command::Begin);
+        assert_eq!(parse_command("COMMIT").unwrap(), Command::Commit);
+        assert_eq!(parse_command("rollback").unwrap(), Command::Rollback);
+    }
+
+    #[test]
+    fn test_should_parse_undelete_and_tombstones_commands() {
+        assert_eq!(
+            parse_command("undelete hostname=web01").unwrap(),
+            Command::Undelete(vec![(Some("hostname".to_string()), "web01".to_string())])
+        );
+        // Undeleting needs a selection; listing tombstones doesn't.
+        assert_eq!(parse_command("undelete"), Err(ProtocolError::SyntaxError));
+        assert_eq!(parse_command("tombstones").unwrap(), Command::Tombstones(vec![]));
     }
 
     #[test]
@@ -431,9 +917,43 @@ mod tests {
         assert!(debug_str.contains("Jane Smith"), "Add field values must NOT be redacted (out of scope for #161): {debug_str}");
     }
 
+    fn private_schema() -> FieldSchema {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("field-schema.toml");
+        std::fs::write(&path, "[types.machine.fields.bmc_password]\nencrypted = true\n[fields.owner_phone]\nprivate = true").unwrap();
+        FieldSchema::load(&path).unwrap()
+    }
+
+    #[test]
+    fn test_should_redact_private_and_encrypted_values_from_wire_lines() {
+        let schema = private_schema();
+        let cases = [
+            ("change hostname=bmc01 force bmc_password=calvin", "change hostname=bmc01 force bmc_password=<redacted>"),
+            ("add type=machine owner_phone=\"555 0100\" hostname=web01", "add type=machine owner_phone=<redacted> hostname=web01"),
+            ("query bmc_password>b owner_phone!=x", "query bmc_password><redacted> owner_phone!=<redacted>"),
+            ("query \"bmc_password>calvin\"", "query \"bmc_password><redacted>"),
+            ("change hostname=bmc01 make bmc_password:=a bmc_password-=b owner_phone=", "change hostname=bmc01 make bmc_password:=<redacted> bmc_password-=<redacted> owner_phone="),
+            ("change hostname=bmc01 force bmc_password=\"unclosed calvin", "change hostname=bmc01 force bmc_password=<redacted>"),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(redact_wire_line_for_logging(line, &schema), expected);
+        }
+        assert_eq!(redact_wire_line_for_logging("change hostname=bmc01 make status=up", &schema), "change hostname=bmc01 make status=up");
+    }
+
+    #[test]
+    fn test_should_redact_private_values_from_a_command_copy() {
+        let schema = private_schema();
+        let command = parse_command("change bmc_password=calvin force bmc_password=hunter2 status=up").unwrap();
+        let debug_str = format!("{:?}", command.redacted(&schema));
+        assert!(!debug_str.contains("calvin") && !debug_str.contains("hunter2"), "{debug_str}");
+        assert!(debug_str.contains("status") && debug_str.contains("up"), "{debug_str}");
+        assert!(format!("{:?}", command).contains("hunter2"), "the command itself is left as typed");
+    }
+
     #[test]
     fn test_should_redact_auth_wire_line() {
-        let redacted = redact_wire_line_for_logging("auth mypubkey123 TOP-SECRET-SIGNATURE");
+        let redacted = redact_wire_line_for_logging("auth mypubkey123 TOP-SECRET-SIGNATURE", &FieldSchema::default());
         assert!(!redacted.contains("TOP-SECRET-SIGNATURE"), "raw signature must not appear: {redacted}");
         assert!(!redacted.contains("mypubkey123"), "public_key is also dropped for this raw-line case: {redacted}");
         assert_eq!(redacted, "auth <redacted>");
@@ -441,7 +961,7 @@ mod tests {
 
     #[test]
     fn test_should_redact_auth_check_wire_line() {
-        let redacted = redact_wire_line_for_logging("auth-check mypubkey123 TOP-SECRET-SIG TOP-SECRET-CHALLENGE");
+        let redacted = redact_wire_line_for_logging("auth-check mypubkey123 TOP-SECRET-SIG TOP-SECRET-CHALLENGE", &FieldSchema::default());
         assert!(!redacted.contains("TOP-SECRET-SIG"), "raw signature must not appear: {redacted}");
         assert!(!redacted.contains("TOP-SECRET-CHALLENGE"), "raw challenge must not appear: {redacted}");
         assert_eq!(redacted, "auth-check <redacted>");
@@ -449,27 +969,27 @@ mod tests {
 
     #[test]
     fn test_should_redact_auth_wire_line_case_insensitively() {
-        let redacted = redact_wire_line_for_logging("AUTH mypubkey123 TOP-SECRET-SIGNATURE");
+        let redacted = redact_wire_line_for_logging("AUTH mypubkey123 TOP-SECRET-SIGNATURE", &FieldSchema::default());
         assert!(!redacted.contains("TOP-SECRET-SIGNATURE"), "raw signature must not appear regardless of case: {redacted}");
     }
 
     #[test]
     fn test_should_not_redact_non_auth_wire_line() {
         let line = "add name=\"Jane Smith\" mail=\"jane@example.com\"";
-        let redacted = redact_wire_line_for_logging(line);
+        let redacted = redact_wire_line_for_logging(line, &FieldSchema::default());
         assert_eq!(redacted, line, "non-auth wire lines must be logged unchanged");
     }
 
     #[test]
     fn test_should_handle_malformed_auth_wire_line_without_panicking() {
         // Unclosed quote / garbage input must still redact cleanly, not panic, not leak.
-        let redacted = redact_wire_line_for_logging("auth-check \"unclosed TOP-SECRET-CHALLENGE");
+        let redacted = redact_wire_line_for_logging("auth-check \"unclosed TOP-SECRET-CHALLENGE", &FieldSchema::default());
         assert!(!redacted.contains("TOP-SECRET-CHALLENGE"), "must redact even on malformed input: {redacted}");
     }
 
     #[test]
     fn test_should_handle_empty_line_without_panicking() {
-        let redacted = redact_wire_line_for_logging("");
+        let redacted = redact_wire_line_for_logging("", &FieldSchema::default());
         assert_eq!(redacted, "");
     }
 }
@@ -0,0 +1,236 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/recovery.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * A FileStorage data file that didn't parse used to be logged and replaced
+ * by an empty store, and the next write overwrote the corrupt - but mostly
+ * recoverable - inventory with nothing. Now a corrupt data file is copied
+ * aside under a timestamped name, every record that still parses is
+ * salvaged, the ones that don't are reported by line, id and name, and
+ * `PHAROS_STORAGE_ON_CORRUPT` decides whether the server refuses to start
+ * (the default), starts read-only on what survived, or writes it back.
+ * * Traceability:
+ * Called from FileStorage::load_from_disk.
+ * ======================================================================== */
+
+use crate::storage::Record;
+use chrono::{DateTime, Utc};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// What FileStorage does when its data file doesn't parse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CorruptPolicy {
+    /// Refuse to start; the data file is left as it is.
+    #[default]
+    Refuse,
+    /// Start with the salvaged records and refuse every write, so nothing on disk changes.
+    ReadOnly,
+    /// Start with the salvaged records and write them back as the data file.
+    Recover,
+}
+
+impl CorruptPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "refuse" => Some(CorruptPolicy::Refuse),
+            "read-only" | "readonly" => Some(CorruptPolicy::ReadOnly),
+            "recover" => Some(CorruptPolicy::Recover),
+            _ => None,
+        }
+    }
+
+    /// Reads `PHAROS_STORAGE_ON_CORRUPT`, falling back to `refuse` (with a warning) on an
+    /// unrecognized value - the one fallback that can't cost data.
+    pub fn from_env() -> Self {
+        match std::env::var("PHAROS_STORAGE_ON_CORRUPT") {
+            Ok(raw) => CorruptPolicy::parse(&raw).unwrap_or_else(|| {
+                warn!("Ignoring unrecognized PHAROS_STORAGE_ON_CORRUPT value '{}' (expected refuse, read-only or recover)", raw);
+                CorruptPolicy::default()
+            }),
+            Err(_) => CorruptPolicy::default(),
+        }
+    }
+}
+
+/// A record of a corrupt data file that could not be salvaged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LostRecord {
+    /// The line its entry starts on.
+    pub line: usize,
+    /// Its id and hostname (or alias), as far as they can still be read.
+    pub id: Option<usize>,
+    pub name: Option<String>,
+    pub error: String,
+}
+
+impl std::fmt::Display for LostRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.id {
+            Some(id) => write!(f, "record #{}", id)?,
+            None => write!(f, "a record")?,
+        }
+        if let Some(name) = &self.name {
+            write!(f, " ({})", name)?;
+        }
+        write!(f, " at line {}: {}", self.line, self.error)
+    }
+}
+
+/// What `salvage` got out of a corrupt data file.
+#[derive(Debug, Default)]
+pub struct Salvage {
+    pub records: Vec<Record>,
+    pub lost: Vec<LostRecord>,
+    /// The data file's next id, if its line survived.
+    pub next_id: Option<usize>,
+}
+
+/// Recovers what it can from a data file that doesn't parse as a whole. FileStorage writes it
+/// pretty-printed with one record per entry of its `records` array, from a `    {` line to a
+/// `    }` line (`  {` to `  }` in a bare array, as written before the next id was kept), so
+/// each entry is parsed on its own and a damaged entry costs only its own record. An entry
+/// that never closes - a torn end, or a closing line that was damaged - is lost with it.
+pub fn salvage(data: &str) -> Salvage {
+    let indent = if data.trim_start().starts_with('[') { "  " } else { "    " };
+    let (open, close) = (format!("{}{{", indent), format!("{}}}", indent));
+    let mut salvage = Salvage::default();
+    let mut entry: Option<(usize, Vec<&str>)> = None;
+    for (index, line) in data.lines().enumerate() {
+        let line = line.trim_end();
+        if line == open {
+            if let Some((start, lines)) = entry.take() {
+                salvage.keep(start, &lines, Some("entry never closes"));
+            }
+            entry = Some((index + 1, vec!["{"]));
+        } else if let Some((start, lines)) = entry.as_mut() {
+            if line == close || line.strip_suffix(',') == Some(close.as_str()) {
+                lines.push("}");
+                let (start, lines) = (*start, std::mem::take(lines));
+                entry = None;
+                salvage.keep(start, &lines, None);
+            } else {
+                lines.push(line.strip_prefix(indent).unwrap_or(line));
+            }
+        } else if let Some(next_id) = line.strip_prefix("  \"next_id\": ") {
+            salvage.next_id = next_id.trim_end_matches(',').parse().ok();
+        }
+    }
+    if let Some((start, lines)) = entry {
+        salvage.keep(start, &lines, Some("file ends inside it"));
+    }
+    salvage
+}
+
+impl Salvage {
+    fn keep(&mut self, line: usize, lines: &[&str], unclosed: Option<&str>) {
+        let parsed = match unclosed {
+            Some(reason) => Err(reason.to_string()),
+            None => serde_json::from_str::<Record>(&lines.join("\n")).map_err(|e| e.to_string()),
+        };
+        match parsed {
+            Ok(record) => self.records.push(record),
+            Err(error) => self.lost.push(LostRecord { line, id: id_of(lines), name: name_of(lines), error }),
+        }
+    }
+}
+
+/// The record's `"id": N` line, indented one level inside the entry.
+fn id_of(lines: &[&str]) -> Option<usize> {
+    lines
+        .iter()
+        .find_map(|l| l.strip_prefix("  \"id\": "))
+        .and_then(|id| id.trim_end_matches(',').parse().ok())
+}
+
+fn name_of(lines: &[&str]) -> Option<String> {
+    let value = |key: &str| {
+        let prefix = format!("\"{}\": \"", key);
+        lines.iter().find_map(|l| l.trim_start().strip_prefix(prefix.as_str())?.split_once('"').map(|(v, _)| v.to_string()))
+    };
+    value("hostname").or_else(|| value("alias"))
+}
+
+/// Copies the corrupt data file at `path` aside as `<file name>.corrupt-<UTC time>` in the same
+/// directory and returns the copy's path; the original stays put. A server that keeps refusing
+/// to start doesn't pile up copies: an identical earlier copy is returned instead.
+pub fn quarantine(path: &Path, data: &[u8], now: DateTime<Utc>) -> std::io::Result<PathBuf> {
+    let file_name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let prefix = format!("{}.corrupt-", file_name);
+    let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+
+    for entry in std::fs::read_dir(dir)?.flatten() {
+        let copy = entry.path();
+        if entry.file_name().to_string_lossy().starts_with(&prefix) && std::fs::read(&copy).is_ok_and(|c| c == data) {
+            return Ok(copy);
+        }
+    }
+    let copy = dir.join(format!("{}{}", prefix, now.format("%Y%m%dT%H%M%SZ")));
+    std::fs::write(&copy, data)?;
+    Ok(copy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn record(id: usize, hostname: &str) -> Record {
+        let fields = HashMap::from([("type".to_string(), "machine".to_string()), ("hostname".to_string(), hostname.to_string())]);
+        Record { id, fields, ..Default::default() }
+    }
+
+    #[test]
+    fn test_should_parse_corrupt_policies() {
+        assert_eq!(CorruptPolicy::parse("Refuse"), Some(CorruptPolicy::Refuse));
+        assert_eq!(CorruptPolicy::parse("read-only"), Some(CorruptPolicy::ReadOnly));
+        assert_eq!(CorruptPolicy::parse("recover"), Some(CorruptPolicy::Recover));
+        assert_eq!(CorruptPolicy::parse("ignore"), None);
+    }
+
+    #[test]
+    fn test_should_salvage_every_entry_that_still_parses() {
+        let records = vec![record(1, "web01"), record(2, "web02"), record(3, "web03"), record(4, "web04")];
+        let data = serde_json::to_string_pretty(&records).unwrap();
+        // Damage web02's entry, and tear the file off in the middle of web04's.
+        let data = data.replacen("\"web02\"", "\"web02\" oops", 1);
+        let data = &data[..data.find("web04").unwrap()];
+        assert!(serde_json::from_str::<Vec<Record>>(data).is_err());
+
+        let salvage = salvage(data);
+        assert_eq!(salvage.records.iter().map(|r| r.id).collect::<Vec<_>>(), vec![1, 3]);
+        let lost: Vec<(Option<usize>, Option<&str>)> = salvage.lost.iter().map(|l| (l.id, l.name.as_deref())).collect();
+        assert_eq!(lost, vec![(Some(2), Some("web02")), (Some(4), None)]);
+        assert!(salvage.lost[1].to_string().contains("file ends inside it"), "{}", salvage.lost[1]);
+        assert_eq!(salvage.next_id, None);
+    }
+
+    #[test]
+    fn test_should_salvage_entries_and_the_next_id_of_a_data_file() {
+        let records = vec![record(1, "web01"), record(2, "web02"), record(3, "web03")];
+        let data = serde_json::to_string_pretty(&serde_json::json!({ "next_id": 9, "records": records })).unwrap();
+        let data = data.replacen("\"web02\"", "\"web02\" oops", 1);
+
+        let salvage = salvage(&data);
+        assert_eq!(salvage.records.iter().map(|r| r.id).collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(salvage.lost.iter().map(|l| l.id).collect::<Vec<_>>(), vec![Some(2)]);
+        assert_eq!(salvage.next_id, Some(9));
+    }
+
+    #[test]
+    fn test_should_quarantine_a_corrupt_file_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.json");
+        std::fs::write(&path, "[ not json").unwrap();
+        let now = DateTime::parse_from_rfc3339("2026-10-17T08:30:00+00:00").unwrap().with_timezone(&Utc);
+
+        let copy = quarantine(&path, b"[ not json", now).unwrap();
+        assert_eq!(copy, dir.path().join("data.json.corrupt-20261017T083000Z"));
+        assert_eq!(std::fs::read_to_string(&copy).unwrap(), "[ not json");
+        assert!(path.exists(), "the original stays put");
+        assert_eq!(quarantine(&path, b"[ not json", now + chrono::Duration::hours(1)).unwrap(), copy);
+    }
+}
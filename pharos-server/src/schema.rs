@@ -0,0 +1,612 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/schema.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * FIELDS used to describe six hardcoded fields and label everything else
+ * "max 256", while writes accepted any value of any length. The field schema
+ * declares, per record type, what each field is: its description, maximum
+ * length, whether it is required, what kind of value it holds, whether it is
+ * multi-valued and whether it is public. Every backend checks writes against
+ * it, and FIELDS describes exactly what is enforced.
+ * * Traceability:
+ * Backs the `fields` protocol command (RFC 2378 Section 3.4) and the 512
+ * validation errors of add/upsert/change.
+ * ======================================================================== */
+
+use crate::compare;
+use crate::storage::{self, Record, StorageError};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+/// Maximum length of fields no schema entry declares, unless the schema file sets
+/// `user_field_max_len`.
+pub const DEFAULT_MAX_LEN: usize = 256;
+
+/// Fields the server itself writes (or that replication carries along), so a strict type never
+/// rejects them for being undeclared.
+const SERVER_FIELDS: [&str; 5] = ["type", "created_at", "last_seen_at", "source", "forwarded"];
+
+/// The fields an upsert finds an existing record by when its type declares no `identity`.
+const DEFAULT_IDENTITY: [&str; 2] = ["hostname", "alias"];
+
+const USER_FIELD_DESCRIPTION: &str = "User-defined field; no additional metadata available.";
+
+fn default_max_len() -> usize {
+    DEFAULT_MAX_LEN
+}
+
+/// What a field's values must parse as.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueKind {
+    #[default]
+    String,
+    Int,
+    Bool,
+    Timestamp,
+    Ip,
+    Mac,
+    Enum,
+    /// A dotted version such as `1.10.2` or `v2.0.0-rc.1`, ordered the semver way.
+    Version,
+    /// The hostname or alias of another live record (of the `target` type, if one is given).
+    Link,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldDef {
+    #[serde(default)]
+    pub description: String,
+    /// Longest accepted value, in characters.
+    #[serde(default = "default_max_len")]
+    pub max_len: usize,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default)]
+    pub kind: ValueKind,
+    /// The accepted values of an `enum` field.
+    #[serde(default)]
+    pub values: Vec<String>,
+    /// Every value written is kept (once), instead of replacing the previous one.
+    #[serde(default)]
+    pub multi: bool,
+    /// Private fields are only shown to the record's owner and to admins.
+    #[serde(default)]
+    pub private: bool,
+    /// The record type a `link` field must point at; any type when unset.
+    #[serde(default)]
+    pub target: Option<String>,
+    /// Encrypted fields are sealed at rest with the server's field key, shown only where private
+    /// ones are, and only overwritten by `change ... force` (RFC 2378's Encrypt attribute).
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+impl FieldDef {
+    fn new(description: &str, max_len: usize) -> Self {
+        Self {
+            description: description.to_string(),
+            max_len,
+            required: false,
+            kind: ValueKind::String,
+            values: Vec::new(),
+            multi: false,
+            private: false,
+            target: None,
+            encrypted: false,
+        }
+    }
+
+    fn kind(mut self, kind: ValueKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    /// The FIELDS attribute line: `max N Public|Private`, then `Required`, `Multiple`, `Encrypt` and
+    /// the value kind (`Integer`, `Boolean`, `Timestamp`, `IP`, `MAC`, `Enum(a|b)`, `Version`,
+    /// `Link` or `Link(type)`) when they apply.
+    pub fn attributes(&self) -> String {
+        let mut words = vec![
+            format!("max {}", self.max_len),
+            if self.private { "Private" } else { "Public" }.to_string(),
+        ];
+        if self.required {
+            words.push("Required".to_string());
+        }
+        if self.multi {
+            words.push("Multiple".to_string());
+        }
+        if self.encrypted {
+            words.push("Encrypt".to_string());
+        }
+        match self.kind {
+            ValueKind::String => {}
+            ValueKind::Int => words.push("Integer".to_string()),
+            ValueKind::Bool => words.push("Boolean".to_string()),
+            ValueKind::Timestamp => words.push("Timestamp".to_string()),
+            ValueKind::Ip => words.push("IP".to_string()),
+            ValueKind::Mac => words.push("MAC".to_string()),
+            ValueKind::Enum => words.push(format!("Enum({})", self.values.join("|"))),
+            ValueKind::Version => words.push("Version".to_string()),
+            ValueKind::Link => words.push(match &self.target {
+                Some(target) => format!("Link({})", target),
+                None => "Link".to_string(),
+            }),
+        }
+        words.join(" ")
+    }
+
+    fn check_value(&self, name: &str, value: &str) -> Result<(), StorageError> {
+        let illegal = |expected: &str| {
+            Err(StorageError::InvalidArgument(format!("'{}' is not a valid value for field '{}' (expected {})", value, name, expected)))
+        };
+
+        if value.chars().count() > self.max_len {
+            return Err(StorageError::InvalidArgument(format!(
+                "value for field '{}' is longer than {} characters",
+                name, self.max_len
+            )));
+        }
+        let value = value.trim();
+        match self.kind {
+            ValueKind::String => Ok(()),
+            ValueKind::Int if value.parse::<i64>().is_err() => illegal("an integer"),
+            ValueKind::Bool if !["true", "false"].iter().any(|b| value.eq_ignore_ascii_case(b)) => illegal("true or false"),
+            ValueKind::Timestamp if chrono::DateTime::parse_from_rfc3339(value).is_err() => illegal("an RFC 3339 timestamp"),
+            ValueKind::Ip if value.parse::<std::net::IpAddr>().is_err() => illegal("an IP address"),
+            ValueKind::Mac if !storage::is_valid_mac_address(value) => illegal("a MAC address"),
+            ValueKind::Enum if !self.values.iter().any(|v| v == value) => illegal(&format!("one of {}", self.values.join(", "))),
+            ValueKind::Version if compare::Version::parse(value).is_none() => illegal("a version such as 1.2.3"),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TypeSchema {
+    /// Reject fields that neither this type nor the shared `[fields]` table declares.
+    #[serde(default)]
+    pub strict: bool,
+    /// The fields an upsert finds an existing record of this type by, strongest first, e.g.
+    /// `["uuid", "serial_number", "hostname", "mac_addr"]`.
+    #[serde(default)]
+    pub identity: Vec<String>,
+    #[serde(default)]
+    pub fields: BTreeMap<String, FieldDef>,
+}
+
+/// The field schema: shared field definitions, per-type definitions that take precedence over
+/// them, and the limit for fields nobody declared. Loaded from `PHAROS_FIELD_SCHEMA_PATH` (TOML).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldSchema {
+    #[serde(default = "default_max_len")]
+    pub user_field_max_len: usize,
+    /// Definitions shared by every type.
+    #[serde(default)]
+    pub fields: BTreeMap<String, FieldDef>,
+    /// Keyed by lowercased record type.
+    #[serde(default)]
+    pub types: BTreeMap<String, TypeSchema>,
+}
+
+impl Default for FieldSchema {
+    fn default() -> Self {
+        let mut record_type = FieldDef::new("Record type discriminator (e.g. \"person\" or \"machine\").", 64);
+        record_type.required = true;
+        let mut ip_addr = FieldDef::new("IP address of a machine entry; every address given is kept.", 64).kind(ValueKind::Ip);
+        ip_addr.multi = true;
+        let mut mac_addr = FieldDef::new("MAC address of a machine entry; every address given is kept.", 32).kind(ValueKind::Mac);
+        mac_addr.multi = true;
+
+        let fields = BTreeMap::from([
+            ("type".to_string(), record_type),
+            ("hostname".to_string(), FieldDef::new("Unique identifier for a machine entry; used to detect an existing record on add/upsert.", 256)),
+            ("alias".to_string(), FieldDef::new("Unique short identifier for a person entry; used to detect an existing record on add/upsert.", 32)),
+            ("created_at".to_string(), FieldDef::new("ISO-8601 timestamp of when this entry was first created (server-injected).", 64).kind(ValueKind::Timestamp)),
+            ("last_seen_at".to_string(), FieldDef::new("ISO-8601 timestamp of the most recent update to this entry (server-injected).", 64).kind(ValueKind::Timestamp)),
+            ("status".to_string(), FieldDef::new("Free-form status/presence value (e.g. \"active\", \"online\", \"offline\").", 64)),
+            ("ip_addr".to_string(), ip_addr),
+            ("mac_addr".to_string(), mac_addr),
+        ]);
+
+        Self { user_field_max_len: DEFAULT_MAX_LEN, fields, types: BTreeMap::new() }
+    }
+}
+
+impl FieldSchema {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path)?;
+        let mut schema: FieldSchema = toml::from_str(&raw)?;
+        schema.types = std::mem::take(&mut schema.types)
+            .into_iter()
+            .map(|(name, fields)| (name.to_lowercase(), fields))
+            .collect();
+
+        if schema.user_field_max_len == 0 {
+            anyhow::bail!("user_field_max_len in {:?} must be at least 1", path);
+        }
+        let declared = schema.fields.iter().map(|(name, def)| (None, name, def)).chain(
+            schema.types.iter().flat_map(|(t, ts)| ts.fields.iter().map(move |(name, def)| (Some(t), name, def))),
+        );
+        for (record_type, ts) in &schema.types {
+            if ts.identity.iter().any(|key| key.trim().is_empty()) {
+                anyhow::bail!("identity of type '{}' in {:?} names an empty field", record_type, path);
+            }
+        }
+        for (record_type, name, def) in declared {
+            let at = match record_type {
+                Some(t) => format!("field '{}' of type '{}' in {:?}", name, t, path),
+                None => format!("field '{}' in {:?}", name, path),
+            };
+            if def.max_len == 0 {
+                anyhow::bail!("{} has max_len 0", at);
+            }
+            if (def.kind == ValueKind::Enum) == def.values.is_empty() {
+                anyhow::bail!("{}: `values` must be given for, and only for, kind = \"enum\"", at);
+            }
+            if def.target.is_some() && def.kind != ValueKind::Link {
+                anyhow::bail!("{}: `target` only applies to kind = \"link\"", at);
+            }
+        }
+        Ok(schema)
+    }
+
+    /// The schema file at `PHAROS_FIELD_SCHEMA_PATH`, or the built-in schema.
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var("PHAROS_FIELD_SCHEMA_PATH") {
+            Ok(path) if !path.trim().is_empty() => Self::load(Path::new(&path)),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    /// The declared definition of `name` for `record_type`: the type's own, else the shared
+    /// one. Without a type, a field only some types declare resolves to the first of them.
+    pub fn field(&self, record_type: Option<&str>, name: &str) -> Option<&FieldDef> {
+        match record_type {
+            Some(t) => self.types.get(&t.to_lowercase()).and_then(|ts| ts.fields.get(name)),
+            None => None,
+        }
+        .or_else(|| self.fields.get(name))
+        .or_else(|| match record_type {
+            Some(_) => None,
+            None => self.types.values().find_map(|ts| ts.fields.get(name)),
+        })
+    }
+
+    /// What applies to `name`: its declaration, or the limits every user-defined field gets.
+    pub fn describe(&self, record_type: Option<&str>, name: &str) -> FieldDef {
+        self.field(record_type, name)
+            .cloned()
+            .unwrap_or_else(|| FieldDef::new(USER_FIELD_DESCRIPTION, self.user_field_max_len))
+    }
+
+    /// Whether `name` holds a list of values on records of `record_type`.
+    pub fn is_multi(&self, record_type: &str, name: &str) -> bool {
+        self.field(Some(record_type), name).is_some_and(|def| def.multi)
+    }
+
+    /// Every field name some type (or the shared table) declares multi-valued.
+    pub fn multi_valued_names(&self) -> BTreeSet<&str> {
+        let shared = self.fields.iter();
+        let typed = self.types.values().flat_map(|ts| ts.fields.iter());
+        shared.chain(typed).filter(|(_, def)| def.multi).map(|(name, _)| name.as_str()).collect()
+    }
+
+    /// The fields an upsert finds an existing record of `record_type` by, strongest first.
+    pub fn identity_keys(&self, record_type: &str) -> Vec<&str> {
+        match self.types.get(&record_type.to_lowercase()) {
+            Some(ts) if !ts.identity.is_empty() => ts.identity.iter().map(String::as_str).collect(),
+            _ => DEFAULT_IDENTITY.to_vec(),
+        }
+    }
+
+    /// Whether `name` links records of `record_type` to other records.
+    pub fn is_link(&self, record_type: &str, name: &str) -> bool {
+        self.field(Some(record_type), name).is_some_and(|def| def.kind == ValueKind::Link)
+    }
+
+    /// Every field name some type (or the shared table) declares a link.
+    pub fn link_names(&self) -> BTreeSet<&str> {
+        let shared = self.fields.iter();
+        let typed = self.types.values().flat_map(|ts| ts.fields.iter());
+        shared.chain(typed).filter(|(_, def)| def.kind == ValueKind::Link).map(|(name, _)| name.as_str()).collect()
+    }
+
+    fn is_strict(&self, record_type: &str) -> bool {
+        self.types.get(&record_type.to_lowercase()).is_some_and(|ts| ts.strict)
+    }
+
+    /// Checks the values a write sets on a record of `record_type`: length, kind and, for a
+    /// strict type, that the field is declared at all.
+    pub fn check_values(&self, record_type: &str, fields: &[(String, String)]) -> Result<(), StorageError> {
+        for (name, value) in fields {
+            match self.field(Some(record_type), name) {
+                Some(def) => def.check_value(name, value)?,
+                None if self.is_strict(record_type) && !SERVER_FIELDS.contains(&name.as_str()) => {
+                    return Err(StorageError::InvalidArgument(format!(
+                        "field '{}' is not declared for type '{}'",
+                        name, record_type
+                    )));
+                }
+                None => self.describe(Some(record_type), name).check_value(name, value)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that `record` has every field its type requires.
+    pub fn check_required(&self, record: &Record) -> Result<(), StorageError> {
+        let record_type = type_of(record);
+        let type_fields = self.types.get(&record_type.to_lowercase()).map(|ts| &ts.fields);
+        let required = self.fields.iter().chain(type_fields.into_iter().flatten());
+        for (name, _) in required.filter(|(name, _)| self.field(Some(record_type), name).is_some_and(|def| def.required)) {
+            let present = record.fields.get(name).is_some_and(|v| !v.trim().is_empty())
+                || record.multi_fields.get(name).is_some_and(|v| !v.is_empty());
+            if !present {
+                return Err(StorageError::InvalidArgument(format!(
+                    "field '{}' is required for type '{}'",
+                    name, record_type
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves single values of fields the schema declares multi-valued for `record`'s type into
+    /// its multi-valued fields, e.g. after a field is newly declared `multi`. Returns whether
+    /// anything moved.
+    pub fn migrate_multi_values(&self, record: &mut Record) -> bool {
+        let record_type = type_of(record).to_string();
+        let to_move: Vec<String> = record.fields.keys().filter(|name| self.is_multi(&record_type, name)).cloned().collect();
+        for name in &to_move {
+            if let Some(value) = record.fields.remove(name) {
+                let values = record.multi_fields.entry(name.clone()).or_default();
+                if !values.contains(&value) {
+                    values.insert(0, value);
+                }
+            }
+        }
+        !to_move.is_empty()
+    }
+
+    /// Whether `name` is encrypted on records of `record_type`.
+    pub fn is_encrypted(&self, record_type: &str, name: &str) -> bool {
+        self.field(Some(record_type), name).is_some_and(|def| def.encrypted)
+    }
+
+    /// Whether any field, shared or of any type, is declared encrypted.
+    pub fn has_encrypted_fields(&self) -> bool {
+        self.fields.values().chain(self.types.values().flat_map(|ts| ts.fields.values())).any(|def| def.encrypted)
+    }
+
+    /// Whether any field is private or encrypted, so `redact` has anything to strip.
+    pub fn has_private_fields(&self) -> bool {
+        self.fields.values().chain(self.types.values().flat_map(|ts| ts.fields.values())).any(|def| def.private || def.encrypted)
+    }
+
+    /// Whether `name` is private or encrypted, shared or for any type. What goes to logs and
+    /// webhooks is redacted by it, since a command line doesn't say which type it touches.
+    pub fn is_private_anywhere(&self, name: &str) -> bool {
+        self.fields.get(name).into_iter().chain(self.types.values().filter_map(|ts| ts.fields.get(name))).any(|def| def.private || def.encrypted)
+    }
+
+    /// Strips the private and encrypted fields of `record`'s type, including from its revision
+    /// history.
+    pub fn redact(&self, record: &mut Record) {
+        let record_type = type_of(record).to_string();
+        let is_private = |name: &str| self.field(Some(&record_type), name).is_some_and(|def| def.private || def.encrypted);
+        record.fields.retain(|name, _| !is_private(name));
+        record.multi_fields.retain(|name, _| !is_private(name));
+        for revision in &mut record.history {
+            revision.changes.retain(|change| !is_private(&change.field));
+        }
+    }
+
+    /// The FIELDS listing: every declared or stored field, numbered in name order so a field
+    /// keeps its id whichever subset is asked for. With a type, only the fields that apply to it
+    /// (shared, declared for it, or stored on one of its records) are listed.
+    pub fn catalog(&self, records: &[Record], record_type: Option<&str>) -> Vec<(usize, String, FieldDef)> {
+        let mut stored: HashMap<String, BTreeSet<&str>> = HashMap::new();
+        for record in records {
+            let t = type_of(record);
+            for name in record.fields.keys().chain(record.multi_fields.keys()) {
+                stored.entry(t.to_lowercase()).or_default().insert(name.as_str());
+            }
+        }
+
+        let mut names: BTreeSet<&str> = self.fields.keys().map(String::as_str).collect();
+        names.extend(self.types.values().flat_map(|ts| ts.fields.keys().map(String::as_str)));
+        names.extend(stored.values().flatten().copied());
+
+        let applies = |name: &str| match record_type.map(str::to_lowercase) {
+            None => true,
+            Some(t) => {
+                self.field(Some(&t), name).is_some() || stored.get(&t).is_some_and(|names| names.contains(name))
+            }
+        };
+        names
+            .into_iter()
+            .enumerate()
+            .filter(|(_, name)| applies(name))
+            .map(|(i, name)| (i + 1, name.to_string(), self.describe(record_type, name)))
+            .collect()
+    }
+}
+
+/// The type a record's fields are checked against.
+pub fn type_of(record: &Record) -> &str {
+    record
+        .fields
+        .get("type")
+        .map(String::as_str)
+        .or_else(|| record.record_type.as_ref().map(|t| t.as_str()))
+        .unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owned(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn custom_schema() -> FieldSchema {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("field-schema.toml");
+        std::fs::write(&path, r#"
+            [fields.hostname]
+            description = "Host name"
+            max_len = 16
+
+            [types.machine]
+            strict = true
+            identity = ["uuid", "serial_number", "hostname", "mac_addr"]
+
+            [types.machine.fields.cpu_cores]
+            kind = "int"
+            required = true
+
+            [types.machine.fields.env]
+            kind = "enum"
+            values = ["prod", "staging"]
+
+            [types.machine.fields.root_password]
+            private = true
+        "#).unwrap();
+        FieldSchema::load(&path).unwrap()
+    }
+
+    #[test]
+    fn test_should_check_length_kind_and_strictness_per_type() {
+        let schema = custom_schema();
+        assert!(schema.check_values("machine", &owned(&[("hostname", "web01"), ("cpu_cores", "8"), ("env", "prod")])).is_ok());
+
+        for bad in [
+            owned(&[("hostname", "a-very-long-hostname")]),
+            owned(&[("cpu_cores", "eight")]),
+            owned(&[("env", "dev")]),
+            owned(&[("undeclared", "x")]),
+        ] {
+            assert!(matches!(schema.check_values("machine", &bad), Err(StorageError::InvalidArgument(_))), "{:?}", bad);
+        }
+        // Other types aren't strict, and server-written fields are always allowed.
+        assert!(schema.check_values("person", &owned(&[("undeclared", "x")])).is_ok());
+        assert!(schema.check_values("machine", &owned(&[("source", "mdb"), ("forwarded", "true")])).is_ok());
+        assert!(schema.check_values("person", &owned(&[("notes", &"x".repeat(257))])).is_err());
+    }
+
+    #[test]
+    fn test_should_require_declared_fields_and_redact_private_ones() {
+        let schema = custom_schema();
+        let mut record = Record {
+            fields: HashMap::from([("type".to_string(), "machine".to_string())]),
+            ..Default::default()
+        };
+        assert!(schema.check_required(&record).is_err());
+
+        record.fields.insert("cpu_cores".to_string(), "8".to_string());
+        record.fields.insert("root_password".to_string(), "hunter2".to_string());
+        assert!(schema.check_required(&record).is_ok());
+
+        schema.redact(&mut record);
+        assert!(!record.fields.contains_key("root_password"));
+        assert!(record.fields.contains_key("cpu_cores"));
+    }
+
+    #[test]
+    fn test_should_describe_and_redact_encrypted_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("field-schema.toml");
+        std::fs::write(&path, "[types.machine.fields.bmc_password]\nencrypted = true").unwrap();
+        let schema = FieldSchema::load(&path).unwrap();
+        assert!(schema.has_encrypted_fields());
+        assert!(!FieldSchema::default().has_encrypted_fields());
+        assert!(schema.is_encrypted("Machine", "bmc_password"));
+        assert!(!schema.is_encrypted("person", "bmc_password"));
+        assert_eq!(schema.field(Some("machine"), "bmc_password").unwrap().attributes(), "max 256 Public Encrypt");
+
+        let mut record = Record {
+            fields: HashMap::from([("type".to_string(), "machine".to_string()), ("bmc_password".to_string(), "calvin".to_string())]),
+            ..Default::default()
+        };
+        schema.redact(&mut record);
+        assert!(!record.fields.contains_key("bmc_password"));
+    }
+
+    #[test]
+    fn test_should_migrate_fields_newly_declared_multi_valued() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("field-schema.toml");
+        std::fs::write(&path, "[types.machine.fields.tags]\nmulti = true").unwrap();
+        let schema = FieldSchema::load(&path).unwrap();
+
+        let mut record = Record {
+            fields: HashMap::from([
+                ("type".to_string(), "machine".to_string()),
+                ("tags".to_string(), "web".to_string()),
+            ]),
+            multi_fields: HashMap::from([("tags".to_string(), vec!["db".to_string()])]),
+            ..Default::default()
+        };
+        assert!(schema.migrate_multi_values(&mut record));
+        assert!(!record.fields.contains_key("tags"));
+        assert_eq!(record.multi_fields["tags"], vec!["web", "db"]);
+        assert!(!schema.migrate_multi_values(&mut record), "already migrated");
+
+        let mut person = Record {
+            fields: HashMap::from([("type".to_string(), "person".to_string()), ("tags".to_string(), "x".to_string())]),
+            ..Default::default()
+        };
+        assert!(!schema.migrate_multi_values(&mut person), "tags is only multi-valued for machines");
+    }
+
+    #[test]
+    fn test_should_reject_inconsistent_schema_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("field-schema.toml");
+        for bad in [
+            "[fields.env]\nkind = \"enum\"",
+            "[fields.notes]\nvalues = [\"a\"]",
+            "[fields.notes]\nmax_len = 0",
+            "[fields.notes]\nunknown_key = 1",
+            "[types.machine]\nidentity = [\"uuid\", \"\"]",
+        ] {
+            std::fs::write(&path, bad).unwrap();
+            assert!(FieldSchema::load(&path).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_should_default_identity_keys_to_hostname_and_alias() {
+        let schema = custom_schema();
+        assert_eq!(schema.identity_keys("Machine"), vec!["uuid", "serial_number", "hostname", "mac_addr"]);
+        assert_eq!(schema.identity_keys("person"), vec!["hostname", "alias"]);
+        assert_eq!(schema.identity_keys(""), vec!["hostname", "alias"]);
+    }
+
+    #[test]
+    fn test_should_number_catalog_by_name_across_types() {
+        let schema = custom_schema();
+        let record = Record {
+            fields: HashMap::from([("type".to_string(), "person".to_string()), ("email".to_string(), "a@b".to_string())]),
+            ..Default::default()
+        };
+        let all = schema.catalog(std::slice::from_ref(&record), None);
+        let names: Vec<&str> = all.iter().map(|(_, name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["cpu_cores", "email", "env", "hostname", "root_password", "type"]);
+
+        let machine = schema.catalog(std::slice::from_ref(&record), Some("machine"));
+        let ids: Vec<(usize, &str)> = machine.iter().map(|(id, name, _)| (*id, name.as_str())).collect();
+        assert_eq!(ids, vec![(1, "cpu_cores"), (3, "env"), (4, "hostname"), (5, "root_password")]);
+        assert_eq!(machine[0].2.attributes(), "max 256 Public Required Integer");
+        assert_eq!(machine[3].2.attributes(), "max 256 Private");
+    }
+}
@@ -0,0 +1,299 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/sealing.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * iLO passwords and BMC credentials were kept out of Pharos because every
+ * value landed in FileStorage's data file in plaintext. Fields the schema
+ * declares `encrypted` are now sealed with AES-256-GCM under a server key
+ * whenever FileStorage writes them (data file, journal and named snapshots)
+ * and opened again on load, so they only exist in the clear in memory. Each
+ * sealed value carries the id of the key that sealed it, so a wrong key is
+ * reported as such instead of as corrupt data.
+ * * Traceability:
+ * Backs RFC 2378's Encrypt field attribute and `change ... force`.
+ * ======================================================================== */
+
+use crate::schema::{self, FieldSchema};
+use crate::storage::Record;
+use anyhow::{Context, anyhow, bail};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use ring::aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use ring::digest::{SHA256, digest};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::path::Path;
+
+/// Marks a sealed value: `sealed:v1:<key id>:<base64 of nonce, ciphertext and tag>`.
+const SEALED_PREFIX: &str = "sealed:v1:";
+
+/// Bytes of key material: AES-256.
+const KEY_LEN: usize = 32;
+
+/// A server key values are sealed with: encrypted fields, and whole files when FileStorage
+/// encrypts its files (`crate::file_cipher`).
+pub struct SealingKey {
+    key: LessSafeKey,
+    /// The first 4 bytes of the key's SHA-256, in hex: names the key without revealing it.
+    id: String,
+}
+
+impl std::fmt::Debug for SealingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SealingKey").field("id", &self.id).finish_non_exhaustive()
+    }
+}
+
+impl SealingKey {
+    /// A key given as 64 hex digits or as the base64 of 32 bytes (`openssl rand -base64 32`).
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let text = text.trim();
+        let bytes = match hex::decode(text) {
+            Ok(bytes) => bytes,
+            Err(_) => STANDARD.decode(text).map_err(|_| anyhow!("key is neither hex nor base64"))?,
+        };
+        if bytes.len() != KEY_LEN {
+            bail!("key must be {} bytes, got {}", KEY_LEN, bytes.len());
+        }
+        let key = UnboundKey::new(&AES_256_GCM, &bytes).map_err(|_| anyhow!("key is not a valid AES-256 key"))?;
+        Ok(Self { key: LessSafeKey::new(key), id: hex::encode(&digest(&SHA256, &bytes).as_ref()[..4]) })
+    }
+
+    /// The field key: `PHAROS_FIELD_KEY`, else the file named by `PHAROS_FIELD_KEY_FILE`.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        Self::from_env_vars("PHAROS_FIELD_KEY", "PHAROS_FIELD_KEY_FILE")
+    }
+
+    /// The key in env var `value_var`, else in the file named by `file_var`; `None` when neither
+    /// is set. A key that's set but unusable is an error, never silently ignored.
+    pub fn from_env_vars(value_var: &str, file_var: &str) -> anyhow::Result<Option<Self>> {
+        if let Ok(value) = std::env::var(value_var).map(|v| v.trim().to_string())
+            && !value.is_empty()
+        {
+            return Self::parse(&value).with_context(|| value_var.to_string()).map(Some);
+        }
+        match std::env::var(file_var) {
+            Ok(path) if !path.trim().is_empty() => Self::load(Path::new(path.trim())).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("failed to read key file {:?}", path))?;
+        Self::parse(&text).with_context(|| format!("key file {:?}", path))
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Seals `value` of field `field` (or, for a whole file, what the file holds). The field name
+    /// is authenticated with it, so a sealed value moved to another field no longer opens.
+    pub fn seal(&self, field: &str, value: &str) -> String {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).expect("system random number generator failed");
+        let mut sealed = value.as_bytes().to_vec();
+        self.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(field.as_bytes()), &mut sealed)
+            .expect("AES-GCM sealing failed");
+        let mut payload = nonce.to_vec();
+        payload.extend_from_slice(&sealed);
+        format!("{}{}:{}", SEALED_PREFIX, self.id, STANDARD.encode(payload))
+    }
+
+    /// Opens a value `seal` produced for `field`.
+    pub fn open(&self, field: &str, value: &str) -> anyhow::Result<String> {
+        let id = key_id_of(value).ok_or_else(|| anyhow!("value is not sealed, or has no key id"))?;
+        if id != self.id {
+            bail!("sealed with key {}, but the configured key is {}", id, self.id);
+        }
+        let payload = &value[SEALED_PREFIX.len() + id.len() + 1..];
+        let payload = STANDARD.decode(payload).map_err(|_| anyhow!("sealed value is not valid base64"))?;
+        if payload.len() < NONCE_LEN {
+            bail!("sealed value is truncated");
+        }
+        let (nonce, sealed) = payload.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| anyhow!("sealed value has a bad nonce"))?;
+        let mut sealed = sealed.to_vec();
+        let opened = self
+            .key
+            .open_in_place(nonce, Aad::from(field.as_bytes()), &mut sealed)
+            .map_err(|_| anyhow!("sealed value failed authentication"))?;
+        String::from_utf8(opened.to_vec()).map_err(|_| anyhow!("sealed value is not UTF-8"))
+    }
+}
+
+pub fn is_sealed(value: &str) -> bool {
+    value.starts_with(SEALED_PREFIX)
+}
+
+/// The id of the key that sealed `value`.
+pub fn key_id_of(value: &str) -> Option<&str> {
+    value.strip_prefix(SEALED_PREFIX)?.split_once(':').map(|(id, _)| id)
+}
+
+/// Whether `record` holds a sealed value in a field its type declares encrypted, or in that
+/// field's history. Anywhere else the prefix is only text.
+pub fn has_sealed_values(schema: &FieldSchema, record: &Record) -> bool {
+    let record_type = schema::type_of(record);
+    let encrypted = |name: &str| schema.is_encrypted(record_type, name);
+    record.fields.iter().any(|(name, v)| encrypted(name) && is_sealed(v))
+        || record.multi_fields.iter().any(|(name, values)| encrypted(name) && values.iter().any(|v| is_sealed(v)))
+        || record
+            .history
+            .iter()
+            .flat_map(|rev| &rev.changes)
+            .filter(|c| encrypted(&c.field))
+            .flat_map(|c| c.from.iter().chain(c.to.iter()))
+            .any(|v| is_sealed(v))
+}
+
+/// `record` as it is written to disk: the values of every field its type declares encrypted,
+/// including the old and new values in its history, sealed with `key`. Every such value is
+/// sealed, whatever it looks like - the write paths refuse the prefix, and the in-memory record
+/// only ever holds opened values.
+pub fn seal_record(schema: &FieldSchema, key: &SealingKey, record: &Record) -> Record {
+    let record_type = schema::type_of(record).to_string();
+    let encrypted = |name: &str| schema.is_encrypted(&record_type, name);
+    let seal = |name: &str, value: &mut String| *value = key.seal(name, value);
+
+    let mut sealed = record.clone();
+    for (name, value) in sealed.fields.iter_mut().filter(|(name, _)| encrypted(name)) {
+        seal(name, value);
+    }
+    for (name, values) in sealed.multi_fields.iter_mut().filter(|(name, _)| encrypted(name)) {
+        values.iter_mut().for_each(|value| seal(name, value));
+    }
+    for change in sealed.history.iter_mut().flat_map(|rev| rev.changes.iter_mut()).filter(|c| encrypted(&c.field)) {
+        for value in change.from.iter_mut().chain(change.to.iter_mut()) {
+            seal(&change.field, value);
+        }
+    }
+    sealed
+}
+
+/// Opens the sealed values of the fields `record`'s type declares encrypted, in place; a value
+/// elsewhere that merely starts like a sealed one is left as it is. Fails on the first value
+/// `key` can't open, naming the record and field.
+pub fn open_record(schema: &FieldSchema, key: &SealingKey, record: &mut Record) -> anyhow::Result<()> {
+    let id = record.id;
+    let record_type = schema::type_of(record).to_string();
+    let encrypted = |name: &str| schema.is_encrypted(&record_type, name);
+    let open = |name: &str, value: &mut String| -> anyhow::Result<()> {
+        if is_sealed(value) {
+            *value = key.open(name, value).with_context(|| format!("record #{} field '{}'", id, name))?;
+        }
+        Ok(())
+    };
+
+    for (name, value) in record.fields.iter_mut().filter(|(name, _)| encrypted(name)) {
+        open(name, value)?;
+    }
+    for (name, values) in record.multi_fields.iter_mut().filter(|(name, _)| encrypted(name)) {
+        for value in values.iter_mut() {
+            open(name, value)?;
+        }
+    }
+    for change in record.history.iter_mut().flat_map(|rev| rev.changes.iter_mut()).filter(|c| encrypted(&c.field)) {
+        for value in change.from.iter_mut().chain(change.to.iter_mut()) {
+            open(&change.field, value)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::{FieldChange, Revision};
+    use std::collections::HashMap;
+
+    const HEX_KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn schema() -> FieldSchema {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("field-schema.toml");
+        std::fs::write(&path, "[types.machine.fields.bmc_password]\nencrypted = true").unwrap();
+        FieldSchema::load(&path).unwrap()
+    }
+
+    #[test]
+    fn test_should_parse_hex_and_base64_keys_of_32_bytes() {
+        let hex_key = SealingKey::parse(HEX_KEY).unwrap();
+        let base64_key = SealingKey::parse(&STANDARD.encode(hex::decode(HEX_KEY).unwrap())).unwrap();
+        assert_eq!(hex_key.id(), base64_key.id());
+        assert!(SealingKey::parse("00ff").is_err());
+        assert!(SealingKey::parse("not a key!").is_err());
+    }
+
+    #[test]
+    fn test_should_only_open_with_the_same_key_and_field() {
+        let key = SealingKey::parse(HEX_KEY).unwrap();
+        let sealed = key.seal("bmc_password", "calvin");
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.contains("calvin"));
+        assert_ne!(sealed, key.seal("bmc_password", "calvin"), "every seal uses a fresh nonce");
+        assert_eq!(key.open("bmc_password", &sealed).unwrap(), "calvin");
+        assert!(key.open("ilo_password", &sealed).is_err());
+
+        let other = SealingKey::parse(&"ab".repeat(32)).unwrap();
+        let err = other.open("bmc_password", &sealed).unwrap_err().to_string();
+        assert!(err.contains(key.id()) && err.contains(other.id()), "{}", err);
+    }
+
+    #[test]
+    fn test_should_seal_encrypted_fields_and_their_history_only() {
+        let key = SealingKey::parse(HEX_KEY).unwrap();
+        let record = Record {
+            id: 7,
+            fields: HashMap::from([
+                ("type".to_string(), "machine".to_string()),
+                ("hostname".to_string(), "web01".to_string()),
+                ("bmc_password".to_string(), "calvin".to_string()),
+            ]),
+            history: vec![Revision {
+                revision: 2,
+                at: "2026-10-01T00:00:00+00:00".to_string(),
+                fingerprint: None,
+                team: None,
+                source: None,
+                changes: vec![FieldChange { field: "bmc_password".to_string(), from: Some("root".to_string()), to: Some("calvin".to_string()) }],
+            }],
+            ..Default::default()
+        };
+
+        let mut sealed = seal_record(&schema(), &key, &record);
+        assert_eq!(sealed.fields["hostname"], "web01");
+        assert!(is_sealed(&sealed.fields["bmc_password"]));
+        assert!(!serde_json::to_string(&sealed).unwrap().contains("calvin"));
+        assert!(has_sealed_values(&schema(), &sealed));
+
+        open_record(&schema(), &key, &mut sealed).unwrap();
+        assert_eq!(sealed.fields, record.fields);
+        assert_eq!(sealed.history, record.history);
+        assert!(!has_sealed_values(&schema(), &sealed));
+    }
+
+    #[test]
+    fn test_should_seal_a_value_that_looks_sealed_and_open_only_encrypted_fields() {
+        let key = SealingKey::parse(HEX_KEY).unwrap();
+        let lookalike = "sealed:v1:00000000:AAAA".to_string();
+        let record = Record {
+            id: 3,
+            fields: HashMap::from([
+                ("type".to_string(), "machine".to_string()),
+                ("notes".to_string(), lookalike.clone()),
+                ("bmc_password".to_string(), lookalike.clone()),
+            ]),
+            ..Default::default()
+        };
+
+        let mut sealed = seal_record(&schema(), &key, &record);
+        assert_eq!(sealed.fields["notes"], lookalike);
+        assert_eq!(key_id_of(&sealed.fields["bmc_password"]), Some(key.id()));
+
+        open_record(&schema(), &key, &mut sealed).unwrap();
+        assert_eq!(sealed.fields, record.fields);
+    }
+}
@@ -0,0 +1,186 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/snapshot.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Copying FileStorage's data file while the hub runs can catch the
+ * persistence worker mid-compaction, or miss whatever is still only in the
+ * journal. Named snapshots are written from the live record set instead,
+ * under its lock, so each one is a consistent point-in-time copy with ids,
+ * ownership, multi-valued fields, history and tombstones intact. This module
+ * owns the snapshot directory; FileStorage decides when to read and write.
+ * * Traceability:
+ * Backs the admin-only `snapshot create|list|restore` protocol command.
+ * ======================================================================== */
+
+use crate::file_cipher::{self, FileCipher};
+use crate::journal;
+use crate::storage::{Record, StorageError};
+use chrono::{DateTime, Utc};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// Longest accepted snapshot name, so a name always fits comfortably in a file name.
+const MAX_NAME_LEN: usize = 64;
+
+/// One snapshot in the snapshot directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub created_at: String,
+    pub size_bytes: u64,
+}
+
+/// Where snapshots of `data_path` live: `PHAROS_SNAPSHOT_DIR` if set, else a directory next to
+/// the data file (`data.json` snapshots into `data.snapshots/`).
+pub fn snapshot_dir(data_path: &Path) -> PathBuf {
+    match std::env::var("PHAROS_SNAPSHOT_DIR") {
+        Ok(dir) if !dir.trim().is_empty() => PathBuf::from(dir),
+        _ => data_path.with_extension("snapshots"),
+    }
+}
+
+/// Names become file names, so only letters, digits, `.`, `_` and `-` are allowed, and not a
+/// leading `.` (no hidden files, no `..`).
+pub fn validate_name(name: &str) -> Result<(), StorageError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if valid {
+        Ok(())
+    } else {
+        Err(StorageError::InvalidArgument(format!(
+            "snapshot name '{}' must be 1-{} letters, digits, '.', '_' or '-' and not start with '.'",
+            name, MAX_NAME_LEN
+        )))
+    }
+}
+
+fn snapshot_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.json", name))
+}
+
+fn io_error(context: &str, e: impl std::fmt::Display) -> StorageError {
+    StorageError::Internal(format!("{}: {}", context, e))
+}
+
+fn info_for(path: &Path, name: String) -> Result<SnapshotInfo, StorageError> {
+    let metadata = std::fs::metadata(path).map_err(|e| io_error("failed to stat snapshot", e))?;
+    let created_at = metadata
+        .modified()
+        .map(|t| DateTime::<Utc>::from(t).to_rfc3339())
+        .unwrap_or_default();
+    Ok(SnapshotInfo { name, created_at, size_bytes: metadata.len() })
+}
+
+/// Writes `records` and the next id as the snapshot `name`, laid out and sealed like the data
+/// file. Names are never reused: an existing snapshot is a backup someone may be counting on, so
+/// overwriting it is refused.
+pub fn write(dir: &Path, name: &str, records: &[Record], next_id: usize, cipher: &FileCipher) -> Result<SnapshotInfo, StorageError> {
+    validate_name(name)?;
+    std::fs::create_dir_all(dir).map_err(|e| io_error("failed to create snapshot directory", e))?;
+    let path = snapshot_path(dir, name);
+    if path.exists() {
+        return Err(StorageError::InvalidArgument(format!("snapshot '{}' already exists", name)));
+    }
+
+    let refs: Vec<&Record> = records.iter().collect();
+    journal::write_snapshot_atomic(&path, &refs, next_id, cipher).map_err(|e| io_error("failed to write snapshot", e))?;
+    info_for(&path, name.to_string())
+}
+
+/// Every snapshot in `dir`, oldest first. A missing directory means no snapshots yet.
+pub fn list(dir: &Path) -> Result<Vec<SnapshotInfo>, StorageError> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(io_error("failed to read snapshot directory", e)),
+    };
+
+    let mut snapshots = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(|e| io_error("failed to read snapshot directory", e))?.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_suffix(".json")) else {
+            continue;
+        };
+        if validate_name(name).is_ok() {
+            snapshots.push(info_for(&path, name.to_string())?);
+        }
+    }
+    snapshots.sort_by(|a, b| (&a.created_at, &a.name).cmp(&(&b.created_at, &b.name)));
+    Ok(snapshots)
+}
+
+/// Reads and checks snapshot `name`. Nothing is restored from a file that doesn't decrypt with
+/// `cipher`, doesn't parse or holds two records with the same id. Snapshots are never rewritten,
+/// so one sealed with a retired storage key only opens while that key is the previous key.
+pub fn read(dir: &Path, name: &str, cipher: &FileCipher) -> Result<Vec<Record>, StorageError> {
+    validate_name(name)?;
+    let path = snapshot_path(dir, name);
+    let data = match std::fs::read_to_string(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(StorageError::InvalidArgument(format!("no snapshot named '{}'", name)));
+        }
+        Err(e) => return Err(io_error("failed to read snapshot", e)),
+    };
+
+    let data = cipher
+        .open(file_cipher::RECORDS, &data)
+        .map_err(|e| StorageError::InvalidArgument(format!("snapshot '{}' does not decrypt: {:#}", name, e)))?;
+    let (records, _) = journal::parse_data_file(&data)
+        .map_err(|e| StorageError::InvalidArgument(format!("snapshot '{}' is not readable: {}", name, e)))?;
+    let mut ids = BTreeSet::new();
+    if let Some(duplicate) = records.iter().find(|r| !ids.insert(r.id)) {
+        return Err(StorageError::InvalidArgument(format!(
+            "snapshot '{}' holds record id {} more than once",
+            name, duplicate.id
+        )));
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_reject_names_that_are_not_plain_file_names() {
+        for name in ["nightly", "2026-03-01_pre-upgrade", "v1.2"] {
+            assert!(validate_name(name).is_ok(), "{} should be accepted", name);
+        }
+        for name in ["", "..", ".hidden", "a/b", "../etc", "sp ace", &"x".repeat(65)] {
+            assert!(validate_name(name).is_err(), "{} should be rejected", name);
+        }
+    }
+
+    #[test]
+    fn test_should_write_list_and_read_back_without_overwriting() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshots = dir.path().join("snapshots");
+        assert!(list(&snapshots).unwrap().is_empty());
+
+        let record = Record { id: 7, owner_team: Some("ops".to_string()), ..Default::default() };
+        let info = write(&snapshots, "nightly", std::slice::from_ref(&record), 8, &FileCipher::default()).unwrap();
+        assert_eq!(info.name, "nightly");
+        assert!(info.size_bytes > 0);
+        assert!(matches!(write(&snapshots, "nightly", &[], 1, &FileCipher::default()), Err(StorageError::InvalidArgument(_))));
+
+        assert_eq!(list(&snapshots).unwrap(), vec![info]);
+        let restored = read(&snapshots, "nightly", &FileCipher::default()).unwrap();
+        assert_eq!(restored[0].id, 7);
+        assert_eq!(restored[0].owner_team.as_deref(), Some("ops"));
+        assert!(matches!(read(&snapshots, "missing", &FileCipher::default()), Err(StorageError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_should_refuse_snapshot_with_duplicate_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let record = Record { id: 1, ..Default::default() };
+        write(dir.path(), "dup", &[record.clone(), record], 2, &FileCipher::default()).unwrap();
+        assert!(matches!(read(dir.path(), "dup", &FileCipher::default()), Err(StorageError::InvalidArgument(_))));
+    }
+}
@@ -0,0 +1,1258 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/sqlite.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * An embedded SQLite storage tier between FileStorage and LdapStorage. Every
+ * write is a single SQLite transaction touching only the affected rows, so
+ * it is crash-safe without ever rewriting the whole data set. Records are
+ * stored in normalized tables; a derived table of lowercased field words
+ * lets exact and prefix selections run as indexed SQL lookups. Everything
+ * that defines Ph semantics (word matching, ownership, upsert merging) is
+ * the shared logic in storage.rs, applied to the rows SQL narrowed down.
+ * * Traceability:
+ * Implements RFC 2378 Section 3 (query) and Section 4 (add/change/delete).
+ * ======================================================================== */
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use async_trait::async_trait;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Transaction};
+use tracing::{instrument, info, error};
+use chrono::{DateTime, Utc};
+use crate::compare;
+use crate::history::{self, Author, FieldChange, HistoryConfig, Revision};
+use crate::index;
+use crate::links;
+use crate::merge;
+use crate::schema::{self, FieldSchema};
+use crate::storage::{self, BatchError, BatchOp, BatchOutcome, Record, RecordGuard, RecordType, Storage, StorageError, UpsertOutcome};
+use crate::tombstone;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS records (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        record_type TEXT,
+        owner_fingerprint TEXT,
+        owner_team TEXT,
+        deleted_at TEXT,
+        revision INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX IF NOT EXISTS records_by_deleted_at ON records(deleted_at);
+    CREATE TABLE IF NOT EXISTS fields (
+        record_id INTEGER NOT NULL REFERENCES records(id) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (record_id, name)
+    );
+    CREATE INDEX IF NOT EXISTS fields_by_name_value ON fields(name, value);
+    CREATE TABLE IF NOT EXISTS multi_fields (
+        record_id INTEGER NOT NULL REFERENCES records(id) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (record_id, name, position)
+    );
+    CREATE TABLE IF NOT EXISTS field_words (
+        record_id INTEGER NOT NULL REFERENCES records(id) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        word TEXT NOT NULL,
+        PRIMARY KEY (record_id, name, word)
+    );
+    CREATE INDEX IF NOT EXISTS field_words_by_name_word ON field_words(name, word);
+    CREATE INDEX IF NOT EXISTS field_words_by_word ON field_words(word);
+    CREATE TABLE IF NOT EXISTS revisions (
+        record_id INTEGER NOT NULL REFERENCES records(id) ON DELETE CASCADE,
+        revision INTEGER NOT NULL,
+        at TEXT NOT NULL,
+        fingerprint TEXT,
+        team TEXT,
+        source TEXT,
+        changes TEXT NOT NULL,
+        PRIMARY KEY (record_id, revision)
+    );
+";
+
+fn internal(e: rusqlite::Error) -> StorageError {
+    StorageError::Internal(format!("sqlite: {}", e))
+}
+
+/// One query word that SQL can answer from `field_words`.
+enum WordFilter {
+    Exact(String),
+    /// `literal*` - every word starting with `literal`.
+    Prefix(String),
+}
+
+impl WordFilter {
+    /// Only exact words and trailing-`*` prefixes are pushed down; any other pattern is left
+    /// to the shared wildcard matcher.
+    fn parse(word: &str) -> Option<Self> {
+        if !index::is_wildcard_word(word) {
+            return Some(WordFilter::Exact(word.to_string()));
+        }
+        let literal = word.strip_suffix('*')?;
+        if literal.is_empty() || index::is_wildcard_word(literal) {
+            return None;
+        }
+        Some(WordFilter::Prefix(literal.to_string()))
+    }
+}
+
+/// The smallest string greater than every string starting with `prefix`, so a prefix scan is
+/// an indexable `word >= prefix AND word < bound` range.
+fn prefix_upper_bound(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        if let Some(next) = char::from_u32(last as u32 + 1) {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+    history: HistoryConfig,
+    schema: Arc<FieldSchema>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> Result<Self, StorageError> {
+        Self::open_with_field_schema(path, FieldSchema::default())
+    }
+
+    /// Opens the database checking writes against `schema`. Records holding single values of
+    /// fields it declares multi-valued are migrated first.
+    #[instrument(skip(schema))]
+    pub fn open_with_field_schema(path: &Path, schema: FieldSchema) -> Result<Self, StorageError> {
+        let mut conn = Connection::open(path).map_err(internal)?;
+        conn.pragma_update(None, "journal_mode", "WAL").map_err(internal)?;
+        conn.pragma_update(None, "foreign_keys", true).map_err(internal)?;
+        conn.execute_batch(SCHEMA).map_err(internal)?;
+        let migrated = Self::migrate_multi_values(&mut conn, &schema)?;
+        if migrated > 0 {
+            info!("Migrated {} records to the multi-valued fields of the field schema", migrated);
+        }
+
+        info!("Opened SQLite storage at {:?} ({} records)", path, Self::count(&conn)?);
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            history: HistoryConfig::from_env(),
+            schema: Arc::new(schema),
+        })
+    }
+
+    /// Moves single values of fields `schema` declares multi-valued into `multi_fields`, for
+    /// records written before the field was declared `multi`.
+    fn migrate_multi_values(conn: &mut Connection, schema: &FieldSchema) -> Result<usize, StorageError> {
+        let names: Vec<&str> = schema.multi_valued_names().into_iter().collect();
+        if names.is_empty() {
+            return Ok(0);
+        }
+
+        let tx = conn.transaction().map_err(internal)?;
+        let placeholders = vec!["?"; names.len()].join(", ");
+        let ids = tx
+            .prepare(&format!("SELECT DISTINCT record_id FROM fields WHERE name IN ({})", placeholders))
+            .map_err(internal)?
+            .query_map(params_from_iter(names.iter()), |row| row.get::<_, i64>(0))
+            .map_err(internal)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(internal)?;
+
+        let mut migrated = 0;
+        for id in ids {
+            if let Some(mut record) = Self::read_record(&tx, id)?
+                && schema.migrate_multi_values(&mut record)
+            {
+                Self::write_record(&tx, &record)?;
+                migrated += 1;
+            }
+        }
+        tx.commit().map_err(internal)?;
+        Ok(migrated)
+    }
+
+    fn lock(conn: &Mutex<Connection>) -> Result<MutexGuard<'_, Connection>, StorageError> {
+        conn.lock()
+            .map_err(|_| StorageError::Internal("sqlite connection lock poisoned".to_string()))
+    }
+
+    /// Runs `f` against the connection on tokio's blocking pool, so SQLite's disk I/O never
+    /// stalls a runtime worker.
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, StorageError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, StorageError> + Send + 'static,
+    {
+        let conn = Arc::clone(&self.conn);
+        tokio::task::spawn_blocking(move || f(&mut *Self::lock(&conn)?))
+            .await
+            .map_err(|e| StorageError::Internal(format!("sqlite task failed: {}", e)))?
+    }
+
+    fn count(conn: &Connection) -> Result<usize, StorageError> {
+        conn.query_row("SELECT COUNT(*) FROM records WHERE deleted_at IS NULL", [], |row| row.get::<_, i64>(0))
+            .map(|n| n as usize)
+            .map_err(internal)
+    }
+
+    /// Ids of every live record (or, with `tombstones`, every deleted one) that can possibly
+    /// match, in id order. Each pushable word becomes an `IN (...)` lookup against
+    /// `field_words`; the rows still go through the full matcher.
+    fn candidate_ids(conn: &Connection, selections: &[(Option<String>, String)], tombstones: bool) -> Result<Vec<i64>, StorageError> {
+        let mut sql = String::from(if tombstones {
+            "SELECT id FROM records WHERE deleted_at IS NOT NULL"
+        } else {
+            "SELECT id FROM records WHERE deleted_at IS NULL"
+        });
+        let mut args: Vec<String> = Vec::new();
+
+        for (field_opt, value) in selections {
+            // Comparisons aren't word matches; they are checked on the records read back.
+            if compare::is_comparison(field_opt.as_deref()) {
+                continue;
+            }
+            let lower = value.to_lowercase();
+            for filter in lower.split_whitespace().filter_map(WordFilter::parse) {
+                sql.push_str(" AND id IN (SELECT record_id FROM field_words WHERE ");
+                if let Some(field) = field_opt {
+                    sql.push_str("name = ? AND ");
+                    args.push(field.clone());
+                }
+                match filter {
+                    WordFilter::Exact(word) => {
+                        sql.push_str("word = ?)");
+                        args.push(word);
+                    }
+                    WordFilter::Prefix(literal) => {
+                        sql.push_str("word >= ?");
+                        if let Some(bound) = prefix_upper_bound(&literal) {
+                            sql.push_str(" AND word < ?");
+                            args.push(literal);
+                            args.push(bound);
+                        } else {
+                            args.push(literal);
+                        }
+                        sql.push(')');
+                    }
+                }
+            }
+        }
+        sql.push_str(" ORDER BY id");
+
+        let mut stmt = conn.prepare(&sql).map_err(internal)?;
+        let ids = stmt
+            .query_map(params_from_iter(args.iter()), |row| row.get::<_, i64>(0))
+            .map_err(internal)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(internal)?;
+        Ok(ids)
+    }
+
+    fn read_record(conn: &Connection, id: i64) -> Result<Option<Record>, StorageError> {
+        let header = conn
+            .prepare_cached("SELECT record_type, owner_fingerprint, owner_team, deleted_at, revision FROM records WHERE id = ?1")
+            .map_err(internal)?
+            .query_row(params![id], |row| {
+                Ok((
+                    row.get::<_, Option<String>>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, i64>(4)?,
+                ))
+            })
+            .optional()
+            .map_err(internal)?;
+        let Some((record_type, owner_fingerprint, owner_team, deleted_at, revision)) = header else {
+            return Ok(None);
+        };
+
+        let mut fields = HashMap::new();
+        let mut stmt = conn
+            .prepare_cached("SELECT name, value FROM fields WHERE record_id = ?1")
+            .map_err(internal)?;
+        let rows = stmt
+            .query_map(params![id], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+            .map_err(internal)?;
+        for row in rows {
+            let (name, value) = row.map_err(internal)?;
+            fields.insert(name, value);
+        }
+
+        let mut multi_fields: HashMap<String, Vec<String>> = HashMap::new();
+        let mut stmt = conn
+            .prepare_cached("SELECT name, value FROM multi_fields WHERE record_id = ?1 ORDER BY name, position")
+            .map_err(internal)?;
+        let rows = stmt
+            .query_map(params![id], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+            .map_err(internal)?;
+        for row in rows {
+            let (name, value) = row.map_err(internal)?;
+            multi_fields.entry(name).or_default().push(value);
+        }
+
+        Ok(Some(Record {
+            id: id as usize,
+            record_type: record_type.as_deref().map(RecordType::from),
+            fields,
+            multi_fields,
+            owner_fingerprint,
+            owner_team,
+            history: Self::read_history(conn, id)?,
+            deleted_at,
+            revision: revision as u64,
+        }))
+    }
+
+    fn read_history(conn: &Connection, id: i64) -> Result<Vec<Revision>, StorageError> {
+        let mut stmt = conn
+            .prepare_cached("SELECT revision, at, fingerprint, team, source, changes FROM revisions WHERE record_id = ?1 ORDER BY revision")
+            .map_err(internal)?;
+        let rows = stmt
+            .query_map(params![id], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                    row.get::<_, String>(5)?,
+                ))
+            })
+            .map_err(internal)?;
+
+        let mut history = Vec::new();
+        for row in rows {
+            let (revision, at, fingerprint, team, source, changes) = row.map_err(internal)?;
+            let changes: Vec<FieldChange> = serde_json::from_str(&changes)
+                .map_err(|e| StorageError::Internal(format!("sqlite: corrupt revision {} of record {}: {}", revision, id, e)))?;
+            history.push(Revision { revision: revision as u64, at, fingerprint, team, source, changes });
+        }
+        Ok(history)
+    }
+
+    /// Brings the stored revisions in line with the record's log: revisions trimmed from the
+    /// front are deleted, new ones appended. Revisions are immutable, so existing rows stay.
+    fn write_history(tx: &Transaction, record: &Record) -> Result<(), StorageError> {
+        let id = record.id as i64;
+        let oldest_kept = record.history.first().map_or(i64::MAX, |r| r.revision as i64);
+        tx.execute("DELETE FROM revisions WHERE record_id = ?1 AND revision < ?2", params![id, oldest_kept])
+            .map_err(internal)?;
+
+        let mut insert = tx
+            .prepare_cached(
+                "INSERT OR IGNORE INTO revisions (record_id, revision, at, fingerprint, team, source, changes)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )
+            .map_err(internal)?;
+        for revision in &record.history {
+            let changes = serde_json::to_string(&revision.changes)
+                .map_err(|e| StorageError::Internal(format!("sqlite: {}", e)))?;
+            insert
+                .execute(params![id, revision.revision as i64, revision.at, revision.fingerprint, revision.team, revision.source, changes])
+                .map_err(internal)?;
+        }
+        Ok(())
+    }
+
+    fn matching_records(conn: &Connection, schema: &FieldSchema, selections: &[(Option<String>, String)], tombstones: bool) -> Result<Vec<Record>, StorageError> {
+        let mut records = Vec::new();
+        for id in Self::candidate_ids(conn, selections, tombstones)? {
+            if let Some(record) = Self::read_record(conn, id)?
+                && storage::record_matches_selections(&record, selections, schema)?
+            {
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+
+    /// Replaces the stored rows of an existing record (or creates them for a new id).
+    fn write_record(tx: &Transaction, record: &Record) -> Result<(), StorageError> {
+        let id = record.id as i64;
+        tx.execute(
+            "INSERT INTO records (id, record_type, owner_fingerprint, owner_team, deleted_at, revision) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(id) DO UPDATE SET record_type = excluded.record_type,
+                 owner_fingerprint = excluded.owner_fingerprint, owner_team = excluded.owner_team,
+                 deleted_at = excluded.deleted_at, revision = excluded.revision",
+            params![id, record.record_type.as_ref().map(RecordType::as_str), record.owner_fingerprint, record.owner_team, record.deleted_at, record.revision as i64],
+        )
+        .map_err(internal)?;
+        Self::write_fields(tx, record)?;
+        Self::write_history(tx, record)
+    }
+
+    fn write_fields(tx: &Transaction, record: &Record) -> Result<(), StorageError> {
+        let id = record.id as i64;
+        for table in ["fields", "multi_fields", "field_words"] {
+            tx.execute(&format!("DELETE FROM {} WHERE record_id = ?1", table), params![id])
+                .map_err(internal)?;
+        }
+
+        let mut words: BTreeSet<(String, String)> = BTreeSet::new();
+        let mut collect_words = |name: &str, value: &str| {
+            let lower = value.to_lowercase();
+            for word in index::field_words(&lower).filter(|w| !w.is_empty()) {
+                words.insert((name.to_string(), word.to_string()));
+            }
+        };
+
+        let mut insert_field = tx
+            .prepare_cached("INSERT INTO fields (record_id, name, value) VALUES (?1, ?2, ?3)")
+            .map_err(internal)?;
+        for (name, value) in &record.fields {
+            insert_field.execute(params![id, name, value]).map_err(internal)?;
+            collect_words(name, value);
+        }
+
+        let mut insert_multi = tx
+            .prepare_cached("INSERT INTO multi_fields (record_id, name, position, value) VALUES (?1, ?2, ?3, ?4)")
+            .map_err(internal)?;
+        for (name, values) in &record.multi_fields {
+            for (position, value) in values.iter().enumerate() {
+                insert_multi.execute(params![id, name, position as i64, value]).map_err(internal)?;
+                collect_words(name, value);
+            }
+        }
+
+        let mut insert_word = tx
+            .prepare_cached("INSERT INTO field_words (record_id, name, word) VALUES (?1, ?2, ?3)")
+            .map_err(internal)?;
+        for (name, word) in &words {
+            insert_word.execute(params![id, name, word]).map_err(internal)?;
+        }
+        Ok(())
+    }
+
+    fn insert_new(tx: &Transaction, mut record: Record) -> Result<(), StorageError> {
+        tx.execute(
+            "INSERT INTO records (record_type, owner_fingerprint, owner_team, revision) VALUES (?1, ?2, ?3, ?4)",
+            params![record.record_type.as_ref().map(RecordType::as_str), record.owner_fingerprint, record.owner_team, record.revision as i64],
+        )
+        .map_err(internal)?;
+        record.id = tx.last_insert_rowid() as usize;
+        Self::write_fields(tx, &record)?;
+        Self::write_history(tx, &record)
+    }
+
+    /// Upsert identity: the first live record whose hostname or alias equals `id_val` exactly.
+    fn find_identity_match(conn: &Connection, id_val: &str) -> Result<Option<i64>, StorageError> {
+        conn.query_row(
+            "SELECT f.record_id FROM fields f JOIN records r ON r.id = f.record_id
+             WHERE f.name IN ('hostname', 'alias') AND f.value = ?1 AND r.deleted_at IS NULL
+             ORDER BY f.record_id LIMIT 1",
+            params![id_val],
+            |row| row.get::<_, i64>(0),
+        )
+        .optional()
+        .map_err(internal)
+    }
+
+    /// The live record an upsert of `fields` updates, if its identity values name one.
+    fn resolve_upsert(conn: &Connection, schema: &FieldSchema, fields: &[(String, String)]) -> Result<Option<Record>, StorageError> {
+        let mut stmt = conn
+            .prepare_cached(
+                "SELECT record_id FROM fields WHERE name = ?1 AND value = ?2 COLLATE NOCASE
+                 UNION SELECT record_id FROM multi_fields WHERE name = ?1 AND value = ?2 COLLATE NOCASE
+                 ORDER BY record_id",
+            )
+            .map_err(internal)?;
+        let mut found = Vec::new();
+        for (key, value) in storage::upsert_identity(schema, fields) {
+            let names: &[&str] = match key {
+                "hostname" | "alias" => &["hostname", "alias"],
+                _ => &[key],
+            };
+            let mut ids = BTreeSet::new();
+            for name in names {
+                let rows = stmt.query_map(params![name, value], |row| row.get::<_, i64>(0)).map_err(internal)?;
+                for row in rows {
+                    ids.insert(row.map_err(internal)?);
+                }
+            }
+            for id in ids {
+                if let Some(record) = Self::read_record(conn, id)?.filter(|r| r.deleted_at.is_none() && storage::holds_identity(schema, r, key, value)) {
+                    found.push((key, value, id, links::label(&record)));
+                }
+            }
+        }
+        match storage::resolve_identity(&found)? {
+            Some(id) => Self::read_record(conn, id),
+            None => Ok(None),
+        }
+    }
+
+    /// Checks the links a write sets on a record of `record_type` against the live records.
+    fn check_links(conn: &Connection, schema: &FieldSchema, record_type: &str, fields: &[(String, String)]) -> Result<(), StorageError> {
+        links::check_links(schema, record_type, fields, |identity| {
+            let target = match Self::find_identity_match(conn, identity)? {
+                Some(id) => Self::read_record(conn, id)?,
+                None => None,
+            };
+            Ok(target.map(|r| schema::type_of(&r).to_string()))
+        })
+    }
+
+    /// Drops the links live records hold to `deleted` (or points them at `replacement`),
+    /// logging each record it touches as a revision by the deleting author.
+    fn unlink_deleted(tx: &Transaction, schema: &FieldSchema, history_limit: usize, deleted: &[Record], author: &Author, replacement: Option<&str>) -> Result<(), StorageError> {
+        let identities: BTreeSet<String> = deleted.iter().flat_map(|r| links::identities(r).map(str::to_string)).collect();
+        let mut ids = BTreeSet::new();
+        let mut stmt = tx
+            .prepare_cached(
+                "SELECT record_id FROM fields WHERE name = ?1 AND value = ?2
+                 UNION SELECT record_id FROM multi_fields WHERE name = ?1 AND value = ?2",
+            )
+            .map_err(internal)?;
+        for name in schema.link_names() {
+            for identity in &identities {
+                let rows = stmt.query_map(params![name, identity], |row| row.get::<_, i64>(0)).map_err(internal)?;
+                for row in rows {
+                    ids.insert(row.map_err(internal)?);
+                }
+            }
+        }
+
+        for id in ids {
+            let Some(mut record) = Self::read_record(tx, id)?.filter(|r| r.deleted_at.is_none()) else {
+                continue;
+            };
+            let before = record.clone();
+            if links::unlink(schema, &mut record, &identities, replacement) {
+                history::record_revision(Some(&before), &mut record, author, history_limit);
+                Self::write_record(tx, &record)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Matching live records, failing the whole operation if any of them isn't the caller's to
+    /// modify.
+    fn authorized_matches(conn: &Connection, schema: &FieldSchema, selections: &[(Option<String>, String)], fingerprint: Option<&String>, teams: &[String]) -> Result<Vec<Record>, StorageError> {
+        let records = Self::matching_records(conn, schema, selections, false)?;
+        if records.iter().any(|r| !storage::is_authorized(r, fingerprint, teams)) {
+            return Err(StorageError::Unauthorized);
+        }
+        Ok(records)
+    }
+
+    fn upsert_in(tx: &Transaction, schema: &FieldSchema, history_limit: usize, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<UpsertOutcome, StorageError> {
+        let existing = Self::resolve_upsert(tx, schema, &fields)?;
+
+        let author = storage::upsert_author(&fields, fingerprint.as_ref(), team.as_ref());
+        match existing {
+            Some(mut record) => {
+                for (k, v) in &fields {
+                    storage::validate_ip_mac_field(k, v)?;
+                }
+                storage::check_upsert(&record, &fields, fingerprint.as_ref(), team.as_ref(), schema)?;
+                Self::check_links(tx, schema, schema::type_of(&record), &fields)?;
+                let before = record.clone();
+                storage::apply_upsert(&mut record, fields, fingerprint, team, schema);
+                history::record_revision(Some(&before), &mut record, &author, history_limit);
+                Self::write_record(tx, &record)?;
+                Ok(UpsertOutcome::Updated)
+            }
+            None => {
+                Self::check_links(tx, schema, storage::declared_type(&fields), &fields)?;
+                let mut record = storage::new_record(0, fields, fingerprint, team, schema)?;
+                history::record_revision(None, &mut record, &author, history_limit);
+                Self::insert_new(tx, record)?;
+                Ok(UpsertOutcome::Created)
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn merge_in(tx: &Transaction, schema: &FieldSchema, history_limit: usize, from: &[(Option<String>, String)], into: &[(Option<String>, String)], precedence: &[String], fingerprint: Option<String>, teams: &[String], source: Option<String>) -> Result<Option<Record>, StorageError> {
+        let lose = storage::only_match(Self::authorized_matches(tx, schema, from, fingerprint.as_ref(), teams)?)?;
+        let keep = storage::only_match(Self::authorized_matches(tx, schema, into, fingerprint.as_ref(), teams)?)?;
+        let (Some(mut lose), Some(keep)) = (lose, keep) else {
+            return Ok(None);
+        };
+        let mut merged = merge::combine(&keep, &lose, precedence)?;
+        storage::check_merge(schema, &lose, &keep, &merged)?;
+        let author = storage::change_author(fingerprint.as_ref(), teams, source);
+
+        tombstone::mark_deleted(&mut lose, Utc::now().to_rfc3339(), &author, history_limit);
+        Self::write_record(tx, &lose)?;
+        history::record_revision(Some(&keep), &mut merged, &author, history_limit);
+        Self::write_record(tx, &merged)?;
+        let replacement = links::identities(&merged).next().map(str::to_string);
+        Self::unlink_deleted(tx, schema, history_limit, &[lose], &author, replacement.as_deref())?;
+        Ok(Some(merged))
+    }
+
+    fn delete_in(tx: &Transaction, schema: &FieldSchema, history_limit: usize, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String], still: &dyn Fn(&Record) -> bool) -> Result<usize, StorageError> {
+        let author = storage::change_author(fingerprint.as_ref(), teams, None);
+        let records = Self::authorized_matches(tx, schema, selections, fingerprint.as_ref(), teams)?;
+        if !records.iter().all(still) {
+            return Ok(0);
+        }
+        let now = Utc::now().to_rfc3339();
+        for record in &records {
+            let mut record = record.clone();
+            tombstone::mark_deleted(&mut record, now.clone(), &author, history_limit);
+            Self::write_record(tx, &record)?;
+        }
+        Self::unlink_deleted(tx, schema, history_limit, &records, &author, None)?;
+        Ok(records.len())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn change_in(tx: &Transaction, schema: &FieldSchema, history_limit: usize, selections: &[(Option<String>, String)], modifications: &[(String, String)], fingerprint: Option<String>, teams: &[String], source: Option<String>, force: bool, expected_revision: Option<u64>) -> Result<usize, StorageError> {
+        storage::validate_modifications(modifications)?;
+        let author = storage::change_author(fingerprint.as_ref(), teams, source);
+        let records = Self::authorized_matches(tx, schema, selections, fingerprint.as_ref(), teams)?;
+        storage::check_revision(&records, expected_revision)?;
+        storage::check_change(schema, &records, modifications, force)?;
+        let written = storage::written_values(modifications);
+        for record in &records {
+            Self::check_links(tx, schema, schema::type_of(record), &written)?;
+        }
+        for before in &records {
+            let mut record = before.clone();
+            storage::apply_modifications(&mut record, modifications, schema);
+            history::record_revision(Some(before), &mut record, &author, history_limit);
+            Self::write_record(tx, &record)?;
+        }
+        Ok(records.len())
+    }
+
+    fn apply_op(tx: &Transaction, schema: &FieldSchema, history_limit: usize, op: BatchOp) -> Result<BatchOutcome, StorageError> {
+        match op {
+            BatchOp::Upsert { fields, fingerprint, team } => {
+                Self::upsert_in(tx, schema, history_limit, fields, fingerprint, team).map(BatchOutcome::Upserted)
+            }
+            BatchOp::Change { selections, modifications, fingerprint, teams, source, force, limits, if_revision } => {
+                if !limits.is_unlimited() {
+                    limits.check(&Self::matching_records(tx, schema, &selections, false)?, &modifications)?;
+                }
+                Self::change_in(tx, schema, history_limit, &selections, &modifications, fingerprint, &teams, source, force, if_revision).map(BatchOutcome::Changed)
+            }
+            BatchOp::Delete { selections, fingerprint, teams, limits } => {
+                if !limits.is_unlimited() {
+                    limits.check(&Self::matching_records(tx, schema, &selections, false)?, &[])?;
+                }
+                Self::delete_in(tx, schema, history_limit, &selections, fingerprint, &teams, &|_| true).map(BatchOutcome::Deleted)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    #[instrument(skip(self))]
+    async fn record_count(&self) -> usize {
+        match self.with_conn(|conn| Self::count(conn)).await {
+            Ok(n) => n,
+            Err(e) => {
+                error!("Failed to count records: {}", e);
+                0
+            }
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn add_record(&self, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<(), StorageError> {
+        let author = storage::upsert_author(&fields, fingerprint.as_ref(), team.as_ref());
+        let written = fields.clone();
+        let mut record = storage::new_record(0, fields, fingerprint, team, &self.schema)?;
+        history::record_revision(None, &mut record, &author, self.history.limit);
+        let schema = Arc::clone(&self.schema);
+        self.with_conn(move |conn| {
+            let tx = conn.transaction().map_err(internal)?;
+            Self::check_links(&tx, &schema, schema::type_of(&record), &written)?;
+            Self::insert_new(&tx, record)?;
+            tx.commit().map_err(internal)
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn query(&self, selections: &[(Option<String>, String)], default_type: Option<RecordType>) -> Result<Vec<Record>, StorageError> {
+        let selections = selections.to_vec();
+        let schema = Arc::clone(&self.schema);
+        self.with_conn(move |conn| {
+            let records = Self::matching_records(conn, &schema, &selections, false)?;
+            Ok(records
+                .into_iter()
+                .filter(|r| storage::passes_type_filter(r, &selections, default_type.as_ref()))
+                .collect())
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn upsert_record(&self, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<UpsertOutcome, StorageError> {
+        let history_limit = self.history.limit;
+        let schema = Arc::clone(&self.schema);
+        self.with_conn(move |conn| {
+            let tx = conn.transaction().map_err(internal)?;
+            let outcome = Self::upsert_in(&tx, &schema, history_limit, fields, fingerprint, team)?;
+            tx.commit().map_err(internal)?;
+            Ok(outcome)
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn delete_record(&self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String]) -> Result<usize, StorageError> {
+        let history_limit = self.history.limit;
+        let schema = Arc::clone(&self.schema);
+        let (selections, teams) = (selections.to_vec(), teams.to_vec());
+        self.with_conn(move |conn| {
+            let tx = conn.transaction().map_err(internal)?;
+            let count = Self::delete_in(&tx, &schema, history_limit, &selections, fingerprint, &teams, &|_| true)?;
+            tx.commit().map_err(internal)?;
+            Ok(count)
+        })
+        .await
+    }
+
+    #[instrument(skip(self, still))]
+    async fn delete_record_if(&self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String], still: RecordGuard) -> Result<usize, StorageError> {
+        let history_limit = self.history.limit;
+        let schema = Arc::clone(&self.schema);
+        let (selections, teams) = (selections.to_vec(), teams.to_vec());
+        self.with_conn(move |conn| {
+            let tx = conn.transaction().map_err(internal)?;
+            let count = Self::delete_in(&tx, &schema, history_limit, &selections, fingerprint, &teams, &*still)?;
+            tx.commit().map_err(internal)?;
+            Ok(count)
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn change_record(&self, selections: &[(Option<String>, String)], modifications: &[(String, String)], fingerprint: Option<String>, teams: &[String], source: Option<String>, force: bool) -> Result<usize, StorageError> {
+        let history_limit = self.history.limit;
+        let schema = Arc::clone(&self.schema);
+        let (selections, modifications, teams) = (selections.to_vec(), modifications.to_vec(), teams.to_vec());
+        self.with_conn(move |conn| {
+            let tx = conn.transaction().map_err(internal)?;
+            let count = Self::change_in(&tx, &schema, history_limit, &selections, &modifications, fingerprint, &teams, source, force, None)?;
+            tx.commit().map_err(internal)?;
+            Ok(count)
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    #[allow(clippy::too_many_arguments)]
+    async fn change_record_if_revision(&self, selections: &[(Option<String>, String)], modifications: &[(String, String)], fingerprint: Option<String>, teams: &[String], source: Option<String>, force: bool, revision: u64) -> Result<usize, StorageError> {
+        let history_limit = self.history.limit;
+        let schema = Arc::clone(&self.schema);
+        let (selections, modifications, teams) = (selections.to_vec(), modifications.to_vec(), teams.to_vec());
+        self.with_conn(move |conn| {
+            let tx = conn.transaction().map_err(internal)?;
+            let count = Self::change_in(&tx, &schema, history_limit, &selections, &modifications, fingerprint, &teams, source, force, Some(revision))?;
+            tx.commit().map_err(internal)?;
+            Ok(count)
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn merge_records(&self, from: &[(Option<String>, String)], into: &[(Option<String>, String)], precedence: &[String], fingerprint: Option<String>, teams: &[String], source: Option<String>) -> Result<Option<Record>, StorageError> {
+        let history_limit = self.history.limit;
+        let schema = Arc::clone(&self.schema);
+        let (from, into, precedence, teams) = (from.to_vec(), into.to_vec(), precedence.to_vec(), teams.to_vec());
+        self.with_conn(move |conn| {
+            let tx = conn.transaction().map_err(internal)?;
+            let survivor = Self::merge_in(&tx, &schema, history_limit, &from, &into, &precedence, fingerprint, &teams, source)?;
+            tx.commit().map_err(internal)?;
+            Ok(survivor)
+        })
+        .await
+    }
+
+    /// Runs the whole transaction inside one SQLite transaction, committed only if every op
+    /// succeeded.
+    #[instrument(skip(self))]
+    async fn apply_batch(&self, ops: &[BatchOp]) -> Result<Vec<BatchOutcome>, BatchError> {
+        let history_limit = self.history.limit;
+        let schema = Arc::clone(&self.schema);
+        let ops = ops.to_vec();
+        let whole = |error| BatchError { index: 0, error };
+        self.with_conn(move |conn| {
+            let tx = conn.transaction().map_err(internal)?;
+            let mut outcomes = Vec::with_capacity(ops.len());
+            for (index, op) in ops.into_iter().enumerate() {
+                match Self::apply_op(&tx, &schema, history_limit, op) {
+                    Ok(outcome) => outcomes.push(outcome),
+                    Err(error) => return Ok(Err(BatchError { index, error })),
+                }
+            }
+            tx.commit().map_err(internal)?;
+            Ok(Ok(outcomes))
+        })
+        .await
+        .map_err(whole)?
+    }
+
+    /// Runs in one SQLite transaction; deleting the records rows cascades to everything else.
+    #[instrument(skip(self, records))]
+    async fn replace_records(&self, records: Vec<Record>) -> Result<usize, StorageError> {
+        self.with_conn(move |conn| {
+            let tx = conn.transaction().map_err(internal)?;
+            tx.execute("DELETE FROM records", []).map_err(internal)?;
+            for record in &records {
+                Self::write_record(&tx, record)?;
+            }
+            tx.commit().map_err(internal)?;
+            Ok(records.iter().filter(|r| r.deleted_at.is_none()).count())
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn tombstones(&self, selections: &[(Option<String>, String)]) -> Result<Vec<Record>, StorageError> {
+        let selections = selections.to_vec();
+        let schema = Arc::clone(&self.schema);
+        self.with_conn(move |conn| Self::matching_records(conn, &schema, &selections, true)).await
+    }
+
+    #[instrument(skip(self))]
+    async fn undelete_record(&self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String], admin: bool) -> Result<usize, StorageError> {
+        let author = storage::change_author(fingerprint.as_ref(), teams, None);
+        let history_limit = self.history.limit;
+        let schema = Arc::clone(&self.schema);
+        let (selections, teams) = (selections.to_vec(), teams.to_vec());
+        self.with_conn(move |conn| {
+            let tx = conn.transaction().map_err(internal)?;
+            let records = Self::matching_records(&tx, &schema, &selections, true)?;
+            let mut restored_identities = BTreeSet::new();
+            for record in &records {
+                storage::check_undelete(record, fingerprint.as_ref(), &teams, admin)?;
+                for identity in ["hostname", "alias"].iter().filter_map(|k| record.fields.get(*k)) {
+                    if Self::find_identity_match(&tx, identity)?.is_some() || !restored_identities.insert(identity.clone()) {
+                        return Err(storage::undelete_conflict(identity));
+                    }
+                }
+            }
+            for record in &records {
+                let mut record = record.clone();
+                tombstone::mark_restored(&mut record, &author, history_limit);
+                Self::write_record(&tx, &record)?;
+            }
+            tx.commit().map_err(internal)?;
+            Ok(records.len())
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn purge_tombstones(&self, cutoff: DateTime<Utc>) -> Result<usize, StorageError> {
+        self.with_conn(move |conn| {
+            let tx = conn.transaction().map_err(internal)?;
+            let stamps = tx
+                .prepare("SELECT id, deleted_at FROM records WHERE deleted_at IS NOT NULL")
+                .map_err(internal)?
+                .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))
+                .map_err(internal)?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(internal)?;
+            let mut purged = 0;
+            for (id, deleted_at) in stamps {
+                if tombstone::deleted_before(&deleted_at, cutoff) {
+                    tx.execute("DELETE FROM records WHERE id = ?1", params![id]).map_err(internal)?;
+                    purged += 1;
+                }
+            }
+            tx.commit().map_err(internal)?;
+            Ok(purged)
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn import_tombstone(&self, identity: &str, deleted_at: &str) -> Result<bool, StorageError> {
+        let history_limit = self.history.limit;
+        let (identity, deleted_at) = (identity.to_string(), deleted_at.to_string());
+        self.with_conn(move |conn| {
+            let tx = conn.transaction().map_err(internal)?;
+            let record = match Self::find_identity_match(&tx, &identity)? {
+                Some(id) => Self::read_record(&tx, id)?,
+                None => None,
+            };
+            let Some(mut record) = record.filter(|r| tombstone::is_superseded_by(r, &deleted_at)) else {
+                return Ok(false);
+            };
+            tombstone::mark_deleted(&mut record, deleted_at, &Author::default(), history_limit);
+            Self::write_record(&tx, &record)?;
+            tx.commit().map_err(internal)?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn field_schema(&self) -> Arc<FieldSchema> {
+        Arc::clone(&self.schema)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::WriteLimits;
+
+    fn open_temp() -> (tempfile::TempDir, SqliteStorage) {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = SqliteStorage::open(&dir.path().join("pharos.db")).unwrap();
+        (dir, storage)
+    }
+
+    fn machine(hostname: &str) -> Vec<(String, String)> {
+        vec![
+            ("type".to_string(), "machine".to_string()),
+            ("hostname".to_string(), hostname.to_string()),
+        ]
+    }
+
+    fn select(field: &str, value: &str) -> Vec<(Option<String>, String)> {
+        vec![(Some(field.to_string()), value.to_string())]
+    }
+
+    #[tokio::test]
+    async fn test_should_persist_records_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pharos.db");
+        {
+            let storage = SqliteStorage::open(&path).unwrap();
+            let mut fields = machine("srv-persist");
+            fields.push(("ip_addr".to_string(), "10.0.0.1".to_string()));
+            fields.push(("ip_addr".to_string(), "10.0.0.2".to_string()));
+            storage.add_record(fields, Some("fp1".to_string()), Some("ops".to_string())).await.unwrap();
+        }
+
+        let storage = SqliteStorage::open(&path).unwrap();
+        assert_eq!(storage.record_count().await, 1);
+        let records = storage.query(&select("hostname", "srv-persist"), Some(RecordType::Machine)).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].multi_fields.get("ip_addr").unwrap(), &vec!["10.0.0.1".to_string(), "10.0.0.2".to_string()]);
+        assert_eq!(records[0].owner_fingerprint.as_deref(), Some("fp1"));
+        assert_eq!(records[0].owner_team.as_deref(), Some("ops"));
+        assert!(records[0].fields.contains_key("created_at"));
+    }
+
+    #[tokio::test]
+    async fn test_should_migrate_fields_newly_declared_multi_valued_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pharos.db");
+        {
+            let storage = SqliteStorage::open(&path).unwrap();
+            let mut fields = machine("srv-tags");
+            fields.push(("tags".to_string(), "prod".to_string()));
+            storage.add_record(fields, None, None).await.unwrap();
+        }
+
+        let schema_path = dir.path().join("field-schema.toml");
+        std::fs::write(&schema_path, "[types.machine.fields.tags]\nmulti = true").unwrap();
+        let storage = SqliteStorage::open_with_field_schema(&path, FieldSchema::load(&schema_path).unwrap()).unwrap();
+        storage.change_record(&select("hostname", "srv-tags"), &[("tags".to_string(), "web".to_string())], None, &[], None, false).await.unwrap();
+
+        let records = storage.query(&select("tags", "web"), None).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(!records[0].fields.contains_key("tags"));
+        assert_eq!(records[0].multi_fields["tags"], vec!["prod", "web"]);
+    }
+
+    #[tokio::test]
+    async fn test_should_push_down_exact_and_prefix_selections() {
+        let (_dir, storage) = open_temp();
+        storage.add_record(machine("web-01"), None, None).await.unwrap();
+        storage.add_record(machine("web-02"), None, None).await.unwrap();
+        storage.add_record(machine("db-01"), None, None).await.unwrap();
+
+        {
+            let conn = SqliteStorage::lock(&storage.conn).unwrap();
+            assert_eq!(SqliteStorage::candidate_ids(&conn, &select("hostname", "WEB-01"), false).unwrap().len(), 1);
+            assert_eq!(SqliteStorage::candidate_ids(&conn, &select("hostname", "web*"), false).unwrap().len(), 2);
+            assert_eq!(SqliteStorage::candidate_ids(&conn, &[(None, "db-01".to_string())], false).unwrap().len(), 1);
+            // Not pushable: every record is a candidate and the matcher decides.
+            assert_eq!(SqliteStorage::candidate_ids(&conn, &select("hostname", "*-01"), false).unwrap().len(), 3);
+        }
+
+        assert_eq!(storage.query(&select("hostname", "*-01"), None).await.unwrap().len(), 2);
+        assert_eq!(storage.query(&select("hostname", "web-0?"), None).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_should_check_comparisons_after_push_down() {
+        let (_dir, storage) = open_temp();
+        for (hostname, cores) in [("web-01", "4"), ("web-02", "16"), ("db-01", "32")] {
+            let mut fields = machine(hostname);
+            fields.push(("cpu_cores".to_string(), cores.to_string()));
+            storage.add_record(fields, None, None).await.unwrap();
+        }
+
+        let selections = vec![
+            (Some("hostname".to_string()), "web*".to_string()),
+            (Some("cpu_cores>=".to_string()), "8".to_string()),
+        ];
+        let records = storage.query(&selections, None).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].fields["hostname"], "web-02");
+    }
+
+    #[tokio::test]
+    async fn test_should_keep_revisions_across_reopen_and_check_them_on_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pharos.db");
+        let storage = SqliteStorage::open(&path).unwrap();
+        storage.add_record(machine("web-01"), None, None).await.unwrap();
+        let status = vec![("status".to_string(), "online".to_string())];
+        storage.change_record_if_revision(&select("hostname", "web-01"), &status, None, &[], None, false, 1).await.unwrap();
+        drop(storage);
+
+        let storage = SqliteStorage::open(&path).unwrap();
+        assert_eq!(storage.query(&select("hostname", "web-01"), None).await.unwrap()[0].revision, 2);
+        let result = storage.change_record_if_revision(&select("hostname", "web-01"), &status, None, &[], None, false, 1).await;
+        assert!(matches!(result, Err(StorageError::RevisionMismatch { expected: 1, current: 2 })));
+    }
+
+    #[tokio::test]
+    async fn test_should_check_links_and_unlink_deleted_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let schema_path = dir.path().join("field-schema.toml");
+        std::fs::write(&schema_path, "[types.machine.fields.depends_on]\nkind = \"link\"\nmulti = true").unwrap();
+        let storage = SqliteStorage::open_with_field_schema(&dir.path().join("pharos.db"), FieldSchema::load(&schema_path).unwrap()).unwrap();
+        let depends_on = |hostname: &str| vec![("depends_on".to_string(), hostname.to_string())];
+
+        storage.add_record(machine("db-01"), None, None).await.unwrap();
+        storage.add_record(machine("db-02"), None, None).await.unwrap();
+        storage.add_record(machine("api-01"), None, None).await.unwrap();
+        for hostname in ["db-01", "db-02"] {
+            storage.change_record(&select("hostname", "api-01"), &depends_on(hostname), None, &[], None, false).await.unwrap();
+        }
+        let result = storage.change_record(&select("hostname", "api-01"), &depends_on("db-03"), None, &[], None, false).await;
+        assert!(matches!(result, Err(StorageError::InvalidArgument(_))));
+
+        storage.delete_record(&select("hostname", "db-01"), None, &[]).await.unwrap();
+        let api = &storage.query(&select("hostname", "api-01"), None).await.unwrap()[0];
+        assert_eq!(api.multi_fields["depends_on"], vec!["db-02"]);
+        assert_eq!(api.revision, 4);
+    }
+
+    #[tokio::test]
+    async fn test_should_merge_records_in_one_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let schema_path = dir.path().join("field-schema.toml");
+        std::fs::write(&schema_path, "[types.machine.fields.depends_on]\nkind = \"link\"\nmulti = true").unwrap();
+        let storage = SqliteStorage::open_with_field_schema(&dir.path().join("pharos.db"), FieldSchema::load(&schema_path).unwrap()).unwrap();
+        let precedence = crate::merge::MergeConfig::default().precedence;
+
+        let mut scanned = machine("web-01-scan");
+        scanned.extend([("ip_addr".to_string(), "10.0.0.5".to_string()), ("os".to_string(), "linux".to_string()), ("source".to_string(), "pharos-scan".to_string())]);
+        storage.add_record(scanned, None, None).await.unwrap();
+        let mut enrolled = machine("web-01");
+        enrolled.extend([("os".to_string(), "debian13".to_string()), ("source".to_string(), "mdb".to_string())]);
+        storage.add_record(enrolled, None, None).await.unwrap();
+        let mut api = machine("api-01");
+        api.push(("depends_on".to_string(), "web-01-scan".to_string()));
+        storage.add_record(api, None, None).await.unwrap();
+
+        let survivor = storage.merge_records(&select("hostname", "web-01"), &select("hostname", "web-01-scan"), &precedence, None, &[], None).await.unwrap().unwrap();
+        assert_eq!(survivor.fields["os"], "debian13", "mdb outranks the scanner");
+        assert_eq!(survivor.fields["hostname"], "web-01-scan", "the record merged into keeps its name");
+        assert_eq!(survivor.fields["ip_addr"], "10.0.0.5");
+        assert_eq!(storage.record_count().await, 2);
+        let api = &storage.query(&select("hostname", "api-01"), None).await.unwrap()[0];
+        assert_eq!(api.multi_fields["depends_on"], vec!["web-01-scan"], "already pointed at the survivor");
+
+        let result = storage.merge_records(&select("hostname", "api-01"), &select("hostname", "nowhere"), &precedence, None, &[], None).await;
+        assert!(result.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_should_resolve_upsert_by_configured_identity_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let schema_path = dir.path().join("field-schema.toml");
+        std::fs::write(&schema_path, "[types.machine]\nidentity = [\"serial_number\", \"hostname\", \"mac_addr\"]").unwrap();
+        let storage = SqliteStorage::open_with_field_schema(&dir.path().join("pharos.db"), FieldSchema::load(&schema_path).unwrap()).unwrap();
+        let with = |hostname: &str, extra: &[(&str, &str)]| {
+            let mut fields = machine(hostname);
+            fields.extend(extra.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+            fields
+        };
+
+        storage.upsert_record(with("web-01", &[("serial_number", "SN-1"), ("mac_addr", "AA:BB:CC:00:00:01")]), None, None).await.unwrap();
+        assert_eq!(storage.upsert_record(with("web-renamed", &[("serial_number", "SN-1")]), None, None).await.unwrap(), UpsertOutcome::Updated);
+        assert_eq!(storage.upsert_record(with("web-renamed", &[("mac_addr", "aa:bb:cc:00:00:01")]), None, None).await.unwrap(), UpsertOutcome::Updated);
+        assert_eq!(storage.record_count().await, 1);
+
+        storage.add_record(with("db-01", &[("mac_addr", "AA:BB:CC:00:00:02")]), None, None).await.unwrap();
+        let result = storage.upsert_record(with("web-renamed", &[("mac_addr", "AA:BB:CC:00:00:02")]), None, None).await;
+        assert!(matches!(result, Err(StorageError::IdentityConflict(_))), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn test_should_roll_back_a_failed_batch() {
+        let (_dir, storage) = open_temp();
+        storage.add_record(machine("web-01"), None, None).await.unwrap();
+        let delete = BatchOp::Delete { selections: select("hostname", "web-01"), fingerprint: None, teams: Vec::new(), limits: WriteLimits::default() };
+        let bad_change = BatchOp::Change {
+            selections: select("hostname", "web-02"),
+            modifications: vec![("ip_addr".to_string(), "not-an-ip".to_string())],
+            fingerprint: None,
+            teams: Vec::new(),
+            source: None,
+            force: false,
+            limits: WriteLimits::default(),
+            if_revision: None,
+        };
+        let add = BatchOp::Upsert { fields: machine("web-02"), fingerprint: None, team: None };
+
+        let error = storage.apply_batch(&[delete.clone(), add.clone(), bad_change]).await.unwrap_err();
+        assert_eq!(error.index, 2);
+        assert!(matches!(error.error, StorageError::InvalidArgument(_)));
+        assert_eq!(storage.query(&select("hostname", "web-01"), None).await.unwrap().len(), 1);
+        assert!(storage.query(&select("hostname", "web-02"), None).await.unwrap().is_empty());
+
+        let outcomes = storage.apply_batch(&[delete, add]).await.unwrap();
+        assert_eq!(outcomes, vec![BatchOutcome::Deleted(1), BatchOutcome::Upserted(UpsertOutcome::Created)]);
+        assert!(storage.query(&select("hostname", "web-01"), None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_should_preserve_upsert_semantics() {
+        let (_dir, storage) = open_temp();
+        let mut fields = machine("srv-up");
+        fields.push(("source".to_string(), "pulse".to_string()));
+        assert_eq!(storage.upsert_record(fields, Some("fp1".to_string()), None).await.unwrap(), UpsertOutcome::Created);
+
+        let mut update = machine("srv-up");
+        update.push(("source".to_string(), "manual".to_string()));
+        update.push(("os".to_string(), "linux".to_string()));
+        assert_eq!(storage.upsert_record(update, Some("fp1".to_string()), None).await.unwrap(), UpsertOutcome::Updated);
+
+        let records = storage.query(&select("hostname", "srv-up"), None).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].fields.get("source").unwrap(), "pulse");
+        assert_eq!(records[0].fields.get("os").unwrap(), "linux");
+
+        let result = storage.upsert_record(machine("srv-up"), Some("fp2".to_string()), None).await;
+        assert!(matches!(result, Err(StorageError::Collision)));
+
+        let retyped = vec![
+            ("type".to_string(), "person".to_string()),
+            ("hostname".to_string(), "srv-up".to_string()),
+        ];
+        let result = storage.upsert_record(retyped, Some("fp1".to_string()), None).await;
+        assert!(matches!(result, Err(StorageError::InvalidArgument(_))));
+    }
+
+    #[tokio::test]
+    async fn test_should_enforce_team_authorization_on_change_and_delete() {
+        let (_dir, storage) = open_temp();
+        storage.add_record(machine("srv-team"), Some("fp1".to_string()), Some("ops".to_string())).await.unwrap();
+
+        let selection = select("hostname", "srv-team");
+        let modifications = [("os".to_string(), "bsd".to_string())];
+        let result = storage.change_record(&selection, &modifications, Some("fp2".to_string()), &["dev".to_string()], None, false).await;
+        assert!(matches!(result, Err(StorageError::Unauthorized)));
+        let result = storage.delete_record(&selection, Some("fp2".to_string()), &["dev".to_string()]).await;
+        assert!(matches!(result, Err(StorageError::Unauthorized)));
+
+        assert_eq!(storage.change_record(&selection, &modifications, None, &["ops".to_string()], None, false).await.unwrap(), 1);
+        assert_eq!(storage.query(&select("os", "bsd"), None).await.unwrap().len(), 1);
+
+        assert_eq!(storage.delete_record(&selection, Some("fp1".to_string()), &[]).await.unwrap(), 1);
+        assert_eq!(storage.record_count().await, 0);
+        assert!(storage.query(&[(None, "srv-team".to_string())], None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_should_reject_type_change_and_bad_addresses() {
+        let (_dir, storage) = open_temp();
+        storage.add_record(machine("srv-val"), None, None).await.unwrap();
+
+        let selection = select("hostname", "srv-val");
+        let result = storage.change_record(&selection, &[("type".to_string(), "person".to_string())], None, &[], None, false).await;
+        assert!(matches!(result, Err(StorageError::InvalidArgument(_))));
+        let result = storage.change_record(&selection, &[("ip_addr".to_string(), "not-an-ip".to_string())], None, &[], None, false).await;
+        assert!(matches!(result, Err(StorageError::InvalidArgument(_))));
+        let result = storage.add_record(vec![("hostname".to_string(), "untyped".to_string())], None, None).await;
+        assert!(matches!(result, Err(StorageError::InvalidArgument(_))));
+    }
+
+    #[tokio::test]
+    async fn test_should_store_and_trim_revision_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pharos.db");
+        {
+            let mut storage = SqliteStorage::open(&path).unwrap();
+            storage.history = HistoryConfig { limit: 2 };
+            storage.add_record(machine("web-01"), Some("SHA256:a".to_string()), None).await.unwrap();
+            for status in ["up", "down"] {
+                storage
+                    .change_record(&select("hostname", "web-01"), &[("status".to_string(), status.to_string())], Some("SHA256:a".to_string()), &[], Some("mdb".to_string()), false)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let storage = SqliteStorage::open(&path).unwrap();
+        let history = storage.query(&select("hostname", "web-01"), None).await.unwrap().remove(0).history;
+        let numbers: Vec<u64> = history.iter().map(|r| r.revision).collect();
+        assert_eq!(numbers, vec![2, 3]);
+        assert_eq!(history[1].changes[0].summary(), "up -> down");
+        assert_eq!(history[1].source.as_deref(), Some("mdb"));
+        let stored: i64 = SqliteStorage::lock(&storage.conn).unwrap()
+            .query_row("SELECT COUNT(*) FROM revisions", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, 2);
+    }
+
+    #[tokio::test]
+    async fn test_should_soft_delete_undelete_and_purge() {
+        let (_dir, storage) = open_temp();
+        let owner = Some("SHA256:a".to_string());
+        storage.add_record(machine("web-01"), owner.clone(), None).await.unwrap();
+        storage.add_record(machine("web-02"), None, None).await.unwrap();
+
+        assert_eq!(storage.delete_record(&select("hostname", "web-01"), owner.clone(), &[]).await.unwrap(), 1);
+        assert!(storage.query(&select("hostname", "web-01"), None).await.unwrap().is_empty());
+        assert_eq!(storage.record_count().await, 1);
+        assert_eq!(storage.tombstones(&[]).await.unwrap().len(), 1);
+
+        // The tombstone no longer claims its hostname: an upsert creates a new record...
+        let outcome = storage.upsert_record(machine("web-01"), None, None).await.unwrap();
+        assert_eq!(outcome, UpsertOutcome::Created);
+        // ...which then blocks restoring the old one.
+        assert!(matches!(
+            storage.undelete_record(&select("hostname", "web-01"), owner.clone(), &[], false).await,
+            Err(StorageError::InvalidArgument(_))
+        ));
+        storage.delete_record(&select("hostname", "web-01"), None, &[]).await.unwrap();
+        assert!(matches!(
+            storage.undelete_record(&select("hostname", "web-01"), Some("SHA256:b".to_string()), &[], false).await,
+            Err(StorageError::Unauthorized)
+        ));
+
+        assert_eq!(storage.purge_tombstones(Utc::now() - chrono::Duration::days(1)).await.unwrap(), 0);
+        assert_eq!(storage.purge_tombstones(Utc::now() + chrono::Duration::seconds(1)).await.unwrap(), 2);
+        assert!(storage.tombstones(&[]).await.unwrap().is_empty());
+        assert_eq!(storage.record_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_should_delete_only_while_the_guard_still_holds() {
+        let (_dir, storage) = open_temp();
+        storage.add_record(machine("web-01"), None, None).await.unwrap();
+        let never: RecordGuard = Arc::new(|_: &Record| false);
+        assert_eq!(storage.delete_record_if(&select("hostname", "web-01"), None, &[], never).await.unwrap(), 0);
+        assert_eq!(storage.record_count().await, 1);
+        let unchanged: RecordGuard = Arc::new(|r: &Record| r.revision == 1);
+        assert_eq!(storage.delete_record_if(&select("hostname", "web-01"), None, &[], unchanged).await.unwrap(), 1);
+        assert_eq!(storage.tombstones(&[]).await.unwrap().len(), 1);
+    }
+}
@@ -12,14 +12,26 @@
  * Implements RFC 2378 Section 1.1 and Section 3.
  * ======================================================================== */
 
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use serde::{Serialize, Deserialize};
-use tracing::{instrument, info, error, debug};
-use chrono::Utc;
-use tokio::sync::mpsc;
+use tracing::{instrument, info, error};
+use chrono::{DateTime, Utc};
+use async_trait::async_trait;
+use tokio::sync::{mpsc, oneshot, RwLock};
+use crate::compare;
+use crate::file_cipher::{self, FileCipher};
+use crate::history::{self, Author, HistoryConfig, Revision};
+use crate::index::{self, RecordIndex};
+use crate::journal::{self, JournalConfig, JournalEntry, JournalWriter};
+use crate::links;
+use crate::merge;
+use crate::recovery::{self, CorruptPolicy, LostRecord};
+use crate::schema::{self, FieldSchema, ValueKind};
+use crate::sealing::{self, SealingKey};
+use crate::snapshot::{self, SnapshotInfo};
+use crate::tombstone;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum RecordType {
@@ -57,14 +69,106 @@ pub struct Record {
     pub multi_fields: HashMap<String, Vec<String>>,
     pub owner_fingerprint: Option<String>,
     pub owner_team: Option<String>,
+    /// Bounded revision log, oldest first (see history.rs).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<Revision>,
+    /// Set when the record was deleted; a tombstone is hidden from every query (see tombstone.rs).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<String>,
+    /// Counts up with every write that changes a tracked field (see history.rs), so a client can
+    /// make its write conditional on the revision it read.
+    #[serde(default)]
+    pub revision: u64,
 }
 
+/// The pseudo-field query output reports a record's revision under. Clients can't write it.
+pub const REVISION_FIELD: &str = "revision";
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum UpsertOutcome {
     Created,
     Updated,
 }
 
+/// The session's `set limit` and `set addonly` options, checked against the records a change or
+/// delete matched before it runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteLimits {
+    pub limit: Option<usize>,
+    pub addonly: bool,
+}
+
+impl WriteLimits {
+    /// With neither option set there is nothing to check, so callers can skip the matching scan.
+    pub fn is_unlimited(&self) -> bool {
+        self.limit.is_none() && !self.addonly
+    }
+
+    /// `limit` caps how many records one write may touch. `addonly` refuses modifications that
+    /// would override what a matched record already holds: setting over a single value overrides
+    /// it and appending to a list doesn't; unsetting, removing or replacing anything present does.
+    pub fn check(&self, matched: &[Record], modifications: &[(String, String)]) -> Result<(), StorageError> {
+        if self.limit.is_some_and(|limit| matched.len() > limit) {
+            return Err(StorageError::TooManyEntries(matched.len()));
+        }
+        if self.addonly {
+            let overridden = matched.iter().any(|record| {
+                modifications.iter().any(|(key, value)| match parse_modification(key, value) {
+                    (field, Edit::Set(_)) => record.fields.contains_key(field),
+                    (field, _) => record.fields.contains_key(field) || record.multi_fields.contains_key(field),
+                })
+            });
+            if overridden {
+                return Err(StorageError::AddOnlyViolation);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One write staged by a transaction (`begin` ... `commit`), with the identity and session
+/// limits it was staged under.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Upsert {
+        fields: Vec<(String, String)>,
+        fingerprint: Option<String>,
+        team: Option<String>,
+    },
+    Change {
+        selections: Vec<(Option<String>, String)>,
+        modifications: Vec<(String, String)>,
+        fingerprint: Option<String>,
+        teams: Vec<String>,
+        source: Option<String>,
+        force: bool,
+        limits: WriteLimits,
+        if_revision: Option<u64>,
+    },
+    Delete {
+        selections: Vec<(Option<String>, String)>,
+        fingerprint: Option<String>,
+        teams: Vec<String>,
+        limits: WriteLimits,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchOutcome {
+    Upserted(UpsertOutcome),
+    Changed(usize),
+    Deleted(usize),
+}
+
+/// Why a batch was refused: the first op that failed, by position, and its error. Nothing in
+/// the batch was applied.
+#[derive(Debug, thiserror::Error)]
+#[error("command {} of the transaction failed: {}", .index + 1, .error)]
+pub struct BatchError {
+    pub index: usize,
+    pub error: StorageError,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum StorageError {
     #[error("Record already exists and is bonded to a different fingerprint (Collision)")]
@@ -81,160 +185,317 @@ pub enum StorageError {
     AddOnlyViolation,
     #[error("Operation failed because database is read-only")]
     ReadOnly,
+    #[error("Storage backend unavailable: {0}")]
+    Unavailable(String),
+    #[error("Not supported by this storage backend: {0}")]
+    Unsupported(String),
+    #[error("Record changed since revision {expected} (now at revision {current})")]
+    RevisionMismatch { expected: u64, current: u64 },
+    #[error("Identity conflict: {0}")]
+    IdentityConflict(String),
+    #[error("Field '{0}' is encrypted; overwrite it with 'change ... force'")]
+    Encrypted(String),
 }
 
+/// A condition a conditional write re-checks against each record it would touch, under the
+/// backend's own lock or transaction.
+pub type RecordGuard = Arc<dyn Fn(&Record) -> bool + Send + Sync>;
+
+/// A storage tier, shared across connections as `Arc<dyn Storage>`. Every method takes `&self`:
+/// each backend owns its concurrency (a lock around the record set, a connection pool, ...),
+/// so I/O in one backend never holds a server-wide lock.
+#[async_trait]
 pub trait Storage: Send + Sync {
-    fn record_count(&self) -> usize;
-    fn add_record(&mut self, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<(), StorageError>;
-    fn query(&self, selections: &[(Option<String>, String)], default_type: Option<RecordType>) -> Result<Vec<Record>, StorageError>;
-    fn upsert_record(&mut self, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<UpsertOutcome, StorageError>;
-    fn delete_record(&mut self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String]) -> Result<usize, StorageError>;
+    async fn record_count(&self) -> usize;
+    async fn add_record(&self, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<(), StorageError>;
+    async fn query(&self, selections: &[(Option<String>, String)], default_type: Option<RecordType>) -> Result<Vec<Record>, StorageError>;
+    async fn upsert_record(&self, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<UpsertOutcome, StorageError>;
+    async fn delete_record(&self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String]) -> Result<usize, StorageError>;
+    /// `delete_record`, applied only if `still` holds for every matched record at the moment of
+    /// the delete; otherwise nothing is deleted and the count is 0. A caller that decided to
+    /// delete from an earlier read (the expiry reaper) acts on the record as it is now. Backends
+    /// that don't override this check first and delete after, so a write can land in between.
+    async fn delete_record_if(&self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String], still: RecordGuard) -> Result<usize, StorageError> {
+        if !self.query(selections, None).await?.iter().all(|r| still(r)) {
+            return Ok(0);
+        }
+        self.delete_record(selections, fingerprint, teams).await
+    }
     /// Purpose (The "Why"): Modifies matching and authorized records' fields in-place.
     /// This matches selections, authorizes modifications using fingerprint/team checks,
-    /// and applies field modifications.
-    fn change_record(&mut self, selections: &[(Option<String>, String)], modifications: &[(String, String)], fingerprint: Option<String>, teams: &[String]) -> Result<usize, StorageError>;
+    /// and applies field modifications. `source` is the client the change came from, kept
+    /// in each record's revision history; `force` lets it touch fields the schema encrypts.
+    async fn change_record(&self, selections: &[(Option<String>, String)], modifications: &[(String, String)], fingerprint: Option<String>, teams: &[String], source: Option<String>, force: bool) -> Result<usize, StorageError>;
+    /// `change_record`, applied only if every matched record is still at `revision`; otherwise
+    /// nothing is written and the error reports the revision it has moved on to.
+    #[allow(clippy::too_many_arguments)]
+    async fn change_record_if_revision(&self, _selections: &[(Option<String>, String)], _modifications: &[(String, String)], _fingerprint: Option<String>, _teams: &[String], _source: Option<String>, _force: bool, _revision: u64) -> Result<usize, StorageError> {
+        Err(StorageError::Unsupported("conditional changes need memory, file or SQLite storage".to_string()))
+    }
+    /// Deleted records (tombstones) the selections match; every tombstone for no selections.
+    async fn tombstones(&self, selections: &[(Option<String>, String)]) -> Result<Vec<Record>, StorageError>;
+    /// Restores matching tombstones. Only their owners may, unless `admin` is set.
+    async fn undelete_record(&self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String], admin: bool) -> Result<usize, StorageError>;
+    /// Permanently removes tombstones deleted before `cutoff`, returning how many went.
+    async fn purge_tombstones(&self, cutoff: DateTime<Utc>) -> Result<usize, StorageError>;
+    /// Applies a deletion a peer made at `deleted_at` to the live record with that hostname or
+    /// alias, unless the record has been seen since. Returns whether a record was tombstoned.
+    async fn import_tombstone(&self, identity: &str, deleted_at: &str) -> Result<bool, StorageError>;
+    /// The field schema this backend checks writes against (and FIELDS describes).
+    async fn field_schema(&self) -> Arc<FieldSchema>;
+    /// Writes a point-in-time copy of every record, tombstones included, as snapshot `name`.
+    /// Only FileStorage keeps snapshots; the other backends have their own backup tooling.
+    async fn create_snapshot(&self, _name: &str) -> Result<SnapshotInfo, StorageError> {
+        Err(snapshots_unsupported())
+    }
+    async fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>, StorageError> {
+        Err(snapshots_unsupported())
+    }
+    /// Replaces the whole record set with snapshot `name`, returning the number of live records.
+    async fn restore_snapshot(&self, _name: &str) -> Result<usize, StorageError> {
+        Err(snapshots_unsupported())
+    }
+    /// Folds the one live record `from` selects into the one `into` selects (see
+    /// `merge::combine`) and deletes it; links to it are pointed at the survivor. The caller
+    /// must be allowed to modify both. `None` when either selection matches nothing.
+    async fn merge_records(&self, _from: &[(Option<String>, String)], _into: &[(Option<String>, String)], _precedence: &[String], _fingerprint: Option<String>, _teams: &[String], _source: Option<String>) -> Result<Option<Record>, StorageError> {
+        Err(StorageError::Unsupported("merging needs memory, file or SQLite storage".to_string()))
+    }
+    /// Applies every op of a transaction, in order, or none of them: each op sees the ones
+    /// before it, and the first failure leaves storage untouched. Backends that can't apply
+    /// several writes atomically refuse the whole batch.
+    async fn apply_batch(&self, _ops: &[BatchOp]) -> Result<Vec<BatchOutcome>, BatchError> {
+        Err(BatchError {
+            index: 0,
+            error: StorageError::Unsupported("transactions need memory, file or SQLite storage".to_string()),
+        })
+    }
+    /// Replaces every record, tombstones included, with `records` exactly as given - ids,
+    /// ownership and history untouched - returning the number of live records. For offline
+    /// tools (`pharos-server import` and `migrate`) writing a stopped server's data.
+    async fn replace_records(&self, _records: Vec<Record>) -> Result<usize, StorageError> {
+        Err(StorageError::Unsupported("replacing every record needs memory, file or SQLite storage".to_string()))
+    }
 }
 
-pub struct MemoryStorage {
-    records: Vec<Record>,
+fn snapshots_unsupported() -> StorageError {
+    StorageError::Unsupported("snapshots need file storage (PHAROS_STORAGE_PATH)".to_string())
+}
+
+/// The in-memory record set behind MemoryStorage and FileStorage. Not synchronized itself -
+/// the owning backend holds it behind its own lock.
+#[derive(Clone)]
+pub(crate) struct RecordSet {
+    /// Keyed by id; ids are handed out in increasing order, so iteration is insertion order.
+    records: BTreeMap<usize, Record>,
+    index: RecordIndex,
     next_id: usize,
+    /// Ids of records added, modified or removed since the last `take_journal_entries` call.
+    /// Only FileStorage drains this; for a bare MemoryStorage it is just a small set of ids.
+    dirty: BTreeSet<usize>,
+    /// Ids of the records that are tombstones, so counting and purging don't scan every record.
+    tombstoned: BTreeSet<usize>,
+    history: HistoryConfig,
+    schema: Arc<FieldSchema>,
+    /// Set while `apply_batch` runs, to roll a failed transaction back.
+    undo: Option<UndoLog>,
 }
 
-impl Default for MemoryStorage {
-    fn default() -> Self {
-        Self::new()
+/// What each record a transaction touched looked like before it first did - `None` for one it
+/// added - and whether it was already dirty, so a failed transaction puts back just those.
+#[derive(Clone, Default)]
+struct UndoLog(BTreeMap<usize, (Option<Record>, bool)>);
+
+impl UndoLog {
+    fn note(&mut self, id: usize, before: impl FnOnce() -> Option<Record>, was_dirty: bool) {
+        self.0.entry(id).or_insert_with(|| (before(), was_dirty));
     }
 }
 
-impl MemoryStorage {
-    pub fn new() -> Self {
+impl RecordSet {
+    fn new() -> Self {
         Self {
-            records: Vec::new(),
+            records: BTreeMap::new(),
+            index: RecordIndex::new(),
             next_id: 1,
+            dirty: BTreeSet::new(),
+            tombstoned: BTreeSet::new(),
+            history: HistoryConfig::from_env(),
+            schema: Arc::new(FieldSchema::default()),
+            undo: None,
         }
     }
 
-    fn matches(&self, field_val: &str, query_val: &str) -> Result<bool, StorageError> {
-        let field_val_lower = field_val.to_lowercase();
-        let query_val_lower = query_val.to_lowercase();
+    /// Notes `id` in the undo log, if a transaction is running, before it is changed.
+    fn touch(&mut self, id: usize) {
+        if let Some(undo) = self.undo.as_mut() {
+            undo.note(id, || self.records.get(&id).cloned(), self.dirty.contains(&id));
+        }
+    }
 
-        // Simple word-based matching for MVP
-        // RFC 2378 says "normally done on a word-by-word basis"
-        let query_words: Vec<&str> = query_val_lower.split_whitespace().collect();
-        let field_words: Vec<&str> = field_val_lower.split(|c: char| c.is_whitespace() || c == ',' || c == ';' || c == ':').collect();
+    /// Replaces the whole record set (used when loading from disk) and rebuilds the indexes.
+    /// The next id stays a high-water mark: it never drops below what it was.
+    fn load_records(&mut self, records: Vec<Record>) {
+        self.index = RecordIndex::new();
+        self.records = records.into_iter().map(|r| (r.id, r)).collect();
+        for record in self.records.values() {
+            self.index.insert(record);
+        }
+        self.tombstoned = self.records.values().filter(|r| r.deleted_at.is_some()).map(|r| r.id).collect();
+        self.next_id = journal::high_water_mark(self.records.values(), self.next_id);
+    }
+
+    /// Switches to `schema`, migrating every record holding a single value of a field it
+    /// declares multi-valued (see `FieldSchema::migrate_multi_values`). Returns how many moved.
+    fn set_schema(&mut self, schema: FieldSchema) -> usize {
+        self.schema = Arc::new(schema);
+        let mut migrated = 0;
+        for record in self.records.values_mut() {
+            let before = record.clone();
+            if self.schema.migrate_multi_values(record) {
+                self.index.remove(&before);
+                self.index.insert(record);
+                self.dirty.insert(record.id);
+                migrated += 1;
+            }
+        }
+        migrated
+    }
 
-        for qw in query_words {
-            let mut matched = false;
-            for fw in &field_words {
-                if qw.contains('*') || qw.contains('?') || qw.contains('+') || qw.contains('[') || qw.contains(']') {
-                    if self.wildcard_match(fw, qw)? {
-                        matched = true;
-                        break;
-                    }
-                } else {
-                    if fw == &qw {
-                        matched = true;
-                        break;
-                    }
+    /// Drains the dirty set into journal entries: a `Put` of the current record for every id
+    /// that still exists, a `Remove` for every id that doesn't.
+    fn take_journal_entries(&mut self) -> Vec<JournalEntry> {
+        let dirty = std::mem::take(&mut self.dirty);
+        dirty
+            .into_iter()
+            .map(|id| match self.records.get(&id) {
+                Some(record) => JournalEntry::Put { record: Box::new(record.clone()) },
+                None => JournalEntry::Remove { id },
+            })
+            .collect()
+    }
+}
+
+/// Word-by-word match of one query value against one field value (RFC 2378 Section 3).
+/// Every query word must match some word of the field, exactly or as a wildcard pattern.
+pub(crate) fn matches(field_val: &str, query_val: &str) -> Result<bool, StorageError> {
+    let field_val_lower = field_val.to_lowercase();
+    let query_val_lower = query_val.to_lowercase();
+
+    // Simple word-based matching for MVP
+    // RFC 2378 says "normally done on a word-by-word basis"
+    let query_words: Vec<&str> = query_val_lower.split_whitespace().collect();
+    let field_words: Vec<&str> = index::field_words(&field_val_lower).collect();
+
+    for qw in query_words {
+        let mut matched = false;
+        for fw in &field_words {
+            if index::is_wildcard_word(qw) {
+                if wildcard_match(fw, qw)? {
+                    matched = true;
+                    break;
                 }
-            }
-            if !matched {
-                return Ok(false);
+            } else if fw == &qw {
+                matched = true;
+                break;
             }
         }
-        Ok(true)
-    }
-
-    fn wildcard_match(&self, word: &str, pattern: &str) -> Result<bool, StorageError> {
-        #[derive(Debug, Clone, PartialEq, Eq)]
-        enum PatternToken {
-            Literal(char),
-            Star,
-            Plus,
-            Question,
-            Set(std::collections::HashSet<char>),
-        }
-
-        let mut tokens = Vec::new();
-        let mut chars = pattern.chars().peekable();
-
-        while let Some(c) = chars.next() {
-            match c {
-                '*' => tokens.push(PatternToken::Star),
-                '+' => tokens.push(PatternToken::Plus),
-                '?' => tokens.push(PatternToken::Question),
-                '[' => {
-                    let mut set_chars = std::collections::HashSet::new();
-                    let mut closed = false;
-                    while let Some(&next_c) = chars.peek() {
-                        if next_c == ']' {
-                            chars.next();
-                            closed = true;
-                            break;
-                        } else {
-                            set_chars.insert(chars.next().unwrap());
-                        }
-                    }
-                    if !closed {
-                        return Err(StorageError::InvalidArgument(format!("Unclosed bracket in pattern '{}'", pattern)));
-                    }
-                    if set_chars.is_empty() {
-                        return Err(StorageError::InvalidArgument(format!("Empty bracket set in pattern '{}'", pattern)));
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+pub(crate) fn wildcard_match(word: &str, pattern: &str) -> Result<bool, StorageError> {
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum PatternToken {
+        Literal(char),
+        Star,
+        Plus,
+        Question,
+        Set(std::collections::HashSet<char>),
+    }
+
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => tokens.push(PatternToken::Star),
+            '+' => tokens.push(PatternToken::Plus),
+            '?' => tokens.push(PatternToken::Question),
+            '[' => {
+                let mut set_chars = std::collections::HashSet::new();
+                let mut closed = false;
+                while let Some(&next_c) = chars.peek() {
+                    if next_c == ']' {
+                        chars.next();
+                        closed = true;
+                        break;
+                    } else {
+                        set_chars.insert(chars.next().unwrap());
                     }
-                    tokens.push(PatternToken::Set(set_chars));
                 }
-                ']' => {
-                    return Err(StorageError::InvalidArgument(format!("Stray ']' with no preceding '[' in pattern '{}'", pattern)));
+                if !closed {
+                    return Err(StorageError::InvalidArgument(format!("Unclosed bracket in pattern '{}'", pattern)));
                 }
-                other => {
-                    tokens.push(PatternToken::Literal(other));
+                if set_chars.is_empty() {
+                    return Err(StorageError::InvalidArgument(format!("Empty bracket set in pattern '{}'", pattern)));
                 }
+                tokens.push(PatternToken::Set(set_chars));
+            }
+            ']' => {
+                return Err(StorageError::InvalidArgument(format!("Stray ']' with no preceding '[' in pattern '{}'", pattern)));
+            }
+            other => {
+                tokens.push(PatternToken::Literal(other));
             }
         }
+    }
 
-        let w: Vec<char> = word.chars().collect();
-        let n = w.len();
-        let m = tokens.len();
+    let w: Vec<char> = word.chars().collect();
+    let n = w.len();
+    let m = tokens.len();
 
-        let mut dp = vec![vec![false; m + 1]; n + 1];
-        dp[0][0] = true;
+    let mut dp = vec![vec![false; m + 1]; n + 1];
+    dp[0][0] = true;
 
-        for j in 1..=m {
-            if let PatternToken::Star = tokens[j - 1] {
-                dp[0][j] = dp[0][j - 1];
-            } else {
-                dp[0][j] = false;
-            }
+    for j in 1..=m {
+        if let PatternToken::Star = tokens[j - 1] {
+            dp[0][j] = dp[0][j - 1];
+        } else {
+            dp[0][j] = false;
         }
+    }
 
-        for i in 1..=n {
-            for j in 1..=m {
-                match &tokens[j - 1] {
-                    PatternToken::Literal(c) => {
-                        dp[i][j] = dp[i - 1][j - 1] && w[i - 1] == *c;
-                    }
-                    PatternToken::Question => {
-                        dp[i][j] = dp[i - 1][j - 1];
-                    }
-                    PatternToken::Set(s) => {
-                        dp[i][j] = dp[i - 1][j - 1] && s.contains(&w[i - 1]);
-                    }
-                    PatternToken::Star => {
-                        dp[i][j] = dp[i][j - 1] || dp[i - 1][j];
-                    }
-                    PatternToken::Plus => {
-                        dp[i][j] = dp[i - 1][j - 1] || dp[i - 1][j];
-                    }
+    for i in 1..=n {
+        for j in 1..=m {
+            match &tokens[j - 1] {
+                PatternToken::Literal(c) => {
+                    dp[i][j] = dp[i - 1][j - 1] && w[i - 1] == *c;
+                }
+                PatternToken::Question => {
+                    dp[i][j] = dp[i - 1][j - 1];
+                }
+                PatternToken::Set(s) => {
+                    dp[i][j] = dp[i - 1][j - 1] && s.contains(&w[i - 1]);
+                }
+                PatternToken::Star => {
+                    dp[i][j] = dp[i][j - 1] || dp[i - 1][j];
+                }
+                PatternToken::Plus => {
+                    dp[i][j] = dp[i - 1][j - 1] || dp[i - 1][j];
                 }
             }
         }
-
-        Ok(dp[n][m])
     }
+
+    Ok(dp[n][m])
 }
 
-fn is_valid_mac_address(s: &str) -> bool {
-    let parts: Vec<&str> = s.split(|c| c == ':' || c == '-').collect();
+pub(crate) fn is_valid_mac_address(s: &str) -> bool {
+    let parts: Vec<&str> = s.split([':', '-']).collect();
     if parts.len() != 6 {
         return false;
     }
@@ -249,7 +510,7 @@ fn is_valid_mac_address(s: &str) -> bool {
     true
 }
 
-fn validate_ip_mac_field(key: &str, value: &str) -> Result<(), StorageError> {
+pub(crate) fn validate_ip_mac_field(key: &str, value: &str) -> Result<(), StorageError> {
     if key == "ip" || key == "ip_addr" {
         if value.parse::<std::net::IpAddr>().is_err() {
             return Err(StorageError::InvalidArgument(format!(
@@ -257,650 +518,1664 @@ fn validate_ip_mac_field(key: &str, value: &str) -> Result<(), StorageError> {
                 value, key
             )));
         }
-    } else if key == "mac" || key == "mac_addr" {
-        if !is_valid_mac_address(value) {
-            return Err(StorageError::InvalidArgument(format!(
-                "invalid MAC address '{}' for field '{}'",
-                value, key
-            )));
-        }
+    } else if (key == "mac" || key == "mac_addr")
+        && !is_valid_mac_address(value)
+    {
+        return Err(StorageError::InvalidArgument(format!(
+            "invalid MAC address '{}' for field '{}'",
+            value, key
+        )));
     }
     Ok(())
 }
 
-impl MemoryStorage {
-    fn record_matches_selections(&self, record: &Record, selections: &[(Option<String>, String)]) -> Result<bool, StorageError> {
-        for (field_opt, value) in selections {
-            match field_opt {
-                Some(field_name) => {
-                    if field_name == "ip_addr" || field_name == "mac_addr" {
-                        if let Some(list) = record.multi_fields.get(field_name) {
-                            let mut match_found = false;
-                            for item in list {
-                                if self.matches(item, value)? {
-                                    match_found = true;
-                                    break;
-                                }
-                            }
-                            if !match_found {
-                                return Ok(false);
+/// Whether a record satisfies every selection (implicit AND). A named selection on a
+/// multi-valued field matches if any of its values does; a field-less selection matches if any
+/// field does. A comparison (`field>value`, see compare.rs) compares as the schema's value kind
+/// for the field and only matches records that have it; `!=` needs every value to differ.
+pub(crate) fn record_matches_selections(record: &Record, selections: &[(Option<String>, String)], schema: &FieldSchema) -> Result<bool, StorageError> {
+    let now = Utc::now();
+    for (field_opt, value) in selections {
+        if let Some((field_name, op)) = field_opt.as_deref().and_then(compare::split_key) {
+            let def = schema.field(Some(schema::type_of(record)), field_name);
+            let comparison = compare::Comparison::new(field_name, def, op, value, now)?;
+            let stored: Vec<&String> = record.fields.get(field_name).into_iter().chain(record.multi_fields.get(field_name).into_iter().flatten()).collect();
+            let matched = match op {
+                compare::Op::Ne => !stored.is_empty() && stored.iter().all(|v| comparison.matches(v, now)),
+                _ => stored.iter().any(|v| comparison.matches(v, now)),
+            };
+            if !matched {
+                return Ok(false);
+            }
+            continue;
+        }
+
+        match field_opt {
+            Some(field_name) => {
+                if record.multi_fields.contains_key(field_name) {
+                    if let Some(list) = record.multi_fields.get(field_name) {
+                        let mut match_found = false;
+                        for item in list {
+                            if matches(item, value)? {
+                                match_found = true;
+                                break;
                             }
-                        } else {
-                            return Ok(false);
                         }
-                    } else if let Some(field_val) = record.fields.get(field_name) {
-                        if !self.matches(field_val, value)? {
+                        if !match_found {
                             return Ok(false);
                         }
                     } else {
                         return Ok(false);
                     }
+                } else if let Some(field_val) = record.fields.get(field_name) {
+                    if !matches(field_val, value)? {
+                        return Ok(false);
+                    }
+                } else {
+                    return Ok(false);
                 }
-                None => {
-                    let mut any_match = false;
-                    for field_val in record.fields.values() {
-                        if self.matches(field_val, value)? {
-                            any_match = true;
-                            break;
-                        }
+            }
+            None => {
+                let mut any_match = false;
+                for field_val in record.fields.values() {
+                    if matches(field_val, value)? {
+                        any_match = true;
+                        break;
                     }
-                    if !any_match {
-                        for list in record.multi_fields.values() {
-                            for item in list {
-                                if self.matches(item, value)? {
-                                    any_match = true;
-                                    break;
-                                }
-                            }
-                            if any_match {
+                }
+                if !any_match {
+                    for list in record.multi_fields.values() {
+                        for item in list {
+                            if matches(item, value)? {
+                                any_match = true;
                                 break;
                             }
                         }
+                        if any_match {
+                            break;
+                        }
                     }
-                    if !any_match {
-                        return Ok(false);
-                    }
+                }
+                if !any_match {
+                    return Ok(false);
                 }
             }
         }
-        Ok(true)
     }
+    Ok(true)
 }
 
-impl Storage for MemoryStorage {
-    #[instrument(skip(self))]
-    fn record_count(&self) -> usize {
-        self.records.len()
+/// The type discriminator applied by `ph`/`mdb`: a record of another type is only returned
+/// when the query selects on `type` explicitly; an untyped record never is.
+pub(crate) fn passes_type_filter(record: &Record, selections: &[(Option<String>, String)], default_type: Option<&RecordType>) -> bool {
+    match (default_type, &record.record_type) {
+        (None, _) => true,
+        (Some(dt), Some(rt)) => rt == dt || selections.iter().any(|(f, _)| f.as_deref() == Some("type")),
+        (Some(_), None) => false,
+    }
+}
+
+/// Validates the fields of an `add` against the field schema and builds the record that will be
+/// stored under `id`.
+pub(crate) fn new_record(id: usize, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>, schema: &FieldSchema) -> Result<Record, StorageError> {
+    let type_val = declared_type(&fields).to_string();
+    if type_val.is_empty() {
+        return Err(StorageError::InvalidArgument(
+            "a 'type' field is required (e.g. type=machine)".to_string(),
+        ));
     }
 
-    #[instrument(skip(self))]
-    fn add_record(&mut self, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<(), StorageError> {
-        let type_val = fields.iter().find(|(k, _)| k == "type").map(|(_, v)| v.trim()).unwrap_or("");
-        if type_val.is_empty() {
-            return Err(StorageError::InvalidArgument(
-                "a 'type' field is required (e.g. type=machine)".to_string(),
-            ));
+    for (k, v) in &fields {
+        check_writable(k)?;
+        check_unsealed(k, v)?;
+        validate_ip_mac_field(k, v)?;
+    }
+    schema.check_values(&type_val, &fields)?;
+
+    let mut record_fields = HashMap::new();
+    let mut multi_fields: HashMap<String, Vec<String>> = HashMap::new();
+
+    for (k, v) in fields {
+        if schema.is_multi(&type_val, &k) {
+            let vec = multi_fields.entry(k).or_default();
+            if !vec.contains(&v) {
+                vec.push(v);
+            }
+        } else {
+            record_fields.insert(k, v);
         }
+    }
 
-        for (k, v) in &fields {
-            validate_ip_mac_field(k, v)?;
+    let now = Utc::now().to_rfc3339();
+    record_fields.entry("created_at".to_string()).or_insert_with(|| now.clone());
+    record_fields.insert("last_seen_at".to_string(), now);
+
+    let record_type = record_fields.get("type").map(|s| RecordType::from(s.as_str()));
+    let record = Record {
+        id,
+        record_type,
+        fields: record_fields,
+        multi_fields,
+        owner_fingerprint: fingerprint,
+        owner_team: team,
+        history: Vec::new(),
+        deleted_at: None,
+        revision: 0,
+    };
+    schema.check_required(&record)?;
+    Ok(record)
+}
+
+/// The self-heal FileStorage applies to what it loads: `record_type` is brought in line with the
+/// `type` field, and single values of fields `schema` declares multi-valued move into
+/// `multi_fields`. Returns how many records had each fix.
+pub fn heal_records(records: &mut [Record], schema: &FieldSchema) -> (usize, usize) {
+    let mut corrected_count = 0;
+    let mut migrated_multi_value_count = 0;
+    for record in records.iter_mut() {
+        if let Some(type_str) = record.fields.get("type") {
+            let parsed_type = RecordType::from(type_str.as_str());
+            if record.record_type.as_ref() != Some(&parsed_type) {
+                record.record_type = Some(parsed_type);
+                corrected_count += 1;
+            }
+        } else {
+            tracing::warn!(
+                "record ID {} has no type field, cannot self-heal, remains invisible to mdb/ph queries — needs manual correction",
+                record.id
+            );
         }
 
-        let mut record_fields = HashMap::new();
-        let mut multi_fields: HashMap<String, Vec<String>> = HashMap::new();
+        // Migrate plain-string values of fields declared multi-valued since they were
+        // written (ip_addr/mac_addr on records from before they became lists, or any field
+        // newly marked `multi` in the schema) into multi_fields. A record with the same key
+        // present in both maps would otherwise silently shadow the correct multi_fields data
+        // forever in query responses (fields is checked first) - confirmed live in
+        // production on a record created before this feature existed.
+        if schema.migrate_multi_values(record) {
+            migrated_multi_value_count += 1;
+        }
+    }
+    (corrected_count, migrated_multi_value_count)
+}
+
+/// The `type` an add or upsert's fields give, or "" if they give none.
+pub(crate) fn declared_type(fields: &[(String, String)]) -> &str {
+    fields.iter().find(|(k, _)| k == "type").map_or("", |(_, v)| v.trim())
+}
+
+/// The author of an add or upsert: the caller, acting for `team`, from the client named by the
+/// `source` field the connection handler injects.
+pub(crate) fn upsert_author(fields: &[(String, String)], fingerprint: Option<&String>, team: Option<&String>) -> Author {
+    Author {
+        fingerprint: fingerprint.cloned(),
+        team: team.cloned(),
+        source: fields.iter().find(|(k, _)| k == "source").map(|(_, v)| v.clone()),
+    }
+}
+
+/// The author of a change. Like `add`, a member of several teams is recorded as acting for
+/// the first of them.
+pub(crate) fn change_author(fingerprint: Option<&String>, teams: &[String], source: Option<String>) -> Author {
+    Author {
+        fingerprint: fingerprint.cloned(),
+        team: teams.first().cloned(),
+        source,
+    }
+}
+
+/// The values an upsert identifies its target record by, as (key, value) pairs in the order of
+/// its type's identity keys: `uuid`, `serial_number`, ... if the schema declares them, else
+/// hostname then alias.
+pub(crate) fn upsert_identity<'a>(schema: &FieldSchema, fields: &'a [(String, String)]) -> Vec<(&'a str, &'a str)> {
+    let mut identity = Vec::new();
+    for key in schema.identity_keys(declared_type(fields)) {
+        identity.extend(fields.iter().filter(|(k, v)| k == key && !v.is_empty()).map(|(k, v)| (k.as_str(), v.as_str())));
+    }
+    identity
+}
+
+/// Whether `record` holds `value` as its `key` identity. A hostname or alias names a record by
+/// either, as links do; any other key matches a single or multi-valued field exactly, except that
+/// MAC addresses ignore case.
+pub(crate) fn holds_identity(schema: &FieldSchema, record: &Record, key: &str, value: &str) -> bool {
+    if key == "hostname" || key == "alias" {
+        return is_identity_match(record, value);
+    }
+    let mac = key == "mac_addr" || schema.field(Some(schema::type_of(record)), key).is_some_and(|def| def.kind == ValueKind::Mac);
+    record
+        .fields
+        .get(key)
+        .into_iter()
+        .chain(record.multi_fields.get(key).into_iter().flatten())
+        .any(|held| if mac { held.eq_ignore_ascii_case(value) } else { held == value })
+}
+
+/// The record an upsert resolves to, given each (key, value, record id, record label) match of
+/// its identity values, strongest key first. Identity values that name different records are a
+/// conflict rather than a guess.
+pub(crate) fn resolve_identity<I: Copy + PartialEq>(found: &[(&str, &str, I, String)]) -> Result<Option<I>, StorageError> {
+    let Some((key, value, id, label)) = found.first() else {
+        return Ok(None);
+    };
+    match found.iter().find(|(_, _, other, _)| other != id) {
+        Some((other_key, other_value, _, other_label)) => Err(StorageError::IdentityConflict(format!(
+            "{}={} is {} but {}={} is {}",
+            key, value, label, other_key, other_value, other_label
+        ))),
+        None => Ok(Some(*id)),
+    }
+}
+
+/// Upsert identity is exact, unlike query matching: the whole hostname or alias must be equal.
+pub(crate) fn is_identity_match(record: &Record, id_val: &str) -> bool {
+    record.fields.get("hostname").map(String::as_str) == Some(id_val)
+        || record.fields.get("alias").map(String::as_str) == Some(id_val)
+}
+
+/// The one record a selection matched, if any; a merge refuses to guess between several.
+pub(crate) fn only_match<T>(mut matched: Vec<T>) -> Result<Option<T>, StorageError> {
+    match matched.len() {
+        0 | 1 => Ok(matched.pop()),
+        n => Err(StorageError::TooManyEntries(n)),
+    }
+}
+
+/// Rejects merging a record into itself, or a merge that would replace an encrypted value the
+/// surviving record holds - `merge` has no `force`.
+pub(crate) fn check_merge(schema: &FieldSchema, lose: &Record, keep: &Record, merged: &Record) -> Result<(), StorageError> {
+    if lose.id == keep.id {
+        return Err(StorageError::InvalidArgument(format!("both selections match {}, so there is nothing to merge", links::label(keep))));
+    }
+    for (name, value) in &keep.fields {
+        if merged.fields.get(name) != Some(value) {
+            check_force(schema, keep, name, false)?;
+        }
+    }
+    Ok(())
+}
+
+/// RFC 2378's `force`: a field the schema marks `encrypted` is only overwritten by
+/// `change ... force`. Refuses writing `field` on `record` unless `force` is set.
+pub(crate) fn check_force(schema: &FieldSchema, record: &Record, field: &str, force: bool) -> Result<(), StorageError> {
+    if !force && schema.is_encrypted(schema::type_of(record), field) {
+        return Err(StorageError::Encrypted(field.to_string()));
+    }
+    Ok(())
+}
+
+/// Rejects an upsert onto an existing record that is bonded to another fingerprint, owned by
+/// another team, would change its type, replaces an encrypted value (`add` has no `force`), or
+/// sets values its type's schema doesn't allow.
+pub(crate) fn check_upsert(record: &Record, fields: &[(String, String)], fingerprint: Option<&String>, team: Option<&String>, schema: &FieldSchema) -> Result<(), StorageError> {
+    if record.owner_fingerprint.as_ref().is_some_and(|bonded| Some(bonded) != fingerprint) {
+        return Err(StorageError::Collision);
+    }
+
+    // Check Member Authorization logic (Team match)
+    if let Some(ref record_team) = record.owner_team {
+        if let Some(user_team) = team {
+             // User must be in the team that owns the record
+             if record_team != user_team {
+                 return Err(StorageError::Unauthorized);
+             }
+        } else if record.owner_fingerprint.is_none() {
+             return Err(StorageError::Unauthorized);
+        }
+    }
+
+    for (k, v) in fields {
+        check_writable(k)?;
+        check_unsealed(k, v)?;
+        if record.fields.get(k).is_some_and(|current| current != v) {
+            check_force(schema, record, k, false)?;
+        }
+    }
+    if let Some((_, incoming_type)) = fields.iter().find(|(k, _)| k == "type")
+        && let Some(existing_type) = record.fields.get("type")
+        && incoming_type != existing_type
+    {
+        return Err(StorageError::InvalidArgument(
+            "type is immutable after creation and cannot be changed".to_string(),
+        ));
+    }
+    schema.check_values(schema::type_of(record), fields)
+}
+
+/// `revision` is kept by the server; a client-supplied one would shadow it in query output.
+fn check_writable(field: &str) -> Result<(), StorageError> {
+    if field.eq_ignore_ascii_case(REVISION_FIELD) {
+        return Err(StorageError::InvalidArgument(format!(
+            "'{}' is maintained by the server and cannot be written",
+            REVISION_FIELD
+        )));
+    }
+    Ok(())
+}
+
+/// `sealed:v1:` marks a value FileStorage sealed. Written by a client, it would pass for one: left
+/// in the clear on disk in an encrypted field, and failing to open on the next start.
+fn check_unsealed(field: &str, value: &str) -> Result<(), StorageError> {
+    if sealing::is_sealed(value) {
+        return Err(StorageError::InvalidArgument(format!("the value of '{}' starts with the reserved prefix 'sealed:v1:'", field)));
+    }
+    Ok(())
+}
+
+/// A conditional change goes ahead only if no record it matched has moved past `expected`.
+pub(crate) fn check_revision<'a>(records: impl IntoIterator<Item = &'a Record>, expected: Option<u64>) -> Result<(), StorageError> {
+    let Some(expected) = expected else {
+        return Ok(());
+    };
+    match records.into_iter().find(|r| r.revision != expected) {
+        Some(record) => Err(StorageError::RevisionMismatch { expected, current: record.revision }),
+        None => Ok(()),
+    }
+}
+
+/// The values a change writes: those it sets or replaces, but not those it removes.
+pub(crate) fn written_values(modifications: &[(String, String)]) -> Vec<(String, String)> {
+    modifications
+        .iter()
+        .filter_map(|(key, value)| match parse_modification(key, value) {
+            (field, Edit::Set(value) | Edit::Replace(value)) => Some((field.to_string(), value.to_string())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Checks a change's modifications against the schema of every record it would touch, before
+/// any of them is modified.
+pub(crate) fn check_change<'a>(schema: &FieldSchema, records: impl IntoIterator<Item = &'a Record>, modifications: &[(String, String)], force: bool) -> Result<(), StorageError> {
+    let written = written_values(modifications);
+    for record in records {
+        for (key, value) in modifications {
+            check_force(schema, record, parse_modification(key, value).0, force)?;
+        }
+        schema.check_values(schema::type_of(record), &written)?;
+        // Only a removal can take away a required field.
+        if written.len() < modifications.len() {
+            let mut after = record.clone();
+            apply_modifications(&mut after, modifications, schema);
+            schema.check_required(&after)?;
+        }
+    }
+    Ok(())
+}
+
+/// Stores one written value: appended (once) to a multi-valued field, else replacing the field.
+/// A field that already holds a list stays one even if the schema no longer declares it
+/// multi-valued, and a single value left over from before it was declared joins the list.
+fn put_value(record: &mut Record, multi: bool, field: String, value: String) {
+    if multi || record.multi_fields.contains_key(&field) {
+        let legacy = record.fields.remove(&field);
+        let values = record.multi_fields.entry(field).or_default();
+        for value in legacy.into_iter().chain(std::iter::once(value)) {
+            if !values.contains(&value) {
+                values.push(value);
+            }
+        }
+    } else {
+        record.fields.insert(field, value);
+    }
+}
 
-        for (k, v) in fields {
-            if k == "ip_addr" || k == "mac_addr" {
-                let vec = multi_fields.entry(k).or_default();
-                if !vec.contains(&v) {
-                    vec.push(v);
+/// Merges an upsert that passed `check_upsert` into the existing record.
+pub(crate) fn apply_upsert(record: &mut Record, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>, schema: &FieldSchema) {
+    if record.owner_fingerprint.is_none() {
+        record.owner_fingerprint = fingerprint;
+    }
+    if record.owner_team.is_none() {
+        record.owner_team = team;
+    }
+
+    let record_type = schema::type_of(record).to_string();
+    for (k, v) in fields {
+        if k == "source" && record.fields.contains_key("source") {
+            // source describes a record's provenance (how it was created), not who last
+            // touched it - once set, it must never be overwritten by a later write.
+        } else {
+            put_value(record, schema.is_multi(&record_type, &k), k, v);
+        }
+    }
+    record.fields.insert("last_seen_at".to_string(), Utc::now().to_rfc3339());
+}
+
+/// Ownership policy shared by delete and change: the bonded fingerprint, a member of the
+/// owning team, or anyone at all for records with no owner.
+pub(crate) fn is_authorized(record: &Record, fingerprint: Option<&String>, teams: &[String]) -> bool {
+    match (&record.owner_fingerprint, &record.owner_team) {
+        (Some(fp), _) if fingerprint == Some(fp) => true,
+        (_, Some(team)) if teams.contains(team) => true,
+        (None, None) => true, // System records?
+        _ => false,
+    }
+}
+
+/// Undelete follows the delete ownership policy, except that an admin may restore anything.
+pub(crate) fn check_undelete(record: &Record, fingerprint: Option<&String>, teams: &[String], admin: bool) -> Result<(), StorageError> {
+    if admin || is_authorized(record, fingerprint, teams) {
+        Ok(())
+    } else {
+        Err(StorageError::Unauthorized)
+    }
+}
+
+/// Restoring a tombstone whose hostname or alias has since been reused would leave two records
+/// with the same upsert identity.
+pub(crate) fn undelete_conflict(identity: &str) -> StorageError {
+    StorageError::InvalidArgument(format!(
+        "a live record already uses '{}' - rename or delete it before undeleting",
+        identity
+    ))
+}
+
+/// What one `make` item of a change does to its field. The operation rides on the key, the way
+/// comparison operators ride on selection keys: `notes=` (or `clear notes`) unsets the field,
+/// `ip_addr-=v` removes one value and `ip_addr:=v` replaces all of them (repeat it to keep
+/// several). A plain `field=value` sets the field, appending to a multi-valued one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edit<'a> {
+    Set(&'a str),
+    Unset,
+    Remove(&'a str),
+    Replace(&'a str),
+}
+
+/// Splits a modification into the field it touches and what it does to it.
+pub fn parse_modification<'a>(key: &'a str, value: &'a str) -> (&'a str, Edit<'a>) {
+    if let Some(field) = key.strip_suffix('-').filter(|f| !f.is_empty()) {
+        return (field, Edit::Remove(value));
+    }
+    let (field, replace) = match key.strip_suffix(':').filter(|f| !f.is_empty()) {
+        Some(field) => (field, true),
+        None => (key, false),
+    };
+    match (value.is_empty(), replace) {
+        (true, _) => (field, Edit::Unset),
+        (false, true) => (field, Edit::Replace(value)),
+        (false, false) => (field, Edit::Set(value)),
+    }
+}
+
+pub(crate) fn validate_modifications(modifications: &[(String, String)]) -> Result<(), StorageError> {
+    for (key, value) in modifications {
+        match parse_modification(key, value) {
+            (field, _) if field.eq_ignore_ascii_case("type") => {
+                return Err(StorageError::InvalidArgument(
+                    "type cannot be modified via change - it is set once at record creation".to_string(),
+                ));
+            }
+            (field, _) if field.eq_ignore_ascii_case(REVISION_FIELD) => check_writable(field)?,
+            (field, Edit::Remove("")) => {
+                return Err(StorageError::InvalidArgument(format!("'{}-=' needs the value to remove", field)));
+            }
+            (field, Edit::Set(value) | Edit::Replace(value)) => {
+                check_unsealed(field, value)?;
+                validate_ip_mac_field(field, value)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Drops `value` from `field`, whether the field holds a list or a single value.
+fn remove_value(record: &mut Record, field: &str, value: &str) {
+    if let Some(values) = record.multi_fields.get_mut(field) {
+        values.retain(|v| !v.eq_ignore_ascii_case(value));
+        if values.is_empty() {
+            record.multi_fields.remove(field);
+        }
+    }
+    if record.fields.get(field).is_some_and(|v| v.eq_ignore_ascii_case(value)) {
+        record.fields.remove(field);
+    }
+}
+
+pub(crate) fn apply_modifications(record: &mut Record, modifications: &[(String, String)], schema: &FieldSchema) {
+    let record_type = schema::type_of(record).to_string();
+    let mut replaced = BTreeSet::new();
+    for (key, value) in modifications {
+        let (field, edit) = parse_modification(key, value);
+        match edit {
+            Edit::Set(value) => put_value(record, schema.is_multi(&record_type, field), field.to_string(), value.to_string()),
+            Edit::Unset => {
+                record.fields.remove(field);
+                record.multi_fields.remove(field);
+            }
+            Edit::Remove(value) => remove_value(record, field, value),
+            Edit::Replace(value) => {
+                // The first `:=` for a field clears it; later ones add to the replacement.
+                if replaced.insert(field) {
+                    record.fields.remove(field);
+                    record.multi_fields.remove(field);
                 }
-            } else {
-                record_fields.insert(k, v);
+                put_value(record, schema.is_multi(&record_type, field), field.to_string(), value.to_string());
             }
         }
+    }
+}
 
-        let now = Utc::now().to_rfc3339();
-        record_fields.entry("created_at".to_string()).or_insert_with(|| now.clone());
-        record_fields.insert("last_seen_at".to_string(), now);
-        
-        let record_type = record_fields.get("type").map(|s| RecordType::from(s.as_str()));
-        let record = Record {
-            id: self.next_id,
-            record_type,
-            fields: record_fields,
-            multi_fields,
-            owner_fingerprint: fingerprint,
-            owner_team: team,
+impl RecordSet {
+    /// Narrows a selection list to the records that can possibly match, using the indexes for
+    /// every exact selection. Falls back to every record only when no selection is indexable.
+    fn candidate_records<'a>(&'a self, selections: &[(Option<String>, String)]) -> Box<dyn Iterator<Item = &'a Record> + 'a> {
+        let mut narrowed: Option<BTreeSet<usize>> = None;
+        for (field_opt, value) in selections {
+            if let Some(ids) = self.index.candidates(field_opt.as_deref(), value) {
+                narrowed = Some(match narrowed {
+                    Some(acc) => acc.intersection(&ids).copied().collect(),
+                    None => ids,
+                });
+            }
+        }
+        match narrowed {
+            Some(ids) => Box::new(ids.into_iter().filter_map(|id| self.records.get(&id))),
+            None => Box::new(self.records.values()),
+        }
+    }
+
+    /// Upsert identity: the first live record whose hostname or alias equals `id_val` exactly.
+    fn find_identity_match(&self, id_val: &str) -> Option<usize> {
+        let hostname_ids = self.index.candidates(Some("hostname"), id_val);
+        let alias_ids = self.index.candidates(Some("alias"), id_val);
+        match (hostname_ids, alias_ids) {
+            (Some(h), Some(a)) => h.union(&a).copied().find(|id| self.records.get(id).is_some_and(|r| r.deleted_at.is_none() && is_identity_match(r, id_val))),
+            _ => self.records.values().find(|r| r.deleted_at.is_none() && is_identity_match(r, id_val)).map(|r| r.id),
+        }
+    }
+
+    /// Live records holding `value` as their `key` identity, by id.
+    fn identity_holders(&self, key: &str, value: &str) -> Vec<&Record> {
+        // The index splits values into words (a MAC address at its colons); one is enough to
+        // narrow the candidates down.
+        let lowered = value.to_lowercase();
+        let word = index::field_words(&lowered).find(|w| !w.is_empty()).unwrap_or_default();
+        let candidates = match key {
+            "hostname" | "alias" => self
+                .index
+                .candidates(Some("hostname"), word)
+                .zip(self.index.candidates(Some("alias"), word))
+                .map(|(h, a)| h.union(&a).copied().collect()),
+            _ => self.index.candidates(Some(key), word),
+        };
+        let mut holders: Vec<&Record> = match candidates {
+            Some(ids) => ids.iter().filter_map(|id| self.records.get(id)).collect(),
+            None => self.records.values().collect(),
         };
-        self.records.push(record);
+        holders.retain(|r| r.deleted_at.is_none() && holds_identity(&self.schema, r, key, value));
+        holders.sort_by_key(|r| r.id);
+        holders
+    }
+
+    /// The live record an upsert of `fields` updates, if its identity values name one.
+    fn resolve_upsert(&self, fields: &[(String, String)]) -> Result<Option<usize>, StorageError> {
+        let mut found = Vec::new();
+        for (key, value) in upsert_identity(&self.schema, fields) {
+            found.extend(self.identity_holders(key, value).into_iter().map(|r| (key, value, r.id, links::label(r))));
+        }
+        resolve_identity(&found)
+    }
+
+    /// Checks the links a write sets on a record of `record_type` against the live records.
+    fn check_links(&self, record_type: &str, fields: &[(String, String)]) -> Result<(), StorageError> {
+        links::check_links(&self.schema, record_type, fields, |identity| {
+            Ok(self.find_identity_match(identity).and_then(|id| self.records.get(&id)).map(|r| schema::type_of(r).to_string()))
+        })
+    }
+
+    /// Drops the links live records hold to the records in `deleted_ids` (or points them at
+    /// `replacement`), logging each record it touches as a revision by the deleting author.
+    fn unlink_deleted(&mut self, deleted_ids: &[usize], author: &Author, replacement: Option<&str>) {
+        let identities: BTreeSet<String> = deleted_ids
+            .iter()
+            .filter_map(|id| self.records.get(id))
+            .flat_map(|r| links::identities(r).map(str::to_string))
+            .collect();
+        if identities.is_empty() || self.schema.link_names().is_empty() {
+            return;
+        }
+
+        let mut candidates = BTreeSet::new();
+        for identity in &identities {
+            match self.index.candidates(None, identity) {
+                Some(ids) => candidates.extend(ids),
+                None => candidates.extend(self.records.keys().copied()),
+            }
+        }
+        for id in candidates {
+            let Some(record) = self.records.get_mut(&id).filter(|r| r.deleted_at.is_none()) else {
+                continue;
+            };
+            let before = record.clone();
+            if links::unlink(&self.schema, record, &identities, replacement) {
+                if let Some(undo) = self.undo.as_mut() {
+                    undo.note(id, || Some(before.clone()), self.dirty.contains(&id));
+                }
+                self.index.remove(&before);
+                history::record_revision(Some(&before), record, author, self.history.limit);
+                self.index.insert(record);
+                self.dirty.insert(id);
+            }
+        }
+    }
+
+    /// Ids of every live record the selections match, failing the whole operation if any of
+    /// them isn't the caller's to modify.
+    fn authorized_matches(&self, selections: &[(Option<String>, String)], fingerprint: Option<&String>, teams: &[String]) -> Result<Vec<usize>, StorageError> {
+        let mut ids = Vec::new();
+        for record in self.candidate_records(selections) {
+            if record.deleted_at.is_none() && record_matches_selections(record, selections, &self.schema)? {
+                if !is_authorized(record, fingerprint, teams) {
+                    return Err(StorageError::Unauthorized);
+                }
+                ids.push(record.id);
+            }
+        }
+        Ok(ids)
+    }
+}
+
+impl RecordSet {
+    pub(crate) fn record_count(&self) -> usize {
+        self.records.len() - self.tombstoned.len()
+    }
+
+    pub(crate) fn add_record(&mut self, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<(), StorageError> {
+        let author = upsert_author(&fields, fingerprint.as_ref(), team.as_ref());
+        self.check_links(declared_type(&fields), &fields)?;
+        let mut record = new_record(self.next_id, fields, fingerprint, team, &self.schema)?;
+        history::record_revision(None, &mut record, &author, self.history.limit);
+        self.touch(record.id);
+        self.dirty.insert(record.id);
+        self.index.insert(&record);
+        self.records.insert(record.id, record);
         self.next_id += 1;
         Ok(())
     }
 
-    #[instrument(skip(self))]
-    fn query(&self, selections: &[(Option<String>, String)], default_type: Option<RecordType>) -> Result<Vec<Record>, StorageError> {
+    pub(crate) fn query(&self, selections: &[(Option<String>, String)], default_type: Option<RecordType>) -> Result<Vec<Record>, StorageError> {
         let mut results = Vec::new();
-        for record in &self.records {
+        for record in self.candidate_records(selections) {
+            if record.deleted_at.is_some() {
+                continue;
+            }
+
             // Check discriminator
-            if let Some(ref dt) = default_type {
-                if let Some(ref rt) = record.record_type {
-                    if rt != dt && !selections.iter().any(|(f, _)| f.as_deref() == Some("type")) {
-                        continue;
-                    }
-                } else {
-                    continue;
-                }
+            if !passes_type_filter(record, selections, default_type.as_ref()) {
+                continue;
             }
 
-            if self.record_matches_selections(record, selections)? {
+            if record_matches_selections(record, selections, &self.schema)? {
                 results.push(record.clone());
             }
         }
         Ok(results)
     }
 
-    #[instrument(skip(self))]
-    fn upsert_record(&mut self, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<UpsertOutcome, StorageError> {
+    pub(crate) fn upsert_record(&mut self, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<UpsertOutcome, StorageError> {
         for (k, v) in &fields {
             validate_ip_mac_field(k, v)?;
         }
 
+        if let Some(id) = self.resolve_upsert(&fields)? {
+            if let Some(record) = self.records.get(&id) {
+                check_upsert(record, &fields, fingerprint.as_ref(), team.as_ref(), &self.schema)?;
+                self.check_links(schema::type_of(record), &fields)?;
+            }
+            self.touch(id);
+            if let Some(record) = self.records.get_mut(&id) {
+                let author = upsert_author(&fields, fingerprint.as_ref(), team.as_ref());
+                let before = record.clone();
+                self.index.remove(record);
+                apply_upsert(record, fields, fingerprint, team, &self.schema);
+                history::record_revision(Some(&before), record, &author, self.history.limit);
+                self.index.insert(record);
+                self.dirty.insert(record.id);
+                return Ok(UpsertOutcome::Updated);
+            }
+        }
+
+        self.add_record(fields, fingerprint, team).map(|_| UpsertOutcome::Created)
+    }
+
+    pub(crate) fn delete_record(&mut self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String]) -> Result<usize, StorageError> {
+        self.delete_record_if(selections, fingerprint, teams, &|_| true)
+    }
+
+    /// Deletes the records `selections` match only if `still` holds for every one of them.
+    pub(crate) fn delete_record_if(&mut self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String], still: &dyn Fn(&Record) -> bool) -> Result<usize, StorageError> {
+        let to_delete_ids = self.authorized_matches(selections, fingerprint.as_ref(), teams)?;
+        if !to_delete_ids.iter().all(|id| self.records.get(id).is_some_and(still)) {
+            return Ok(0);
+        }
+        let author = change_author(fingerprint.as_ref(), teams, None);
         let now = Utc::now().to_rfc3339();
-        let identifier = fields.iter().find(|(k, _)| k == "hostname" || k == "alias").map(|(_, v)| v.clone());
 
-        if let Some(id_val) = identifier {
-            let existing = self.records.iter_mut().find(|r| {
-                r.fields.get("hostname") == Some(&id_val) || r.fields.get("alias") == Some(&id_val)
-            });
+        let deleted_count = to_delete_ids.len();
+        for id in &to_delete_ids {
+            self.touch(*id);
+            if let Some(record) = self.records.get_mut(id) {
+                tombstone::mark_deleted(record, now.clone(), &author, self.history.limit);
+            }
+        }
+        self.tombstoned.extend(to_delete_ids.iter().copied());
+        self.unlink_deleted(&to_delete_ids, &author, None);
+        self.dirty.extend(to_delete_ids);
 
-            if let Some(record) = existing {
-                if record.owner_fingerprint.as_ref().is_some_and(|bonded| Some(bonded) != fingerprint.as_ref()) {
-                    return Err(StorageError::Collision);
-                }
+        Ok(deleted_count)
+    }
 
-                // Check Member Authorization logic (Team match)
-                if let Some(ref record_team) = record.owner_team {
-                    if let Some(ref user_team) = team {
-                         // User must be in the team that owns the record
-                         if record_team != user_team {
-                             return Err(StorageError::Unauthorized);
-                         }
-                    } else if record.owner_fingerprint.is_none() {
-                         return Err(StorageError::Unauthorized);
-                    }
-                }
+    pub(crate) fn tombstones(&self, selections: &[(Option<String>, String)]) -> Result<Vec<Record>, StorageError> {
+        let mut results = Vec::new();
+        for id in &self.tombstoned {
+            if let Some(record) = self.records.get(id)
+                && record_matches_selections(record, selections, &self.schema)?
+            {
+                results.push(record.clone());
+            }
+        }
+        Ok(results)
+    }
 
-                if let Some((_, incoming_type)) = fields.iter().find(|(k, _)| k == "type") {
-                    if let Some(existing_type) = record.fields.get("type") {
-                        if incoming_type != existing_type {
-                            return Err(StorageError::InvalidArgument(
-                                "type is immutable after creation and cannot be changed".to_string(),
-                            ));
-                        }
-                    }
+    pub(crate) fn undelete_record(&mut self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String], admin: bool) -> Result<usize, StorageError> {
+        let mut to_restore_ids = Vec::new();
+        let mut restored_identities = BTreeSet::new();
+        for record in self.tombstones(selections)? {
+            check_undelete(&record, fingerprint.as_ref(), teams, admin)?;
+            for identity in ["hostname", "alias"].iter().filter_map(|k| record.fields.get(*k)) {
+                if self.find_identity_match(identity).is_some() || !restored_identities.insert(identity.clone()) {
+                    return Err(undelete_conflict(identity));
                 }
+            }
+            to_restore_ids.push(record.id);
+        }
 
-                if record.owner_fingerprint.is_none() {
-                    record.owner_fingerprint = fingerprint;
+        let author = change_author(fingerprint.as_ref(), teams, None);
+        for id in &to_restore_ids {
+            self.touch(*id);
+            if let Some(record) = self.records.get_mut(id) {
+                tombstone::mark_restored(record, &author, self.history.limit);
+                self.tombstoned.remove(id);
+                self.dirty.insert(*id);
+            }
+        }
+        Ok(to_restore_ids.len())
+    }
+
+    pub(crate) fn purge_tombstones(&mut self, cutoff: DateTime<Utc>) -> usize {
+        let expired: Vec<usize> = self
+            .tombstoned
+            .iter()
+            .copied()
+            .filter(|id| self.records.get(id).is_some_and(|r| tombstone::is_expired(r, cutoff)))
+            .collect();
+        for id in &expired {
+            if let Some(record) = self.records.remove(id) {
+                self.index.remove(&record);
+            }
+            self.tombstoned.remove(id);
+        }
+        self.dirty.extend(expired.iter().copied());
+        expired.len()
+    }
+
+    pub(crate) fn import_tombstone(&mut self, identity: &str, deleted_at: &str) -> bool {
+        let Some(id) = self.find_identity_match(identity) else {
+            return false;
+        };
+        let Some(record) = self.records.get_mut(&id) else {
+            return false;
+        };
+        if !tombstone::is_superseded_by(record, deleted_at) {
+            return false;
+        }
+        tombstone::mark_deleted(record, deleted_at.to_string(), &Author::default(), self.history.limit);
+        self.tombstoned.insert(id);
+        self.dirty.insert(id);
+        true
+    }
+
+    /// Purpose (The "Why"): Performs selection matching and authorized in-place modification
+    /// of records. It iterates over existing records, validates ownership fingerprint or team
+    /// matches, and inserts or updates fields as specified by modifications.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn change_record(&mut self, selections: &[(Option<String>, String)], modifications: &[(String, String)], fingerprint: Option<String>, teams: &[String], source: Option<String>, force: bool, expected_revision: Option<u64>) -> Result<usize, StorageError> {
+        validate_modifications(modifications)?;
+
+        let to_change_ids = self.authorized_matches(selections, fingerprint.as_ref(), teams)?;
+        check_revision(to_change_ids.iter().filter_map(|id| self.records.get(id)), expected_revision)?;
+        check_change(&self.schema, to_change_ids.iter().filter_map(|id| self.records.get(id)), modifications, force)?;
+        let written = written_values(modifications);
+        for record in to_change_ids.iter().filter_map(|id| self.records.get(id)) {
+            self.check_links(schema::type_of(record), &written)?;
+        }
+        let author = change_author(fingerprint.as_ref(), teams, source);
+
+        let changed_count = to_change_ids.len();
+        for id in to_change_ids {
+            self.touch(id);
+            if let Some(record) = self.records.get_mut(&id) {
+                let before = record.clone();
+                self.index.remove(record);
+                apply_modifications(record, modifications, &self.schema);
+                history::record_revision(Some(&before), record, &author, self.history.limit);
+                self.index.insert(record);
+                self.dirty.insert(record.id);
+            }
+        }
+
+        Ok(changed_count)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn merge_records(&mut self, from: &[(Option<String>, String)], into: &[(Option<String>, String)], precedence: &[String], fingerprint: Option<String>, teams: &[String], source: Option<String>) -> Result<Option<Record>, StorageError> {
+        let lose_id = only_match(self.authorized_matches(from, fingerprint.as_ref(), teams)?)?;
+        let keep_id = only_match(self.authorized_matches(into, fingerprint.as_ref(), teams)?)?;
+        let (Some(lose), Some(keep)) = (lose_id.and_then(|id| self.records.get(&id)), keep_id.and_then(|id| self.records.get(&id))) else {
+            return Ok(None);
+        };
+        let merged = merge::combine(keep, lose, precedence)?;
+        check_merge(&self.schema, lose, keep, &merged)?;
+        let (lose_id, keep_id) = (lose.id, keep.id);
+        let author = change_author(fingerprint.as_ref(), teams, source);
+
+        self.touch(lose_id);
+        self.touch(keep_id);
+        if let Some(record) = self.records.get_mut(&lose_id) {
+            tombstone::mark_deleted(record, Utc::now().to_rfc3339(), &author, self.history.limit);
+            self.tombstoned.insert(lose_id);
+        }
+        if let Some(record) = self.records.get_mut(&keep_id) {
+            let before = record.clone();
+            self.index.remove(record);
+            *record = merged;
+            history::record_revision(Some(&before), record, &author, self.history.limit);
+            self.index.insert(record);
+        }
+        self.dirty.extend([lose_id, keep_id]);
+        let survivor = self.records.get(&keep_id).cloned();
+        let replacement = survivor.as_ref().and_then(|r| links::identities(r).next().map(str::to_string));
+        self.unlink_deleted(&[lose_id], &author, replacement.as_deref());
+        Ok(survivor)
+    }
+
+    /// Applies a transaction in place, noting what each record it touches looked like first;
+    /// if an op fails, those records alone are put back, so a failure halfway leaves nothing
+    /// behind without copying the whole set per commit.
+    pub(crate) fn apply_batch(&mut self, ops: &[BatchOp]) -> Result<Vec<BatchOutcome>, BatchError> {
+        let next_id = self.next_id;
+        self.undo = Some(UndoLog::default());
+        let mut outcomes = Vec::with_capacity(ops.len());
+        for (index, op) in ops.iter().enumerate() {
+            match self.apply_op(op) {
+                Ok(outcome) => outcomes.push(outcome),
+                Err(error) => {
+                    self.roll_back(next_id);
+                    return Err(BatchError { index, error });
                 }
-                if record.owner_team.is_none() {
-                    record.owner_team = team;
+            }
+        }
+        self.undo = None;
+        Ok(outcomes)
+    }
+
+    /// Puts every record in the undo log back as it was, indexes and tombstones with it.
+    fn roll_back(&mut self, next_id: usize) {
+        let Some(UndoLog(undo)) = self.undo.take() else {
+            return;
+        };
+        for (id, (before, was_dirty)) in undo {
+            if let Some(current) = self.records.remove(&id) {
+                self.index.remove(&current);
+            }
+            self.tombstoned.remove(&id);
+            if !was_dirty {
+                self.dirty.remove(&id);
+            }
+            if let Some(record) = before {
+                self.index.insert(&record);
+                if record.deleted_at.is_some() {
+                    self.tombstoned.insert(id);
                 }
+                self.records.insert(id, record);
+            }
+        }
+        self.next_id = next_id;
+    }
 
-                for (k, v) in fields {
-                    if k == "ip_addr" || k == "mac_addr" {
-                        let vec = record.multi_fields.entry(k).or_default();
-                        if !vec.contains(&v) {
-                            vec.push(v);
-                        }
-                    } else if k == "source" && record.fields.contains_key("source") {
-                        // source describes a record's provenance (how it was created), not who last
-                        // touched it - once set, it must never be overwritten by a later write.
-                    } else {
-                        record.fields.insert(k, v);
-                    }
+    fn apply_op(&mut self, op: &BatchOp) -> Result<BatchOutcome, StorageError> {
+        match op {
+            BatchOp::Upsert { fields, fingerprint, team } => self
+                .upsert_record(fields.clone(), fingerprint.clone(), team.clone())
+                .map(BatchOutcome::Upserted),
+            BatchOp::Change { selections, modifications, fingerprint, teams, source, force, limits, if_revision } => {
+                if !limits.is_unlimited() {
+                    limits.check(&self.query(selections, None)?, modifications)?;
                 }
-                record.fields.insert("last_seen_at".to_string(), now);
-                return Ok(UpsertOutcome::Updated);
+                self.change_record(selections, modifications, fingerprint.clone(), teams, source.clone(), *force, *if_revision)
+                    .map(BatchOutcome::Changed)
             }
+            BatchOp::Delete { selections, fingerprint, teams, limits } => {
+                if !limits.is_unlimited() {
+                    limits.check(&self.query(selections, None)?, &[])?;
+                }
+                self.delete_record(selections, fingerprint.clone(), teams).map(BatchOutcome::Deleted)
+            }
+        }
+    }
+}
+
+pub struct MemoryStorage {
+    records: RwLock<RecordSet>,
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self {
+            records: RwLock::new(RecordSet::new()),
         }
+    }
+
+    pub fn with_field_schema(mut self, schema: FieldSchema) -> Self {
+        self.records.get_mut().set_schema(schema);
+        self
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    #[instrument(skip(self))]
+    async fn record_count(&self) -> usize {
+        self.records.read().await.record_count()
+    }
+
+    #[instrument(skip(self))]
+    async fn add_record(&self, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<(), StorageError> {
+        self.records.write().await.add_record(fields, fingerprint, team)
+    }
+
+    #[instrument(skip(self))]
+    async fn query(&self, selections: &[(Option<String>, String)], default_type: Option<RecordType>) -> Result<Vec<Record>, StorageError> {
+        self.records.read().await.query(selections, default_type)
+    }
+
+    #[instrument(skip(self))]
+    async fn upsert_record(&self, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<UpsertOutcome, StorageError> {
+        self.records.write().await.upsert_record(fields, fingerprint, team)
+    }
 
-        self.add_record(fields, fingerprint, team).map(|_| UpsertOutcome::Created)
+    #[instrument(skip(self))]
+    async fn delete_record(&self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String]) -> Result<usize, StorageError> {
+        self.records.write().await.delete_record(selections, fingerprint, teams)
+    }
+
+    #[instrument(skip(self, still))]
+    async fn delete_record_if(&self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String], still: RecordGuard) -> Result<usize, StorageError> {
+        self.records.write().await.delete_record_if(selections, fingerprint, teams, &*still)
     }
 
     #[instrument(skip(self))]
-    fn delete_record(&mut self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String]) -> Result<usize, StorageError> {
-        let mut to_delete_ids = Vec::new();
-
-        for record in &self.records {
-            if self.record_matches_selections(record, selections)? {
-                // Check authorization for deletion
-                let authorized = match (&record.owner_fingerprint, &record.owner_team) {
-                    (Some(fp), _) if fingerprint.as_ref() == Some(fp) => true,
-                    (_, Some(team)) if teams.contains(team) => true,
-                    (None, None) => true, // System records?
-                    _ => false,
-                };
-
-                if authorized {
-                    to_delete_ids.push(record.id);
-                } else {
-                    return Err(StorageError::Unauthorized);
-                }
-            }
-        }
+    async fn change_record(&self, selections: &[(Option<String>, String)], modifications: &[(String, String)], fingerprint: Option<String>, teams: &[String], source: Option<String>, force: bool) -> Result<usize, StorageError> {
+        self.records.write().await.change_record(selections, modifications, fingerprint, teams, source, force, None)
+    }
 
-        let deleted_count = to_delete_ids.len();
-        self.records.retain(|r| !to_delete_ids.contains(&r.id));
+    #[instrument(skip(self))]
+    #[allow(clippy::too_many_arguments)]
+    async fn change_record_if_revision(&self, selections: &[(Option<String>, String)], modifications: &[(String, String)], fingerprint: Option<String>, teams: &[String], source: Option<String>, force: bool, revision: u64) -> Result<usize, StorageError> {
+        self.records.write().await.change_record(selections, modifications, fingerprint, teams, source, force, Some(revision))
+    }
 
-        Ok(deleted_count)
+    #[instrument(skip(self))]
+    async fn merge_records(&self, from: &[(Option<String>, String)], into: &[(Option<String>, String)], precedence: &[String], fingerprint: Option<String>, teams: &[String], source: Option<String>) -> Result<Option<Record>, StorageError> {
+        self.records.write().await.merge_records(from, into, precedence, fingerprint, teams, source)
     }
 
-    /// Purpose (The "Why"): Performs selection matching and authorized in-place modification
-    /// of records. It iterates over existing records, validates ownership fingerprint or team
-    /// matches, and inserts or updates fields as specified by modifications.
     #[instrument(skip(self))]
-    fn change_record(&mut self, selections: &[(Option<String>, String)], modifications: &[(String, String)], fingerprint: Option<String>, teams: &[String]) -> Result<usize, StorageError> {
-        if modifications.iter().any(|(k, _)| k.eq_ignore_ascii_case("type")) {
-            return Err(StorageError::InvalidArgument(
-                "type cannot be modified via change - it is set once at record creation".to_string(),
-            ));
-        }
+    async fn apply_batch(&self, ops: &[BatchOp]) -> Result<Vec<BatchOutcome>, BatchError> {
+        self.records.write().await.apply_batch(ops)
+    }
 
-        for (k, v) in modifications {
-            validate_ip_mac_field(k, v)?;
-        }
+    #[instrument(skip(self, records))]
+    async fn replace_records(&self, records: Vec<Record>) -> Result<usize, StorageError> {
+        let mut set = self.records.write().await;
+        set.load_records(records);
+        set.dirty.clear();
+        Ok(set.record_count())
+    }
 
-        let mut to_change_ids = Vec::new();
+    #[instrument(skip(self))]
+    async fn tombstones(&self, selections: &[(Option<String>, String)]) -> Result<Vec<Record>, StorageError> {
+        self.records.read().await.tombstones(selections)
+    }
 
-        for record in &self.records {
-            if self.record_matches_selections(record, selections)? {
-                // Check authorization for modification - identical policy to delete_record
-                let authorized = match (&record.owner_fingerprint, &record.owner_team) {
-                    (Some(fp), _) if fingerprint.as_ref() == Some(fp) => true,
-                    (_, Some(team)) if teams.contains(team) => true,
-                    (None, None) => true, // System records?
-                    _ => false,
-                };
+    #[instrument(skip(self))]
+    async fn undelete_record(&self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String], admin: bool) -> Result<usize, StorageError> {
+        self.records.write().await.undelete_record(selections, fingerprint, teams, admin)
+    }
 
-                if authorized {
-                    to_change_ids.push(record.id);
-                } else {
-                    return Err(StorageError::Unauthorized);
-                }
-            }
-        }
+    #[instrument(skip(self))]
+    async fn purge_tombstones(&self, cutoff: DateTime<Utc>) -> Result<usize, StorageError> {
+        Ok(self.records.write().await.purge_tombstones(cutoff))
+    }
 
-        let changed_count = to_change_ids.len();
-        for record in self.records.iter_mut() {
-            if to_change_ids.contains(&record.id) {
-                for (field, value) in modifications {
-                    if field == "ip_addr" || field == "mac_addr" {
-                        let vec = record.multi_fields.entry(field.clone()).or_default();
-                        if !vec.contains(value) {
-                            vec.push(value.clone());
-                        }
-                    } else {
-                        record.fields.insert(field.clone(), value.clone());
-                    }
-                }
-            }
-        }
+    #[instrument(skip(self))]
+    async fn import_tombstone(&self, identity: &str, deleted_at: &str) -> Result<bool, StorageError> {
+        Ok(self.records.write().await.import_tombstone(identity, deleted_at))
+    }
 
-        Ok(changed_count)
+    async fn field_schema(&self) -> Arc<FieldSchema> {
+        Arc::clone(&self.records.read().await.schema)
     }
 }
 
+/// What FileStorage hands its persistence worker, in the order the writes were applied.
+enum WorkerMessage {
+    Entries(Vec<JournalEntry>),
+    /// Replace the data file with a restored record set and empty the journal. Everything queued
+    /// before it is written first; the outcome is reported back on `done`.
+    Restore { records: Vec<Record>, done: oneshot::Sender<anyhow::Result<()>> },
+}
+
 pub struct FileStorage {
-    memory: MemoryStorage,
+    records: RwLock<RecordSet>,
     path: PathBuf,
-    tx: mpsc::UnboundedSender<Vec<Record>>,
+    snapshot_dir: PathBuf,
+    tx: mpsc::UnboundedSender<WorkerMessage>,
+    /// Seals encrypted fields on their way to disk; without one they are written as they are.
+    field_key: Option<SealingKey>,
+    /// Seals whole files - data file, journal and named snapshots - when there's a storage key.
+    cipher: FileCipher,
+    /// What to do if the data file doesn't parse.
+    on_corrupt: CorruptPolicy,
+    /// Set when a corrupt data file was loaded under `CorruptPolicy::ReadOnly`: every write is
+    /// refused and nothing is persisted.
+    read_only: bool,
+}
+
+/// What the data file held, as `read_snapshot` found it.
+#[derive(Default)]
+struct DataFile {
+    records: Vec<Record>,
+    next_id: usize,
+    /// It isn't sealed with the current storage key, so it should be rewritten.
+    stale: bool,
+    corruption: Option<Corruption>,
+}
+
+/// A data file or journal that didn't read back: which, why, where it was copied, and the
+/// records that didn't survive.
+struct Corruption {
+    kind: &'static str,
+    file: PathBuf,
+    error: String,
+    quarantined: PathBuf,
+    lost: Vec<LostRecord>,
 }
 
 impl FileStorage {
+    /// Loads the last snapshot plus the journal tail, then spawns the background persistence
+    /// worker. Every later write appends only the records it touched to the journal; the worker
+    /// folds the journal back into a fresh snapshot every `compact_every` entries. Files sealed
+    /// with a key `cipher` doesn't hold fail here rather than loading as an empty store, and
+    /// files that aren't sealed with its current key are rewritten with it straight away. A data
+    /// file that doesn't parse is handled as `on_corrupt` says.
     #[instrument]
-    pub fn new(path: PathBuf) -> Self {
-        let (tx, mut rx) = mpsc::unbounded_channel::<Vec<Record>>();
-        let worker_path = path.clone();
+    pub fn open(path: PathBuf, config: JournalConfig, cipher: FileCipher, on_corrupt: CorruptPolicy) -> anyhow::Result<Self> {
+        let (tx, mut rx) = mpsc::unbounded_channel::<WorkerMessage>();
+
+        let mut storage = Self {
+            records: RwLock::new(RecordSet::new()),
+            snapshot_dir: snapshot::snapshot_dir(&path),
+            path,
+            tx,
+            field_key: None,
+            cipher,
+            on_corrupt,
+            read_only: false,
+        };
+        let compact_on_start = storage.load_from_disk()?;
+        if storage.read_only {
+            return Ok(storage);
+        }
+
+        let worker_path = storage.path.clone();
+        let worker_cipher = storage.cipher.clone();
+        let initial_records = storage.records.get_mut().records.values().cloned().collect();
+        let next_id = storage.records.get_mut().next_id;
 
         // Spawn background persistence worker
         tokio::spawn(async move {
             info!("Persistence worker started for {:?}", worker_path);
-            while let Some(records) = rx.recv().await {
-                if let Err(e) = Self::persist_to_disk_atomic(&worker_path, &records) {
-                    error!("Failed to persist records to disk: {}", e);
+            let mut writer = match JournalWriter::open(&worker_path, initial_records, next_id, config, worker_cipher) {
+                Ok(w) => w,
+                Err(e) => {
+                    error!("Failed to open journal for {:?}, writes will not be persisted: {}", worker_path, e);
+                    return;
+                }
+            };
+
+            // A replayed journal is folded into the snapshot before anything new is appended -
+            // this also discards a torn final line, which a later append would otherwise extend.
+            if compact_on_start
+                && let Err(e) = writer.compact()
+            {
+                error!("Failed to compact journal on startup: {}", e);
+            }
+
+            while let Some(message) = rx.recv().await {
+                let mut pending = Some(message);
+                while let Some(message) = pending.take() {
+                    match message {
+                        WorkerMessage::Entries(batch) => {
+                            Self::append_logged(&mut writer, batch);
+                            pending = rx.try_recv().ok();
+                        }
+                        WorkerMessage::Restore { records, done } => {
+                            let _ = done.send(writer.replace(records));
+                        }
+                    }
+                }
+                if let Err(e) = writer.end_batch() {
+                    error!("Failed to fsync journal: {}", e);
+                }
+                if writer.needs_compaction()
+                    && let Err(e) = writer.compact()
+                {
+                    error!("Failed to compact journal: {}", e);
                 }
             }
             info!("Persistence worker shutting down for {:?}", worker_path);
         });
 
-        let mut storage = Self {
-            memory: MemoryStorage::new(),
-            path,
-            tx,
-        };
-        storage.load_from_disk();
-        storage
+        Ok(storage)
+    }
+
+    /// Switches to `schema`. Records holding single values of fields it declares multi-valued
+    /// are migrated, and the migrated records journaled. Set it before the field key, which opens
+    /// the fields it declares encrypted.
+    pub fn with_field_schema(mut self, schema: FieldSchema) -> Self {
+        debug_assert!(self.field_key.is_none(), "the field schema must be set before the field key");
+        let records = self.records.get_mut();
+        let migrated = records.set_schema(schema);
+        if migrated > 0 && !self.read_only {
+            tracing::warn!("Migrated {} records to the multi-valued fields of the field schema", migrated);
+            let entries = records.take_journal_entries();
+            let entries = Self::seal_entries(self.field_key.as_ref(), &records.schema, entries);
+            if let Err(e) = self.tx.send(WorkerMessage::Entries(entries)) {
+                error!("Failed to queue persistence: {}", e);
+            }
+        }
+        self.reseal();
+        self
+    }
+
+    /// Opens the encrypted field values loaded from disk with `key`, and seals with it from now
+    /// on. A sealed value that won't open - what a wrong key looks like - is an error rather than
+    /// a store full of values nobody can read. Only the fields the schema declares encrypted are
+    /// opened, so set the field schema first.
+    pub fn with_field_key(mut self, key: SealingKey) -> anyhow::Result<Self> {
+        let records = self.records.get_mut();
+        let opened = Self::open_sealed(&records.schema, &key, records.records.values().cloned().collect())
+            .map_err(|e| e.context(format!("failed to open the encrypted fields in {:?} with field key {}", self.path, key.id())))?;
+        records.load_records(opened);
+        self.field_key = Some(key);
+        self.reseal();
+        Ok(self)
+    }
+
+    fn open_sealed(schema: &FieldSchema, key: &SealingKey, mut records: Vec<Record>) -> anyhow::Result<Vec<Record>> {
+        for record in records.iter_mut().filter(|r| sealing::has_sealed_values(schema, r)) {
+            sealing::open_record(schema, key, record)?;
+        }
+        Ok(records)
     }
 
-    #[instrument(skip(self))]
-    fn load_from_disk(&mut self) {
+    /// `record` as it is written to disk: with a field key, its encrypted fields are sealed.
+    fn sealed(key: Option<&SealingKey>, schema: &FieldSchema, record: &Record) -> Record {
+        match key {
+            Some(key) => sealing::seal_record(schema, key, record),
+            None => record.clone(),
+        }
+    }
+
+    fn seal_entries(key: Option<&SealingKey>, schema: &FieldSchema, entries: Vec<JournalEntry>) -> Vec<JournalEntry> {
+        if key.is_none() || !schema.has_encrypted_fields() {
+            return entries;
+        }
+        entries
+            .into_iter()
+            .map(|entry| match entry {
+                JournalEntry::Put { record } => JournalEntry::Put { record: Box::new(Self::sealed(key, schema, &record)) },
+                remove => remove,
+            })
+            .collect()
+    }
+
+    /// Rewrites the data file with every encrypted field sealed once both a field key and a schema
+    /// declaring encrypted fields are in place, so values written before the field was declared
+    /// encrypted don't linger on disk in the clear.
+    fn reseal(&mut self) {
+        let records = self.records.get_mut();
+        if self.read_only || self.field_key.is_none() || !records.schema.has_encrypted_fields() {
+            return;
+        }
+        let sealed = records.records.values().map(|r| Self::sealed(self.field_key.as_ref(), &records.schema, r)).collect();
+        let (done, _) = oneshot::channel();
+        if let Err(e) = self.tx.send(WorkerMessage::Restore { records: sealed, done }) {
+            error!("Failed to queue resealing of encrypted fields: {}", e);
+        }
+    }
+
+    fn append_logged(writer: &mut JournalWriter, entries: Vec<JournalEntry>) {
+        if let Err(e) = writer.append(entries) {
+            error!("Failed to append to journal: {}", e);
+        }
+    }
+
+    /// Reads the snapshot file, if any. A missing or empty file is an empty snapshot. One that
+    /// can't be read or doesn't decrypt is an error; one that doesn't parse is copied aside and
+    /// salvaged record by record.
+    fn read_snapshot(&self) -> anyhow::Result<DataFile> {
         if !self.path.exists() {
             info!("No existing data file found at {:?}", self.path);
-            return;
+            return Ok(DataFile::default());
         }
 
-        let mut file = match File::open(&self.path) {
-            Ok(f) => f,
-            Err(e) => {
-                error!("Failed to open storage file: {}", e);
-                return;
-            }
-        };
+        let bytes = std::fs::read(&self.path)
+            .map_err(|e| anyhow::Error::from(e).context(format!("failed to read data file {:?}", self.path)))?;
+        let text = String::from_utf8_lossy(&bytes);
+        let stale = self.cipher.is_stale(&text);
+        if text.is_empty() {
+            return Ok(DataFile { stale, ..Default::default() });
+        }
 
-        let mut data = String::new();
-        if let Err(e) = file.read_to_string(&mut data) {
-            error!("Failed to read storage file: {}", e);
-            return;
+        // A key that isn't configured is a configuration error; one that is but fails to
+        // authenticate the file means the file is damaged, and nothing in it can be salvaged.
+        let key = self.cipher.key_for(&text).map_err(|e| e.context(format!("failed to decrypt data file {:?}", self.path)))?;
+        let data = match key {
+            Some(key) => match key.open(file_cipher::RECORDS, &text) {
+                Ok(opened) => std::borrow::Cow::Owned(opened),
+                Err(e) => return self.corrupt_data_file(&bytes, format!("it does not decrypt with storage key {}: {}", key.id(), e), "", stale),
+            },
+            None => std::borrow::Cow::Borrowed(text.as_ref()),
+        };
+        let parsed = match text {
+            std::borrow::Cow::Borrowed(_) => journal::parse_data_file(&data).map_err(|e| e.to_string()),
+            std::borrow::Cow::Owned(_) => Err("not valid UTF-8".to_string()),
+        };
+        match parsed {
+            Ok((records, next_id)) => Ok(DataFile { records, next_id, stale, corruption: None }),
+            Err(error) => self.corrupt_data_file(&bytes, error, &data, stale),
         }
+    }
 
-        if data.is_empty() {
-            return;
+    /// Copies a corrupt data file aside and salvages what still parses of `data`, its contents.
+    fn corrupt_data_file(&self, bytes: &[u8], error: String, data: &str, stale: bool) -> anyhow::Result<DataFile> {
+        error!("Failed to read storage file {:?}: {}", self.path, error);
+        let quarantined = recovery::quarantine(&self.path, bytes, Utc::now()).map_err(|e| {
+            anyhow::Error::from(e).context(format!("data file {:?} is corrupt ({}) and could not be copied aside", self.path, error))
+        })?;
+        let salvage = recovery::salvage(data);
+        let next_id = journal::high_water_mark(&salvage.records, salvage.next_id.unwrap_or(1));
+        let corruption = Corruption { kind: "data file", file: self.path.clone(), error, quarantined, lost: salvage.lost };
+        Ok(DataFile { records: salvage.records, next_id, stale, corruption: Some(corruption) })
+    }
+
+    /// Copies a damaged journal aside. Every entry from the damaged line on is lost.
+    fn damaged_journal(path: &Path, damage: journal::JournalDamage) -> anyhow::Result<Corruption> {
+        let bytes = std::fs::read(path).map_err(|e| anyhow::Error::from(e).context(format!("failed to read journal {:?}", path)))?;
+        let quarantined = recovery::quarantine(path, &bytes, Utc::now()).map_err(|e| {
+            anyhow::Error::from(e).context(format!("journal {:?} is corrupt ({}) and could not be copied aside", path, damage.error))
+        })?;
+        let lost = damage
+            .dropped
+            .iter()
+            .enumerate()
+            .map(|(i, line)| LostRecord {
+                line: *line,
+                id: None,
+                name: None,
+                error: if i == 0 { damage.error.clone() } else { "follows a damaged line".to_string() },
+            })
+            .collect();
+        let error = format!("line {}: {}", damage.dropped[0], damage.error);
+        Ok(Corruption { kind: "journal", file: path.to_path_buf(), error, quarantined, lost })
+    }
+
+    /// Reports a corrupt data file or journal, each lost record on its own line, and applies
+    /// `on_corrupt`: an error under `Refuse`, read-only under `ReadOnly`. Records the journal put
+    /// back aren't lost. Returns whether the salvaged set should be written back as the data file.
+    fn handle_corruption(&mut self, corruption: Corruption, records: &[Record]) -> anyhow::Result<bool> {
+        let lost: Vec<LostRecord> = corruption
+            .lost
+            .into_iter()
+            .filter(|l| l.id.is_none_or(|id| !records.iter().any(|r| r.id == id)))
+            .collect();
+        for record in &lost {
+            error!("Lost {} of corrupt {} {:?}", record, corruption.kind, corruption.file);
         }
+        let report = format!(
+            "{} {:?} is corrupt ({}) and was copied to {:?}; {} records were recovered and {} lost",
+            corruption.kind, corruption.file, corruption.error, corruption.quarantined, records.len(), lost.len()
+        );
+        match self.on_corrupt {
+            CorruptPolicy::Refuse => {
+                let lost: Vec<String> = lost.iter().map(LostRecord::to_string).collect();
+                anyhow::bail!(
+                    "{}{}{}. Set PHAROS_STORAGE_ON_CORRUPT=read-only to inspect what was recovered, or recover to write it back",
+                    report,
+                    if lost.is_empty() { "" } else { ": " },
+                    lost.join("; ")
+                )
+            }
+            CorruptPolicy::ReadOnly => {
+                error!("{}; starting read-only", report);
+                self.read_only = true;
+                Ok(false)
+            }
+            CorruptPolicy::Recover => {
+                tracing::warn!("{}; writing the recovered records back", report);
+                Ok(true)
+            }
+        }
+    }
 
-        match serde_json::from_str::<Vec<Record>>(&data) {
-            Ok(mut records) => {
-                let max_id = records.iter().map(|r| r.id).max().unwrap_or(0);
-                let mut corrected_count = 0;
-                let mut migrated_multi_value_count = 0;
+    /// Recovers the record set: the snapshot, with the journal tail replayed over it, followed
+    /// by the self-heal and legacy-field migrations. Returns whether the result differs from
+    /// what's in the snapshot (or the snapshot isn't sealed with the current storage key), i.e.
+    /// whether the worker should compact before its first append.
+    #[instrument(skip(self))]
+    fn load_from_disk(&mut self) -> anyhow::Result<bool> {
+        let (mut records, next_id, rewrite) = self.read_from_disk()?;
+        let schema = Arc::clone(&self.records.get_mut().schema);
+        let (corrected_count, migrated_multi_value_count) = heal_records(&mut records, &schema);
+
+        if corrected_count > 0 {
+            tracing::warn!(
+                "Self-healed {} records with missing or stale record_type",
+                corrected_count
+            );
+        }
+        if migrated_multi_value_count > 0 {
+            tracing::warn!(
+                "Migrated plain-string values of multi-valued fields into multi_fields on {} records",
+                migrated_multi_value_count
+            );
+        }
 
-                for record in records.iter_mut() {
-                    if let Some(type_str) = record.fields.get("type") {
-                        let parsed_type = RecordType::from(type_str.as_str());
-                        if record.record_type.as_ref() != Some(&parsed_type) {
-                            record.record_type = Some(parsed_type);
-                            corrected_count += 1;
-                        }
-                    } else {
-                        tracing::warn!(
-                            "record ID {} has no type field, cannot self-heal, remains invisible to mdb/ph queries — needs manual correction",
-                            record.id
-                        );
-                    }
+        let loaded = self.records.get_mut();
+        loaded.next_id = next_id;
+        loaded.load_records(records);
+        info!("Loaded {} records from {:?}", loaded.records.len(), self.path);
 
-                    // Migrate legacy plain-string ip_addr/mac_addr fields (from before these
-                    // became multi-valued) into multi_fields. A record with the same key present
-                    // in both maps would otherwise silently shadow the correct multi_fields data
-                    // forever in query responses (fields is checked first) - confirmed live in
-                    // production on a record created before this feature existed.
-                    for key in ["ip_addr", "mac_addr"] {
-                        if let Some(legacy_val) = record.fields.remove(key) {
-                            let vec = record.multi_fields.entry(key.to_string()).or_default();
-                            if !vec.contains(&legacy_val) {
-                                vec.push(legacy_val);
-                            }
-                            migrated_multi_value_count += 1;
-                        }
-                    }
-                }
+        Ok(rewrite || corrected_count > 0 || migrated_multi_value_count > 0)
+    }
 
-                if corrected_count > 0 {
-                    tracing::warn!(
-                        "Self-healed {} records with missing or stale record_type",
-                        corrected_count
-                    );
-                }
-                if migrated_multi_value_count > 0 {
-                    tracing::warn!(
-                        "Migrated {} legacy plain-string ip_addr/mac_addr fields into multi_fields",
-                        migrated_multi_value_count
-                    );
-                }
+    /// The snapshot with the journal tail replayed over it, exactly as stored, a corrupt data
+    /// file handled as `on_corrupt` says, and the next id. Also returns whether the data file
+    /// should be rewritten: it's stale or was salvaged, or the journal has entries or a torn tail.
+    fn read_from_disk(&mut self) -> anyhow::Result<(Vec<Record>, usize, bool)> {
+        let DataFile { mut records, mut next_id, stale, corruption } = self.read_snapshot()?;
 
-                self.memory.records = records;
-                self.memory.next_id = max_id + 1;
-                if corrected_count > 0 || migrated_multi_value_count > 0 {
-                    self.queue_persistence();
-                }
-                info!("Loaded {} records from {:?}", self.memory.records.len(), self.path);
-            }
-            Err(e) => {
-                error!("Failed to parse storage file: {}", e);
+        let journal_path = journal::journal_path(&self.path);
+        let replay = match journal::read_journal(&journal_path, &self.cipher) {
+            Ok(r) => r,
+            Err(e) if e.is::<std::io::Error>() => {
+                error!("Failed to read journal {:?}: {}", journal_path, e);
+                journal::Replay::default()
             }
+            Err(e) => return Err(e.context(format!("failed to decrypt journal {:?}", journal_path))),
+        };
+        let replayed_count = replay.entries.len();
+        let journal_torn = replay.truncated_at_line.is_some();
+        let journal_damage = replay.damage.map(|damage| Self::damaged_journal(&journal_path, damage)).transpose()?;
+        if replayed_count > 0 {
+            let mut by_id: BTreeMap<usize, Record> = records.into_iter().map(|r| (r.id, r)).collect();
+            next_id = next_id.max(journal::apply_entries(&mut by_id, replay.entries));
+            records = by_id.into_values().collect();
+            info!("Replayed {} journal entries from {:?}", replayed_count, journal_path);
+        }
+        let mut recovered = false;
+        for corruption in corruption.into_iter().chain(journal_damage) {
+            recovered |= self.handle_corruption(corruption, &records)?;
         }
+        Ok((records, next_id, stale || recovered || replayed_count > 0 || journal_torn))
     }
 
-    /// Atomically replaces the storage file using a temporary file and rename.
-    fn persist_to_disk_atomic(path: &Path, records: &[Record]) -> anyhow::Result<()> {
-        debug!("Starting atomic persistence to {:?}", path);
-        let data = serde_json::to_string_pretty(records)?;
-        
-        let tmp_path = path.with_extension("tmp");
-        {
-            let mut file = File::create(&tmp_path)?;
-            file.write_all(data.as_bytes())?;
-            file.sync_all()?;
-        }
+    /// The records in the data file at `path` and its journal, exactly as stored: not healed or
+    /// migrated, and with encrypted fields still sealed. Nothing is written, apart from the copy
+    /// of a corrupt data file. For offline tools reading a stopped server's data.
+    pub fn read_records(path: PathBuf, cipher: FileCipher, on_corrupt: CorruptPolicy) -> anyhow::Result<Vec<Record>> {
+        let (tx, _) = mpsc::unbounded_channel();
+        let mut storage = Self {
+            records: RwLock::new(RecordSet::new()),
+            snapshot_dir: snapshot::snapshot_dir(&path),
+            path,
+            tx,
+            field_key: None,
+            cipher,
+            on_corrupt,
+            read_only: true,
+        };
+        Ok(storage.read_from_disk()?.0)
+    }
 
-        std::fs::rename(tmp_path, path)?;
-        debug!("Atomic persistence completed successfully for {:?}", path);
-        Ok(())
+    /// Whether writes are refused because a corrupt data file was loaded read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// The record set, locked for a write - refused while the store is read-only.
+    async fn writable(&self) -> Result<tokio::sync::RwLockWriteGuard<'_, RecordSet>, StorageError> {
+        if self.read_only {
+            return Err(StorageError::ReadOnly);
+        }
+        Ok(self.records.write().await)
     }
 
-    fn queue_persistence(&self) {
-        if let Err(e) = self.tx.send(self.memory.records.clone()) {
+    /// Hands the records a write touched to the persistence worker. Called with the write lock
+    /// still held, so journal order always matches the order writes were applied in.
+    fn queue_persistence(&self, records: &mut RecordSet) {
+        let entries = records.take_journal_entries();
+        if entries.is_empty() {
+            return;
+        }
+        let entries = Self::seal_entries(self.field_key.as_ref(), &records.schema, entries);
+        if let Err(e) = self.tx.send(WorkerMessage::Entries(entries)) {
             error!("Failed to queue persistence: {}", e);
         }
     }
 }
 
+#[async_trait]
 impl Storage for FileStorage {
-    fn record_count(&self) -> usize {
-        self.memory.record_count()
+    async fn record_count(&self) -> usize {
+        self.records.read().await.record_count()
     }
 
-    fn add_record(&mut self, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<(), StorageError> {
-        self.memory.add_record(fields, fingerprint, team)?;
-        self.queue_persistence();
+    async fn add_record(&self, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<(), StorageError> {
+        let mut records = self.writable().await?;
+        records.add_record(fields, fingerprint, team)?;
+        self.queue_persistence(&mut records);
         Ok(())
     }
 
-    fn query(&self, selections: &[(Option<String>, String)], default_type: Option<RecordType>) -> Result<Vec<Record>, StorageError> {
-        self.memory.query(selections, default_type)
+    async fn query(&self, selections: &[(Option<String>, String)], default_type: Option<RecordType>) -> Result<Vec<Record>, StorageError> {
+        self.records.read().await.query(selections, default_type)
     }
 
-    fn upsert_record(&mut self, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<UpsertOutcome, StorageError> {
-        let outcome = self.memory.upsert_record(fields, fingerprint, team)?;
-        self.queue_persistence();
+    async fn upsert_record(&self, fields: Vec<(String, String)>, fingerprint: Option<String>, team: Option<String>) -> Result<UpsertOutcome, StorageError> {
+        let mut records = self.writable().await?;
+        let outcome = records.upsert_record(fields, fingerprint, team)?;
+        self.queue_persistence(&mut records);
         Ok(outcome)
     }
 
-    fn delete_record(&mut self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String]) -> Result<usize, StorageError> {
-        let count = self.memory.delete_record(selections, fingerprint, teams)?;
+    async fn delete_record(&self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String]) -> Result<usize, StorageError> {
+        let mut records = self.writable().await?;
+        let count = records.delete_record(selections, fingerprint, teams)?;
         if count > 0 {
-            self.queue_persistence();
+            self.queue_persistence(&mut records);
         }
         Ok(count)
     }
 
-    /// Purpose (The "Why"): Delegates modification to MemoryStorage and triggers storage
-    /// persistence when modifications are actually applied.
-    fn change_record(&mut self, selections: &[(Option<String>, String)], modifications: &[(String, String)], fingerprint: Option<String>, teams: &[String]) -> Result<usize, StorageError> {
-        let count = self.memory.change_record(selections, modifications, fingerprint, teams)?;
+    async fn delete_record_if(&self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String], still: RecordGuard) -> Result<usize, StorageError> {
+        let mut records = self.writable().await?;
+        let count = records.delete_record_if(selections, fingerprint, teams, &*still)?;
         if count > 0 {
-            self.queue_persistence();
+            self.queue_persistence(&mut records);
         }
         Ok(count)
     }
-}
-
-pub struct LdapStorage {
-    // Config
-    url: String,
-    bind_dn: String,
-    bind_pw: String,
-    base_dn: String,
-    
-    // Schema mapping
-    // Ph Field -> LDAP Attribute
-    field_map: HashMap<String, String>,
-}
 
-impl LdapStorage {
-    pub fn new(url: String, bind_dn: String, bind_pw: String, base_dn: String) -> Self {
-        let mut field_map = HashMap::new();
-        // Default mappings
-        field_map.insert("name".to_string(), "cn".to_string());
-        field_map.insert("email".to_string(), "mail".to_string());
-        field_map.insert("phone".to_string(), "telephoneNumber".to_string());
-        field_map.insert("hostname".to_string(), "cn".to_string());
-        field_map.insert("ip".to_string(), "ipHostNumber".to_string());
-
-        Self {
-            url,
-            bind_dn,
-            bind_pw,
-            base_dn,
-            field_map,
+    /// Purpose (The "Why"): Delegates modification to the record set and triggers storage
+    /// persistence when modifications are actually applied.
+    async fn change_record(&self, selections: &[(Option<String>, String)], modifications: &[(String, String)], fingerprint: Option<String>, teams: &[String], source: Option<String>, force: bool) -> Result<usize, StorageError> {
+        let mut records = self.writable().await?;
+        let count = records.change_record(selections, modifications, fingerprint, teams, source, force, None)?;
+        if count > 0 {
+            self.queue_persistence(&mut records);
         }
+        Ok(count)
     }
 
-    fn build_filter(&self, selections: &[(Option<String>, String)], default_type: Option<RecordType>) -> String {
-        let mut filters = Vec::new();
-
-        if let Some(ref dt) = default_type {
-            match dt {
-                RecordType::Person => filters.push("(objectClass=inetOrgPerson)".to_string()),
-                RecordType::Machine => filters.push("(objectClass=ipHost)".to_string()),
-                RecordType::Other(s) => filters.push(format!("(objectClass={})", s)),
-            }
-        }
-
-        for (field_opt, val) in selections {
-            if let Some(field_name) = field_opt {
-                let ldap_attr = self.field_map.get(field_name).cloned().unwrap_or_else(|| field_name.clone());
-                filters.push(format!("({}={})", ldap_attr, val));
-            } else {
-                filters.push(format!("(|(cn={})(mail={}))", val, val));
-            }
+    #[allow(clippy::too_many_arguments)]
+    async fn change_record_if_revision(&self, selections: &[(Option<String>, String)], modifications: &[(String, String)], fingerprint: Option<String>, teams: &[String], source: Option<String>, force: bool, revision: u64) -> Result<usize, StorageError> {
+        let mut records = self.writable().await?;
+        let count = records.change_record(selections, modifications, fingerprint, teams, source, force, Some(revision))?;
+        if count > 0 {
+            self.queue_persistence(&mut records);
         }
+        Ok(count)
+    }
 
-        if filters.len() > 1 {
-            format!("(&{})", filters.join(""))
-        } else if !filters.is_empty() {
-            filters[0].clone()
-        } else {
-            "(objectClass=*)".to_string()
+    async fn merge_records(&self, from: &[(Option<String>, String)], into: &[(Option<String>, String)], precedence: &[String], fingerprint: Option<String>, teams: &[String], source: Option<String>) -> Result<Option<Record>, StorageError> {
+        let mut records = self.writable().await?;
+        let survivor = records.merge_records(from, into, precedence, fingerprint, teams, source)?;
+        if survivor.is_some() {
+            self.queue_persistence(&mut records);
         }
+        Ok(survivor)
     }
-}
 
-impl Storage for LdapStorage {
-    #[instrument(skip(self))]
-    fn record_count(&self) -> usize {
-        0
+    /// The whole transaction is queued for persistence as one journal batch.
+    async fn apply_batch(&self, ops: &[BatchOp]) -> Result<Vec<BatchOutcome>, BatchError> {
+        let mut records = self.writable().await.map_err(|error| BatchError { index: 0, error })?;
+        let outcomes = records.apply_batch(ops)?;
+        self.queue_persistence(&mut records);
+        Ok(outcomes)
     }
 
-    #[instrument(skip(self))]
-    fn add_record(&mut self, _fields: Vec<(String, String)>, _fingerprint: Option<String>, _team: Option<String>) -> Result<(), StorageError> {
-        error!("LDAP storage is currently read-only (Write operations pending Task 4.3)");
-        Err(StorageError::ReadOnly)
+    async fn replace_records(&self, records: Vec<Record>) -> Result<usize, StorageError> {
+        let count = self.replace_all(records).await?;
+        info!("Replaced every record ({} live)", count);
+        Ok(count)
     }
 
-    #[instrument(skip(self))]
-    fn query(&self, selections: &[(Option<String>, String)], default_type: Option<RecordType>) -> Result<Vec<Record>, StorageError> {
-        info!("Executing LDAP query...");
-        
-        let filter = self.build_filter(selections, default_type);
-        info!("LDAP Filter: {}", filter);
-
-        let mut ldap = match ldap3::LdapConn::new(&self.url) {
-            Ok(conn) => conn,
-            Err(e) => {
-                error!("Failed to connect to LDAP server: {}", e);
-                return Ok(Vec::new());
-            }
-        };
+    async fn tombstones(&self, selections: &[(Option<String>, String)]) -> Result<Vec<Record>, StorageError> {
+        self.records.read().await.tombstones(selections)
+    }
 
-        if let Err(e) = ldap.simple_bind(&self.bind_dn, &self.bind_pw) {
-            error!("Failed to bind to LDAP: {}", e);
-            return Ok(Vec::new());
+    async fn undelete_record(&self, selections: &[(Option<String>, String)], fingerprint: Option<String>, teams: &[String], admin: bool) -> Result<usize, StorageError> {
+        let mut records = self.writable().await?;
+        let count = records.undelete_record(selections, fingerprint, teams, admin)?;
+        if count > 0 {
+            self.queue_persistence(&mut records);
         }
+        Ok(count)
+    }
 
-        let rs = match ldap.search(
-            &self.base_dn,
-            ldap3::Scope::Subtree,
-            &filter,
-            vec!["*"]
-        ) {
-            Ok(res) => match res.success() {
-                Ok((entries, _)) => entries,
-                Err(e) => {
-                    error!("LDAP search successful but returned error result: {}", e);
-                    return Ok(Vec::new());
-                }
-            },
-            Err(e) => {
-                error!("LDAP search failed: {}", e);
-                return Ok(Vec::new());
-            }
-        };
-
-        let mut records = Vec::new();
-        for (i, entry) in rs.into_iter().enumerate() {
-            let search_entry = ldap3::SearchEntry::construct(entry);
-            let mut fields = HashMap::new();
-            
-            for (attr, vals) in search_entry.attrs {
-                if !vals.is_empty() {
-                    let ph_field = self.field_map.iter()
-                        .find(|(_, ldap_attr)| **ldap_attr == attr)
-                        .map(|(k, _)| k.clone())
-                        .unwrap_or(attr);
-                    
-                    fields.insert(ph_field, vals.join(", "));
-                }
-            }
-
-            let record_type = if fields.get("objectClass").map(|s| s.contains("inetOrgPerson")).unwrap_or(false) {
-                Some(RecordType::Person)
-            } else if fields.get("objectClass").map(|s| s.contains("ipHost")).unwrap_or(false) {
-                Some(RecordType::Machine)
-            } else {
-                None
-            };
-
-            records.push(Record {
-                id: i + 1,
-                record_type,
-                fields,
-                multi_fields: HashMap::new(),
-                owner_fingerprint: None,
-                owner_team: None,
-            });
+    async fn purge_tombstones(&self, cutoff: DateTime<Utc>) -> Result<usize, StorageError> {
+        let mut records = self.writable().await?;
+        let count = records.purge_tombstones(cutoff);
+        if count > 0 {
+            self.queue_persistence(&mut records);
         }
+        Ok(count)
+    }
 
-        Ok(records)
+    async fn import_tombstone(&self, identity: &str, deleted_at: &str) -> Result<bool, StorageError> {
+        let mut records = self.writable().await?;
+        let applied = records.import_tombstone(identity, deleted_at);
+        if applied {
+            self.queue_persistence(&mut records);
+        }
+        Ok(applied)
     }
 
-    #[instrument(skip(self))]
-    fn upsert_record(&mut self, _fields: Vec<(String, String)>, _fingerprint: Option<String>, _team: Option<String>) -> Result<UpsertOutcome, StorageError> {
-        error!("LDAP storage is currently read-only (Write operations pending Task 4.3)");
-        Err(StorageError::ReadOnly)
+    async fn field_schema(&self) -> Arc<FieldSchema> {
+        Arc::clone(&self.records.read().await.schema)
     }
 
-    #[instrument(skip(self))]
-    fn delete_record(&mut self, _selections: &[(Option<String>, String)], _fingerprint: Option<String>, _teams: &[String]) -> Result<usize, StorageError> {
-        error!("LDAP storage is currently read-only");
-        Err(StorageError::ReadOnly)
+    /// Copies the record set under the read lock - writers wait, so the copy is one point in
+    /// time - then writes it out after the lock is released.
+    async fn create_snapshot(&self, name: &str) -> Result<SnapshotInfo, StorageError> {
+        snapshot::validate_name(name)?;
+        let (records, next_id): (Vec<Record>, usize) = {
+            let set = self.records.read().await;
+            (set.records.values().map(|r| Self::sealed(self.field_key.as_ref(), &set.schema, r)).collect(), set.next_id)
+        };
+        let (dir, name, cipher) = (self.snapshot_dir.clone(), name.to_string(), self.cipher.clone());
+        let info = tokio::task::spawn_blocking(move || snapshot::write(&dir, &name, &records, next_id, &cipher))
+            .await
+            .map_err(|e| StorageError::Internal(format!("snapshot task failed: {}", e)))??;
+        info!("Wrote snapshot '{}' to {:?}", info.name, self.snapshot_dir);
+        Ok(info)
+    }
+
+    async fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>, StorageError> {
+        let dir = self.snapshot_dir.clone();
+        tokio::task::spawn_blocking(move || snapshot::list(&dir))
+            .await
+            .map_err(|e| StorageError::Internal(format!("snapshot task failed: {}", e)))?
+    }
+
+    /// Swapped in with `replace_all`, so nothing changes if the data file can't be rewritten.
+    async fn restore_snapshot(&self, name: &str) -> Result<usize, StorageError> {
+        let (dir, snapshot_name, cipher) = (self.snapshot_dir.clone(), name.to_string(), self.cipher.clone());
+        let restored = tokio::task::spawn_blocking(move || snapshot::read(&dir, &snapshot_name, &cipher))
+            .await
+            .map_err(|e| StorageError::Internal(format!("snapshot task failed: {}", e)))??;
+
+        let restored = match &self.field_key {
+            Some(key) => Self::open_sealed(&*self.field_schema().await, key, restored).map_err(|e| StorageError::Internal(format!("failed to open the snapshot's encrypted fields: {:#}", e)))?,
+            None => restored,
+        };
+        let count = self.replace_all(restored).await?;
+        info!("Restored snapshot '{}' ({} records)", name, count);
+        Ok(count)
     }
+}
 
-    /// Purpose: Enforces read-only behavior for LDAP storage when changes are attempted.
-    #[instrument(skip(self))]
-    fn change_record(&mut self, _selections: &[(Option<String>, String)], _modifications: &[(String, String)], _fingerprint: Option<String>, _teams: &[String]) -> Result<usize, StorageError> {
-        error!("LDAP storage is currently read-only (Write operations pending Task 4.3)");
-        Err(StorageError::ReadOnly)
+impl FileStorage {
+    /// Swaps in `replacement` as the whole record set, under the write lock for the whole swap:
+    /// no write can slip in between, and the persistence worker has drained everything queued
+    /// before it and rewritten the data file before the in-memory set is swapped. If the worker
+    /// can't write, nothing changes.
+    async fn replace_all(&self, replacement: Vec<Record>) -> Result<usize, StorageError> {
+        let mut records = self.writable().await?;
+        let to_disk = replacement.iter().map(|r| Self::sealed(self.field_key.as_ref(), &records.schema, r)).collect();
+        let (done, written) = oneshot::channel();
+        self.tx
+            .send(WorkerMessage::Restore { records: to_disk, done })
+            .map_err(|_| StorageError::Internal("persistence worker is not running".to_string()))?;
+        written
+            .await
+            .map_err(|_| StorageError::Internal("persistence worker stopped during restore".to_string()))?
+            .map_err(|e| StorageError::Internal(format!("failed to write restored data file: {}", e)))?;
+
+        records.load_records(replacement);
+        records.dirty.clear();
+        Ok(records.record_count())
     }
 }
 
@@ -908,237 +2183,249 @@ impl Storage for LdapStorage {
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_should_inject_created_at_and_last_seen_at_on_add() {
-        let mut storage = MemoryStorage::new();
+    /// FileStorage tests share fixed paths under the system temp dir, so both the snapshot and
+    /// its journal must be removed - a leftover journal would be replayed by the next run.
+    fn remove_storage_files(path: &std::path::Path) {
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(journal::journal_path(path));
+    }
+
+    /// A FileStorage over `path` whose files are in the clear, with the environment's journal
+    /// settings.
+    fn open_file_storage(path: PathBuf) -> FileStorage {
+        FileStorage::open(path, JournalConfig::from_env(), FileCipher::default(), CorruptPolicy::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_should_inject_created_at_and_last_seen_at_on_add() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "person".to_string()),
             ("name".to_string(), "John Doe".to_string()),
         ];
-        storage.add_record(fields, None, None).unwrap();
+        storage.add_record(fields, None, None).await.unwrap();
 
-        let results = storage.query(&[(Some("name".to_string()), "john".to_string())], None).unwrap();
+        let results = storage.query(&[(Some("name".to_string()), "john".to_string())], None).await.unwrap();
         assert!(results[0].fields.contains_key("created_at"));
         assert!(results[0].fields.contains_key("last_seen_at"));
     }
 
-    #[test]
-    fn test_should_update_last_seen_at_but_preserve_created_at_on_upsert() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_update_last_seen_at_but_preserve_created_at_on_upsert() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "machine".to_string()),
             ("hostname".to_string(), "srv-01".to_string()),
         ];
-        storage.upsert_record(fields.clone(), None, None).unwrap();
+        storage.upsert_record(fields.clone(), None, None).await.unwrap();
 
-        let initial_results = storage.query(&[(Some("hostname".to_string()), "srv-01".to_string())], None).unwrap();
+        let initial_results = storage.query(&[(Some("hostname".to_string()), "srv-01".to_string())], None).await.unwrap();
         let created_at = initial_results[0].fields.get("created_at").unwrap().clone();
 
         let mut update_fields = fields.clone();
         update_fields.push(("status".to_string(), "online".to_string()));
-        storage.upsert_record(update_fields, None, None).unwrap();
+        storage.upsert_record(update_fields, None, None).await.unwrap();
 
-        let updated_results = storage.query(&[(Some("hostname".to_string()), "srv-01".to_string())], None).unwrap();
+        let updated_results = storage.query(&[(Some("hostname".to_string()), "srv-01".to_string())], None).await.unwrap();
         assert_eq!(updated_results[0].fields.get("created_at").unwrap(), &created_at);
         assert!(updated_results[0].fields.contains_key("last_seen_at"));
     }
 
-    #[test]
-    fn test_should_return_matching_record_when_query_matches_name() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_return_matching_record_when_query_matches_name() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "person".to_string()),
             ("name".to_string(), "John Doe".to_string()),
             ("email".to_string(), "john@example.com".to_string()),
         ];
-        storage.add_record(fields, None, None).unwrap();
+        storage.add_record(fields, None, None).await.unwrap();
 
         let selections = vec![(Some("name".to_string()), "john".to_string())];
-        let results = storage.query(&selections, None).unwrap();
+        let results = storage.query(&selections, None).await.unwrap();
         assert_eq!(results.len(), 1);
         assert_eq!(results[0].fields.get("email").unwrap(), "john@example.com");
     }
 
-    #[test]
-    fn test_should_return_empty_when_query_does_not_match() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_return_empty_when_query_does_not_match() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "person".to_string()),
             ("name".to_string(), "John Doe".to_string()),
         ];
-        storage.add_record(fields, None, None).unwrap();
+        storage.add_record(fields, None, None).await.unwrap();
 
         let selections = vec![(Some("name".to_string()), "jane".to_string())];
-        let results = storage.query(&selections, None).unwrap();
+        let results = storage.query(&selections, None).await.unwrap();
         assert_eq!(results.len(), 0);
     }
 
-    #[test]
-    fn test_should_support_wildcard_matching() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_support_wildcard_matching() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "person".to_string()),
             ("name".to_string(), "John Doe".to_string()),
         ];
-        storage.add_record(fields, None, None).unwrap();
+        storage.add_record(fields, None, None).await.unwrap();
 
         let selections = vec![(Some("name".to_string()), "jo*".to_string())];
-        let results = storage.query(&selections, None).unwrap();
+        let results = storage.query(&selections, None).await.unwrap();
         assert_eq!(results.len(), 1);
     }
 
     #[test]
     fn test_hand_checkable_cases() {
-        let storage = MemoryStorage::new();
-        assert!(storage.wildcard_match("ab", "+").unwrap());
-        assert!(!storage.wildcard_match("", "+").unwrap());
-        assert!(storage.wildcard_match("abc", "a?c").unwrap());
-        assert!(!storage.wildcard_match("ac", "a?c").unwrap());
+        assert!(wildcard_match("ab", "+").unwrap());
+        assert!(!wildcard_match("", "+").unwrap());
+        assert!(wildcard_match("abc", "a?c").unwrap());
+        assert!(!wildcard_match("ac", "a?c").unwrap());
     }
 
-    #[test]
-    fn test_wildcard_advanced_patterns() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_wildcard_advanced_patterns() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "person".to_string()),
             ("name".to_string(), "John Doe".to_string()),
         ];
-        storage.add_record(fields, None, None).unwrap();
+        storage.add_record(fields, None, None).await.unwrap();
 
-        let results = storage.query(&[(Some("name".to_string()), "*doe".to_string())], None).unwrap();
+        let results = storage.query(&[(Some("name".to_string()), "*doe".to_string())], None).await.unwrap();
         assert_eq!(results.len(), 1);
-        let results = storage.query(&[(Some("name".to_string()), "j*n".to_string())], None).unwrap();
+        let results = storage.query(&[(Some("name".to_string()), "j*n".to_string())], None).await.unwrap();
         assert_eq!(results.len(), 1);
 
-        let results = storage.query(&[(Some("name".to_string()), "jo+n".to_string())], None).unwrap();
+        let results = storage.query(&[(Some("name".to_string()), "jo+n".to_string())], None).await.unwrap();
         assert_eq!(results.len(), 1);
-        let results = storage.query(&[(Some("name".to_string()), "jn+".to_string())], None).unwrap();
+        let results = storage.query(&[(Some("name".to_string()), "jn+".to_string())], None).await.unwrap();
         assert_eq!(results.len(), 0);
 
-        let results = storage.query(&[(Some("name".to_string()), "j?hn".to_string())], None).unwrap();
+        let results = storage.query(&[(Some("name".to_string()), "j?hn".to_string())], None).await.unwrap();
         assert_eq!(results.len(), 1);
-        let results = storage.query(&[(Some("name".to_string()), "j??hn".to_string())], None).unwrap();
+        let results = storage.query(&[(Some("name".to_string()), "j??hn".to_string())], None).await.unwrap();
         assert_eq!(results.len(), 0);
 
-        let results = storage.query(&[(Some("name".to_string()), "[jrg]ohn".to_string())], None).unwrap();
+        let results = storage.query(&[(Some("name".to_string()), "[jrg]ohn".to_string())], None).await.unwrap();
         assert_eq!(results.len(), 1);
 
-        let results = storage.query(&[(Some("name".to_string()), "j?hn*".to_string())], None).unwrap();
+        let results = storage.query(&[(Some("name".to_string()), "j?hn*".to_string())], None).await.unwrap();
         assert_eq!(results.len(), 1);
-        let results = storage.query(&[(Some("name".to_string()), "[jrg]oh?".to_string())], None).unwrap();
+        let results = storage.query(&[(Some("name".to_string()), "[jrg]oh?".to_string())], None).await.unwrap();
         assert_eq!(results.len(), 1);
 
-        let results = storage.query(&[(Some("name".to_string()), "[ab".to_string())], None);
+        let results = storage.query(&[(Some("name".to_string()), "[ab".to_string())], None).await;
         assert!(matches!(results, Err(StorageError::InvalidArgument(_))));
-        let results = storage.query(&[(Some("name".to_string()), "[]".to_string())], None);
+        let results = storage.query(&[(Some("name".to_string()), "[]".to_string())], None).await;
         assert!(matches!(results, Err(StorageError::InvalidArgument(_))));
 
-        let results = storage.query(&[(Some("name".to_string()), "abc]".to_string())], None);
+        let results = storage.query(&[(Some("name".to_string()), "abc]".to_string())], None).await;
         assert!(matches!(results, Err(StorageError::InvalidArgument(_))));
     }
 
-    #[test]
-    fn test_should_match_any_field_when_no_field_name_provided() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_match_any_field_when_no_field_name_provided() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "person".to_string()),
             ("name".to_string(), "John Doe".to_string()),
             ("alias".to_string(), "jdoe".to_string()),
         ];
-        storage.add_record(fields, None, None).unwrap();
+        storage.add_record(fields, None, None).await.unwrap();
 
         let selections = vec![(None, "jdoe".to_string())];
-        let results = storage.query(&selections, None).unwrap();
+        let results = storage.query(&selections, None).await.unwrap();
         assert_eq!(results.len(), 1);
     }
 
-    #[test]
-    fn test_should_match_multiple_criteria_with_implicit_and() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_match_multiple_criteria_with_implicit_and() {
+        let storage = MemoryStorage::new();
         let fields1 = vec![
             ("type".to_string(), "person".to_string()),
             ("name".to_string(), "John Doe".to_string()),
             ("city".to_string(), "New York".to_string()),
         ];
-        storage.add_record(fields1, None, None).unwrap();
+        storage.add_record(fields1, None, None).await.unwrap();
         let fields2 = vec![
             ("type".to_string(), "person".to_string()),
             ("name".to_string(), "Jane Doe".to_string()),
             ("city".to_string(), "London".to_string()),
         ];
-        storage.add_record(fields2, None, None).unwrap();
+        storage.add_record(fields2, None, None).await.unwrap();
 
         let selections = vec![
             (Some("name".to_string()), "doe".to_string()),
             (Some("city".to_string()), "london".to_string()),
         ];
-        let results = storage.query(&selections, None).unwrap();
+        let results = storage.query(&selections, None).await.unwrap();
         assert_eq!(results.len(), 1);
         assert_eq!(results[0].fields.get("name").unwrap(), "Jane Doe");
     }
 
-    #[test]
-    fn test_should_filter_by_type_discriminator() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_filter_by_type_discriminator() {
+        let storage = MemoryStorage::new();
         
         let fields1 = vec![
             ("name".to_string(), "John Person".to_string()),
             ("type".to_string(), "person".to_string()),
         ];
-        storage.add_record(fields1, None, None).unwrap();
+        storage.add_record(fields1, None, None).await.unwrap();
 
         let fields2 = vec![
             ("name".to_string(), "Server Machine".to_string()),
             ("type".to_string(), "machine".to_string()),
         ];
-        storage.add_record(fields2, None, None).unwrap();
+        storage.add_record(fields2, None, None).await.unwrap();
 
         let selections = vec![(Some("name".to_string()), "server".to_string())];
         
-        let results = storage.query(&selections, Some(RecordType::Person)).unwrap();
+        let results = storage.query(&selections, Some(RecordType::Person)).await.unwrap();
         assert_eq!(results.len(), 0);
 
-        let results = storage.query(&selections, Some(RecordType::Machine)).unwrap();
+        let results = storage.query(&selections, Some(RecordType::Machine)).await.unwrap();
         assert_eq!(results.len(), 1);
         assert_eq!(results[0].fields.get("name").unwrap(), "Server Machine");
 
-        let results = storage.query(&selections, None).unwrap();
+        let results = storage.query(&selections, None).await.unwrap();
         assert_eq!(results.len(), 1);
     }
 
-    #[test]
-    fn test_should_bond_record_to_fingerprint_when_upserted_first_time() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_bond_record_to_fingerprint_when_upserted_first_time() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "machine".to_string()),
             ("hostname".to_string(), "server-01".to_string()),
         ];
         
         let fingerprint = Some("SHA256:abcd".to_string());
-        storage.upsert_record(fields, fingerprint.clone(), None).unwrap();
+        storage.upsert_record(fields, fingerprint.clone(), None).await.unwrap();
         
-        let results = storage.query(&[(Some("hostname".to_string()), "server-01".to_string())], None).unwrap();
+        let results = storage.query(&[(Some("hostname".to_string()), "server-01".to_string())], None).await.unwrap();
         assert_eq!(results.len(), 1);
         assert_eq!(results[0].owner_fingerprint, fingerprint);
     }
 
-    #[test]
-    fn test_should_fail_upsert_when_fingerprint_mismatch() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_fail_upsert_when_fingerprint_mismatch() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "machine".to_string()),
             ("hostname".to_string(), "server-01".to_string()),
         ];
         
-        storage.upsert_record(fields.clone(), Some("SHA256:abcd".to_string()), None).unwrap();
+        storage.upsert_record(fields.clone(), Some("SHA256:abcd".to_string()), None).await.unwrap();
         
-        let result = storage.upsert_record(fields, Some("SHA256:wrong".to_string()), None);
+        let result = storage.upsert_record(fields, Some("SHA256:wrong".to_string()), None).await;
         assert!(matches!(result, Err(StorageError::Collision)));
     }
 
-    #[test]
-    fn test_should_allow_upsert_when_fingerprint_matches() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_allow_upsert_when_fingerprint_matches() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "machine".to_string()),
             ("hostname".to_string(), "server-01".to_string()),
@@ -1146,13 +2433,13 @@ mod tests {
         ];
         
         let fingerprint = Some("SHA256:abcd".to_string());
-        storage.upsert_record(fields.clone(), fingerprint.clone(), None).unwrap();
+        storage.upsert_record(fields.clone(), fingerprint.clone(), None).await.unwrap();
         
         let mut update_fields = fields.clone();
         update_fields.push(("status".to_string(), "busy".to_string()));
-        storage.upsert_record(update_fields, fingerprint.clone(), None).unwrap();
+        storage.upsert_record(update_fields, fingerprint.clone(), None).await.unwrap();
         
-        let results = storage.query(&[(Some("hostname".to_string()), "server-01".to_string())], None).unwrap();
+        let results = storage.query(&[(Some("hostname".to_string()), "server-01".to_string())], None).await.unwrap();
         assert_eq!(results[0].fields.get("status").unwrap(), "busy");
     }
 
@@ -1161,143 +2448,238 @@ mod tests {
         let temp_dir = std::env::temp_dir();
         let storage_path = temp_dir.join("pharos_test_rbac.json");
         
-        if storage_path.exists() {
-            let _ = std::fs::remove_file(&storage_path);
+        remove_storage_files(&storage_path);
+
+        {
+            let storage = open_file_storage(storage_path.clone());
+            let fields = vec![
+                ("type".to_string(), "person".to_string()),
+                ("name".to_string(), "Persistent Pete".to_string()),
+            ];
+            storage.add_record(fields, None, None).await.unwrap();
+            assert_eq!(storage.record_count().await, 1);
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+
+        {
+            let storage = open_file_storage(storage_path.clone());
+            assert_eq!(storage.record_count().await, 1);
+            let results = storage.query(&[(Some("name".to_string()), "pete".to_string())], None).await.unwrap();
+            assert_eq!(results.len(), 1);
+        }
+
+        remove_storage_files(&storage_path);
+    }
+
+    #[tokio::test]
+    async fn test_should_append_only_touched_records_to_journal_instead_of_rewriting_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_path = dir.path().join("data.json");
+        let config = JournalConfig { fsync: journal::FsyncPolicy::Never, compact_every: 100 };
+
+        let storage = FileStorage::open(storage_path.clone(), config, FileCipher::default(), CorruptPolicy::default()).unwrap();
+        for name in ["a", "b"] {
+            let fields = vec![
+                ("type".to_string(), "machine".to_string()),
+                ("hostname".to_string(), name.to_string()),
+            ];
+            storage.add_record(fields, None, None).await.unwrap();
         }
+        let selections = vec![(Some("hostname".to_string()), "a".to_string())];
+        storage.change_record(&selections, &[("status".to_string(), "up".to_string())], None, &[], None, false).await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
 
-        {
-            let mut storage = FileStorage::new(storage_path.clone());
+        assert!(!storage_path.exists(), "no write should have rewritten the snapshot");
+        let replay = journal::read_journal(&journal::journal_path(&storage_path), &FileCipher::default()).unwrap();
+        assert_eq!(replay.entries.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_should_compact_journal_into_snapshot_after_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_path = dir.path().join("data.json");
+        let config = JournalConfig { fsync: journal::FsyncPolicy::Batch, compact_every: 2 };
+
+        let storage = FileStorage::open(storage_path.clone(), config, FileCipher::default(), CorruptPolicy::default()).unwrap();
+        for name in ["a", "b", "c"] {
             let fields = vec![
-                ("type".to_string(), "person".to_string()),
-                ("name".to_string(), "Persistent Pete".to_string()),
+                ("type".to_string(), "machine".to_string()),
+                ("hostname".to_string(), name.to_string()),
             ];
-            storage.add_record(fields, None, None).unwrap();
-            assert_eq!(storage.record_count(), 1);
-            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            storage.add_record(fields, None, None).await.unwrap();
+            tokio::time::sleep(tokio::time::Duration::from_millis(20)).await;
         }
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
 
-        {
-            let storage = FileStorage::new(storage_path.clone());
-            assert_eq!(storage.record_count(), 1);
-            let results = storage.query(&[(Some("name".to_string()), "pete".to_string())], None).unwrap();
-            assert_eq!(results.len(), 1);
-        }
+        let (snapshot, _) = journal::parse_data_file(&std::fs::read_to_string(&storage_path).unwrap()).unwrap();
+        assert!(snapshot.len() >= 2);
+        let reloaded = FileStorage::open(storage_path, config, FileCipher::default(), CorruptPolicy::default()).unwrap();
+        assert_eq!(reloaded.record_count().await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_should_replay_journal_tail_over_snapshot_after_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_path = dir.path().join("data.json");
+
+        let raw_json = r#"[
+            {"id": 1, "record_type": "Machine", "fields": {"hostname": "keep", "type": "machine"}, "owner_fingerprint": null, "owner_team": null},
+            {"id": 2, "record_type": "Machine", "fields": {"hostname": "gone", "type": "machine"}, "owner_fingerprint": null, "owner_team": null}
+        ]"#;
+        std::fs::write(&storage_path, raw_json).unwrap();
+
+        let mut changed = Record { id: 1, record_type: Some(RecordType::Machine), ..Default::default() };
+        changed.fields.insert("hostname".to_string(), "keep".to_string());
+        changed.fields.insert("type".to_string(), "machine".to_string());
+        changed.fields.insert("status".to_string(), "changed".to_string());
+        let mut added = Record { id: 3, record_type: Some(RecordType::Machine), ..Default::default() };
+        added.fields.insert("hostname".to_string(), "new".to_string());
+        added.fields.insert("type".to_string(), "machine".to_string());
+
+        let lines: Vec<String> = [
+            JournalEntry::Put { record: Box::new(changed) },
+            JournalEntry::Remove { id: 2 },
+            JournalEntry::Put { record: Box::new(added) },
+        ]
+        .iter()
+        .map(|e| serde_json::to_string(e).unwrap())
+        .collect();
+        // Last line simulates an append torn by the crash.
+        std::fs::write(journal::journal_path(&storage_path), format!("{}\n{{\"op\":\"put\",\"rec", lines.join("\n"))).unwrap();
+
+        let storage = open_file_storage(storage_path.clone());
+        assert_eq!(storage.record_count().await, 2);
+        let kept = storage.query(&[(Some("hostname".to_string()), "keep".to_string())], None).await.unwrap();
+        assert_eq!(kept[0].fields.get("status").unwrap(), "changed");
+        assert!(storage.query(&[(Some("hostname".to_string()), "gone".to_string())], None).await.unwrap().is_empty());
+
+        // New ids continue after the highest replayed one, and appends after the torn tail survive a reload.
+        let fields = vec![
+            ("type".to_string(), "machine".to_string()),
+            ("hostname".to_string(), "after-crash".to_string()),
+        ];
+        storage.add_record(fields, None, None).await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
 
-        let _ = std::fs::remove_file(&storage_path);
+        let reloaded = open_file_storage(storage_path);
+        let after = reloaded.query(&[(Some("hostname".to_string()), "after-crash".to_string())], None).await.unwrap();
+        assert_eq!(after.len(), 1);
+        assert_eq!(after[0].id, 4);
+        assert_eq!(reloaded.record_count().await, 3);
     }
 
-    #[test]
-    fn test_should_change_matching_record_when_authorized() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_change_matching_record_when_authorized() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "machine".to_string()),
             ("hostname".to_string(), "vm1".to_string()),
             ("status".to_string(), "up".to_string()),
         ];
-        storage.add_record(fields, Some("fp1".to_string()), None).unwrap();
+        storage.add_record(fields, Some("fp1".to_string()), None).await.unwrap();
 
         let selections = vec![(Some("hostname".to_string()), "vm1".to_string())];
         let modifications = vec![("status".to_string(), "down".to_string())];
-        let result = storage.change_record(&selections, &modifications, Some("fp1".to_string()), &[]);
+        let result = storage.change_record(&selections, &modifications, Some("fp1".to_string()), &[], None, false).await;
 
         assert_eq!(result.unwrap(), 1);
-        let updated = storage.query(&selections, None).unwrap();
+        let updated = storage.query(&selections, None).await.unwrap();
         assert_eq!(updated[0].fields.get("status").unwrap(), "down");
     }
 
-    #[test]
-    fn test_should_reject_change_when_unauthorized() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_reject_change_when_unauthorized() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "machine".to_string()),
             ("hostname".to_string(), "vm1".to_string()),
         ];
-        storage.add_record(fields, Some("fp1".to_string()), None).unwrap();
+        storage.add_record(fields, Some("fp1".to_string()), None).await.unwrap();
 
         let selections = vec![(Some("hostname".to_string()), "vm1".to_string())];
         let modifications = vec![("status".to_string(), "down".to_string())];
-        let result = storage.change_record(&selections, &modifications, Some("someone-else".to_string()), &[]);
+        let result = storage.change_record(&selections, &modifications, Some("someone-else".to_string()), &[], None, false).await;
 
         assert!(matches!(result, Err(StorageError::Unauthorized)));
     }
 
-    #[test]
-    fn test_should_return_zero_when_no_matches_to_change() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_return_zero_when_no_matches_to_change() {
+        let storage = MemoryStorage::new();
         let selections = vec![(Some("hostname".to_string()), "does-not-exist".to_string())];
         let modifications = vec![("status".to_string(), "down".to_string())];
-        let result = storage.change_record(&selections, &modifications, None, &[]);
+        let result = storage.change_record(&selections, &modifications, None, &[], None, false).await;
         assert_eq!(result.unwrap(), 0);
     }
 
-    #[test]
-    fn test_should_change_multiple_matching_records() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_change_multiple_matching_records() {
+        let storage = MemoryStorage::new();
         for i in 0..3 {
             let fields = vec![
                 ("type".to_string(), "machine".to_string()),
                 ("hostname".to_string(), format!("vm{}", i)),
             ];
-            storage.add_record(fields, None, None).unwrap();
+            storage.add_record(fields, None, None).await.unwrap();
         }
         let selections = vec![(Some("type".to_string()), "machine".to_string())];
         let modifications = vec![("status".to_string(), "maintenance".to_string())];
-        let result = storage.change_record(&selections, &modifications, None, &[]);
+        let result = storage.change_record(&selections, &modifications, None, &[], None, false).await;
         assert_eq!(result.unwrap(), 3);
     }
 
-    #[test]
-    fn test_should_return_invalid_argument_when_add_record_missing_type() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_return_invalid_argument_when_add_record_missing_type() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("hostname".to_string(), "srv-01".to_string()),
         ];
-        let res = storage.add_record(fields, None, None);
+        let res = storage.add_record(fields, None, None).await;
         assert!(matches!(res, Err(StorageError::InvalidArgument(_))));
     }
 
-    #[test]
-    fn test_should_reject_upsert_when_type_mismatches_existing_field() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_reject_upsert_when_type_mismatches_existing_field() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("hostname".to_string(), "srv-01".to_string()),
             ("type".to_string(), "machine".to_string()),
         ];
-        storage.upsert_record(fields.clone(), None, None).unwrap();
+        storage.upsert_record(fields.clone(), None, None).await.unwrap();
 
         let mismatch_fields = vec![
             ("hostname".to_string(), "srv-01".to_string()),
             ("type".to_string(), "person".to_string()),
         ];
-        let res = storage.upsert_record(mismatch_fields, None, None);
+        let res = storage.upsert_record(mismatch_fields, None, None).await;
         assert!(matches!(res, Err(StorageError::InvalidArgument(_))));
     }
 
-    #[test]
-    fn test_should_allow_upsert_when_type_matches_existing_field() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_allow_upsert_when_type_matches_existing_field() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("hostname".to_string(), "srv-01".to_string()),
             ("type".to_string(), "machine".to_string()),
         ];
-        storage.upsert_record(fields.clone(), None, None).unwrap();
+        storage.upsert_record(fields.clone(), None, None).await.unwrap();
 
-        let res = storage.upsert_record(fields, None, None);
+        let res = storage.upsert_record(fields, None, None).await;
         assert!(res.is_ok());
     }
 
-    #[test]
-    fn test_should_reject_change_when_modifications_contain_type() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_reject_change_when_modifications_contain_type() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("hostname".to_string(), "srv-01".to_string()),
             ("type".to_string(), "machine".to_string()),
         ];
-        storage.add_record(fields, None, None).unwrap();
+        storage.add_record(fields, None, None).await.unwrap();
 
         let selections = vec![(Some("hostname".to_string()), "srv-01".to_string())];
         let modifications = vec![("type".to_string(), "person".to_string())];
-        let res = storage.change_record(&selections, &modifications, None, &[]);
+        let res = storage.change_record(&selections, &modifications, None, &[], None, false).await;
         assert!(matches!(res, Err(StorageError::InvalidArgument(_))));
     }
 
@@ -1305,9 +2687,7 @@ mod tests {
     async fn test_should_self_heal_record_type_on_load_from_disk() {
         let temp_dir = std::env::temp_dir();
         let storage_path = temp_dir.join("pharos_test_self_heal.json");
-        if storage_path.exists() {
-            let _ = std::fs::remove_file(&storage_path);
-        }
+        remove_storage_files(&storage_path);
 
         let raw_json = r#"[
             {
@@ -1323,12 +2703,12 @@ mod tests {
         ]"#;
         std::fs::write(&storage_path, raw_json).unwrap();
 
-        let storage = FileStorage::new(storage_path.clone());
-        let records = storage.query(&[(Some("hostname".to_string()), "srv-heal".to_string())], None).unwrap();
+        let storage = open_file_storage(storage_path.clone());
+        let records = storage.query(&[(Some("hostname".to_string()), "srv-heal".to_string())], None).await.unwrap();
         assert_eq!(records.len(), 1);
         assert_eq!(records[0].record_type, Some(RecordType::Machine));
 
-        let _ = std::fs::remove_file(&storage_path);
+        remove_storage_files(&storage_path);
     }
 
     #[tokio::test]
@@ -1340,9 +2720,7 @@ mod tests {
         // the stale single value forever - confirmed live, this is the exact bug that was found.
         let temp_dir = std::env::temp_dir();
         let storage_path = temp_dir.join("pharos_test_legacy_ip_addr_migration.json");
-        if storage_path.exists() {
-            let _ = std::fs::remove_file(&storage_path);
-        }
+        remove_storage_files(&storage_path);
 
         let raw_json = r#"[
             {
@@ -1360,8 +2738,8 @@ mod tests {
         ]"#;
         std::fs::write(&storage_path, raw_json).unwrap();
 
-        let storage = FileStorage::new(storage_path.clone());
-        let records = storage.query(&[(Some("hostname".to_string()), "legacy-host".to_string())], None).unwrap();
+        let storage = open_file_storage(storage_path.clone());
+        let records = storage.query(&[(Some("hostname".to_string()), "legacy-host".to_string())], None).await.unwrap();
         assert_eq!(records.len(), 1);
 
         // The stale plain-string entries must be gone from `fields`...
@@ -1371,12 +2749,12 @@ mod tests {
         assert_eq!(records[0].multi_fields.get("ip_addr"), Some(&vec!["172.17.0.1".to_string()]));
         assert_eq!(records[0].multi_fields.get("mac_addr"), Some(&vec!["de:16:42:a0:af:ee".to_string()]));
 
-        let _ = std::fs::remove_file(&storage_path);
+        remove_storage_files(&storage_path);
     }
 
-    #[test]
-    fn test_should_store_multi_valued_ip_and_mac_fields_on_add() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_store_multi_valued_ip_and_mac_fields_on_add() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "machine".to_string()),
             ("hostname".to_string(), "srv-01".to_string()),
@@ -1384,122 +2762,949 @@ mod tests {
             ("ip_addr".to_string(), "192.168.86.6".to_string()),
             ("mac_addr".to_string(), "e0:51:d8:1d:e3:22".to_string()),
         ];
-        storage.add_record(fields, None, None).unwrap();
-        let records = storage.query(&[(Some("hostname".to_string()), "srv-01".to_string())], None).unwrap();
+        storage.add_record(fields, None, None).await.unwrap();
+        let records = storage.query(&[(Some("hostname".to_string()), "srv-01".to_string())], None).await.unwrap();
         assert_eq!(records.len(), 1);
         assert_eq!(records[0].multi_fields.get("ip_addr").unwrap(), &vec!["192.168.86.5".to_string(), "192.168.86.6".to_string()]);
         assert_eq!(records[0].multi_fields.get("mac_addr").unwrap(), &vec!["e0:51:d8:1d:e3:22".to_string()]);
     }
 
-    #[test]
-    fn test_should_append_new_ip_on_later_change_without_duplication() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_append_new_ip_on_later_change_without_duplication() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "machine".to_string()),
             ("hostname".to_string(), "srv-01".to_string()),
             ("ip_addr".to_string(), "192.168.86.5".to_string()),
             ("mac_addr".to_string(), "e0:51:d8:1d:e3:22".to_string()),
         ];
-        storage.add_record(fields, None, None).unwrap();
+        storage.add_record(fields, None, None).await.unwrap();
 
         let selections = vec![(Some("hostname".to_string()), "srv-01".to_string())];
         let modifications = vec![("ip_addr".to_string(), "192.168.86.6".to_string())];
-        storage.change_record(&selections, &modifications, None, &[]).unwrap();
+        storage.change_record(&selections, &modifications, None, &[], None, false).await.unwrap();
 
         // Repeat change with duplicate IP
-        storage.change_record(&selections, &modifications, None, &[]).unwrap();
+        storage.change_record(&selections, &modifications, None, &[], None, false).await.unwrap();
 
-        let records = storage.query(&selections, None).unwrap();
+        let records = storage.query(&selections, None).await.unwrap();
         assert_eq!(records[0].multi_fields.get("ip_addr").unwrap(), &vec!["192.168.86.5".to_string(), "192.168.86.6".to_string()]);
         assert_eq!(records[0].multi_fields.get("mac_addr").unwrap(), &vec!["e0:51:d8:1d:e3:22".to_string()]);
     }
 
-    #[test]
-    fn test_should_reject_malformed_ip_or_mac_and_fail_closed() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_unset_remove_and_replace_field_values_on_change() {
+        let storage = MemoryStorage::new();
+        storage.add_record(vec![
+            ("type".to_string(), "machine".to_string()),
+            ("hostname".to_string(), "srv-01".to_string()),
+            ("notes".to_string(), "old rack".to_string()),
+            ("ip_addr".to_string(), "10.0.0.1".to_string()),
+            ("ip_addr".to_string(), "10.0.0.2".to_string()),
+            ("mac_addr".to_string(), "aa:bb:cc:dd:ee:01".to_string()),
+        ], None, None).await.unwrap();
+        let selections = vec![(Some("hostname".to_string()), "srv-01".to_string())];
+
+        storage.change_record(&selections, &[
+            ("notes".to_string(), String::new()),
+            ("ip_addr-".to_string(), "10.0.0.1".to_string()),
+            ("mac_addr:".to_string(), "aa:bb:cc:dd:ee:02".to_string()),
+            ("mac_addr:".to_string(), "aa:bb:cc:dd:ee:03".to_string()),
+        ], None, &[], None, false).await.unwrap();
+
+        let record = &storage.query(&selections, None).await.unwrap()[0];
+        assert!(!record.fields.contains_key("notes"));
+        assert_eq!(record.multi_fields["ip_addr"], vec!["10.0.0.2"]);
+        assert_eq!(record.multi_fields["mac_addr"], vec!["aa:bb:cc:dd:ee:02", "aa:bb:cc:dd:ee:03"]);
+        let removed = record.history.last().unwrap().changes.iter().find(|c| c.field == "notes").unwrap();
+        assert_eq!((removed.from.as_deref(), removed.to.as_deref()), (Some("old rack"), None));
+
+        // Removing the last value drops the field; a required field can't be removed.
+        storage.change_record(&selections, &[("ip_addr-".to_string(), "10.0.0.2".to_string())], None, &[], None, false).await.unwrap();
+        assert!(!storage.query(&selections, None).await.unwrap()[0].multi_fields.contains_key("ip_addr"));
+        let schema_dir = tempfile::tempdir().unwrap();
+        let schema_path = schema_dir.path().join("field-schema.toml");
+        std::fs::write(&schema_path, "[fields.hostname]\nrequired = true").unwrap();
+        let storage = storage.with_field_schema(FieldSchema::load(&schema_path).unwrap());
+        let result = storage.change_record(&selections, &[("hostname".to_string(), String::new())], None, &[], None, false).await;
+        assert!(matches!(result, Err(StorageError::InvalidArgument(_))));
+        assert!(matches!(
+            storage.change_record(&selections, &[("ip_addr-".to_string(), String::new())], None, &[], None, false).await,
+            Err(StorageError::InvalidArgument(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_should_refuse_a_conditional_change_once_the_record_moved_on() {
+        let storage = MemoryStorage::new();
+        storage.add_record(vec![("type".to_string(), "machine".to_string()), ("hostname".to_string(), "srv-01".to_string())], None, None).await.unwrap();
+        let selections = vec![(Some("hostname".to_string()), "srv-01".to_string())];
+        let set = |status: &str| vec![("status".to_string(), status.to_string())];
+        assert_eq!(storage.query(&selections, None).await.unwrap()[0].revision, 1);
+
+        assert_eq!(storage.change_record_if_revision(&selections, &set("online"), None, &[], None, false, 1).await.unwrap(), 1);
+        // A heartbeat that only bumps last_seen_at doesn't move the revision.
+        storage.upsert_record(vec![("type".to_string(), "machine".to_string()), ("hostname".to_string(), "srv-01".to_string())], None, None).await.unwrap();
+        let result = storage.change_record_if_revision(&selections, &set("retired"), None, &[], None, false, 1).await;
+        assert!(matches!(result, Err(StorageError::RevisionMismatch { expected: 1, current: 2 })));
+        assert_eq!(storage.query(&selections, None).await.unwrap()[0].fields["status"], "online");
+
+        assert!(matches!(
+            storage.change_record(&selections, &[("revision".to_string(), "9".to_string())], None, &[], None, false).await,
+            Err(StorageError::InvalidArgument(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_should_validate_links_on_write_and_drop_them_on_delete() {
+        let schema_dir = tempfile::tempdir().unwrap();
+        let schema_path = schema_dir.path().join("field-schema.toml");
+        std::fs::write(&schema_path, "[types.machine.fields.runs_on]\nkind = \"link\"\ntarget = \"machine\"").unwrap();
+        let storage = MemoryStorage::new().with_field_schema(FieldSchema::load(&schema_path).unwrap());
+        let machine = |hostname: &str, runs_on: Option<&str>| {
+            let mut fields = vec![("type".to_string(), "machine".to_string()), ("hostname".to_string(), hostname.to_string())];
+            fields.extend(runs_on.map(|h| ("runs_on".to_string(), h.to_string())));
+            fields
+        };
+        let select = |hostname: &str| vec![(Some("hostname".to_string()), hostname.to_string())];
+
+        assert!(matches!(storage.add_record(machine("vm-01", Some("pve01")), None, None).await, Err(StorageError::InvalidArgument(_))));
+        storage.add_record(vec![("type".to_string(), "person".to_string()), ("alias".to_string(), "rdavis".to_string())], None, None).await.unwrap();
+        assert!(matches!(storage.upsert_record(machine("vm-01", Some("rdavis")), None, None).await, Err(StorageError::InvalidArgument(_))));
+
+        storage.add_record(machine("pve01", None), None, None).await.unwrap();
+        storage.add_record(machine("vm-01", Some("pve01")), None, None).await.unwrap();
+        let moved = storage.change_record(&select("vm-01"), &[("runs_on".to_string(), "pve02".to_string())], None, &[], None, false).await;
+        assert!(matches!(moved, Err(StorageError::InvalidArgument(_))));
+
+        storage.delete_record(&select("pve01"), None, &[]).await.unwrap();
+        let vm = &storage.query(&select("vm-01"), None).await.unwrap()[0];
+        assert!(!vm.fields.contains_key("runs_on"));
+        assert_eq!(vm.revision, 2);
+        assert_eq!(vm.history.last().unwrap().changes[0].field, "runs_on");
+    }
+
+    #[tokio::test]
+    async fn test_should_apply_a_batch_in_order_or_not_at_all() {
+        let storage = MemoryStorage::new();
+        storage.add_record(vec![("type".to_string(), "machine".to_string()), ("hostname".to_string(), "srv-01".to_string())], None, None).await.unwrap();
+        let select = |hostname: &str| vec![(Some("hostname".to_string()), hostname.to_string())];
+        let change = |hostname: &str, limits| BatchOp::Change {
+            selections: select(hostname),
+            modifications: vec![("ip_addr".to_string(), "10.0.0.9".to_string())],
+            fingerprint: None,
+            teams: Vec::new(),
+            source: None,
+            force: false,
+            limits,
+            if_revision: None,
+        };
+        let add = BatchOp::Upsert {
+            fields: vec![("type".to_string(), "machine".to_string()), ("hostname".to_string(), "srv-02".to_string())],
+            fingerprint: None,
+            team: None,
+        };
+
+        // The third op matches two records under `limit 1`: the first two are undone with it.
+        let refused = storage.apply_batch(&[add.clone(), change("srv-01", WriteLimits::default()), change("srv-*", WriteLimits { limit: Some(1), addonly: false })]).await;
+        let error = refused.unwrap_err();
+        assert_eq!(error.index, 2);
+        assert!(matches!(error.error, StorageError::TooManyEntries(2)));
+        assert!(storage.query(&select("srv-02"), None).await.unwrap().is_empty());
+        assert!(!storage.query(&select("srv-01"), None).await.unwrap()[0].multi_fields.contains_key("ip_addr"));
+
+        // Each op sees the ones before it, so a change can target a record the batch adds.
+        let outcomes = storage.apply_batch(&[add, change("srv-02", WriteLimits::default())]).await.unwrap();
+        assert_eq!(outcomes, vec![BatchOutcome::Upserted(UpsertOutcome::Created), BatchOutcome::Changed(1)]);
+        assert_eq!(storage.query(&select("srv-02"), None).await.unwrap()[0].multi_fields["ip_addr"], vec!["10.0.0.9"]);
+    }
+
+    #[tokio::test]
+    async fn test_should_put_back_only_what_a_failed_batch_touched() {
+        let storage = MemoryStorage::new();
+        for hostname in ["srv-01", "srv-02"] {
+            storage.add_record(vec![("type".to_string(), "machine".to_string()), ("hostname".to_string(), hostname.to_string())], None, None).await.unwrap();
+        }
+        let select = |hostname: &str| vec![(Some("hostname".to_string()), hostname.to_string())];
+        let ops = [
+            BatchOp::Delete { selections: select("srv-01"), fingerprint: None, teams: Vec::new(), limits: WriteLimits::default() },
+            BatchOp::Upsert {
+                fields: vec![("type".to_string(), "machine".to_string()), ("hostname".to_string(), "srv-03".to_string())],
+                fingerprint: None,
+                team: None,
+            },
+            BatchOp::Change {
+                selections: select("srv-*"),
+                modifications: vec![("os".to_string(), "debian13".to_string())],
+                fingerprint: None,
+                teams: Vec::new(),
+                source: None,
+                force: false,
+                limits: WriteLimits { limit: Some(1), addonly: false },
+                if_revision: None,
+            },
+        ];
+        assert_eq!(storage.apply_batch(&ops).await.unwrap_err().index, 2);
+
+        let set = storage.records.read().await;
+        assert_eq!(set.records.keys().copied().collect::<Vec<_>>(), vec![1, 2]);
+        assert!(set.tombstoned.is_empty());
+        assert_eq!(set.next_id, 3);
+        assert_eq!(set.records[&1].revision, 1);
+        assert!(set.undo.is_none());
+        drop(set);
+        assert_eq!(storage.query(&[(None, "srv-01".to_string())], None).await.unwrap().len(), 1, "the word index is back too");
+        assert!(storage.query(&select("srv-03"), None).await.unwrap().is_empty());
+        assert!(storage.tombstones(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_should_only_overwrite_encrypted_fields_with_force() {
+        let dir = tempfile::tempdir().unwrap();
+        let schema_path = dir.path().join("field-schema.toml");
+        std::fs::write(&schema_path, "[types.machine.fields.bmc_password]\nencrypted = true").unwrap();
+        let storage = MemoryStorage::new().with_field_schema(FieldSchema::load(&schema_path).unwrap());
+        let machine = |hostname: &str, password: &str| vec![("type".to_string(), "machine".to_string()), ("hostname".to_string(), hostname.to_string()), ("bmc_password".to_string(), password.to_string())];
+        storage.add_record(machine("bmc01", "calvin"), None, None).await.unwrap();
+        let scanned = machine("bmc02", "admin").into_iter().chain([("source".to_string(), "pharos-scan".to_string())]).collect();
+        storage.add_record(scanned, None, None).await.unwrap();
+        let select = |hostname: &str| vec![(Some("hostname".to_string()), hostname.to_string())];
+        let password = || vec![("bmc_password".to_string(), "hunter2".to_string())];
+        let encrypted = |result: Result<_, StorageError>| matches!(result, Err(StorageError::Encrypted(field)) if field == "bmc_password");
+
+        assert_eq!(storage.upsert_record(machine("bmc01", "calvin"), None, None).await.unwrap(), UpsertOutcome::Updated, "the same value isn't an overwrite");
+        assert!(encrypted(storage.upsert_record(machine("bmc01", "hunter2"), None, None).await.map(|_| 0)));
+        assert!(encrypted(storage.change_record(&select("bmc01"), &password(), None, &[], None, false).await));
+        let op = BatchOp::Change { selections: select("bmc01"), modifications: password(), fingerprint: None, teams: Vec::new(), source: None, force: false, limits: WriteLimits::default(), if_revision: None };
+        assert!(encrypted(storage.apply_batch(&[op]).await.map_err(|e| e.error).map(|_| 0)));
+        assert!(encrypted(storage.merge_records(&select("bmc02"), &select("bmc01"), &["pharos-scan".to_string()], None, &[], None).await.map(|_| 0)));
+        assert_eq!(storage.query(&select("bmc01"), None).await.unwrap()[0].fields["bmc_password"], "calvin");
+
+        assert_eq!(storage.change_record(&select("bmc01"), &password(), None, &[], None, true).await.unwrap(), 1);
+        assert_eq!(storage.query(&select("bmc01"), None).await.unwrap()[0].fields["bmc_password"], "hunter2");
+    }
+
+    #[tokio::test]
+    async fn test_should_refuse_values_with_the_sealed_prefix_on_every_write() {
+        let storage = MemoryStorage::new();
+        let lookalike = "sealed:v1:00000000:AAAA".to_string();
+        let machine = |notes: &str| vec![("type".to_string(), "machine".to_string()), ("hostname".to_string(), "web01".to_string()), ("notes".to_string(), notes.to_string())];
+        let select = vec![(Some("hostname".to_string()), "web01".to_string())];
+        let refused = |result: Result<usize, StorageError>| matches!(result, Err(StorageError::InvalidArgument(msg)) if msg.contains("sealed:v1:"));
+
+        assert!(refused(storage.add_record(machine(&lookalike), None, None).await.map(|_| 0)));
+        storage.add_record(machine("racked in row 4"), None, None).await.unwrap();
+        assert!(refused(storage.upsert_record(machine(&lookalike), None, None).await.map(|_| 0)));
+        for key in ["notes", "notes:"] {
+            assert!(refused(storage.change_record(&select, &[(key.to_string(), lookalike.clone())], None, &[], None, true).await));
+        }
+        assert_eq!(storage.query(&select, None).await.unwrap()[0].fields["notes"], "racked in row 4");
+    }
+
+    #[tokio::test]
+    async fn test_should_delete_only_while_the_guard_still_holds() {
+        let storage = MemoryStorage::new();
+        let machine = |seen: &str| vec![("type".to_string(), "machine".to_string()), ("hostname".to_string(), "scan-01".to_string()), ("last_seen_at".to_string(), seen.to_string())];
+        storage.add_record(machine("2026-01-01T00:00:00+00:00"), None, None).await.unwrap();
+        let selections = vec![(Some("hostname".to_string()), "scan-01".to_string())];
+        let unseen_since_january: RecordGuard = Arc::new(|r: &Record| r.fields["last_seen_at"].starts_with("2026-01"));
+
+        storage.upsert_record(machine("2026-06-01T00:00:00+00:00"), None, None).await.unwrap();
+        assert_eq!(storage.delete_record_if(&selections, None, &[], Arc::clone(&unseen_since_january)).await.unwrap(), 0);
+        assert_eq!(storage.record_count().await, 1, "seen again in between, so kept");
+
+        storage.change_record(&selections, &[("last_seen_at".to_string(), "2026-01-02T00:00:00+00:00".to_string())], None, &[], None, false).await.unwrap();
+        assert_eq!(storage.delete_record_if(&selections, None, &[], unseen_since_january).await.unwrap(), 1);
+        assert_eq!(storage.tombstones(&[]).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_should_reject_malformed_ip_or_mac_and_fail_closed() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "machine".to_string()),
             ("hostname".to_string(), "srv-01".to_string()),
             ("ip_addr".to_string(), "192.168.86.5".to_string()),
             ("ip_addr".to_string(), "not-an-ip".to_string()),
         ];
-        let res = storage.add_record(fields, None, None);
+        let res = storage.add_record(fields, None, None).await;
         assert!(matches!(res, Err(StorageError::InvalidArgument(_))));
-        assert_eq!(storage.record_count(), 0);
+        assert_eq!(storage.record_count().await, 0);
     }
 
-    #[test]
-    fn test_should_allow_records_without_ip_mac_fields() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_allow_records_without_ip_mac_fields() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("name".to_string(), "Jane Smith".to_string()),
             ("type".to_string(), "person".to_string()),
         ];
-        let res = storage.add_record(fields, None, None);
+        let res = storage.add_record(fields, None, None).await;
         assert!(res.is_ok());
     }
 
-    #[test]
-    fn test_should_return_created_outcome_when_adding_new_record_via_upsert() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_return_created_outcome_when_adding_new_record_via_upsert() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "machine".to_string()),
             ("hostname".to_string(), "srv-new".to_string()),
         ];
-        let outcome = storage.upsert_record(fields, None, None).unwrap();
+        let outcome = storage.upsert_record(fields, None, None).await.unwrap();
         assert_eq!(outcome, UpsertOutcome::Created);
     }
 
-    #[test]
-    fn test_should_return_updated_outcome_when_upserting_existing_record() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_return_updated_outcome_when_upserting_existing_record() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "machine".to_string()),
             ("hostname".to_string(), "srv-exist".to_string()),
         ];
-        let outcome1 = storage.upsert_record(fields.clone(), None, None).unwrap();
+        let outcome1 = storage.upsert_record(fields.clone(), None, None).await.unwrap();
         assert_eq!(outcome1, UpsertOutcome::Created);
 
         let mut update_fields = fields;
         update_fields.push(("status".to_string(), "online".to_string()));
-        let outcome2 = storage.upsert_record(update_fields, None, None).unwrap();
+        let outcome2 = storage.upsert_record(update_fields, None, None).await.unwrap();
         assert_eq!(outcome2, UpsertOutcome::Updated);
     }
 
-    #[test]
-    fn test_should_keep_existing_source_field_immutable_on_upsert() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_keep_existing_source_field_immutable_on_upsert() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "machine".to_string()),
             ("hostname".to_string(), "srv-source".to_string()),
             ("source".to_string(), "pharos-scan".to_string()),
         ];
-        storage.upsert_record(fields, None, None).unwrap();
+        storage.upsert_record(fields, None, None).await.unwrap();
 
         let update_fields = vec![
             ("type".to_string(), "machine".to_string()),
             ("hostname".to_string(), "srv-source".to_string()),
             ("source".to_string(), "mdb".to_string()),
         ];
-        storage.upsert_record(update_fields, None, None).unwrap();
+        storage.upsert_record(update_fields, None, None).await.unwrap();
 
-        let records = storage.query(&[(Some("hostname".to_string()), "srv-source".to_string())], None).unwrap();
+        let records = storage.query(&[(Some("hostname".to_string()), "srv-source".to_string())], None).await.unwrap();
         assert_eq!(records.len(), 1);
         assert_eq!(records[0].fields.get("source").unwrap(), "pharos-scan");
     }
 
-    #[test]
-    fn test_should_set_source_field_on_first_creation_via_upsert() {
-        let mut storage = MemoryStorage::new();
+    #[tokio::test]
+    async fn test_should_set_source_field_on_first_creation_via_upsert() {
+        let storage = MemoryStorage::new();
         let fields = vec![
             ("type".to_string(), "machine".to_string()),
             ("hostname".to_string(), "srv-first".to_string()),
             ("source".to_string(), "web-console".to_string()),
         ];
-        storage.upsert_record(fields, None, None).unwrap();
+        storage.upsert_record(fields, None, None).await.unwrap();
 
-        let records = storage.query(&[(Some("hostname".to_string()), "srv-first".to_string())], None).unwrap();
+        let records = storage.query(&[(Some("hostname".to_string()), "srv-first".to_string())], None).await.unwrap();
         assert_eq!(records.len(), 1);
         assert_eq!(records[0].fields.get("source").unwrap(), "web-console");
     }
+
+    async fn hostname_query(storage: &MemoryStorage, hostname: &str) -> Vec<Record> {
+        storage.query(&[(Some("hostname".to_string()), hostname.to_string())], None).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_should_keep_indexes_in_step_with_change_and_delete() {
+        let storage = MemoryStorage::new();
+        storage.add_record(vec![
+            ("type".to_string(), "machine".to_string()),
+            ("hostname".to_string(), "old-name".to_string()),
+        ], None, None).await.unwrap();
+
+        let selection = [(Some("hostname".to_string()), "old-name".to_string())];
+        let modifications = [
+            ("hostname".to_string(), "new-name".to_string()),
+            ("ip_addr".to_string(), "10.1.1.1".to_string()),
+        ];
+        storage.change_record(&selection, &modifications, None, &[], None, false).await.unwrap();
+
+        assert!(hostname_query(&storage, "old-name").await.is_empty());
+        assert_eq!(hostname_query(&storage, "new-name").await.len(), 1);
+        let by_ip = storage.query(&[(Some("ip_addr".to_string()), "10.1.1.1".to_string())], None).await.unwrap();
+        assert_eq!(by_ip.len(), 1);
+        let by_token = storage.query(&[(None, "NEW-NAME".to_string())], None).await.unwrap();
+        assert_eq!(by_token.len(), 1);
+
+        storage.delete_record(&[(Some("hostname".to_string()), "new-name".to_string())], None, &[]).await.unwrap();
+        assert!(hostname_query(&storage, "new-name").await.is_empty());
+        assert!(storage.query(&[(None, "10.1.1.1".to_string())], None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_should_find_upsert_identity_by_alias_through_index() {
+        let storage = MemoryStorage::new();
+        storage.upsert_record(vec![
+            ("type".to_string(), "machine".to_string()),
+            ("hostname".to_string(), "srv-a".to_string()),
+            ("alias".to_string(), "primary".to_string()),
+        ], None, None).await.unwrap();
+
+        let outcome = storage.upsert_record(vec![
+            ("alias".to_string(), "primary".to_string()),
+            ("ip_addr".to_string(), "10.0.0.9".to_string()),
+        ], None, None).await.unwrap();
+
+        assert_eq!(outcome, UpsertOutcome::Updated);
+        assert_eq!(storage.record_count().await, 1);
+        // Identity is exact, not word-based or case-insensitive like queries.
+        let outcome = storage.upsert_record(vec![
+            ("type".to_string(), "machine".to_string()),
+            ("alias".to_string(), "Primary".to_string()),
+        ], None, None).await.unwrap();
+        assert_eq!(outcome, UpsertOutcome::Created);
+    }
+
+    #[tokio::test]
+    async fn test_should_merge_a_duplicate_and_repoint_links_to_it() {
+        let schema_dir = tempfile::tempdir().unwrap();
+        let schema_path = schema_dir.path().join("field-schema.toml");
+        std::fs::write(&schema_path, "[fields.mac_addr]\nkind = \"mac\"\nmulti = true\n\n[types.machine.fields.runs_on]\nkind = \"link\"\ntarget = \"machine\"").unwrap();
+        let storage = MemoryStorage::new().with_field_schema(FieldSchema::load(&schema_path).unwrap());
+        let machine = |pairs: &[(&str, &str)]| {
+            let mut fields = vec![("type".to_string(), "machine".to_string())];
+            fields.extend(pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+            fields
+        };
+        let select = |field: &str, value: &str| vec![(Some(field.to_string()), value.to_string())];
+        let precedence = merge::MergeConfig::default().precedence;
+
+        storage.add_record(machine(&[("alias", "scan-ab12"), ("source", "pharos-scan"), ("mac_addr", "AA:BB:CC:00:00:01"), ("created_at", "2026-01-01T00:00:00+00:00")]), None, None).await.unwrap();
+        storage.add_record(machine(&[("hostname", "web01"), ("source", "pharos-pulse"), ("mac_addr", "aa:bb:cc:00:00:01"), ("mac_addr", "aa:bb:cc:00:00:02")]), None, None).await.unwrap();
+        storage.add_record(machine(&[("hostname", "vm-1"), ("runs_on", "scan-ab12")]), None, None).await.unwrap();
+
+        let survivor = storage.merge_records(&select("alias", "scan-ab12"), &select("hostname", "web01"), &precedence, None, &[], Some("mdb".to_string())).await.unwrap().unwrap();
+        assert_eq!(survivor.fields["alias"], "scan-ab12");
+        assert_eq!(survivor.fields["created_at"], "2026-01-01T00:00:00+00:00", "the earlier record's creation time");
+        assert_eq!(survivor.multi_fields["mac_addr"], vec!["aa:bb:cc:00:00:01", "aa:bb:cc:00:00:02"]);
+        assert_eq!(survivor.history.last().unwrap().source.as_deref(), Some("mdb"));
+        assert_eq!(storage.record_count().await, 2);
+        assert_eq!(storage.tombstones(&select("alias", "scan-ab12")).await.unwrap().len(), 1);
+        let vm = &storage.query(&select("hostname", "vm-1"), None).await.unwrap()[0];
+        assert_eq!(vm.fields["runs_on"], "web01");
+
+        // The survivor now answers to both names, so there's nothing left to merge.
+        let same = storage.merge_records(&select("alias", "scan-ab12"), &select("hostname", "web01"), &precedence, None, &[], None).await;
+        assert!(matches!(same, Err(StorageError::InvalidArgument(_))));
+        let several = storage.merge_records(&select("type", "machine"), &select("hostname", "web01"), &precedence, None, &[], None).await;
+        assert!(matches!(several, Err(StorageError::TooManyEntries(_))));
+        storage.add_record(machine(&[("hostname", "db01")]), Some("SHA256:other".to_string()), None).await.unwrap();
+        let foreign = storage.merge_records(&select("hostname", "db01"), &select("hostname", "web01"), &precedence, None, &[], None).await;
+        assert!(matches!(foreign, Err(StorageError::Unauthorized)));
+        assert!(storage.merge_records(&select("hostname", "gone"), &select("hostname", "web01"), &precedence, None, &[], None).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_should_resolve_upsert_by_configured_identity_keys() {
+        let schema_dir = tempfile::tempdir().unwrap();
+        let schema_path = schema_dir.path().join("field-schema.toml");
+        std::fs::write(&schema_path, "[types.machine]\nidentity = [\"uuid\", \"serial_number\", \"hostname\", \"mac_addr\"]").unwrap();
+        let storage = MemoryStorage::new().with_field_schema(FieldSchema::load(&schema_path).unwrap());
+        let machine = |pairs: &[(&str, &str)]| {
+            let mut fields = vec![("type".to_string(), "machine".to_string())];
+            fields.extend(pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+            fields
+        };
+
+        storage.upsert_record(machine(&[("hostname", "srv-a"), ("uuid", "4c4c-01"), ("mac_addr", "AA:BB:CC:00:00:01")]), None, None).await.unwrap();
+        // Reinstalled under a new name: the uuid still finds it, and the hostname follows.
+        let outcome = storage.upsert_record(machine(&[("hostname", "srv-a2"), ("uuid", "4c4c-01")]), None, None).await.unwrap();
+        assert_eq!(outcome, UpsertOutcome::Updated);
+        assert_eq!(storage.record_count().await, 1);
+        assert_eq!(hostname_query(&storage, "srv-a2").await.len(), 1);
+
+        let outcome = storage.upsert_record(machine(&[("mac_addr", "aa:bb:cc:00:00:01"), ("notes", "rack 4")]), None, None).await.unwrap();
+        assert_eq!(outcome, UpsertOutcome::Updated, "a MAC matches whatever its case");
+
+        storage.add_record(machine(&[("hostname", "srv-b"), ("serial_number", "SN-2")]), None, None).await.unwrap();
+        let conflict = storage.upsert_record(machine(&[("uuid", "4c4c-01"), ("serial_number", "SN-2")]), None, None).await;
+        match conflict {
+            Err(StorageError::IdentityConflict(msg)) => assert_eq!(msg, "uuid=4c4c-01 is srv-a2 but serial_number=SN-2 is srv-b"),
+            other => panic!("expected an identity conflict, got {:?}", other),
+        }
+        assert_eq!(storage.record_count().await, 2);
+    }
+
+    #[test]
+    fn test_should_only_visit_indexed_candidates_at_scale() {
+        let mut storage = RecordSet::new();
+        let records = (1..=100_000)
+            .map(|id| Record {
+                id,
+                record_type: Some(RecordType::Machine),
+                fields: HashMap::from([
+                    ("type".to_string(), "machine".to_string()),
+                    ("hostname".to_string(), format!("host-{}", id)),
+                ]),
+                ..Default::default()
+            })
+            .collect();
+        storage.load_records(records);
+
+        let selection = [(Some("hostname".to_string()), "host-73421".to_string())];
+        assert_eq!(storage.candidate_records(&selection).count(), 1);
+        assert_eq!(storage.candidate_records(&[(None, "host-5".to_string())]).count(), 1);
+        assert_eq!(storage.candidate_records(&[(Some("hostname".to_string()), "host-7*".to_string())]).count(), 100_000);
+
+        let results = storage.query(&selection, Some(RecordType::Machine)).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, 73421);
+        assert_eq!(storage.record_count(), 100_000);
+    }
+
+    #[tokio::test]
+    async fn test_should_record_who_changed_what_in_revision_history() {
+        let storage = MemoryStorage::new();
+        let owner = Some("SHA256:owner".to_string());
+        storage.upsert_record(vec![
+            ("type".to_string(), "machine".to_string()),
+            ("hostname".to_string(), "web01".to_string()),
+            ("ip_addr".to_string(), "10.0.0.1".to_string()),
+            ("source".to_string(), "pharos-pulse".to_string()),
+        ], owner.clone(), Some("ops".to_string())).await.unwrap();
+
+        // A heartbeat that only bumps last_seen_at is not a revision.
+        storage.upsert_record(vec![
+            ("hostname".to_string(), "web01".to_string()),
+            ("source".to_string(), "pharos-pulse".to_string()),
+        ], owner.clone(), Some("ops".to_string())).await.unwrap();
+
+        let selections = [(Some("hostname".to_string()), "web01".to_string())];
+        storage.change_record(&selections, &[("ip_addr".to_string(), "10.0.0.2".to_string())], owner.clone(), &["ops".to_string()], Some("mdb".to_string()), false).await.unwrap();
+
+        let history = hostname_query(&storage, "web01").await.remove(0).history;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].revision, 1);
+        assert_eq!(history[0].source.as_deref(), Some("pharos-pulse"));
+        assert!(history[0].changes.iter().any(|c| c.field == "hostname" && c.from.is_none()));
+
+        let ip_change = &history[1];
+        assert_eq!(ip_change.revision, 2);
+        assert_eq!(ip_change.fingerprint, owner);
+        assert_eq!(ip_change.team.as_deref(), Some("ops"));
+        assert_eq!(ip_change.source.as_deref(), Some("mdb"));
+        assert_eq!(ip_change.changes.len(), 1);
+        assert_eq!(ip_change.changes[0].summary(), "10.0.0.1 -> 10.0.0.1, 10.0.0.2");
+    }
+
+    #[tokio::test]
+    async fn test_should_persist_revision_history_across_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_path = dir.path().join("data.json");
+        let storage = open_file_storage(storage_path.clone());
+        storage.add_record(vec![
+            ("type".to_string(), "machine".to_string()),
+            ("hostname".to_string(), "web01".to_string()),
+        ], None, None).await.unwrap();
+        let selections = [(Some("hostname".to_string()), "web01".to_string())];
+        storage.change_record(&selections, &[("status".to_string(), "down".to_string())], None, &[], None, false).await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let reloaded = open_file_storage(storage_path);
+        let records = reloaded.query(&selections, None).await.unwrap();
+        assert_eq!(records[0].history.len(), 2);
+        assert_eq!(records[0].history[1].changes[0].summary(), "(none) -> down");
+    }
+
+    #[tokio::test]
+    async fn test_should_hide_deleted_records_until_owner_or_admin_undeletes() {
+        let storage = MemoryStorage::new();
+        let owner = Some("SHA256:owner".to_string());
+        storage.add_record(vec![
+            ("type".to_string(), "machine".to_string()),
+            ("hostname".to_string(), "web01".to_string()),
+        ], owner.clone(), None).await.unwrap();
+
+        let selections = [(Some("hostname".to_string()), "web01".to_string())];
+        assert_eq!(storage.delete_record(&selections, owner.clone(), &[]).await.unwrap(), 1);
+        assert!(hostname_query(&storage, "web01").await.is_empty());
+        assert_eq!(storage.record_count().await, 0);
+        assert_eq!(storage.delete_record(&selections, owner.clone(), &[]).await.unwrap(), 0, "a tombstone can't be deleted twice");
+        assert_eq!(storage.change_record(&selections, &[("status".to_string(), "up".to_string())], owner.clone(), &[], None, false).await.unwrap(), 0);
+
+        let tombstones = storage.tombstones(&selections).await.unwrap();
+        assert_eq!(tombstones.len(), 1);
+        assert!(tombstones[0].deleted_at.is_some());
+
+        let stranger = Some("SHA256:stranger".to_string());
+        assert!(matches!(storage.undelete_record(&selections, stranger.clone(), &[], false).await, Err(StorageError::Unauthorized)));
+        assert_eq!(storage.undelete_record(&selections, stranger, &[], true).await.unwrap(), 1);
+
+        let restored = hostname_query(&storage, "web01").await.remove(0);
+        assert!(restored.deleted_at.is_none());
+        assert_eq!(restored.owner_fingerprint, owner, "undelete must not change ownership");
+        let deletion = &restored.history[1].changes[0];
+        assert_eq!(deletion.field, "deleted_at");
+        assert!(deletion.from.is_none());
+        assert!(storage.tombstones(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_should_refuse_undelete_when_hostname_was_reused() {
+        let storage = MemoryStorage::new();
+        let machine = vec![
+            ("type".to_string(), "machine".to_string()),
+            ("hostname".to_string(), "web01".to_string()),
+        ];
+        storage.add_record(machine.clone(), None, None).await.unwrap();
+        let selections = [(Some("hostname".to_string()), "web01".to_string())];
+        storage.delete_record(&selections, None, &[]).await.unwrap();
+
+        // A heartbeat after the delete creates a fresh record rather than reviving the tombstone.
+        assert_eq!(storage.upsert_record(machine, None, None).await.unwrap(), UpsertOutcome::Created);
+        let result = storage.undelete_record(&selections, None, &[], false).await;
+        assert!(matches!(result, Err(StorageError::InvalidArgument(_))));
+        assert_eq!(storage.record_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_should_keep_tombstones_across_reload_until_purged() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_path = dir.path().join("data.json");
+        let storage = open_file_storage(storage_path.clone());
+        for hostname in ["web01", "web02"] {
+            storage.add_record(vec![
+                ("type".to_string(), "machine".to_string()),
+                ("hostname".to_string(), hostname.to_string()),
+            ], None, None).await.unwrap();
+        }
+        storage.delete_record(&[(Some("hostname".to_string()), "web01".to_string())], None, &[]).await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let reloaded = open_file_storage(storage_path.clone());
+        assert_eq!(reloaded.record_count().await, 1);
+        assert_eq!(reloaded.tombstones(&[]).await.unwrap().len(), 1);
+
+        let past = Utc::now() - chrono::Duration::days(1);
+        assert_eq!(reloaded.purge_tombstones(past).await.unwrap(), 0, "a fresh tombstone is within retention");
+        assert_eq!(reloaded.purge_tombstones(Utc::now() + chrono::Duration::seconds(1)).await.unwrap(), 1);
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let purged = open_file_storage(storage_path);
+        assert!(purged.tombstones(&[]).await.unwrap().is_empty());
+        assert_eq!(purged.record_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_should_not_reuse_the_id_of_a_purged_record_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_path = dir.path().join("data.json");
+        let add = |hostname: &str| vec![("type".to_string(), "machine".to_string()), ("hostname".to_string(), hostname.to_string())];
+        let storage = open_file_storage(storage_path.clone());
+        for hostname in ["web01", "web02"] {
+            storage.add_record(add(hostname), None, None).await.unwrap();
+        }
+        storage.delete_record(&[(Some("hostname".to_string()), "web02".to_string())], None, &[]).await.unwrap();
+        assert_eq!(storage.purge_tombstones(Utc::now() + chrono::Duration::seconds(1)).await.unwrap(), 1);
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        drop(storage);
+
+        // The first reload replays the journal and compacts it; the second reads the data file alone.
+        drop(open_file_storage(storage_path.clone()));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        assert_eq!(std::fs::metadata(journal::journal_path(&storage_path)).unwrap().len(), 0);
+        let reloaded = open_file_storage(storage_path);
+        reloaded.add_record(add("web03"), None, None).await.unwrap();
+        let web03 = reloaded.query(&[(Some("hostname".to_string()), "web03".to_string())], None).await.unwrap();
+        assert_eq!(web03[0].id, 3);
+    }
+
+    #[tokio::test]
+    async fn test_should_apply_peer_tombstone_only_to_records_not_seen_since() {
+        let storage = MemoryStorage::new();
+        storage.add_record(vec![
+            ("type".to_string(), "machine".to_string()),
+            ("hostname".to_string(), "web01".to_string()),
+            ("last_seen_at".to_string(), "2026-03-01T12:00:00+00:00".to_string()),
+        ], None, None).await.unwrap();
+        // new_record stamps last_seen_at with the current time; pin it to a known past one.
+        let selections = [(Some("hostname".to_string()), "web01".to_string())];
+        storage.change_record(&selections, &[("last_seen_at".to_string(), "2026-03-01T12:00:00+00:00".to_string())], None, &[], None, false).await.unwrap();
+
+        assert!(!storage.import_tombstone("web01", "2026-03-01T11:00:00+00:00").await.unwrap());
+        assert!(!storage.import_tombstone("web99", "2026-03-01T13:00:00+00:00").await.unwrap());
+        assert!(storage.import_tombstone("web01", "2026-03-01T13:00:00+00:00").await.unwrap());
+
+        assert!(hostname_query(&storage, "web01").await.is_empty());
+        let tombstones = storage.tombstones(&selections).await.unwrap();
+        assert_eq!(tombstones[0].deleted_at.as_deref(), Some("2026-03-01T13:00:00+00:00"));
+    }
+
+    #[tokio::test]
+    async fn test_should_restore_snapshot_into_live_file_storage_and_persist_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_path = dir.path().join("data.json");
+        let storage = open_file_storage(storage_path.clone());
+        storage.add_record(vec![
+            ("type".to_string(), "machine".to_string()),
+            ("hostname".to_string(), "web01".to_string()),
+            ("ip_addr".to_string(), "10.0.0.1".to_string()),
+            ("ip_addr".to_string(), "10.0.0.2".to_string()),
+        ], Some("SHA256:owner".to_string()), Some("ops".to_string())).await.unwrap();
+
+        let info = storage.create_snapshot("before-cleanup").await.unwrap();
+        assert!(dir.path().join("data.snapshots").join("before-cleanup.json").exists());
+        assert_eq!(storage.list_snapshots().await.unwrap(), vec![info]);
+
+        storage.delete_record(&[(Some("hostname".to_string()), "web01".to_string())], Some("SHA256:owner".to_string()), &[]).await.unwrap();
+        storage.add_record(vec![
+            ("type".to_string(), "machine".to_string()),
+            ("hostname".to_string(), "web02".to_string()),
+        ], None, None).await.unwrap();
+
+        assert_eq!(storage.restore_snapshot("before-cleanup").await.unwrap(), 1);
+        let restored = storage.query(&[(Some("hostname".to_string()), "web01".to_string())], None).await.unwrap();
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored[0].id, 1);
+        assert_eq!(restored[0].owner_team.as_deref(), Some("ops"));
+        assert_eq!(restored[0].multi_fields["ip_addr"], vec!["10.0.0.1", "10.0.0.2"]);
+        assert!(storage.tombstones(&[]).await.unwrap().is_empty());
+        assert!(storage.query(&[(Some("hostname".to_string()), "web02".to_string())], None).await.unwrap().is_empty());
+
+        // Writes after the restore land on top of it, and all of it survives a restart.
+        storage.add_record(vec![
+            ("type".to_string(), "machine".to_string()),
+            ("hostname".to_string(), "web03".to_string()),
+        ], None, None).await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let reloaded = open_file_storage(storage_path);
+        let hostnames: Vec<String> = reloaded.query(&[], None).await.unwrap().iter().map(|r| r.fields["hostname"].clone()).collect();
+        assert_eq!(hostnames, vec!["web01", "web03"]);
+        assert!(matches!(reloaded.restore_snapshot("missing").await, Err(StorageError::InvalidArgument(_))));
+        assert!(matches!(reloaded.create_snapshot("before-cleanup").await, Err(StorageError::InvalidArgument(_))));
+    }
+
+    #[tokio::test]
+    async fn test_should_seal_encrypted_fields_on_disk_and_open_them_with_the_field_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_path = dir.path().join("data.json");
+        let schema_path = dir.path().join("field-schema.toml");
+        std::fs::write(&schema_path, "[types.machine.fields.bmc_password]\nencrypted = true").unwrap();
+        let schema = || FieldSchema::load(&schema_path).unwrap();
+        let key = || SealingKey::parse(&"5a".repeat(32)).unwrap();
+
+        // Written in the clear before the field was declared encrypted...
+        let storage = open_file_storage(storage_path.clone());
+        storage.add_record(vec![
+            ("type".to_string(), "machine".to_string()),
+            ("hostname".to_string(), "bmc01".to_string()),
+            ("bmc_password".to_string(), "calvin".to_string()),
+        ], None, None).await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        drop(storage);
+
+        // ...and sealed once it is, along with everything written from then on.
+        let storage = open_file_storage(storage_path.clone()).with_field_schema(schema()).with_field_key(key()).unwrap();
+        storage.change_record(&[(Some("hostname".to_string()), "bmc01".to_string())], &[("bmc_password".to_string(), "hunter2".to_string())], None, &[], None, true).await.unwrap();
+        storage.create_snapshot("sealed").await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        for path in [storage_path.clone(), journal::journal_path(&storage_path), dir.path().join("data.snapshots").join("sealed.json")] {
+            let on_disk = std::fs::read_to_string(&path).unwrap();
+            assert!(!on_disk.contains("calvin") && !on_disk.contains("hunter2"), "{:?}: {}", path, on_disk);
+        }
+        drop(storage);
+
+        let reloaded = open_file_storage(storage_path.clone()).with_field_schema(schema()).with_field_key(key()).unwrap();
+        let found = reloaded.query(&[(Some("hostname".to_string()), "bmc01".to_string())], None).await.unwrap();
+        assert_eq!(found[0].fields["bmc_password"], "hunter2");
+        assert_eq!(reloaded.restore_snapshot("sealed").await.unwrap(), 1);
+        drop(reloaded);
+
+        let wrong_key = open_file_storage(storage_path.clone()).with_field_schema(schema()).with_field_key(SealingKey::parse(&"a5".repeat(32)).unwrap());
+        let Err(e) = wrong_key else { panic!("a wrong field key must not load") };
+        assert!(format!("{:#}", e).contains("field key"));
+        let keyless = open_file_storage(storage_path);
+        let found = keyless.query(&[(Some("hostname".to_string()), "bmc01".to_string())], None).await.unwrap();
+        assert!(sealing::is_sealed(&found[0].fields["bmc_password"]));
+    }
+
+    #[tokio::test]
+    async fn test_should_encrypt_files_with_the_storage_key_and_rotate_to_a_new_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_path = dir.path().join("data.json");
+        let config = JournalConfig { fsync: journal::FsyncPolicy::Never, compact_every: 1 };
+        let old_key = || SealingKey::parse(&"11".repeat(32)).unwrap();
+        let new_key = || SealingKey::parse(&"22".repeat(32)).unwrap();
+        let old_id = old_key().id().to_string();
+
+        let storage = FileStorage::open(storage_path.clone(), config, FileCipher::new(Some(old_key())), CorruptPolicy::default()).unwrap();
+        storage.add_record(vec![("type".to_string(), "machine".to_string()), ("hostname".to_string(), "secret-web01".to_string())], None, None).await.unwrap();
+        storage.create_snapshot("nightly").await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        for path in [storage_path.clone(), dir.path().join("data.snapshots").join("nightly.json")] {
+            let on_disk = std::fs::read_to_string(&path).unwrap();
+            assert_eq!(sealing::key_id_of(&on_disk), Some(old_id.as_str()), "{:?}: {}", path, on_disk);
+        }
+        drop(storage);
+
+        for cipher in [FileCipher::default(), FileCipher::new(Some(new_key()))] {
+            let Err(e) = FileStorage::open(storage_path.clone(), config, cipher, CorruptPolicy::default()) else { panic!("a data file must not load without its key") };
+            assert!(format!("{:#}", e).contains(&old_id), "{:#}", e);
+        }
+
+        // Rotation: the previous key reads the files, the next persist rewrites them with the new one.
+        let rotated = FileStorage::open(storage_path.clone(), config, FileCipher::new(Some(new_key())).with_previous(old_key()), CorruptPolicy::default()).unwrap();
+        assert_eq!(rotated.query(&[(Some("hostname".to_string()), "secret-web01".to_string())], None).await.unwrap().len(), 1);
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        assert_eq!(sealing::key_id_of(&std::fs::read_to_string(&storage_path).unwrap()), Some(new_key().id()));
+        drop(rotated);
+
+        let reloaded = FileStorage::open(storage_path, config, FileCipher::new(Some(new_key())), CorruptPolicy::default()).unwrap();
+        assert_eq!(reloaded.record_count().await, 1);
+        assert!(matches!(reloaded.restore_snapshot("nightly").await, Err(StorageError::InvalidArgument(_))), "still sealed with the retired key");
+    }
+
+    #[tokio::test]
+    async fn test_should_refuse_a_corrupt_data_file_unless_told_to_start_read_only_or_recover() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_path = dir.path().join("data.json");
+        let config = JournalConfig { fsync: journal::FsyncPolicy::Never, compact_every: 1 };
+        let open = |policy| FileStorage::open(storage_path.clone(), config, FileCipher::default(), policy);
+
+        let storage = open(CorruptPolicy::default()).unwrap();
+        for hostname in ["web01", "web02", "web03"] {
+            storage.add_record(vec![("type".to_string(), "machine".to_string()), ("hostname".to_string(), hostname.to_string())], None, None).await.unwrap();
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        drop(storage);
+        let corrupt = std::fs::read_to_string(&storage_path).unwrap().replacen("\"web02\"", "\"web02\" oops", 1);
+        std::fs::write(&storage_path, &corrupt).unwrap();
+
+        let Err(e) = open(CorruptPolicy::Refuse) else { panic!("a corrupt data file must not load") };
+        let report = format!("{:#}", e);
+        assert!(report.contains("2 records were recovered and 1 lost") && report.contains("(web02)"), "{}", report);
+        let quarantined: Vec<_> = std::fs::read_dir(dir.path()).unwrap().flatten().filter(|e| e.file_name().to_string_lossy().starts_with("data.json.corrupt-")).collect();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(std::fs::read_to_string(quarantined[0].path()).unwrap(), corrupt);
+
+        let read_only = open(CorruptPolicy::ReadOnly).unwrap();
+        assert!(read_only.is_read_only());
+        assert_eq!(read_only.record_count().await, 2);
+        let add = read_only.add_record(vec![("type".to_string(), "machine".to_string()), ("hostname".to_string(), "web04".to_string())], None, None).await;
+        assert!(matches!(add, Err(StorageError::ReadOnly)));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        assert_eq!(std::fs::read_to_string(&storage_path).unwrap(), corrupt, "nothing is written while read-only");
+        drop(read_only);
+
+        let recovered = open(CorruptPolicy::Recover).unwrap();
+        assert!(!recovered.is_read_only());
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        let (on_disk, _) = journal::parse_data_file(&std::fs::read_to_string(&storage_path).unwrap()).unwrap();
+        let mut hostnames: Vec<&str> = on_disk.iter().map(|r| r.fields["hostname"].as_str()).collect();
+        hostnames.sort();
+        assert_eq!(hostnames, vec!["web01", "web03"]);
+    }
+
+    #[tokio::test]
+    async fn test_should_handle_a_file_failing_authentication_under_its_key_as_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_path = dir.path().join("data.json");
+        let journal_path = journal::journal_path(&storage_path);
+        let config = JournalConfig { fsync: journal::FsyncPolicy::Never, compact_every: 3 };
+        let cipher = || FileCipher::new(Some(SealingKey::parse(&"11".repeat(32)).unwrap()));
+        let open = |policy| FileStorage::open(storage_path.clone(), config, cipher(), policy);
+        // Flips one character of a sealed value's ciphertext, keeping the key id readable.
+        let tamper = |sealed: &str| {
+            let at = sealed.len() - 10;
+            let flipped = if &sealed[at..at + 1] == "A" { "B" } else { "A" };
+            format!("{}{}{}", &sealed[..at], flipped, &sealed[at + 1..])
+        };
+
+        let storage = open(CorruptPolicy::default()).unwrap();
+        for hostname in ["web01", "web02", "web03", "web04", "web05"] {
+            storage.add_record(vec![("type".to_string(), "machine".to_string()), ("hostname".to_string(), hostname.to_string())], None, None).await.unwrap();
+            tokio::time::sleep(tokio::time::Duration::from_millis(20)).await;
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        drop(storage);
+
+        // A damaged journal line that isn't the last one isn't a torn append.
+        let journal: Vec<String> = std::fs::read_to_string(&journal_path).unwrap().lines().map(str::to_string).collect();
+        assert_eq!(journal.len(), 2);
+        std::fs::write(&journal_path, format!("{}\n{}\n", tamper(&journal[0]), journal[1])).unwrap();
+        let Err(e) = open(CorruptPolicy::Refuse) else { panic!("a damaged journal must not load") };
+        let report = format!("{:#}", e);
+        assert!(report.contains("journal") && report.contains("does not decrypt") && report.contains("3 records were recovered and 2 lost"), "{}", report);
+        let recovered = open(CorruptPolicy::Recover).unwrap();
+        assert_eq!(recovered.record_count().await, 3);
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        drop(recovered);
+        assert!(std::fs::read_dir(dir.path()).unwrap().flatten().any(|e| e.file_name().to_string_lossy().starts_with("data.journal.corrupt-")));
+
+        // So is a data file failing authentication: it is copied aside, and nothing in it is salvaged.
+        std::fs::write(&storage_path, tamper(&std::fs::read_to_string(&storage_path).unwrap())).unwrap();
+        let Err(e) = open(CorruptPolicy::Refuse) else { panic!("a damaged data file must not load") };
+        assert!(format!("{:#}", e).contains("does not decrypt with storage key"), "{:#}", e);
+        let read_only = open(CorruptPolicy::ReadOnly).unwrap();
+        assert!(read_only.is_read_only());
+        assert_eq!(read_only.record_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_should_migrate_and_append_fields_newly_declared_multi_valued() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_path = dir.path().join("data.json");
+        let schema_path = dir.path().join("field-schema.toml");
+        std::fs::write(&schema_path, "[types.machine.fields.tags]\nmulti = true").unwrap();
+        let selection = vec![(Some("hostname".to_string()), "web01".to_string())];
+
+        let storage = open_file_storage(storage_path.clone());
+        storage.add_record(vec![
+            ("type".to_string(), "machine".to_string()),
+            ("hostname".to_string(), "web01".to_string()),
+            ("tags".to_string(), "prod".to_string()),
+        ], None, None).await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        drop(storage);
+
+        let storage = open_file_storage(storage_path.clone()).with_field_schema(FieldSchema::load(&schema_path).unwrap());
+        let records = storage.query(&selection, None).await.unwrap();
+        assert!(!records[0].fields.contains_key("tags"));
+        assert_eq!(records[0].multi_fields["tags"], vec!["prod"]);
+
+        storage.change_record(&selection, &[("tags".to_string(), "web".to_string())], None, &[], None, false).await.unwrap();
+        let by_tag = storage.query(&[(Some("tags".to_string()), "web".to_string())], None).await.unwrap();
+        assert_eq!(by_tag.len(), 1);
+        assert_eq!(by_tag[0].multi_fields["tags"], vec!["prod", "web"]);
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let reloaded = open_file_storage(storage_path);
+        assert_eq!(reloaded.query(&selection, None).await.unwrap()[0].multi_fields["tags"], vec!["prod", "web"]);
+    }
+
+    #[tokio::test]
+    async fn test_should_compare_multi_valued_and_missing_fields() {
+        let storage = MemoryStorage::new();
+        for (hostname, ips) in [("web01", vec!["10.0.0.5", "10.0.1.5"]), ("web02", vec!["10.0.2.5"]), ("web03", vec![])] {
+            let mut fields = vec![("type".to_string(), "machine".to_string()), ("hostname".to_string(), hostname.to_string())];
+            fields.extend(ips.into_iter().map(|ip| ("ip_addr".to_string(), ip.to_string())));
+            storage.add_record(fields, None, None).await.unwrap();
+        }
+        let hostnames = |records: Vec<Record>| records.iter().map(|r| r.fields["hostname"].clone()).collect::<Vec<_>>();
+
+        // Any value may satisfy an ordering; `!=` needs all of them to differ. Records without
+        // the field never match a comparison.
+        let above = storage.query(&[(Some("ip_addr>".to_string()), "10.0.0.255".to_string())], None).await.unwrap();
+        assert_eq!(hostnames(above), vec!["web01", "web02"]);
+        let not_first = storage.query(&[(Some("ip_addr!=".to_string()), "10.0.0.5".to_string())], None).await.unwrap();
+        assert_eq!(hostnames(not_first), vec!["web02"]);
+
+        let bad = storage.query(&[(Some("ip_addr>".to_string()), "ten".to_string())], None).await;
+        assert!(matches!(bad, Err(StorageError::InvalidArgument(_))));
+    }
+
+    #[tokio::test]
+    async fn test_should_report_snapshots_unsupported_without_file_storage() {
+        let storage = MemoryStorage::new();
+        assert!(matches!(storage.create_snapshot("nightly").await, Err(StorageError::Unsupported(_))));
+        assert!(matches!(storage.list_snapshots().await, Err(StorageError::Unsupported(_))));
+    }
 }
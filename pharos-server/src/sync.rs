@@ -13,7 +13,7 @@
 
 use crate::storage::Storage;
 use pharos_client::PharosClient;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use tracing::{info, error, debug};
 use std::collections::HashMap;
 
@@ -40,7 +40,7 @@ pub fn strip_sync_prefix(line: &str) -> (bool, &str) {
     }
 }
 
-pub async fn register_self(storage: Arc<RwLock<dyn Storage>>, addr: &str) -> anyhow::Result<()> {
+pub async fn register_self(storage: Arc<dyn Storage>, addr: &str) -> anyhow::Result<()> {
     info!("Registering self as pharos-server at {}", addr);
     let mut fields = HashMap::new();
     fields.insert("hostname".to_string(), addr.to_string());
@@ -48,12 +48,11 @@ pub async fn register_self(storage: Arc<RwLock<dyn Storage>>, addr: &str) -> any
     fields.insert("type".to_string(), "machine".to_string());
     fields.insert("status".to_string(), "online".to_string());
 
-    let mut lock = storage.write().map_err(|_| anyhow::anyhow!("Storage lock poisoned"))?;
-    lock.upsert_record(fields.into_iter().collect(), None, None)?;
+    storage.upsert_record(fields.into_iter().collect(), None, None).await?;
     Ok(())
 }
 
-pub async fn bootstrap(storage: Arc<RwLock<dyn Storage>>, peer_addr: &str) -> anyhow::Result<()> {
+pub async fn bootstrap(storage: Arc<dyn Storage>, peer_addr: &str) -> anyhow::Result<()> {
     info!("Bootstrapping from peer: {}", peer_addr);
     let mut client = PharosClient::connect(peer_addr, "pharos-sync-bootstrap").await?;
     
@@ -61,40 +60,114 @@ pub async fn bootstrap(storage: Arc<RwLock<dyn Storage>>, peer_addr: &str) -> an
     let resp = client.execute("query").await?;
     if let pharos_client::PharosResponse::Matches { records, .. } = resp {
         info!("Pulling {} records from bootstrap peer", records.len());
-        let mut lock = storage.write().map_err(|_| anyhow::anyhow!("Storage lock poisoned"))?;
         for record in records {
             let mut fields: Vec<(String, String)> = Vec::new();
-            for field in record.fields {
+            // The peer's revision number is its own; this node counts its own writes.
+            for field in record.fields.into_iter().filter(|f| f.key != crate::storage::REVISION_FIELD) {
                 fields.push((field.key, field.value));
             }
             // Tag as forwarded to avoid immediate re-replication back to the peer
             fields.push(("forwarded".to_string(), "true".to_string()));
-            lock.upsert_record(fields, None, None)?;
+            storage.upsert_record(fields, None, None).await?;
         }
     }
-    
+
+    // Records the peer deleted while this node was away would otherwise live on here (and
+    // spread back out with the next replicated write), so apply the peer's tombstones too.
+    match client.execute("tombstones").await? {
+        pharos_client::PharosResponse::Matches { records, .. } => {
+            let mut applied = 0;
+            for record in records {
+                let fields: Vec<(String, String)> = record.fields.into_iter().map(|f| (f.key, f.value)).collect();
+                if let Some((identity, deleted_at)) = tombstone_identity(&fields)
+                    && storage.import_tombstone(&identity, &deleted_at).await?
+                {
+                    applied += 1;
+                }
+            }
+            info!("Applied {} tombstones from bootstrap peer", applied);
+        }
+        other => debug!("Bootstrap peer returned no tombstones: {:?}", other),
+    }
+
     client.quit().await?;
     Ok(())
 }
 
-pub async fn replicate_command(storage: Arc<RwLock<dyn Storage>>, command: String, my_addr: String) {
-    let peers = {
-        let lock = storage.read().unwrap();
-        let selections = vec![(Some("role".to_string()), "pharos-server".to_string())];
-        match lock.query(&selections, None) {
-            Ok(records) => {
-                records.into_iter()
-                    .filter_map(|r| r.fields.get("hostname").cloned())
-                    .filter(|addr| addr != &my_addr) // Don't push to self
-                    .collect::<Vec<String>>()
-            }
-            Err(e) => {
-                error!("Sync peer discovery error: {}", e);
-                return;
+/// The wire lines that replay a committed transaction on a peer: its commands, each wrapped for
+/// sync, between a `begin` and a `commit`, so the peer applies them as one batch too. Pure and
+/// total.
+pub fn wrap_batch_for_sync(commands: &[String]) -> Vec<String> {
+    std::iter::once("begin")
+        .chain(commands.iter().map(String::as_str))
+        .chain(std::iter::once("commit"))
+        .map(wrap_for_sync)
+        .collect()
+}
+
+/// Replicates a committed transaction to every peer over one connection each.
+pub async fn replicate_batch(storage: Arc<dyn Storage>, commands: Vec<String>, my_addr: String) {
+    let peers = sync_peers(&storage, &my_addr).await;
+    if peers.is_empty() {
+        return;
+    }
+
+    debug!("Replicating a {}-command transaction to {} peers", commands.len(), peers.len());
+
+    let lines = wrap_batch_for_sync(&commands);
+
+    for peer in peers {
+        let lines = lines.clone();
+        tokio::spawn(async move {
+            match PharosClient::connect(&peer, "pharos-sync").await {
+                Ok(mut client) => {
+                    for line in &lines {
+                        if let Err(e) = client.execute_authenticated(line).await {
+                            error!("Failed to replicate transaction to peer {}: {}", peer, e);
+                            break;
+                        }
+                    }
+                    let _ = client.quit().await;
+                }
+                Err(e) => {
+                    error!("Failed to connect to peer {} for replication: {}", peer, e);
+                }
             }
+        });
+    }
+}
+
+/// The upsert identity (hostname, else alias) and deletion time of one entry of a peer's
+/// `tombstones` response. Pure and total.
+pub fn tombstone_identity(fields: &[(String, String)]) -> Option<(String, String)> {
+    let deleted_at = fields.iter().find(|(k, _)| k == "deleted_at").map(|(_, v)| v.clone())?;
+    let identity = fields
+        .iter()
+        .find(|(k, _)| k == "hostname")
+        .or_else(|| fields.iter().find(|(k, _)| k == "alias"))
+        .map(|(_, v)| v.clone())?;
+    Some((identity, deleted_at))
+}
+
+/// Every registered pharos-server other than this one.
+async fn sync_peers(storage: &Arc<dyn Storage>, my_addr: &str) -> Vec<String> {
+    let selections = vec![(Some("role".to_string()), "pharos-server".to_string())];
+    match storage.query(&selections, None).await {
+        Ok(records) => {
+            records.into_iter()
+                .filter_map(|r| r.fields.get("hostname").cloned())
+                .filter(|addr| addr != my_addr) // Don't push to self
+                .collect::<Vec<String>>()
+        }
+        Err(e) => {
+            error!("Sync peer discovery error: {}", e);
+            Vec::new()
         }
-    };
+    }
+}
 
+pub async fn replicate_command(storage: Arc<dyn Storage>, command: String, my_addr: String) {
+    let peers = sync_peers(&storage, &my_addr).await;
     if peers.is_empty() {
         return;
     }
@@ -148,4 +221,21 @@ mod tests {
         let line = "add hostname=SYNC-server-01 status=up";
         assert_eq!(strip_sync_prefix(line), (false, line));
     }
+
+    #[test]
+    fn test_should_wrap_a_transaction_between_sync_begin_and_commit() {
+        let lines = wrap_batch_for_sync(&["change hostname=web01 make ip=10.0.0.2".to_string()]);
+        assert_eq!(lines, vec!["SYNC begin", "SYNC change hostname=web01 make ip=10.0.0.2", "SYNC commit"]);
+    }
+
+    #[test]
+    fn test_should_read_identity_and_deletion_time_from_a_peer_tombstone() {
+        let field = |k: &str, v: &str| (k.to_string(), v.to_string());
+        let by_alias = [field("alias", "primary"), field("type", "machine"), field("deleted_at", "2026-03-01T00:00:00+00:00")];
+        assert_eq!(tombstone_identity(&by_alias), Some(("primary".to_string(), "2026-03-01T00:00:00+00:00".to_string())));
+
+        let both = [field("alias", "primary"), field("hostname", "web01"), field("deleted_at", "t")];
+        assert_eq!(tombstone_identity(&both).unwrap().0, "web01");
+        assert_eq!(tombstone_identity(&[field("hostname", "web01")]), None);
+    }
 }
@@ -0,0 +1,151 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core
+ * File: pharos-server/src/tombstone.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * A `delete` used to drop the record on the spot, so a mistyped selection
+ * was unrecoverable and a peer that still held the record would hand it
+ * back on its next bootstrap. Deleting now leaves a tombstone: the record
+ * stays in storage stamped with `deleted_at`, hidden from every query,
+ * until `undelete` restores it or the retention window expires and the
+ * monitor loop purges it for good.
+ * * Traceability:
+ * Backs the `undelete` and `tombstones` protocol commands and the tombstone
+ * exchange in sync.rs bootstrap.
+ * ======================================================================== */
+
+use crate::history::{self, Author};
+use crate::storage::Record;
+use chrono::{DateTime, Duration, Utc};
+use tracing::warn;
+
+/// Days a tombstone is kept unless overridden by `PHAROS_TOMBSTONE_RETENTION_DAYS`.
+pub const DEFAULT_RETENTION_DAYS: i64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TombstoneConfig {
+    /// Days a deleted record can still be undeleted; 0 purges it on the next monitor pass.
+    pub retention_days: i64,
+}
+
+impl Default for TombstoneConfig {
+    fn default() -> Self {
+        Self { retention_days: DEFAULT_RETENTION_DAYS }
+    }
+}
+
+impl TombstoneConfig {
+    /// Reads `PHAROS_TOMBSTONE_RETENTION_DAYS`, falling back to the default (with a warning) on
+    /// an unparseable or negative value rather than refusing to start.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Ok(raw) = std::env::var("PHAROS_TOMBSTONE_RETENTION_DAYS") {
+            match raw.trim().parse::<i64>() {
+                Ok(days) if days >= 0 => config.retention_days = days,
+                _ => warn!("Ignoring invalid PHAROS_TOMBSTONE_RETENTION_DAYS value '{}'", raw),
+            }
+        }
+        config
+    }
+
+    /// Tombstones older than this are due for purging.
+    pub fn cutoff(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now - Duration::days(self.retention_days)
+    }
+}
+
+fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value).ok().map(|t| t.with_timezone(&Utc))
+}
+
+/// Stamps `record` as deleted at `deleted_at` and logs the deletion in its revision history.
+pub fn mark_deleted(record: &mut Record, deleted_at: String, author: &Author, history_limit: usize) {
+    let before = record.clone();
+    record.deleted_at = Some(deleted_at);
+    history::record_revision(Some(&before), record, author, history_limit);
+}
+
+/// Clears the tombstone on `record` and logs the restore in its revision history.
+pub fn mark_restored(record: &mut Record, author: &Author, history_limit: usize) {
+    let before = record.clone();
+    record.deleted_at = None;
+    history::record_revision(Some(&before), record, author, history_limit);
+}
+
+/// Whether `record` is a tombstone deleted before `cutoff`. A tombstone with an unreadable
+/// timestamp is kept - purging is irreversible, so it only acts on what it can date.
+pub fn is_expired(record: &Record, cutoff: DateTime<Utc>) -> bool {
+    record.deleted_at.as_deref().is_some_and(|deleted_at| deleted_before(deleted_at, cutoff))
+}
+
+/// Whether a `deleted_at` stamp is a readable time before `cutoff`.
+pub fn deleted_before(deleted_at: &str, cutoff: DateTime<Utc>) -> bool {
+    parse_time(deleted_at).is_some_and(|at| at < cutoff)
+}
+
+/// Whether a peer's deletion at `deleted_at` should win over the local live `record`: it does
+/// unless the record has been seen since, i.e. it was re-added or heartbeated after the delete.
+pub fn is_superseded_by(record: &Record, deleted_at: &str) -> bool {
+    let Some(deleted_at) = parse_time(deleted_at) else {
+        return false;
+    };
+    match record.fields.get("last_seen_at").and_then(|seen| parse_time(seen)) {
+        Some(seen) => seen <= deleted_at,
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn seen_at(last_seen_at: &str) -> Record {
+        Record {
+            fields: HashMap::from([("last_seen_at".to_string(), last_seen_at.to_string())]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_should_expire_only_tombstones_older_than_the_cutoff() {
+        let now = parse_time("2026-03-31T00:00:00+00:00").unwrap();
+        let cutoff = TombstoneConfig { retention_days: 30 }.cutoff(now);
+
+        let mut record = seen_at("2026-01-01T00:00:00+00:00");
+        assert!(!is_expired(&record, cutoff), "live records never expire");
+
+        record.deleted_at = Some("2026-02-28T00:00:00+00:00".to_string());
+        assert!(is_expired(&record, cutoff));
+        record.deleted_at = Some("2026-03-02T00:00:00+00:00".to_string());
+        assert!(!is_expired(&record, cutoff));
+        record.deleted_at = Some("yesterday".to_string());
+        assert!(!is_expired(&record, cutoff));
+    }
+
+    #[test]
+    fn test_should_let_a_peer_deletion_win_only_over_records_not_seen_since() {
+        let record = seen_at("2026-03-01T12:00:00+00:00");
+        assert!(is_superseded_by(&record, "2026-03-01T13:00:00+00:00"));
+        assert!(!is_superseded_by(&record, "2026-03-01T11:00:00+00:00"));
+        assert!(!is_superseded_by(&record, "not a timestamp"));
+    }
+
+    #[test]
+    fn test_should_log_delete_and_restore_as_revisions() {
+        let author = Author { fingerprint: Some("SHA256:abc".to_string()), ..Default::default() };
+        let mut record = seen_at("2026-03-01T12:00:00+00:00");
+        mark_deleted(&mut record, "2026-03-02T00:00:00+00:00".to_string(), &author, 10);
+        mark_restored(&mut record, &author, 10);
+
+        assert!(record.deleted_at.is_none());
+        let summaries: Vec<String> = record.history.iter().map(|r| r.changes[0].summary()).collect();
+        assert_eq!(summaries, vec![
+            "(none) -> 2026-03-02T00:00:00+00:00".to_string(),
+            "2026-03-02T00:00:00+00:00 -> (none)".to_string(),
+        ]);
+        assert_eq!(record.history[0].changes[0].field, "deleted_at");
+    }
+}
@@ -22,7 +22,7 @@ use pharos_client::{PharosClient, PharosResponse};
 use tokio::net::TcpListener;
 use tokio_rustls::rustls::{ServerConfig, pki_types::CertificateDer, pki_types::PrivateKeyDer};
 use tokio_rustls::TlsAcceptor;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use std::path::Path;
 use std::fs::File;
 use std::io::BufReader;
@@ -67,14 +67,14 @@ async fn test_should_transparently_auth_retry_when_506_received() {
     // 3. Generate SSH keys using ssh-keygen
     let admin_key_path = dir_path.join("admin_id_ed25519");
     let key_status = Command::new("ssh-keygen")
-        .args(&["-t", "ed25519", "-N", "", "-f", admin_key_path.to_str().unwrap()])
+        .args(["-t", "ed25519", "-N", "", "-f", admin_key_path.to_str().unwrap()])
         .status()
         .expect("Failed to execute ssh-keygen");
     assert!(key_status.success());
 
     let regular_key_path = dir_path.join("regular_id_ed25519");
     let reg_key_status = Command::new("ssh-keygen")
-        .args(&["-t", "ed25519", "-N", "", "-f", regular_key_path.to_str().unwrap()])
+        .args(["-t", "ed25519", "-N", "", "-f", regular_key_path.to_str().unwrap()])
         .status()
         .expect("Failed to execute ssh-keygen for regular user");
     assert!(reg_key_status.success());
@@ -96,7 +96,7 @@ async fn test_should_transparently_auth_retry_when_506_received() {
     let acceptor = TlsAcceptor::from(Arc::new(config));
 
     // Initialize AuthManager & Middleware for Scoped tier (to verify both normal retry & role denial)
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(MemoryStorage::new()));
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
     let auth_manager = Arc::new(AuthManager::new(&keys_dir, SecurityTier::Scoped));
     
     let mut chain = MiddlewareChain::new();
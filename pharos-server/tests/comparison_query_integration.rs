@@ -0,0 +1,127 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core Tests
+ * File: pharos-server/tests/comparison_query_integration.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Verifies over the wire protocol that query selections can compare with
+ * `>`, `<`, `>=`, `<=` and `!=`: numbers numerically, timestamps (absolute
+ * or `now-2h`) in time order, versions the semver way, and that an operand
+ * a declared field can't compare with is rejected with 512.
+ * * Traceability:
+ * Backs `mdb mem_total_kb>=16000000` style inventory queries.
+ * ======================================================================== */
+
+use pharos_server::handle_connection;
+use pharos_server::auth::{AuthManager, SecurityTier};
+use pharos_server::middleware::{MiddlewareChain, SecurityTierMiddleware};
+use pharos_server::schema::FieldSchema;
+use pharos_server::storage::{MemoryStorage, Storage};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncWriteExt, AsyncBufReadExt, BufReader};
+use std::sync::Arc;
+use tempfile::tempdir;
+
+fn machine(hostname: &str, extra: &[(&str, &str)]) -> Vec<(String, String)> {
+    let mut fields = vec![("type".to_string(), "machine".to_string()), ("hostname".to_string(), hostname.to_string())];
+    fields.extend(extra.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+    fields
+}
+
+/// Starts an open-tier server over three machines with differing memory, version and
+/// heartbeat, where `cpu_cores` is declared an integer.
+async fn setup_server() -> std::net::SocketAddr {
+    let dir = tempdir().unwrap();
+    let schema_path = dir.path().join("field-schema.toml");
+    std::fs::write(&schema_path, "[fields.type]\nrequired = true\n\n[fields.cpu_cores]\nkind = \"int\"\n\n[fields.version]\nkind = \"version\"").unwrap();
+    let storage = MemoryStorage::new().with_field_schema(FieldSchema::load(&schema_path).unwrap());
+
+    let hour_ago = (chrono::Utc::now() - chrono::Duration::hours(1)).to_rfc3339();
+    for (hostname, mem, cores, version, seen) in [
+        ("small", "8000000", "4", "1.9.3", "2026-01-01T00:00:00+00:00"),
+        ("large", "32000000", "16", "v1.10.0", hour_ago.as_str()),
+        ("beta", "16000000", "8", "1.10.0-rc.1", hour_ago.as_str()),
+    ] {
+        storage.add_record(machine(hostname, &[("mem_total_kb", mem), ("cpu_cores", cores), ("version", version), ("uplink", &format!("{}-01>lb", hostname))]), None, None).await.unwrap();
+        storage.change_record(&[(Some("hostname".to_string()), hostname.to_string())], &[("last_seen_at".to_string(), seen.to_string())], None, &[], None, false).await.unwrap();
+    }
+
+    let storage: Arc<dyn Storage> = Arc::new(storage);
+    let auth_manager = Arc::new(AuthManager::new(dir.path(), SecurityTier::Open));
+    let mut chain = MiddlewareChain::new();
+    chain.add(Arc::new(SecurityTierMiddleware { default_tier: SecurityTier::Open }));
+    let middleware_chain = Arc::new(chain);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let _dir = dir;
+        loop {
+            if let Ok((socket, peer_addr)) = listener.accept().await {
+                let s = Arc::clone(&storage);
+                let a = Arc::clone(&auth_manager);
+                let m = Arc::clone(&middleware_chain);
+                tokio::spawn(async move {
+                    let _ = handle_connection(socket, peer_addr.to_string(), s, a, m).await;
+                });
+            }
+        }
+    });
+    addr
+}
+
+/// Sends one command and collects its response lines up to and including the final status.
+async fn exec(reader: &mut BufReader<TcpStream>, cmd: &str) -> Vec<String> {
+    reader.get_mut().write_all(format!("{}\n", cmd).as_bytes()).await.unwrap();
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        let trimmed = line.trim().to_string();
+        let done = !trimmed.starts_with('-') && !trimmed.starts_with("1");
+        lines.push(trimmed);
+        if done {
+            return lines;
+        }
+    }
+}
+
+/// The hostnames a query returns, in response order.
+async fn hostnames(reader: &mut BufReader<TcpStream>, query: &str) -> Vec<String> {
+    exec(reader, &format!("{} return hostname", query))
+        .await
+        .iter()
+        .filter_map(|l| l.split_once("hostname: ").map(|(_, h)| h.to_string()))
+        .collect()
+}
+
+#[tokio::test]
+async fn test_should_filter_with_typed_comparisons() {
+    let addr = setup_server().await;
+    let mut reader = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut welcome = String::new();
+    reader.read_line(&mut welcome).await.unwrap();
+
+    assert_eq!(hostnames(&mut reader, "query mem_total_kb>=16000000").await, vec!["large", "beta"]);
+    assert_eq!(hostnames(&mut reader, "query cpu_cores>8").await, vec!["large"]);
+    assert_eq!(hostnames(&mut reader, "query last_seen_at<2026-10-01").await, vec!["small"]);
+    assert_eq!(hostnames(&mut reader, "query last_seen_at>now-2h cpu_cores<=8").await, vec!["beta"]);
+    assert_eq!(hostnames(&mut reader, "query version<1.10.0").await, vec!["small", "beta"]);
+    assert_eq!(hostnames(&mut reader, "query hostname!=small").await, vec!["large", "beta"]);
+
+    assert_eq!(exec(&mut reader, "query cpu_cores>many").await.len(), 1);
+    assert!(exec(&mut reader, "query cpu_cores>many").await[0].starts_with("512:"));
+    assert_eq!(exec(&mut reader, "query mem_total_kb>64000000").await, vec!["501:No matches to query"]);
+}
+
+#[tokio::test]
+async fn test_should_match_a_bare_word_containing_an_operator_as_text() {
+    let addr = setup_server().await;
+    let mut reader = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut welcome = String::new();
+    reader.read_line(&mut welcome).await.unwrap();
+
+    assert_eq!(hostnames(&mut reader, "query large-01>lb").await, vec!["large"]);
+    assert_eq!(hostnames(&mut reader, "query uplink=beta-01>lb").await, vec!["beta"]);
+}
@@ -8,12 +8,15 @@
 
 use pharos_server::alerting::{self, AlertState};
 use pharos_server::handle_connection;
+use pharos_server::file_cipher::FileCipher;
+use pharos_server::journal::JournalConfig;
+use pharos_server::recovery::CorruptPolicy;
 use pharos_server::storage::{FileStorage, Storage};
 use pharos_server::auth::{AuthManager, SecurityTier};
 use pharos_server::middleware::{MiddlewareChain, SecurityTierMiddleware};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use std::collections::HashMap;
 use tempfile::tempdir;
 use warp::Filter;
@@ -77,7 +80,7 @@ async fn test_live_verification_step_5_hostname_collision_avoidance() {
     std::fs::write(keys_dir.join("tester_id_ed25519.pub"), user.pub_key.as_bytes()).unwrap();
 
     let storage_path = temp_dir.path().join("data.json");
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(FileStorage::new(storage_path.clone())));
+    let storage: Arc<dyn Storage> = Arc::new(FileStorage::open(storage_path.clone(), JournalConfig::from_env(), FileCipher::default(), CorruptPolicy::default()).unwrap());
     let auth_manager = Arc::new(AuthManager::new(&keys_dir, SecurityTier::Open));
 
     let mut chain = MiddlewareChain::new();
@@ -124,10 +127,9 @@ async fn test_live_verification_step_5_hostname_collision_avoidance() {
     assert_eq!(resp_console, "200:Ok");
 
     // Verify both records exist independently
-    let lock = storage.read().unwrap();
-    let pulse_records = lock.query(&[(Some("hostname".to_string()), "test-host".to_string())], None).unwrap();
+    let pulse_records = storage.query(&[(Some("hostname".to_string()), "test-host".to_string())], None).await.unwrap();
     assert_eq!(pulse_records.len(), 1);
-    let console_records = lock.query(&[(Some("hostname".to_string()), "test-host-console".to_string())], None).unwrap();
+    let console_records = storage.query(&[(Some("hostname".to_string()), "test-host-console".to_string())], None).await.unwrap();
     assert_eq!(console_records.len(), 1);
 }
 
@@ -135,7 +137,7 @@ async fn test_live_verification_step_5_hostname_collision_avoidance() {
 async fn test_live_verification_step_6_version_mismatch_normalization() {
     let temp_dir = tempdir().unwrap();
     let storage_path = temp_dir.path().join("data.json");
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(FileStorage::new(storage_path)));
+    let storage: Arc<dyn Storage> = Arc::new(FileStorage::open(storage_path, JournalConfig::from_env(), FileCipher::default(), CorruptPolicy::default()).unwrap());
 
     // Setup local mock webhook endpoint
     let webhook_called = Arc::new(AtomicBool::new(false));
@@ -155,13 +157,12 @@ async fn test_live_verification_step_6_version_mismatch_normalization() {
 
     // 1. Add record with self-reported version="v1.10.15" and expected_version="1.10.15" (no 'v' prefix)
     {
-        let mut lock = storage.write().unwrap();
         let mut fields = HashMap::new();
         fields.insert("type".to_string(), "machine".to_string());
         fields.insert("hostname".to_string(), "test-host-console".to_string());
         fields.insert("version".to_string(), "v1.10.15".to_string());
         fields.insert("expected_version".to_string(), "1.10.15".to_string());
-        lock.upsert_record(fields.into_iter().collect(), None, None).unwrap();
+        storage.upsert_record(fields.into_iter().collect(), None, None).await.unwrap();
     }
 
     let mut alert_state = AlertState::default();
@@ -180,13 +181,12 @@ async fn test_live_verification_step_6_version_mismatch_normalization() {
 
     // 2. Now update expected_version to "v2.0.0-different"
     {
-        let mut lock = storage.write().unwrap();
         let mut fields = HashMap::new();
         fields.insert("type".to_string(), "machine".to_string());
         fields.insert("hostname".to_string(), "test-host-console".to_string());
         fields.insert("version".to_string(), "v1.10.15".to_string());
         fields.insert("expected_version".to_string(), "v2.0.0-different".to_string());
-        lock.upsert_record(fields.into_iter().collect(), None, None).unwrap();
+        storage.upsert_record(fields.into_iter().collect(), None, None).await.unwrap();
     }
 
     // Check version mismatches -> SHOULD trigger webhook for genuine mismatch!
@@ -0,0 +1,268 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core Tests
+ * File: pharos-server/tests/encrypted_field_integration.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Verifies over the wire protocol that a field the schema declares
+ * `encrypted` is described with RFC 2378's Encrypt attribute, is only shown
+ * to the record's owner, can't be searched on by anyone else, and is only
+ * overwritten by `change ... force` - a plain `make` is refused with 510,
+ * inside a transaction too, and so is an `add` that would replace it. The
+ * value never reaches the server log or a webhook.
+ * * Traceability:
+ * Backs storing BMC and iLO credentials in Pharos.
+ * ======================================================================== */
+
+use pharos_server::handle_connection;
+use pharos_server::storage::{MemoryStorage, Storage};
+use pharos_server::schema::FieldSchema;
+use pharos_server::auth::{AuthManager, SecurityTier};
+use pharos_server::middleware::{LoggingMiddleware, MiddlewareChain, SecurityTierMiddleware};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, AsyncBufReadExt, BufReader};
+use std::sync::{Arc, Mutex};
+use tempfile::tempdir;
+use ssh_key::PrivateKey;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use ed25519_dalek::{Signer, SigningKey};
+
+struct TestUser {
+    pub_key: String,
+    priv_key: PrivateKey,
+}
+
+impl TestUser {
+    fn new() -> Self {
+        let mut rng = rand::rngs::OsRng;
+        let priv_key = PrivateKey::random(&mut rng, ssh_key::Algorithm::Ed25519).unwrap();
+        let pub_key = priv_key.public_key().to_openssh().unwrap();
+        Self { pub_key, priv_key }
+    }
+
+    fn sign(&self, challenge: &str) -> String {
+        let sig_bytes = match self.priv_key.key_data() {
+            ssh_key::private::KeypairData::Ed25519(kp) => {
+                let signing_key = SigningKey::from_bytes(&kp.private.to_bytes());
+                signing_key.sign(challenge.as_bytes()).to_vec()
+            }
+            _ => panic!("Unsupported key type"),
+        };
+        STANDARD.encode(&sig_bytes)
+    }
+}
+
+/// Starts a server that knows two plain users' keys, with `bmc_password` encrypted on machines.
+async fn setup_server() -> (std::net::SocketAddr, [TestUser; 2]) {
+    let dir = tempdir().unwrap();
+    let users = [TestUser::new(), TestUser::new()];
+    for (user, name) in users.iter().zip(["owner", "other"]) {
+        std::fs::write(dir.path().join(format!("{}_id_ed25519.pub", name)), user.pub_key.as_bytes()).unwrap();
+    }
+    let schema_path = dir.path().join("field-schema.toml");
+    std::fs::write(&schema_path, "[types.machine.fields.bmc_password]\nencrypted = true").unwrap();
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new().with_field_schema(FieldSchema::load(&schema_path).unwrap()));
+    let auth_manager = Arc::new(AuthManager::new(dir.path(), SecurityTier::Open));
+
+    let mut chain = MiddlewareChain::new();
+    chain.add(Arc::new(LoggingMiddleware { schema: storage.field_schema().await }));
+    chain.add(Arc::new(SecurityTierMiddleware { default_tier: SecurityTier::Open }));
+    let middleware_chain = Arc::new(chain);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            if let Ok((socket, peer_addr)) = listener.accept().await {
+                let s = Arc::clone(&storage);
+                let a = Arc::clone(&auth_manager);
+                let m = Arc::clone(&middleware_chain);
+                tokio::spawn(async move {
+                    let _ = handle_connection(socket, peer_addr.to_string(), s, a, m).await;
+                });
+            }
+        }
+    });
+
+    (addr, users)
+}
+
+/// Sends one command and collects its response lines up to and including the final status.
+async fn exec(reader: &mut BufReader<TcpStream>, cmd: &str) -> Vec<String> {
+    reader.get_mut().write_all(format!("{}\n", cmd).as_bytes()).await.unwrap();
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        let trimmed = line.trim().to_string();
+        let done = !trimmed.starts_with('-') && !trimmed.starts_with("1");
+        lines.push(trimmed);
+        if done {
+            return lines;
+        }
+    }
+}
+
+async fn connect_as_mdb(addr: std::net::SocketAddr, user: &TestUser) -> BufReader<TcpStream> {
+    let mut reader = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut welcome = String::new();
+    reader.read_line(&mut welcome).await.unwrap();
+
+    assert_eq!(exec(&mut reader, "id mdb").await, vec!["200:Ok"]);
+    let challenge = exec(&mut reader, "login pharos").await.remove(0);
+    let sig = user.sign(challenge.trim_start_matches("301:"));
+    assert_eq!(exec(&mut reader, &format!("auth \"{}\" \"{}\"", user.pub_key, sig)).await, vec!["200:Ok"]);
+    reader
+}
+
+#[tokio::test]
+async fn test_should_only_overwrite_encrypted_fields_with_force() {
+    let (addr, [owner, other]) = setup_server().await;
+    let mut admin = connect_as_mdb(addr, &owner).await;
+    let mut stranger = connect_as_mdb(addr, &other).await;
+
+    let fields = exec(&mut admin, "fields type=machine").await;
+    assert!(fields.iter().any(|l| l.contains("bmc_password") && l.ends_with("Encrypt")), "{:?}", fields);
+
+    assert_eq!(exec(&mut admin, "add type=machine hostname=bmc01 bmc_password=calvin").await, vec!["200:Ok"]);
+    let lines = exec(&mut admin, "query hostname=bmc01 return bmc_password").await;
+    assert!(lines.contains(&"-200:1:bmc_password: calvin".to_string()), "{:?}", lines);
+    let lines = exec(&mut stranger, "query hostname=bmc01").await;
+    assert!(!lines.iter().any(|l| l.contains("bmc_password")), "{:?}", lines);
+
+    let refused = vec!["510:Field 'bmc_password' is encrypted; overwrite it with 'change ... force'".to_string()];
+    assert_eq!(exec(&mut admin, "change hostname=bmc01 make bmc_password=hunter2").await, refused);
+    assert_eq!(exec(&mut admin, "begin").await, vec!["200:Ok"]);
+    assert_eq!(exec(&mut admin, "change hostname=bmc01 make bmc_password=hunter2").await, vec!["200:Staged (1 pending)"]);
+    assert_eq!(
+        exec(&mut admin, "commit").await,
+        vec!["510:Field 'bmc_password' is encrypted; overwrite it with 'change ... force' (command 1)"]
+    );
+    assert_eq!(exec(&mut stranger, "change hostname=bmc01 make bmc_password=hunter2").await, vec!["510:Not authorized to change this entry"]);
+
+    assert_eq!(exec(&mut admin, "change hostname=bmc01 make status=online").await, vec!["200:1 entry changed."]);
+    assert_eq!(exec(&mut admin, "change hostname=bmc01 force bmc_password=hunter2").await, vec!["200:1 entry changed."]);
+    let lines = exec(&mut admin, "query hostname=bmc01 return bmc_password").await;
+    assert!(lines.contains(&"-200:1:bmc_password: hunter2".to_string()), "{:?}", lines);
+}
+
+#[tokio::test]
+async fn test_should_not_match_other_owners_records_on_encrypted_values() {
+    let (addr, [owner, other]) = setup_server().await;
+    let mut admin = connect_as_mdb(addr, &owner).await;
+    let mut stranger = connect_as_mdb(addr, &other).await;
+    assert_eq!(exec(&mut admin, "add type=machine hostname=bmc01 bmc_password=calvin").await, vec!["200:Ok"]);
+
+    for probe in ["query bmc_password=c*", "query calvin", "query bmc_password>b", "query bmc_password!=x", "history bmc_password=c*", "links bmc_password=c*", "dupes bmc_password=c*"] {
+        let lines = exec(&mut stranger, probe).await;
+        assert!(lines.len() == 1 && lines[0].starts_with("501:"), "{}: {:?}", probe, lines);
+    }
+    assert!(exec(&mut stranger, "query hostname=bmc01").await[0].starts_with("102:"), "the record itself is still found");
+
+    for probe in ["query bmc_password=c*", "query calvin", "query bmc_password>b"] {
+        let lines = exec(&mut admin, probe).await;
+        assert!(lines.contains(&"-200:1:hostname: bmc01".to_string()), "{}: {:?}", probe, lines);
+    }
+}
+
+#[tokio::test]
+async fn test_should_not_replace_an_encrypted_value_through_add() {
+    let (addr, [owner, _]) = setup_server().await;
+    let mut admin = connect_as_mdb(addr, &owner).await;
+    assert_eq!(exec(&mut admin, "add type=machine hostname=bmc01 bmc_password=calvin").await, vec!["200:Ok"]);
+
+    assert_eq!(exec(&mut admin, "add type=machine hostname=bmc01 bmc_password=calvin status=online").await, vec!["200:Ok"]);
+    assert_eq!(
+        exec(&mut admin, "add type=machine hostname=bmc01 bmc_password=hunter2").await,
+        vec!["510:Field 'bmc_password' is encrypted; overwrite it with 'change ... force'"]
+    );
+    let lines = exec(&mut admin, "query hostname=bmc01 return bmc_password").await;
+    assert!(lines.contains(&"-200:1:bmc_password: calvin".to_string()), "{:?}", lines);
+}
+
+/// Log output, captured for the test that writes it.
+#[derive(Clone, Default)]
+struct CapturedLog(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedLog {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A webhook endpoint that answers 200 and hands each request it receives to the returned channel.
+async fn webhook_receiver() -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                loop {
+                    let n = socket.read(&mut buf).await.unwrap_or(0);
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    let body_len = text.lines().find_map(|l| l.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap_or(0)));
+                    if let (Some(head_end), Some(body_len)) = (text.find("\r\n\r\n"), body_len)
+                        && request.len() >= head_end + 4 + body_len
+                    {
+                        let _ = tx.send(text);
+                        break;
+                    }
+                    if n == 0 {
+                        return;
+                    }
+                }
+                let _ = socket.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n").await;
+            });
+        }
+    });
+    (url, rx)
+}
+
+#[tokio::test]
+async fn test_should_keep_encrypted_values_out_of_the_log_and_webhooks() {
+    let log = CapturedLog::default();
+    let writer = log.clone();
+    let subscriber = tracing_subscriber::fmt().with_writer(move || writer.clone()).with_ansi(false).finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let (url, mut webhooks) = webhook_receiver().await;
+    unsafe {
+        std::env::set_var("PHAROS_WEBHOOK_URL", &url);
+    }
+
+    let (addr, [owner, _]) = setup_server().await;
+    let mut admin = connect_as_mdb(addr, &owner).await;
+    assert_eq!(exec(&mut admin, "add type=machine hostname=bmc01 bmc_password=calvin-first").await, vec!["200:Ok"]);
+    assert_eq!(exec(&mut admin, "change hostname=bmc01 make bmc_password=hunter2-second force").await, vec!["200:1 entry changed."]);
+    assert_eq!(exec(&mut admin, "begin").await, vec!["200:Ok"]);
+    assert!(exec(&mut admin, "change hostname=bmc01 force bmc_password=swordfish-third").await[0].starts_with("200:"));
+    assert!(exec(&mut admin, "commit").await.last().unwrap().starts_with("200:"));
+
+    let secrets = ["calvin-first", "hunter2-second", "swordfish-third"];
+    let mut events = Vec::new();
+    while events.len() < 3 {
+        let body = tokio::time::timeout(std::time::Duration::from_secs(5), webhooks.recv()).await.expect("a webhook for each write").unwrap();
+        if body.contains("bmc01") {
+            events.push(body);
+        }
+    }
+    for body in &events {
+        assert!(!secrets.iter().any(|s| body.contains(s)), "{}", body);
+        assert!(body.contains("<redacted>"), "{}", body);
+    }
+
+    let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+    assert!(log.contains("bmc_password=<redacted>"), "{}", log);
+    assert!(!secrets.iter().any(|s| log.contains(s)), "{}", log);
+}
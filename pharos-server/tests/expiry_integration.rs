@@ -0,0 +1,109 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core Tests
+ * File: pharos-server/tests/expiry_integration.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Verifies record expiry the way a running server does it: the reaper in
+ * main.rs's monitor loop deletes a scanner record once its source's TTL runs out,
+ * leaves one with `ttl=never`, and replicates the delete to a peer over TLS
+ * so the peer tombstones its copy too.
+ * * Traceability:
+ * Backs `PHAROS_TTL_BY_SOURCE` and `expiry::reap`.
+ * ======================================================================== */
+
+use chrono::Duration;
+use pharos_server::auth::{AuthManager, SecurityTier};
+use pharos_server::expiry::{self, ExpiryConfig};
+use pharos_server::handle_connection;
+use pharos_server::middleware::{MiddlewareChain, SecurityTierMiddleware};
+use pharos_server::storage::{MemoryStorage, Storage};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use tempfile::tempdir;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+
+/// A TLS acceptor for `localhost`, with its certificate written to `dir` for the client to trust.
+fn tls_acceptor(dir: &Path) -> TlsAcceptor {
+    let _ = tokio_rustls::rustls::crypto::ring::default_provider().install_default();
+    let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let ca = dir.join("ca.crt");
+    std::fs::write(&ca, cert.pem()).unwrap();
+    unsafe {
+        std::env::set_var("PHAROS_CA_CERT", ca.to_str().unwrap());
+    }
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
+    let config = ServerConfig::builder().with_no_client_auth().with_single_cert(vec![cert.der().clone()], key).unwrap();
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// Serves `storage` over TLS, returning the `localhost:<port>` address peers reach it at. The
+/// Open tier mints an admin key into the empty `keys`, which replication then signs with.
+async fn serve(storage: Arc<dyn Storage>, keys: &Path, acceptor: TlsAcceptor) -> String {
+    let auth_manager = Arc::new(AuthManager::new(keys, SecurityTier::Open));
+    unsafe {
+        std::env::set_var("PHAROS_PRIVATE_KEY", keys.join("admin_id_ed25519").to_str().unwrap());
+    }
+    let mut chain = MiddlewareChain::new();
+    chain.add(Arc::new(SecurityTierMiddleware { default_tier: SecurityTier::Open }));
+    let middleware_chain = Arc::new(chain);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            if let Ok((socket, peer_addr)) = listener.accept().await {
+                let (s, a, m, acceptor) = (Arc::clone(&storage), Arc::clone(&auth_manager), Arc::clone(&middleware_chain), acceptor.clone());
+                tokio::spawn(async move {
+                    if let Ok(tls_stream) = acceptor.accept(socket).await {
+                        let _ = handle_connection(tls_stream, peer_addr.to_string(), s, a, m).await;
+                    }
+                });
+            }
+        }
+    });
+    format!("localhost:{}", port)
+}
+
+fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+async fn live(storage: &Arc<dyn Storage>, alias: &str) -> bool {
+    !storage.query(&[(Some("alias".to_string()), alias.to_string())], None).await.unwrap().is_empty()
+}
+
+#[tokio::test]
+async fn test_should_reap_an_expired_record_and_replicate_the_delete_to_peers() {
+    let dir = tempdir().unwrap();
+    let keys = dir.path().join("keys");
+    std::fs::create_dir(&keys).unwrap();
+    let acceptor = tls_acceptor(dir.path());
+    let origin: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let peer: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let peer_addr = serve(Arc::clone(&peer), &keys, acceptor).await;
+
+    origin.add_record(fields(&[("type", "machine"), ("hostname", &peer_addr), ("role", "pharos-server")]), None, None).await.unwrap();
+    for storage in [&origin, &peer] {
+        storage.add_record(fields(&[("type", "machine"), ("alias", "scan-phone"), ("source", "pharos-scan")]), None, None).await.unwrap();
+    }
+    origin.add_record(fields(&[("type", "machine"), ("alias", "scan-nas"), ("source", "pharos-scan"), ("ttl", "never")]), None, None).await.unwrap();
+
+    let config = ExpiryConfig { by_source: BTreeMap::from([("pharos-scan".to_string(), Duration::seconds(1))]) };
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(15);
+    while live(&peer, "scan-phone").await && tokio::time::Instant::now() < deadline {
+        expiry::reap(&origin, &config, chrono::Utc::now(), "origin:2378").await;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+
+    assert!(!live(&origin, "scan-phone").await, "reaped once its TTL ran out");
+    assert!(!live(&peer, "scan-phone").await, "the delete reached the peer");
+    assert_eq!(origin.tombstones(&[]).await.unwrap().len(), 1);
+    assert_eq!(peer.tombstones(&[]).await.unwrap().len(), 1);
+    assert!(live(&origin, "scan-nas").await, "ttl=never keeps it");
+}
@@ -7,12 +7,13 @@
  * ======================================================================== */
 
 use pharos_server::handle_connection;
+use pharos_server::schema::FieldSchema;
 use pharos_server::storage::{MemoryStorage, Storage};
 use pharos_server::auth::{AuthManager, SecurityTier};
 use pharos_server::middleware::{MiddlewareChain, SecurityTierMiddleware};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::io::{AsyncWriteExt, AsyncBufReadExt, BufReader};
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use tempfile::tempdir;
 use ssh_key::PrivateKey;
 use base64::{Engine as _, engine::general_purpose::STANDARD};
@@ -43,8 +44,11 @@ impl TestUser {
     }
 }
 
-async fn setup_test_server(keys_dir: &std::path::Path) -> (std::net::SocketAddr, Arc<RwLock<dyn Storage>>) {
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(MemoryStorage::new()));
+async fn setup_test_server(keys_dir: &std::path::Path) -> (std::net::SocketAddr, Arc<dyn Storage>) {
+    setup_test_server_with_storage(keys_dir, Arc::new(MemoryStorage::new())).await
+}
+
+async fn setup_test_server_with_storage(keys_dir: &std::path::Path, storage: Arc<dyn Storage>) -> (std::net::SocketAddr, Arc<dyn Storage>) {
     let auth_manager = Arc::new(AuthManager::new(keys_dir, SecurityTier::Open));
 
     let mut chain = MiddlewareChain::new();
@@ -113,29 +117,35 @@ async fn test_fields_empty_server() {
     reader.get_mut().write_all(b"fields\n").await.unwrap();
     let response_lines = read_fields_response(&mut reader).await;
 
-    // Expected baseline fields in alphabetical order:
-    // alias (1), created_at (2), hostname (3), last_seen_at (4), status (5), type (6)
-    assert_eq!(response_lines.len(), 13); // 6 fields * 2 lines each + 1 termination line = 13 lines
+    // Expected built-in schema fields in alphabetical order:
+    // alias (1), created_at (2), hostname (3), ip_addr (4), last_seen_at (5), mac_addr (6), status (7), type (8)
+    assert_eq!(response_lines.len(), 17); // 8 fields * 2 lines each + 1 termination line = 17 lines
 
     assert_eq!(response_lines[0], "-200:1:alias:max 32 Public");
     assert_eq!(response_lines[1], "-200:1:alias:Unique short identifier for a person entry; used to detect an existing record on add/upsert.");
 
-    assert_eq!(response_lines[2], "-200:2:created_at:max 32 Public");
+    assert_eq!(response_lines[2], "-200:2:created_at:max 64 Public Timestamp");
     assert_eq!(response_lines[3], "-200:2:created_at:ISO-8601 timestamp of when this entry was first created (server-injected).");
 
     assert_eq!(response_lines[4], "-200:3:hostname:max 256 Public");
     assert_eq!(response_lines[5], "-200:3:hostname:Unique identifier for a machine entry; used to detect an existing record on add/upsert.");
 
-    assert_eq!(response_lines[6], "-200:4:last_seen_at:max 32 Public");
-    assert_eq!(response_lines[7], "-200:4:last_seen_at:ISO-8601 timestamp of the most recent update to this entry (server-injected).");
+    assert_eq!(response_lines[6], "-200:4:ip_addr:max 64 Public Multiple IP");
+    assert_eq!(response_lines[7], "-200:4:ip_addr:IP address of a machine entry; every address given is kept.");
+
+    assert_eq!(response_lines[8], "-200:5:last_seen_at:max 64 Public Timestamp");
+    assert_eq!(response_lines[9], "-200:5:last_seen_at:ISO-8601 timestamp of the most recent update to this entry (server-injected).");
 
-    assert_eq!(response_lines[8], "-200:5:status:max 64 Public");
-    assert_eq!(response_lines[9], "-200:5:status:Free-form status/presence value (e.g. \"active\", \"online\", \"offline\").");
+    assert_eq!(response_lines[10], "-200:6:mac_addr:max 32 Public Multiple MAC");
+    assert_eq!(response_lines[11], "-200:6:mac_addr:MAC address of a machine entry; every address given is kept.");
 
-    assert_eq!(response_lines[10], "-200:6:type:max 64 Public");
-    assert_eq!(response_lines[11], "-200:6:type:Record type discriminator (e.g. \"person\" or \"machine\").");
+    assert_eq!(response_lines[12], "-200:7:status:max 64 Public");
+    assert_eq!(response_lines[13], "-200:7:status:Free-form status/presence value (e.g. \"active\", \"online\", \"offline\").");
 
-    assert_eq!(response_lines[12], "200:Ok.");
+    assert_eq!(response_lines[14], "-200:8:type:max 64 Public Required");
+    assert_eq!(response_lines[15], "-200:8:type:Record type discriminator (e.g. \"person\" or \"machine\").");
+
+    assert_eq!(response_lines[16], "200:Ok.");
 }
 
 #[tokio::test]
@@ -161,9 +171,9 @@ async fn test_fields_with_dynamic_field() {
     reader.get_mut().write_all(b"fields\n").await.unwrap();
     let response_lines = read_fields_response(&mut reader).await;
 
-    // Sorted list of fields: alias, created_at, email, hostname, last_seen_at, status, type
+    // Sorted list of fields: alias, created_at, email, hostname, ip_addr, last_seen_at, mac_addr, status, type
     // email should be at id 3 (0-based index 2 -> two lines each -> lines[4], lines[5])
-    assert_eq!(response_lines.len(), 15); // 7 fields * 2 + 1 = 15 lines
+    assert_eq!(response_lines.len(), 19); // 9 fields * 2 + 1 = 19 lines
 
     assert_eq!(response_lines[4], "-200:3:email:max 256 Public");
     assert_eq!(response_lines[5], "-200:3:email:User-defined field; no additional metadata available.");
@@ -252,5 +262,69 @@ async fn test_fields_id_stability() {
 
     // hostname must still be id 3 - zipcode sorts to the end, not before it.
     assert_eq!(response_lines[4], "-200:3:hostname:max 256 Public");
-    assert_eq!(response_lines[12], "-200:7:zipcode:max 256 Public");
+    assert_eq!(response_lines[16], "-200:9:zipcode:max 256 Public");
+}
+
+#[tokio::test]
+async fn test_should_describe_and_enforce_a_custom_schema_per_type() {
+    let keys_dir = tempdir().unwrap();
+    let test_user = TestUser::new();
+    std::fs::write(keys_dir.path().join("tester_id_ed25519.pub"), test_user.pub_key.as_bytes()).unwrap();
+
+    let schema_path = keys_dir.path().join("field-schema.toml");
+    std::fs::write(&schema_path, r#"
+        [fields.type]
+        required = true
+        max_len = 64
+
+        [types.machine]
+        strict = true
+
+        [types.machine.fields.hostname]
+        description = "Host name."
+        required = true
+
+        [types.machine.fields.cpu_cores]
+        description = "Logical CPU count."
+        max_len = 4
+        kind = "int"
+    "#).unwrap();
+    let storage = MemoryStorage::new().with_field_schema(FieldSchema::load(&schema_path).unwrap());
+    let (addr, _) = setup_test_server_with_storage(keys_dir.path(), Arc::new(storage)).await;
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut reader = BufReader::new(stream);
+
+    let mut line = String::new();
+    reader.read_line(&mut line).await.unwrap(); // Consume welcome
+    authenticate(&mut reader, &test_user).await;
+
+    for (command, expected) in [
+        ("add type=machine cpu_cores=8\n", "512:"),
+        ("add type=machine hostname=x cpu_cores=eight\n", "512:"),
+        ("add type=machine hostname=x cpu_cores=12345\n", "512:"),
+        ("add type=machine hostname=x owner=ops\n", "512:"),
+        ("add type=machine hostname=x cpu_cores=8\n", "200:"),
+        ("change hostname=x make cpu_cores=many\n", "512:"),
+        ("add type=person name=jane nickname=jj\n", "200:"),
+    ] {
+        reader.get_mut().write_all(command.as_bytes()).await.unwrap();
+        line.clear();
+        reader.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with(expected), "{} -> {}", command.trim(), line.trim());
+    }
+
+    // Ids are numbered over every field (name and nickname come from the person record), so
+    // a per-type listing skips the person-only ones.
+    reader.get_mut().write_all(b"fields type=machine\n").await.unwrap();
+    let response_lines = read_fields_response(&mut reader).await;
+    let attribute_lines: Vec<&str> = response_lines.iter().step_by(2).map(String::as_str).collect();
+    assert_eq!(attribute_lines, vec![
+        "-200:1:cpu_cores:max 4 Public Integer",
+        "-200:2:created_at:max 256 Public",
+        "-200:3:hostname:max 256 Public Required",
+        "-200:4:last_seen_at:max 256 Public",
+        "-200:7:type:max 64 Public Required",
+        "200:Ok.",
+    ]);
+    assert_eq!(response_lines[1], "-200:1:cpu_cores:Logical CPU count.");
 }
@@ -33,7 +33,7 @@ use pharos_server::auth::{AuthManager, SecurityTier};
 use pharos_server::middleware::{MiddlewareChain, SecurityTierMiddleware};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::io::{AsyncWriteExt, AsyncBufReadExt, BufReader};
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use std::time::Duration;
 use tempfile::tempdir;
 use tokio_rustls::{TlsAcceptor, TlsConnector};
@@ -68,7 +68,7 @@ fn build_test_tls() -> (TlsAcceptor, TlsConnector) {
 /// inventory (multiple scalar fields plus multi-valued ip_addr/mac_addr),
 /// matching what pharos-01.iamrichardd.com's real data.json actually looks
 /// like - the shape that triggered the original hang.
-fn seed_machine_records(storage: &mut MemoryStorage, count: usize) {
+async fn seed_machine_records(storage: &MemoryStorage, count: usize) {
     for i in 0..count {
         let fields = vec![
             ("type".to_string(), "machine".to_string()),
@@ -89,15 +89,15 @@ fn seed_machine_records(storage: &mut MemoryStorage, count: usize) {
             ("mac_addr".to_string(), format!("bc:24:11:00:{i:02x}:07")),
             ("mac_addr".to_string(), format!("bc:24:11:00:{i:02x}:08")),
         ];
-        storage.add_record(fields, None, None).unwrap();
+        storage.add_record(fields, None, None).await.unwrap();
     }
 }
 
 async fn setup_tls_server(record_count: usize, acceptor: TlsAcceptor) -> std::net::SocketAddr {
     let dir = tempdir().unwrap();
-    let mut storage = MemoryStorage::new();
-    seed_machine_records(&mut storage, record_count);
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(storage));
+    let storage = MemoryStorage::new();
+    seed_machine_records(&storage, record_count).await;
+    let storage: Arc<dyn Storage> = Arc::new(storage);
     let auth_manager = Arc::new(AuthManager::new(dir.path(), SecurityTier::Open));
 
     let mut chain = MiddlewareChain::new();
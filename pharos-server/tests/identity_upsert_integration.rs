@@ -0,0 +1,137 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core Tests
+ * File: pharos-server/tests/identity_upsert_integration.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Verifies over the wire protocol that `add` finds an existing machine by the
+ * identity keys its type declares, so a host reinstalled under a new name
+ * updates its record instead of forking a duplicate, and that identity values
+ * naming two different records are refused with 524.
+ * * Traceability:
+ * Backs re-enrollment of reinstalled and renamed hosts by pulse and mdb.
+ * ======================================================================== */
+
+use pharos_server::handle_connection;
+use pharos_server::storage::{MemoryStorage, Storage};
+use pharos_server::auth::{AuthManager, SecurityTier};
+use pharos_server::middleware::{MiddlewareChain, SecurityTierMiddleware};
+use pharos_server::schema::FieldSchema;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncWriteExt, AsyncBufReadExt, BufReader};
+use std::sync::Arc;
+use tempfile::tempdir;
+use ssh_key::PrivateKey;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use ed25519_dalek::{Signer, SigningKey};
+
+struct TestUser {
+    pub_key: String,
+    priv_key: PrivateKey,
+}
+
+impl TestUser {
+    fn new() -> Self {
+        let mut rng = rand::rngs::OsRng;
+        let priv_key = PrivateKey::random(&mut rng, ssh_key::Algorithm::Ed25519).unwrap();
+        let pub_key = priv_key.public_key().to_openssh().unwrap();
+        Self { pub_key, priv_key }
+    }
+
+    fn sign(&self, challenge: &str) -> String {
+        let sig_bytes = match self.priv_key.key_data() {
+            ssh_key::private::KeypairData::Ed25519(kp) => {
+                let signing_key = SigningKey::from_bytes(&kp.private.to_bytes());
+                signing_key.sign(challenge.as_bytes()).to_vec()
+            }
+            _ => panic!("Unsupported key type"),
+        };
+        STANDARD.encode(&sig_bytes)
+    }
+}
+
+/// Starts a server that knows one plain user's key, where machines are identified by uuid,
+/// then serial number, then hostname, then MAC address.
+async fn setup_server() -> (std::net::SocketAddr, TestUser) {
+    let dir = tempdir().unwrap();
+    let user = TestUser::new();
+    std::fs::write(dir.path().join("owner_id_ed25519.pub"), user.pub_key.as_bytes()).unwrap();
+    let schema_path = dir.path().join("field-schema.toml");
+    std::fs::write(&schema_path, "[types.machine]\nidentity = [\"uuid\", \"serial_number\", \"hostname\", \"mac_addr\"]").unwrap();
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new().with_field_schema(FieldSchema::load(&schema_path).unwrap()));
+    let auth_manager = Arc::new(AuthManager::new(dir.path(), SecurityTier::Open));
+
+    let mut chain = MiddlewareChain::new();
+    chain.add(Arc::new(SecurityTierMiddleware { default_tier: SecurityTier::Open }));
+    let middleware_chain = Arc::new(chain);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let _dir = dir;
+        loop {
+            if let Ok((socket, peer_addr)) = listener.accept().await {
+                let s = Arc::clone(&storage);
+                let a = Arc::clone(&auth_manager);
+                let m = Arc::clone(&middleware_chain);
+                tokio::spawn(async move {
+                    let _ = handle_connection(socket, peer_addr.to_string(), s, a, m).await;
+                });
+            }
+        }
+    });
+
+    (addr, user)
+}
+
+/// Sends one command and collects its response lines up to and including the final status.
+async fn exec(reader: &mut BufReader<TcpStream>, cmd: &str) -> Vec<String> {
+    reader.get_mut().write_all(format!("{}\n", cmd).as_bytes()).await.unwrap();
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        let trimmed = line.trim().to_string();
+        let done = !trimmed.starts_with('-') && !trimmed.starts_with("1");
+        lines.push(trimmed);
+        if done {
+            return lines;
+        }
+    }
+}
+
+async fn connect_as_mdb(addr: std::net::SocketAddr, user: &TestUser) -> BufReader<TcpStream> {
+    let mut reader = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut welcome = String::new();
+    reader.read_line(&mut welcome).await.unwrap();
+
+    assert_eq!(exec(&mut reader, "id mdb").await, vec!["200:Ok"]);
+    let challenge = exec(&mut reader, "login pharos").await.remove(0);
+    let sig = user.sign(challenge.trim_start_matches("301:"));
+    assert_eq!(exec(&mut reader, &format!("auth \"{}\" \"{}\"", user.pub_key, sig)).await, vec!["200:Ok"]);
+    reader
+}
+
+#[tokio::test]
+async fn test_should_update_a_reinstalled_host_and_refuse_conflicting_identities() {
+    let (addr, owner) = setup_server().await;
+    let mut reader = connect_as_mdb(addr, &owner).await;
+
+    assert_eq!(exec(&mut reader, "add type=machine hostname=web01 uuid=4c4c-01 serial_number=SN-1").await, vec!["200:Ok"]);
+    assert_eq!(exec(&mut reader, "add type=machine hostname=web01-new uuid=4c4c-01 os=debian13").await, vec!["200:Ok"]);
+    assert_eq!(exec(&mut reader, "query uuid=4c4c-01 return hostname os").await, vec![
+        "102:There were 1 matches to your request.",
+        "-200:1:hostname: web01-new",
+        "-200:1:os: debian13",
+        "200:Ok",
+    ]);
+
+    assert_eq!(exec(&mut reader, "add type=machine hostname=db01 serial_number=SN-2").await, vec!["200:Ok"]);
+    assert_eq!(
+        exec(&mut reader, "add type=machine hostname=db01 serial_number=SN-1").await,
+        vec!["524:Identity conflict: serial_number=SN-1 is web01-new but hostname=db01 is db01"]
+    );
+    assert_eq!(exec(&mut reader, "query type=machine return hostname").await.len(), 4);
+}
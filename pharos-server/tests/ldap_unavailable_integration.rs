@@ -0,0 +1,53 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core Tests
+ * File: pharos-server/tests/ldap_unavailable_integration.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * A directory outage must reach the client as a 5xx, never as "no matches" -
+ * an empty answer from an unreachable server is indistinguishable from a
+ * record that doesn't exist.
+ * ======================================================================== */
+
+use pharos_server::handle_connection;
+use pharos_server::auth::{AuthManager, SecurityTier};
+use pharos_server::ldap::LdapStorage;
+use pharos_server::ldap_pool::LdapPoolConfig;
+use pharos_server::middleware::{MiddlewareChain, SecurityTierMiddleware};
+use pharos_server::storage::Storage;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::sync::Arc;
+use tempfile::tempdir;
+
+#[tokio::test]
+async fn test_should_answer_queries_with_5xx_when_directory_is_unreachable() {
+    let keys_dir = tempdir().unwrap();
+    // Nothing listens on port 1, so every connection attempt is refused.
+    let config = LdapPoolConfig::new("ldap://127.0.0.1:1".to_string(), String::new(), String::new());
+    let storage: Arc<dyn Storage> = Arc::new(LdapStorage::with_pool_config(config, "dc=example,dc=org".to_string()));
+    let auth_manager = Arc::new(AuthManager::new(keys_dir.path(), SecurityTier::Open));
+    let mut chain = MiddlewareChain::new();
+    chain.add(Arc::new(SecurityTierMiddleware { default_tier: SecurityTier::Open }));
+    let middleware_chain = Arc::new(chain);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        if let Ok((socket, _)) = listener.accept().await {
+            let _ = handle_connection(socket, "127.0.0.1:1234".to_string(), storage, auth_manager, middleware_chain).await;
+        }
+    });
+
+    let mut reader = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).await.unwrap(); // Consume welcome
+
+    for _ in 0..2 {
+        line.clear();
+        reader.get_mut().write_all(b"query hostname=web01\n").await.unwrap();
+        reader.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("500:Storage backend unavailable"), "unexpected response: {}", line);
+    }
+}
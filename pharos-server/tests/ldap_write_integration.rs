@@ -0,0 +1,98 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core Tests
+ * File: pharos-server/tests/ldap_write_integration.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Exercises LdapStorage writes end-to-end against a real directory. Needs a
+ * disposable OpenLDAP (or compatible) server whose bind DN may write under
+ * `ou=hosts` of the base DN and that accepts the Pharos owner attributes,
+ * e.g.:
+ *   PHAROS_TEST_LDAP_URL=ldap://localhost:389 \
+ *   PHAROS_TEST_LDAP_BIND_DN=cn=admin,dc=example,dc=org \
+ *   PHAROS_TEST_LDAP_BIND_PW=admin PHAROS_TEST_LDAP_BASE_DN=dc=example,dc=org \
+ *   cargo test -p pharos-server --test ldap_write_integration -- --ignored
+ * ======================================================================== */
+
+use pharos_server::ldap::LdapStorage;
+use pharos_server::storage::{RecordType, Storage, StorageError, UpsertOutcome};
+
+fn storage_from_env() -> LdapStorage {
+    let var = |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("{} must be set for this test", name));
+    LdapStorage::new(
+        var("PHAROS_TEST_LDAP_URL"),
+        var("PHAROS_TEST_LDAP_BIND_DN"),
+        var("PHAROS_TEST_LDAP_BIND_PW"),
+        var("PHAROS_TEST_LDAP_BASE_DN"),
+    )
+}
+
+fn hostname_selection(hostname: &str) -> Vec<(Option<String>, String)> {
+    vec![(Some("hostname".to_string()), hostname.to_string())]
+}
+
+#[tokio::test]
+#[ignore = "needs a live LDAP server (PHAROS_TEST_LDAP_URL)"]
+async fn test_should_add_change_upsert_and_delete_ldap_entries() {
+    let storage = storage_from_env();
+    let hostname = format!("pharos-it-{}", std::process::id());
+    let owner = Some("SHA256:integration".to_string());
+
+    storage
+        .add_record(
+            vec![
+                ("type".to_string(), "machine".to_string()),
+                ("hostname".to_string(), hostname.clone()),
+                ("ip_addr".to_string(), "192.0.2.10".to_string()),
+            ],
+            owner.clone(),
+            None,
+        )
+        .await
+        .unwrap();
+
+    let duplicate = storage.add_record(
+        vec![
+            ("type".to_string(), "machine".to_string()),
+            ("hostname".to_string(), hostname.clone()),
+            ("ip_addr".to_string(), "192.0.2.10".to_string()),
+        ],
+        owner.clone(),
+        None,
+    ).await;
+    assert!(matches!(duplicate, Err(StorageError::Collision)));
+
+    let records = storage.query(&hostname_selection(&hostname), Some(RecordType::Machine)).await.unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].owner_fingerprint, owner);
+
+    let stranger = storage.change_record(
+        &hostname_selection(&hostname),
+        &[("ip_addr".to_string(), "192.0.2.11".to_string())],
+        Some("SHA256:stranger".to_string()),
+        &[],
+        None,
+        false,
+    ).await;
+    assert!(matches!(stranger, Err(StorageError::Unauthorized)));
+
+    let changed = storage
+        .change_record(&hostname_selection(&hostname), &[("ip_addr".to_string(), "192.0.2.11".to_string())], owner.clone(), &[], None, false)
+        .await
+        .unwrap();
+    assert_eq!(changed, 1);
+
+    let outcome = storage
+        .upsert_record(
+            vec![("hostname".to_string(), hostname.clone()), ("ip_addr".to_string(), "192.0.2.12".to_string())],
+            owner.clone(),
+            None,
+        )
+        .await
+        .unwrap();
+    assert_eq!(outcome, UpsertOutcome::Updated);
+
+    assert_eq!(storage.delete_record(&hostname_selection(&hostname), owner, &[]).await.unwrap(), 1);
+    assert!(storage.query(&hostname_selection(&hostname), None).await.unwrap().is_empty());
+}
@@ -0,0 +1,154 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core Tests
+ * File: pharos-server/tests/link_query_integration.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Verifies over the wire protocol that `links` walks a hypervisor's VMs and
+ * the services on them, a service's parents up to the hypervisor's owner,
+ * and a single link field, and that deleting a record drops the links that
+ * pointed at it. Records are written straight to storage, where a link to a
+ * missing record or one of the wrong type is refused.
+ * * Traceability:
+ * Backs `mdb tree <host>`.
+ * ======================================================================== */
+
+use pharos_server::handle_connection;
+use pharos_server::auth::{AuthManager, SecurityTier};
+use pharos_server::middleware::{MiddlewareChain, SecurityTierMiddleware};
+use pharos_server::schema::FieldSchema;
+use pharos_server::storage::{MemoryStorage, Storage, StorageError};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncWriteExt, AsyncBufReadExt, BufReader};
+use std::sync::Arc;
+use tempfile::tempdir;
+
+const SCHEMA: &str = r#"
+[fields.type]
+required = true
+
+[types.machine.fields.runs_on]
+kind = "link"
+target = "machine"
+
+[types.machine.fields.owned_by]
+kind = "link"
+target = "person"
+
+[types.machine.fields.depends_on]
+kind = "link"
+multi = true
+"#;
+
+fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+/// Starts an open-tier server whose schema declares `runs_on`, `owned_by` and `depends_on` links,
+/// returning its storage too so the test can write without authenticating.
+async fn setup_server() -> (std::net::SocketAddr, Arc<dyn Storage>) {
+    let dir = tempdir().unwrap();
+    let schema_path = dir.path().join("field-schema.toml");
+    std::fs::write(&schema_path, SCHEMA).unwrap();
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new().with_field_schema(FieldSchema::load(&schema_path).unwrap()));
+    let auth_manager = Arc::new(AuthManager::new(dir.path(), SecurityTier::Open));
+    let mut chain = MiddlewareChain::new();
+    chain.add(Arc::new(SecurityTierMiddleware { default_tier: SecurityTier::Open }));
+    let middleware_chain = Arc::new(chain);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_storage = Arc::clone(&storage);
+    tokio::spawn(async move {
+        let _dir = dir;
+        loop {
+            if let Ok((socket, peer_addr)) = listener.accept().await {
+                let s = Arc::clone(&server_storage);
+                let a = Arc::clone(&auth_manager);
+                let m = Arc::clone(&middleware_chain);
+                tokio::spawn(async move {
+                    let _ = handle_connection(socket, peer_addr.to_string(), s, a, m).await;
+                });
+            }
+        }
+    });
+    (addr, storage)
+}
+
+/// Sends one command and collects its response lines up to and including the final status.
+async fn exec(reader: &mut BufReader<TcpStream>, cmd: &str) -> Vec<String> {
+    reader.get_mut().write_all(format!("{}\n", cmd).as_bytes()).await.unwrap();
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        let trimmed = line.trim().to_string();
+        let done = !trimmed.starts_with('-') && !trimmed.starts_with("1");
+        lines.push(trimmed);
+        if done {
+            return lines;
+        }
+    }
+}
+
+/// Each record a `links` command reached, as `hostname depth from via`.
+async fn walk(reader: &mut BufReader<TcpStream>, cmd: &str) -> Vec<String> {
+    let mut entries: Vec<Vec<String>> = Vec::new();
+    for line in exec(reader, cmd).await {
+        let Some(rest) = line.strip_prefix("-200:") else { continue };
+        let (index, field) = rest.split_once(':').unwrap();
+        let index: usize = index.parse().unwrap();
+        if entries.len() < index {
+            entries.resize(index, vec![String::new(); 4]);
+        }
+        let (name, value) = field.split_once(": ").unwrap();
+        let slot = ["hostname", "link_depth", "link_from", "link_via"].iter().position(|n| *n == name);
+        if let Some(slot) = slot {
+            entries[index - 1][slot] = value.to_string();
+        }
+    }
+    entries.into_iter().map(|e| e.join(" ").trim().to_string()).collect()
+}
+
+#[tokio::test]
+async fn test_should_validate_follow_and_clean_up_links() {
+    let (addr, storage) = setup_server().await;
+    let mut reader = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut welcome = String::new();
+    reader.read_line(&mut welcome).await.unwrap();
+
+    let refused = storage.add_record(fields(&[("type", "machine"), ("hostname", "vm-web"), ("runs_on", "pve01")]), None, None).await;
+    assert!(matches!(refused, Err(StorageError::InvalidArgument(_))));
+    for record in [
+        fields(&[("type", "person"), ("alias", "rdavis")]),
+        fields(&[("type", "machine"), ("hostname", "pve01"), ("owned_by", "rdavis")]),
+        fields(&[("type", "machine"), ("hostname", "vm-web"), ("runs_on", "pve01")]),
+        fields(&[("type", "machine"), ("hostname", "vm-db"), ("runs_on", "pve01")]),
+        fields(&[("type", "machine"), ("hostname", "svc-api"), ("depends_on", "vm-db")]),
+    ] {
+        storage.add_record(record, None, None).await.unwrap();
+    }
+    let refused = storage.add_record(fields(&[("type", "machine"), ("hostname", "vm-x"), ("runs_on", "rdavis")]), None, None).await;
+    assert!(matches!(refused, Err(StorageError::InvalidArgument(_))));
+
+    assert_eq!(walk(&mut reader, "links hostname=pve01").await, vec![
+        "pve01 0",
+        "vm-web 1 pve01 runs_on",
+        "vm-db 1 pve01 runs_on",
+    ]);
+    assert_eq!(walk(&mut reader, "links hostname=pve01 children all").await.last().unwrap(), "svc-api 2 vm-db depends_on");
+    assert_eq!(walk(&mut reader, "links hostname=svc-api parents all").await, vec![
+        "svc-api 0",
+        "vm-db 1 svc-api depends_on",
+        "pve01 2 vm-db runs_on",
+        "3 pve01 owned_by",
+    ]);
+    assert_eq!(walk(&mut reader, "links hostname=svc-api parents all via=depends_on").await.len(), 2);
+    assert_eq!(exec(&mut reader, "links hostname=nothing").await, vec!["501:No matches to query"]);
+
+    storage.delete_record(&[(Some("hostname".to_string()), "vm-db".to_string())], None, &[]).await.unwrap();
+    assert_eq!(walk(&mut reader, "links hostname=svc-api parents").await, vec!["svc-api 0"]);
+    let api = exec(&mut reader, "query hostname=svc-api").await;
+    assert!(!api.iter().any(|l| l.contains("depends_on")), "{:?}", api);
+}
@@ -7,12 +7,15 @@
  * ======================================================================== */
 
 use pharos_server::handle_connection;
+use pharos_server::file_cipher::FileCipher;
+use pharos_server::journal::JournalConfig;
+use pharos_server::recovery::CorruptPolicy;
 use pharos_server::storage::{FileStorage, Storage, RecordType};
 use pharos_server::auth::{AuthManager, SecurityTier};
 use pharos_server::middleware::{MiddlewareChain, SecurityTierMiddleware};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use std::collections::HashMap;
 use tempfile::tempdir;
 use ssh_key::PrivateKey;
@@ -74,7 +77,7 @@ async fn test_live_verification_steps_3_4_5_6_7() {
     std::fs::write(keys_dir.join("tester_id_ed25519.pub"), user.pub_key.as_bytes()).unwrap();
 
     let storage_path = temp_dir.path().join("data.json");
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(FileStorage::new(storage_path.clone())));
+    let storage: Arc<dyn Storage> = Arc::new(FileStorage::open(storage_path.clone(), JournalConfig::from_env(), FileCipher::default(), CorruptPolicy::default()).unwrap());
     let auth_manager = Arc::new(AuthManager::new(&keys_dir, SecurityTier::Open));
 
     let mut chain = MiddlewareChain::new();
@@ -122,8 +125,7 @@ async fn test_live_verification_steps_3_4_5_6_7() {
 
     // Verify record fields on server are unchanged
     {
-        let lock = storage.read().unwrap();
-        let records = lock.query(&[(Some("hostname".to_string()), "srv-upsert".to_string())], None).unwrap();
+        let records = storage.query(&[(Some("hostname".to_string()), "srv-upsert".to_string())], None).await.unwrap();
         assert_eq!(records.len(), 1);
         assert_eq!(records[0].fields.get("type").unwrap(), "machine");
         assert_eq!(records[0].fields.get("status").unwrap(), "initial");
@@ -141,8 +143,7 @@ async fn test_live_verification_steps_3_4_5_6_7() {
 
     // Verify record fields on server are unchanged
     {
-        let lock = storage.read().unwrap();
-        let records = lock.query(&[(Some("hostname".to_string()), "srv-upsert".to_string())], None).unwrap();
+        let records = storage.query(&[(Some("hostname".to_string()), "srv-upsert".to_string())], None).await.unwrap();
         assert_eq!(records.len(), 1);
         assert_eq!(records[0].fields.get("type").unwrap(), "machine");
         assert_eq!(records[0].fields.get("status").unwrap(), "initial");
@@ -166,8 +167,8 @@ async fn test_live_verification_steps_3_4_5_6_7() {
     ]"#;
     std::fs::write(&heal_path, broken_json).unwrap();
 
-    let mut heal_storage = FileStorage::new(heal_path.clone());
-    let records = heal_storage.query(&[(Some("hostname".to_string()), "srv-heal".to_string())], None).unwrap();
+    let heal_storage = FileStorage::open(heal_path.clone(), JournalConfig::from_env(), FileCipher::default(), CorruptPolicy::default()).unwrap();
+    let records = heal_storage.query(&[(Some("hostname".to_string()), "srv-heal".to_string())], None).await.unwrap();
     assert_eq!(records.len(), 1);
     assert_eq!(records[0].record_type, Some(RecordType::Machine));
     println!("Step 6 Self-heal Result: record_type correctly healed to Machine: {:?}", records[0].record_type);
@@ -177,9 +178,9 @@ async fn test_live_verification_steps_3_4_5_6_7() {
     heartbeat_fields.insert("hostname".to_string(), "srv-heal".to_string());
     heartbeat_fields.insert("type".to_string(), "machine".to_string());
     heartbeat_fields.insert("status".to_string(), "heartbeat_ok".to_string());
-    let upsert_res = heal_storage.upsert_record(heartbeat_fields.into_iter().collect(), None, None);
+    let upsert_res = heal_storage.upsert_record(heartbeat_fields.into_iter().collect(), None, None).await;
     assert!(upsert_res.is_ok());
-    let updated_heal = heal_storage.query(&[(Some("hostname".to_string()), "srv-heal".to_string())], None).unwrap();
+    let updated_heal = heal_storage.query(&[(Some("hostname".to_string()), "srv-heal".to_string())], None).await.unwrap();
     assert_eq!(updated_heal[0].fields.get("status").unwrap(), "heartbeat_ok");
     println!("Step 6 Heartbeat Upsert Result: heartbeat upsert succeeded normally after self-heal!");
 
@@ -197,8 +198,8 @@ async fn test_live_verification_steps_3_4_5_6_7() {
         }
     ]"#;
     std::fs::write(&missing_type_path, missing_type_json).unwrap();
-    let missing_storage = FileStorage::new(missing_type_path.clone());
-    let missing_records = missing_storage.query(&[(Some("hostname".to_string()), "srv-no-type".to_string())], None).unwrap();
+    let missing_storage = FileStorage::open(missing_type_path.clone(), JournalConfig::from_env(), FileCipher::default(), CorruptPolicy::default()).unwrap();
+    let missing_records = missing_storage.query(&[(Some("hostname".to_string()), "srv-no-type".to_string())], None).await.unwrap();
     assert_eq!(missing_records.len(), 1);
     assert_eq!(missing_records[0].record_type, None);
     println!("Step 7 Missing type field Result: left record_type as None as expected: {:?}", missing_records[0].record_type);
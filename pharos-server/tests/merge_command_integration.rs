@@ -0,0 +1,148 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core Tests
+ * File: pharos-server/tests/merge_command_integration.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Verifies over the wire protocol that `dupes` groups the scanner's and
+ * pulse's records of one box by their shared MAC address, that `merge`
+ * folds one into the other and deletes it, and that merging needs an
+ * authenticated owner of both records and is refused inside a transaction.
+ * * Traceability:
+ * Backs `mdb dupes` and duplicate clean-up after scans.
+ * ======================================================================== */
+
+use pharos_server::handle_connection;
+use pharos_server::storage::{MemoryStorage, Storage};
+use pharos_server::auth::{AuthManager, SecurityTier};
+use pharos_server::middleware::{MiddlewareChain, SecurityTierMiddleware};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncWriteExt, AsyncBufReadExt, BufReader};
+use std::sync::Arc;
+use tempfile::tempdir;
+use ssh_key::PrivateKey;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use ed25519_dalek::{Signer, SigningKey};
+
+struct TestUser {
+    pub_key: String,
+    priv_key: PrivateKey,
+}
+
+impl TestUser {
+    fn new() -> Self {
+        let mut rng = rand::rngs::OsRng;
+        let priv_key = PrivateKey::random(&mut rng, ssh_key::Algorithm::Ed25519).unwrap();
+        let pub_key = priv_key.public_key().to_openssh().unwrap();
+        Self { pub_key, priv_key }
+    }
+
+    fn sign(&self, challenge: &str) -> String {
+        let sig_bytes = match self.priv_key.key_data() {
+            ssh_key::private::KeypairData::Ed25519(kp) => {
+                let signing_key = SigningKey::from_bytes(&kp.private.to_bytes());
+                signing_key.sign(challenge.as_bytes()).to_vec()
+            }
+            _ => panic!("Unsupported key type"),
+        };
+        STANDARD.encode(&sig_bytes)
+    }
+}
+
+/// Starts a server that knows two plain users' keys.
+async fn setup_server() -> (std::net::SocketAddr, [TestUser; 2]) {
+    let dir = tempdir().unwrap();
+    let users = [TestUser::new(), TestUser::new()];
+    for (user, name) in users.iter().zip(["owner", "other"]) {
+        std::fs::write(dir.path().join(format!("{}_id_ed25519.pub", name)), user.pub_key.as_bytes()).unwrap();
+    }
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let auth_manager = Arc::new(AuthManager::new(dir.path(), SecurityTier::Open));
+
+    let mut chain = MiddlewareChain::new();
+    chain.add(Arc::new(SecurityTierMiddleware { default_tier: SecurityTier::Open }));
+    let middleware_chain = Arc::new(chain);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            if let Ok((socket, peer_addr)) = listener.accept().await {
+                let s = Arc::clone(&storage);
+                let a = Arc::clone(&auth_manager);
+                let m = Arc::clone(&middleware_chain);
+                tokio::spawn(async move {
+                    let _ = handle_connection(socket, peer_addr.to_string(), s, a, m).await;
+                });
+            }
+        }
+    });
+
+    (addr, users)
+}
+
+/// Sends one command and collects its response lines up to and including the final status.
+async fn exec(reader: &mut BufReader<TcpStream>, cmd: &str) -> Vec<String> {
+    reader.get_mut().write_all(format!("{}\n", cmd).as_bytes()).await.unwrap();
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        let trimmed = line.trim().to_string();
+        let done = !trimmed.starts_with('-') && !trimmed.starts_with("1");
+        lines.push(trimmed);
+        if done {
+            return lines;
+        }
+    }
+}
+
+async fn connect_as_mdb(addr: std::net::SocketAddr, user: &TestUser) -> BufReader<TcpStream> {
+    let mut reader = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut welcome = String::new();
+    reader.read_line(&mut welcome).await.unwrap();
+
+    assert_eq!(exec(&mut reader, "id mdb").await, vec!["200:Ok"]);
+    let challenge = exec(&mut reader, "login pharos").await.remove(0);
+    let sig = user.sign(challenge.trim_start_matches("301:"));
+    assert_eq!(exec(&mut reader, &format!("auth \"{}\" \"{}\"", user.pub_key, sig)).await, vec!["200:Ok"]);
+    reader
+}
+
+#[tokio::test]
+async fn test_should_find_and_merge_duplicates() {
+    let (addr, [owner, other]) = setup_server().await;
+    let mut reader = connect_as_mdb(addr, &owner).await;
+
+    assert_eq!(exec(&mut reader, "add type=machine alias=scan-ab12 mac_addr=AA:BB:CC:00:00:01 created_at=2026-01-01T00:00:00+00:00").await, vec!["200:Ok"]);
+    assert_eq!(exec(&mut reader, "add type=machine hostname=web01 mac_addr=aa:bb:cc:00:00:01 ip_addr=10.0.0.5").await, vec!["200:Ok"]);
+    assert_eq!(exec(&mut reader, "add type=machine hostname=db01 ip_addr=10.0.0.6").await, vec!["200:Ok"]);
+
+    let dupes = exec(&mut reader, "dupes type=machine").await;
+    assert_eq!(dupes[0], "102:There were 2 matches to your request.");
+    assert!(dupes.contains(&"-200:1:dupe_shared: mac_addr=aa:bb:cc:00:00:01".to_string()), "{:?}", dupes);
+    assert!(dupes.contains(&"-200:2:dupe_group: 1".to_string()), "{:?}", dupes);
+
+    let mut anonymous = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut welcome = String::new();
+    anonymous.read_line(&mut welcome).await.unwrap();
+    assert!(exec(&mut anonymous, "merge alias=scan-ab12 into hostname=web01").await[0].starts_with("506:"));
+    let mut intruder = connect_as_mdb(addr, &other).await;
+    assert_eq!(exec(&mut intruder, "merge alias=scan-ab12 into hostname=web01").await, vec!["516:No authorization for request"]);
+
+    assert_eq!(exec(&mut reader, "begin").await[0].chars().next(), Some('2'));
+    assert!(exec(&mut reader, "merge alias=scan-ab12 into hostname=web01").await[0].starts_with("522:"));
+    assert_eq!(exec(&mut reader, "rollback").await[0].chars().next(), Some('2'));
+
+    assert_eq!(exec(&mut reader, "merge alias=scan-ab12 into hostname=web01").await, vec!["200:Merged into web01."]);
+    assert_eq!(exec(&mut reader, "query hostname=web01 return alias created_at").await, vec![
+        "102:There were 1 matches to your request.",
+        "-200:1:alias: scan-ab12",
+        "-200:1:created_at: 2026-01-01T00:00:00+00:00",
+        "200:Ok",
+    ]);
+    assert_eq!(exec(&mut reader, "dupes").await, vec!["501:No duplicates found"]);
+    assert_eq!(exec(&mut reader, "merge hostname=gone into hostname=web01").await, vec!["501:No matches to merge"]);
+}
@@ -17,11 +17,11 @@ use pharos_server::auth::{AuthManager, SecurityTier};
 use pharos_server::middleware::{MiddlewareChain, ReadOnlyMiddleware, SecurityTierMiddleware};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use tempfile::tempdir;
 
-async fn setup_test_server(middleware_chain: MiddlewareChain) -> (std::net::SocketAddr, Arc<RwLock<dyn Storage>>) {
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(MemoryStorage::new()));
+async fn setup_test_server(middleware_chain: MiddlewareChain) -> (std::net::SocketAddr, Arc<dyn Storage>) {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
     let temp_dir = tempdir().unwrap();
     let auth_manager = Arc::new(AuthManager::new(temp_dir.path(), SecurityTier::Open));
     
@@ -94,7 +94,7 @@ async fn test_should_block_write_in_scoped_tier_without_admin_role() {
 
 #[tokio::test]
 async fn test_should_block_write_when_guest_id_provided() {
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(MemoryStorage::new()));
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
     let temp_dir = tempdir().unwrap();
     let auth_manager = Arc::new(AuthManager::new(temp_dir.path(), SecurityTier::Open));
     
@@ -138,7 +138,7 @@ async fn test_should_block_write_when_guest_id_provided() {
 
 #[tokio::test]
 async fn test_should_allow_write_when_other_id_provided() {
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(MemoryStorage::new()));
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
     let temp_dir = tempdir().unwrap();
     let auth_manager = Arc::new(AuthManager::new(temp_dir.path(), SecurityTier::Open));
     
@@ -192,7 +192,7 @@ async fn test_should_verify_auth_check_command() {
     let key_path = temp_dir.path().join("test.pub");
     std::fs::write(&key_path, pub_key_openssh.as_bytes()).unwrap();
     
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(MemoryStorage::new()));
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
     let auth_manager = Arc::new(AuthManager::new(temp_dir.path(), SecurityTier::Open));
     let mut chain = MiddlewareChain::new();
     chain.add(Arc::new(SecurityTierMiddleware { default_tier: SecurityTier::Open }));
@@ -244,7 +244,7 @@ async fn test_should_reject_auth_check_with_invalid_signature() {
     let key_path = temp_dir.path().join("test.pub");
     std::fs::write(&key_path, pub_key_openssh.as_bytes()).unwrap();
 
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(MemoryStorage::new()));
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
     let auth_manager = Arc::new(AuthManager::new(temp_dir.path(), SecurityTier::Open));
     let mut chain = MiddlewareChain::new();
     chain.add(Arc::new(SecurityTierMiddleware { default_tier: SecurityTier::Open }));
@@ -296,7 +296,7 @@ async fn test_should_require_admin_role_for_authenticated_non_admin_write_in_sco
     let key_path = temp_dir.path().join("regular_id_ed25519.pub");
     std::fs::write(&key_path, pub_key_openssh.as_bytes()).unwrap();
 
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(MemoryStorage::new()));
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
     let auth_manager = Arc::new(AuthManager::new(temp_dir.path(), SecurityTier::Scoped));
     let mut chain = MiddlewareChain::new();
     chain.add(Arc::new(SecurityTierMiddleware { default_tier: SecurityTier::Scoped }));
@@ -372,7 +372,7 @@ async fn test_should_allow_valid_auth_check_in_protected_tier() {
     let key_path = temp_dir.path().join("test.pub");
     std::fs::write(&key_path, pub_key_openssh.as_bytes()).unwrap();
 
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(MemoryStorage::new()));
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
     let auth_manager = Arc::new(AuthManager::new(temp_dir.path(), SecurityTier::Protected));
     let mut chain = MiddlewareChain::new();
     chain.add(Arc::new(SecurityTierMiddleware { default_tier: SecurityTier::Protected }));
@@ -422,7 +422,7 @@ async fn test_should_allow_valid_auth_check_in_scoped_tier() {
     let key_path = temp_dir.path().join("test.pub");
     std::fs::write(&key_path, pub_key_openssh.as_bytes()).unwrap();
 
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(MemoryStorage::new()));
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
     let auth_manager = Arc::new(AuthManager::new(temp_dir.path(), SecurityTier::Scoped));
     let mut chain = MiddlewareChain::new();
     chain.add(Arc::new(SecurityTierMiddleware { default_tier: SecurityTier::Scoped }));
@@ -472,7 +472,7 @@ async fn test_should_reject_invalid_auth_check_in_protected_tier() {
     let key_path = temp_dir.path().join("test.pub");
     std::fs::write(&key_path, pub_key_openssh.as_bytes()).unwrap();
 
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(MemoryStorage::new()));
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
     let auth_manager = Arc::new(AuthManager::new(temp_dir.path(), SecurityTier::Protected));
     let mut chain = MiddlewareChain::new();
     chain.add(Arc::new(SecurityTierMiddleware { default_tier: SecurityTier::Protected }));
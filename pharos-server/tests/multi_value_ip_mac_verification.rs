@@ -11,7 +11,7 @@ use pharos_server::auth::{AuthManager, SecurityTier};
 use pharos_server::middleware::{MiddlewareChain, SecurityTierMiddleware};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::io::{AsyncWriteExt, AsyncBufReadExt, BufReader};
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use tempfile::tempdir;
 use ssh_key::PrivateKey;
 use base64::{Engine as _, engine::general_purpose::STANDARD};
@@ -55,11 +55,11 @@ async fn authenticate(reader: &mut BufReader<tokio::net::tcp::ReadHalf<'_>>, wri
     assert!(line.contains("200:Ok"), "Expected 200:Ok on auth, got: {}", line);
 }
 
-async fn setup_server() -> (std::net::SocketAddr, Arc<RwLock<dyn Storage>>, TestUser) {
+async fn setup_server() -> (std::net::SocketAddr, Arc<dyn Storage>, TestUser) {
     let dir = tempdir().unwrap();
     let user = TestUser::new();
     std::fs::write(dir.path().join("tester_id_ed25519.pub"), user.pub_key.as_bytes()).unwrap();
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(MemoryStorage::new()));
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
     let auth_manager = Arc::new(AuthManager::new(dir.path(), SecurityTier::Open));
 
     let mut chain = MiddlewareChain::new();
@@ -89,7 +89,7 @@ async fn setup_server() -> (std::net::SocketAddr, Arc<RwLock<dyn Storage>>, Test
 #[tokio::test]
 async fn test_verification_step_1_direct_storage_add() {
     println!("\n=== VERIFICATION STEP 1: Direct Storage Add ===");
-    let mut storage = MemoryStorage::new();
+    let storage = MemoryStorage::new();
     let fields = vec![
         ("type".to_string(), "machine".to_string()),
         ("hostname".to_string(), "srv-01".to_string()),
@@ -97,9 +97,9 @@ async fn test_verification_step_1_direct_storage_add() {
         ("ip_addr".to_string(), "192.168.86.6".to_string()),
         ("mac_addr".to_string(), "e0:51:d8:1d:e3:22".to_string()),
     ];
-    storage.add_record(fields, None, None).unwrap();
+    storage.add_record(fields, None, None).await.unwrap();
     
-    let records = storage.query(&[(Some("hostname".to_string()), "srv-01".to_string())], None).unwrap();
+    let records = storage.query(&[(Some("hostname".to_string()), "srv-01".to_string())], None).await.unwrap();
     assert_eq!(records.len(), 1);
     let ip_list = records[0].multi_fields.get("ip_addr").unwrap();
     let mac_list = records[0].multi_fields.get("mac_addr").unwrap();
@@ -112,25 +112,25 @@ async fn test_verification_step_1_direct_storage_add() {
 #[tokio::test]
 async fn test_verification_step_2_append_semantics_and_idempotency() {
     println!("\n=== VERIFICATION STEP 2: Append Semantics & Idempotency ===");
-    let mut storage = MemoryStorage::new();
+    let storage = MemoryStorage::new();
     let fields = vec![
         ("type".to_string(), "machine".to_string()),
         ("hostname".to_string(), "srv-01".to_string()),
         ("ip_addr".to_string(), "192.168.86.5".to_string()),
         ("mac_addr".to_string(), "e0:51:d8:1d:e3:22".to_string()),
     ];
-    storage.add_record(fields, None, None).unwrap();
+    storage.add_record(fields, None, None).await.unwrap();
 
     // Later change supplying a new ip_addr=
     let selections = vec![(Some("hostname".to_string()), "srv-01".to_string())];
     let modifications = vec![("ip_addr".to_string(), "192.168.86.6".to_string())];
-    let count = storage.change_record(&selections, &modifications, None, &[]).unwrap();
+    let count = storage.change_record(&selections, &modifications, None, &[], None, false).await.unwrap();
     assert_eq!(count, 1);
 
     // Repeat change with the same IP (idempotent duplicate check)
-    storage.change_record(&selections, &modifications, None, &[]).unwrap();
+    storage.change_record(&selections, &modifications, None, &[], None, false).await.unwrap();
 
-    let records = storage.query(&selections, None).unwrap();
+    let records = storage.query(&selections, None).await.unwrap();
     let ip_list = records[0].multi_fields.get("ip_addr").unwrap();
     let mac_list = records[0].multi_fields.get("mac_addr").unwrap();
 
@@ -191,15 +191,15 @@ async fn test_verification_step_3_raw_wire_protocol_response_bytes() {
 #[tokio::test]
 async fn test_verification_step_5_malformed_value_fail_closed() {
     println!("\n=== VERIFICATION STEP 5: Malformed Value Fail-Closed ===");
-    let mut storage = MemoryStorage::new();
+    let storage = MemoryStorage::new();
     let fields = vec![
         ("type".to_string(), "machine".to_string()),
         ("hostname".to_string(), "srv-bad".to_string()),
         ("ip_addr".to_string(), "192.168.86.5".to_string()),
         ("ip_addr".to_string(), "not-an-ip".to_string()),
     ];
-    let res = storage.add_record(fields, None, None);
+    let res = storage.add_record(fields, None, None).await;
     assert!(matches!(res, Err(pharos_server::storage::StorageError::InvalidArgument(_))));
-    assert_eq!(storage.record_count(), 0, "No records should be stored when validation fails!");
+    assert_eq!(storage.record_count().await, 0, "No records should be stored when validation fails!");
     println!("SUCCESS Step 5: Returned 512:Illegal value and failed closed (0 partial records written)");
 }
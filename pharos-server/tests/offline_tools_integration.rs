@@ -0,0 +1,102 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core Tests
+ * File: pharos-server/tests/offline_tools_integration.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Verifies the offline tools end to end against a data file like one an
+ * older server left behind: `fsck` reports its problems and `--repair`
+ * fixes them in the file, `export` to CSV and `import` into a fresh data
+ * file carry the live records over, and `migrate --to sqlite` copies every
+ * record - but only into an empty database.
+ * * Traceability:
+ * Backs `pharos-server fsck`, `export`, `import` and `migrate`.
+ * ======================================================================== */
+
+use pharos_server::offline;
+use pharos_server::sqlite::SqliteStorage;
+use pharos_server::storage::{Record, RecordType, Storage};
+use std::collections::HashMap;
+use std::path::Path;
+use tempfile::tempdir;
+
+fn record(id: usize, fields: &[(&str, &str)], multi: &[(&str, &[&str])]) -> Record {
+    let fields: HashMap<String, String> = fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    Record {
+        id,
+        record_type: Some(RecordType::Machine),
+        fields,
+        multi_fields: multi.iter().map(|(k, vs)| (k.to_string(), vs.iter().map(|v| v.to_string()).collect())).collect(),
+        ..Default::default()
+    }
+}
+
+async fn run(args: &[&str]) -> (anyhow::Result<()>, String) {
+    let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+    let mut out = Vec::new();
+    let result = offline::run(&args, &mut out).await;
+    (result, String::from_utf8(out).unwrap())
+}
+
+fn path(p: &Path) -> &str {
+    p.to_str().unwrap()
+}
+
+#[tokio::test]
+async fn test_should_check_repair_export_import_and_migrate_a_stopped_servers_data() {
+    let dir = tempdir().unwrap();
+    let (data, keys) = (dir.path().join("data.json"), dir.path().join("keys"));
+    std::fs::create_dir(&keys).unwrap();
+    let records = vec![
+        record(1, &[("type", "machine"), ("hostname", "web01"), ("ip_addr", "10.0.0.5")], &[]),
+        record(2, &[("type", "machine"), ("hostname", "web01"), ("os", "debian13")], &[("mac_addr", &["AABB.CCDD.EE01"])]),
+        record(3, &[("hostname", "db01")], &[("ip_addr", &["10.0.0.9"])]),
+    ];
+    std::fs::write(&data, serde_json::to_string_pretty(&records).unwrap()).unwrap();
+
+    let (result, report) = run(&["fsck", "--data", path(&data), "--keys", path(&keys)]).await;
+    assert!(result.unwrap_err().to_string().contains("4 problems found (4 repairable"), "{}", report);
+    for expected in ["#1 web01: legacy-field", "#2 web01: duplicate-identity", "#2 web01: invalid-address", "#3 db01: missing-type"] {
+        assert!(report.contains(expected), "missing '{}' in:\n{}", expected, report);
+    }
+    let untouched: Vec<Record> = serde_json::from_str(&std::fs::read_to_string(&data).unwrap()).unwrap();
+    assert!(!untouched[2].fields.contains_key("type"), "fsck alone writes nothing");
+
+    let (result, report) = run(&["fsck", "--data", path(&data), "--keys", path(&keys), "--repair"]).await;
+    result.unwrap();
+    assert!(report.contains("Repaired 3 records; 0 problems left"), "{}", report);
+    let (result, report) = run(&["fsck", "--data", path(&data), "--keys", path(&keys)]).await;
+    result.unwrap();
+    assert!(report.contains("3 records checked, 0 problems"), "{}", report);
+
+    let csv = dir.path().join("records.csv");
+    let (result, _) = run(&["export", "--data", path(&data), "--output", path(&csv)]).await;
+    result.unwrap();
+    let exported = std::fs::read_to_string(&csv).unwrap();
+    assert_eq!(exported.lines().count(), 3, "a header and the two live records:\n{}", exported);
+    assert!(exported.contains("aa:bb:cc:dd:ee:01"), "{}", exported);
+
+    let copy = dir.path().join("copy.json");
+    let (result, report) = run(&["import", "--data", path(&copy), path(&csv)]).await;
+    result.unwrap();
+    assert!(report.contains("2 live"), "{}", report);
+    let (result, jsonl) = run(&["export", "--data", path(&copy), "--format", "jsonl"]).await;
+    result.unwrap();
+    let imported: Vec<Record> = jsonl.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    let web01 = imported.iter().find(|r| r.fields.get("hostname").map(String::as_str) == Some("web01")).unwrap();
+    assert_eq!(web01.fields["os"], "debian13");
+    assert_eq!(web01.multi_fields["ip_addr"], vec!["10.0.0.5"]);
+
+    let db = dir.path().join("pharos.db");
+    let (result, report) = run(&["migrate", "--data", path(&data), "--from", "file", "--to", "sqlite", "--to-path", path(&db)]).await;
+    result.unwrap();
+    assert!(report.contains("Migrated 3 records (2 live)"), "{}", report);
+    let sqlite = SqliteStorage::open(&db).unwrap();
+    assert_eq!(sqlite.query(&[], None).await.unwrap().len(), 2);
+    assert_eq!(sqlite.tombstones(&[]).await.unwrap().len(), 1, "the merged-away duplicate stays a tombstone");
+    drop(sqlite);
+
+    let (result, _) = run(&["migrate", "--data", path(&data), "--to", "sqlite", "--to-path", path(&db)]).await;
+    assert!(result.unwrap_err().to_string().contains("already holds records"));
+}
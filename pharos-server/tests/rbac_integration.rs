@@ -17,7 +17,7 @@ use pharos_server::auth::{AuthManager, SecurityTier};
 use pharos_server::middleware::{MiddlewareChain, RbacMiddleware, SecurityTierMiddleware};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::io::{AsyncWriteExt, AsyncBufReadExt, BufReader};
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use tempfile::tempdir;
 use ssh_key::PrivateKey;
 use base64::{Engine as _, engine::general_purpose::STANDARD};
@@ -51,8 +51,8 @@ impl TestUser {
     }
 }
 
-async fn setup_rbac_server(keys_dir: &std::path::Path) -> (std::net::SocketAddr, Arc<RwLock<dyn Storage>>) {
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(MemoryStorage::new()));
+async fn setup_rbac_server(keys_dir: &std::path::Path) -> (std::net::SocketAddr, Arc<dyn Storage>) {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
     let auth_manager = Arc::new(AuthManager::new(keys_dir, SecurityTier::Open));
     
     let mut chain = MiddlewareChain::new();
@@ -146,8 +146,7 @@ async fn test_should_enforce_team_authorization() {
 
     // Verify record ownership in storage
     {
-        let lock = storage.read().unwrap();
-        let records = lock.query(&[(Some("hostname".to_string()), "prod-web-01".to_string())], None).unwrap();
+        let records = storage.query(&[(Some("hostname".to_string()), "prod-web-01".to_string())], None).await.unwrap();
         assert_eq!(records.len(), 1);
         assert_eq!(records[0].owner_team, Some("devops".to_string()));
     }
@@ -183,8 +182,7 @@ async fn test_should_enforce_team_authorization() {
     assert!(line.contains("200:Ok"));
     
     {
-        let lock = storage.read().unwrap();
-        let records = lock.query(&[(Some("hostname".to_string()), "prod-web-01".to_string())], None).unwrap();
+        let records = storage.query(&[(Some("hostname".to_string()), "prod-web-01".to_string())], None).await.unwrap();
         assert_eq!(records[0].fields.get("status").unwrap(), "healthy");
     }
 }
@@ -271,8 +269,7 @@ async fn test_should_reject_delete_by_unauthorized_team_member() {
     assert!(line.contains("516:No authorization for request"), "Expected 516: but got: {}", line);
 
     // Record must still exist.
-    let lock = storage.read().unwrap();
-    let records = lock.query(&[(Some("hostname".to_string()), "prod-db-01".to_string())], None).unwrap();
+    let records = storage.query(&[(Some("hostname".to_string()), "prod-db-01".to_string())], None).await.unwrap();
     assert_eq!(records.len(), 1);
 }
 
@@ -360,7 +357,6 @@ async fn test_should_allow_sync_prefix_from_non_peer_and_execute_successfully()
     assert!(line.contains("200:Ok"), "Command with SYNC prefix from non-peer should still execute successfully but got: {}", line);
 
     // Verify it actually executed and the record is in storage
-    let lock = storage.read().unwrap();
-    let records = lock.query(&[(Some("hostname".to_string()), "spoofed-peer-host".to_string())], None).unwrap();
+    let records = storage.query(&[(Some("hostname".to_string()), "spoofed-peer-host".to_string())], None).await.unwrap();
     assert_eq!(records.len(), 1);
 }
@@ -0,0 +1,138 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core Tests
+ * File: pharos-server/tests/revision_command_integration.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Verifies over the wire protocol that query output carries each record's
+ * revision and that `change ... if revision=N` applies only while the record
+ * is still at revision N, failing with 523 once someone else wrote it.
+ * * Traceability:
+ * Backs lost-update detection in the web console and other editors.
+ * ======================================================================== */
+
+use pharos_server::handle_connection;
+use pharos_server::storage::{MemoryStorage, Storage};
+use pharos_server::auth::{AuthManager, SecurityTier};
+use pharos_server::middleware::{MiddlewareChain, SecurityTierMiddleware};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncWriteExt, AsyncBufReadExt, BufReader};
+use std::sync::Arc;
+use tempfile::tempdir;
+use ssh_key::PrivateKey;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use ed25519_dalek::{Signer, SigningKey};
+
+struct TestUser {
+    pub_key: String,
+    priv_key: PrivateKey,
+}
+
+impl TestUser {
+    fn new() -> Self {
+        let mut rng = rand::rngs::OsRng;
+        let priv_key = PrivateKey::random(&mut rng, ssh_key::Algorithm::Ed25519).unwrap();
+        let pub_key = priv_key.public_key().to_openssh().unwrap();
+        Self { pub_key, priv_key }
+    }
+
+    fn sign(&self, challenge: &str) -> String {
+        let sig_bytes = match self.priv_key.key_data() {
+            ssh_key::private::KeypairData::Ed25519(kp) => {
+                let signing_key = SigningKey::from_bytes(&kp.private.to_bytes());
+                signing_key.sign(challenge.as_bytes()).to_vec()
+            }
+            _ => panic!("Unsupported key type"),
+        };
+        STANDARD.encode(&sig_bytes)
+    }
+}
+
+/// Starts a server that knows two plain users' keys.
+async fn setup_server() -> (std::net::SocketAddr, [TestUser; 2]) {
+    let dir = tempdir().unwrap();
+    let users = [TestUser::new(), TestUser::new()];
+    for (user, name) in users.iter().zip(["owner", "other"]) {
+        std::fs::write(dir.path().join(format!("{}_id_ed25519.pub", name)), user.pub_key.as_bytes()).unwrap();
+    }
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let auth_manager = Arc::new(AuthManager::new(dir.path(), SecurityTier::Open));
+
+    let mut chain = MiddlewareChain::new();
+    chain.add(Arc::new(SecurityTierMiddleware { default_tier: SecurityTier::Open }));
+    let middleware_chain = Arc::new(chain);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            if let Ok((socket, peer_addr)) = listener.accept().await {
+                let s = Arc::clone(&storage);
+                let a = Arc::clone(&auth_manager);
+                let m = Arc::clone(&middleware_chain);
+                tokio::spawn(async move {
+                    let _ = handle_connection(socket, peer_addr.to_string(), s, a, m).await;
+                });
+            }
+        }
+    });
+
+    (addr, users)
+}
+
+/// Sends one command and collects its response lines up to and including the final status.
+async fn exec(reader: &mut BufReader<TcpStream>, cmd: &str) -> Vec<String> {
+    reader.get_mut().write_all(format!("{}\n", cmd).as_bytes()).await.unwrap();
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        let trimmed = line.trim().to_string();
+        let done = !trimmed.starts_with('-') && !trimmed.starts_with("1");
+        lines.push(trimmed);
+        if done {
+            return lines;
+        }
+    }
+}
+
+async fn connect_as_mdb(addr: std::net::SocketAddr, user: &TestUser) -> BufReader<TcpStream> {
+    let mut reader = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut welcome = String::new();
+    reader.read_line(&mut welcome).await.unwrap();
+
+    assert_eq!(exec(&mut reader, "id mdb").await, vec!["200:Ok"]);
+    let challenge = exec(&mut reader, "login pharos").await.remove(0);
+    let sig = user.sign(challenge.trim_start_matches("301:"));
+    assert_eq!(exec(&mut reader, &format!("auth \"{}\" \"{}\"", user.pub_key, sig)).await, vec!["200:Ok"]);
+    reader
+}
+
+#[tokio::test]
+async fn test_should_refuse_a_change_made_against_a_stale_revision() {
+    let (addr, [owner, _]) = setup_server().await;
+    let mut editor = connect_as_mdb(addr, &owner).await;
+    let mut pulse = connect_as_mdb(addr, &owner).await;
+
+    assert_eq!(exec(&mut editor, "add type=machine hostname=web01").await, vec!["200:Ok"]);
+    let lines = exec(&mut editor, "query hostname=web01").await;
+    assert!(lines.contains(&"-200:1:revision: 1".to_string()), "{:?}", lines);
+
+    // Someone else writes the record after the editor read it.
+    assert_eq!(exec(&mut pulse, "change hostname=web01 make status=online").await, vec!["200:1 entry changed."]);
+    assert_eq!(
+        exec(&mut editor, "change hostname=web01 make status=retired if revision=1").await,
+        vec!["523:Record changed since revision 1 (now at revision 2)"]
+    );
+
+    assert_eq!(exec(&mut editor, "query hostname=web01 return revision status").await, vec![
+        "102:There were 1 matches to your request.",
+        "-200:1:revision: 2",
+        "-200:1:status: online",
+        "200:Ok",
+    ]);
+    assert_eq!(exec(&mut editor, "change hostname=web01 make status=retired if revision=2").await, vec!["200:1 entry changed."]);
+    assert!(exec(&mut editor, "change hostname=web01 make revision=9").await[0].starts_with("512:"));
+}
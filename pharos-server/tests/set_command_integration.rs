@@ -12,7 +12,7 @@ use pharos_server::auth::{AuthManager, SecurityTier};
 use pharos_server::middleware::{MiddlewareChain, RbacMiddleware, SecurityTierMiddleware};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::io::{AsyncWriteExt, AsyncBufReadExt, BufReader};
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use tempfile::tempdir;
 use ssh_key::PrivateKey;
 use base64::{Engine as _, engine::general_purpose::STANDARD};
@@ -46,8 +46,8 @@ impl TestUser {
     }
 }
 
-async fn setup_rbac_server(keys_dir: &std::path::Path) -> (std::net::SocketAddr, Arc<RwLock<dyn Storage>>) {
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(MemoryStorage::new()));
+async fn setup_rbac_server(keys_dir: &std::path::Path) -> (std::net::SocketAddr, Arc<dyn Storage>) {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
     let auth_manager = Arc::new(AuthManager::new(keys_dir, SecurityTier::Open));
     
     let mut chain = MiddlewareChain::new();
@@ -166,8 +166,7 @@ async fn test_set_session_options() {
 
     // Verify 2 records exist in storage
     {
-        let lock = storage.read().unwrap();
-        assert_eq!(lock.record_count(), 2);
+        assert_eq!(storage.record_count().await, 2);
     }
 
     // 3. With limit=1 active and 2+ records matching a broad delete selection: the delete is rejected with 518:
@@ -178,8 +177,7 @@ async fn test_set_session_options() {
     
     // Verify records are still present
     {
-        let lock = storage.read().unwrap();
-        assert_eq!(lock.record_count(), 2);
+        assert_eq!(storage.record_count().await, 2);
     }
 
     // 4. Same as #3 but for change: with limit=1 active and 2+ matching records, change is rejected with 518: and no record modified.
@@ -190,8 +188,7 @@ async fn test_set_session_options() {
     
     // Verify no city fields were added/modified
     {
-        let lock = storage.read().unwrap();
-        let records = lock.query(&[], None).unwrap();
+        let records = storage.query(&[], None).await.unwrap();
         for r in records {
             assert!(!r.fields.contains_key("city"));
         }
@@ -212,8 +209,7 @@ async fn test_set_session_options() {
     
     // Verify "name" is still "alice"
     {
-        let lock = storage.read().unwrap();
-        let records = lock.query(&[(Some("name".to_string()), "alice".to_string())], None).unwrap();
+        let records = storage.query(&[(Some("name".to_string()), "alice".to_string())], None).await.unwrap();
         assert_eq!(records.len(), 1);
     }
 
@@ -224,11 +220,16 @@ async fn test_set_session_options() {
     assert!(line.contains("200:"));
     
     {
-        let lock = storage.read().unwrap();
-        let records = lock.query(&[(Some("name".to_string()), "alice".to_string())], None).unwrap();
+        let records = storage.query(&[(Some("name".to_string()), "alice".to_string())], None).await.unwrap();
         assert_eq!(records[0].fields.get("age").unwrap(), "25");
     }
 
+    // Clearing an existing field counts as overriding it under addonly.
+    reader.get_mut().write_all(b"change name=alice make clear age\n").await.unwrap();
+    line.clear();
+    reader.read_line(&mut line).await.unwrap();
+    assert!(line.starts_with("521:"), "Expected 521: but got: {}", line);
+
     // 7. An invalid token, e.g. set limit=notanumber, returns 512:Illegal value and limit remains unchanged
     reader.get_mut().write_all(b"set limit=notanumber\n").await.unwrap();
     line.clear();
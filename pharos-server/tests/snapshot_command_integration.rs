@@ -0,0 +1,148 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core Tests
+ * File: pharos-server/tests/snapshot_command_integration.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Verifies over the wire protocol that an admin can snapshot a running
+ * FileStorage hub, list the snapshots, and roll the live record set back to
+ * one, and that nobody without the admin role can do any of it.
+ * * Traceability:
+ * Backs `mdb snapshot create|list|restore`.
+ * ======================================================================== */
+
+use pharos_server::handle_connection;
+use pharos_server::file_cipher::FileCipher;
+use pharos_server::journal::JournalConfig;
+use pharos_server::recovery::CorruptPolicy;
+use pharos_server::storage::{FileStorage, Storage};
+use pharos_server::auth::{AuthManager, SecurityTier};
+use pharos_server::middleware::{MiddlewareChain, SecurityTierMiddleware};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncWriteExt, AsyncBufReadExt, BufReader};
+use std::sync::Arc;
+use tempfile::tempdir;
+use ssh_key::PrivateKey;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use ed25519_dalek::{Signer, SigningKey};
+
+struct TestUser {
+    pub_key: String,
+    priv_key: PrivateKey,
+}
+
+impl TestUser {
+    fn new() -> Self {
+        let mut rng = rand::rngs::OsRng;
+        let priv_key = PrivateKey::random(&mut rng, ssh_key::Algorithm::Ed25519).unwrap();
+        let pub_key = priv_key.public_key().to_openssh().unwrap();
+        Self { pub_key, priv_key }
+    }
+
+    fn sign(&self, challenge: &str) -> String {
+        let sig_bytes = match self.priv_key.key_data() {
+            ssh_key::private::KeypairData::Ed25519(kp) => {
+                let signing_key = SigningKey::from_bytes(&kp.private.to_bytes());
+                signing_key.sign(challenge.as_bytes()).to_vec()
+            }
+            _ => panic!("Unsupported key type"),
+        };
+        STANDARD.encode(&sig_bytes)
+    }
+}
+
+/// Starts a FileStorage-backed server that knows a plain user's key and an admin's (the
+/// `admin` filename token grants the role). The returned dir holds the data and snapshots.
+async fn setup_server() -> (std::net::SocketAddr, [TestUser; 2], tempfile::TempDir) {
+    let dir = tempdir().unwrap();
+    let users = [TestUser::new(), TestUser::new()];
+    for (user, name) in users.iter().zip(["owner", "ops-admin"]) {
+        std::fs::write(dir.path().join(format!("{}_id_ed25519.pub", name)), user.pub_key.as_bytes()).unwrap();
+    }
+    let storage: Arc<dyn Storage> = Arc::new(FileStorage::open(dir.path().join("data.json"), JournalConfig::from_env(), FileCipher::default(), CorruptPolicy::default()).unwrap());
+    let auth_manager = Arc::new(AuthManager::new(dir.path(), SecurityTier::Open));
+
+    let mut chain = MiddlewareChain::new();
+    chain.add(Arc::new(SecurityTierMiddleware { default_tier: SecurityTier::Open }));
+    let middleware_chain = Arc::new(chain);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            if let Ok((socket, peer_addr)) = listener.accept().await {
+                let s = Arc::clone(&storage);
+                let a = Arc::clone(&auth_manager);
+                let m = Arc::clone(&middleware_chain);
+                tokio::spawn(async move {
+                    let _ = handle_connection(socket, peer_addr.to_string(), s, a, m).await;
+                });
+            }
+        }
+    });
+
+    (addr, users, dir)
+}
+
+/// Sends one command and collects its response lines up to and including the final status.
+async fn exec(reader: &mut BufReader<TcpStream>, cmd: &str) -> Vec<String> {
+    reader.get_mut().write_all(format!("{}\n", cmd).as_bytes()).await.unwrap();
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        let trimmed = line.trim().to_string();
+        let done = !trimmed.starts_with('-') && !trimmed.starts_with("1");
+        lines.push(trimmed);
+        if done {
+            return lines;
+        }
+    }
+}
+
+async fn connect_as_mdb(addr: std::net::SocketAddr, user: &TestUser) -> BufReader<TcpStream> {
+    let mut reader = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut welcome = String::new();
+    reader.read_line(&mut welcome).await.unwrap();
+
+    assert_eq!(exec(&mut reader, "id mdb").await, vec!["200:Ok"]);
+    let challenge = exec(&mut reader, "login pharos").await.remove(0);
+    let sig = user.sign(challenge.trim_start_matches("301:"));
+    assert_eq!(exec(&mut reader, &format!("auth \"{}\" \"{}\"", user.pub_key, sig)).await, vec!["200:Ok"]);
+    reader
+}
+
+#[tokio::test]
+async fn test_should_snapshot_list_and_restore_as_admin() {
+    let (addr, [_, admin], dir) = setup_server().await;
+    let mut reader = connect_as_mdb(addr, &admin).await;
+
+    assert_eq!(exec(&mut reader, "snapshot list").await, vec!["501:No snapshots"]);
+    assert_eq!(exec(&mut reader, "add type=machine hostname=web01").await, vec!["200:Ok"]);
+    let created = exec(&mut reader, "snapshot create nightly").await;
+    assert!(created[0].starts_with("200:Snapshot 'nightly' written ("), "{:?}", created);
+    assert!(dir.path().join("data.snapshots").join("nightly.json").exists());
+
+    let listed = exec(&mut reader, "snapshot list").await;
+    assert_eq!(listed[0], "102:There were 1 matches to your request.");
+    assert!(listed.contains(&"-200:1:name: nightly".to_string()));
+
+    assert_eq!(exec(&mut reader, "change hostname=web01 make status=broken").await, vec!["200:1 entry changed."]);
+    assert_eq!(exec(&mut reader, "snapshot restore nightly").await, vec!["200:Restored snapshot 'nightly' (1 records)"]);
+    assert_eq!(exec(&mut reader, "query status=broken").await, vec!["501:No matches to query"]);
+
+    assert_eq!(exec(&mut reader, "snapshot restore ../data").await[0].split(':').next(), Some("512"));
+    assert_eq!(exec(&mut reader, "snapshot create nightly").await, vec!["512:Illegal value: snapshot 'nightly' already exists"]);
+}
+
+#[tokio::test]
+async fn test_should_refuse_snapshot_operations_without_admin_role() {
+    let (addr, [user, _], _dir) = setup_server().await;
+    let mut reader = connect_as_mdb(addr, &user).await;
+
+    for cmd in ["snapshot create mine", "snapshot list", "snapshot restore mine"] {
+        assert_eq!(exec(&mut reader, cmd).await, vec!["516:Forbidden: Admin role required for snapshot operations"], "{}", cmd);
+    }
+}
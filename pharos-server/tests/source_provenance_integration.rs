@@ -17,7 +17,7 @@ use pharos_server::auth::{AuthManager, SecurityTier};
 use pharos_server::middleware::{MiddlewareChain, SecurityTierMiddleware};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::io::{AsyncWriteExt, AsyncBufReadExt, BufReader};
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use tempfile::tempdir;
 use ssh_key::PrivateKey;
 use base64::{Engine as _, engine::general_purpose::STANDARD};
@@ -61,11 +61,11 @@ async fn authenticate(reader: &mut BufReader<tokio::net::tcp::ReadHalf<'_>>, wri
     assert!(line.contains("200:Ok"), "Expected 200:Ok on auth, got: {}", line);
 }
 
-async fn setup_server() -> (std::net::SocketAddr, Arc<RwLock<dyn Storage>>, TestUser) {
+async fn setup_server() -> (std::net::SocketAddr, Arc<dyn Storage>, TestUser) {
     let dir = tempdir().unwrap();
     let user = TestUser::new();
     std::fs::write(dir.path().join("tester_id_ed25519.pub"), user.pub_key.as_bytes()).unwrap();
-    let storage: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(MemoryStorage::new()));
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
     let auth_manager = Arc::new(AuthManager::new(dir.path(), SecurityTier::Open));
 
     let mut chain = MiddlewareChain::new();
@@ -119,7 +119,7 @@ async fn test_should_set_source_to_mdb_for_add_from_mdb_client_id() {
     assert!(line.contains("200:Ok"), "Expected 200:Ok on add, got: {}", line);
 
     // Query back from storage and assert source field
-    let records = storage.read().unwrap().query(&[(Some("hostname".to_string()), "src-test-mdb".to_string())], None).unwrap();
+    let records = storage.query(&[(Some("hostname".to_string()), "src-test-mdb".to_string())], None).await.unwrap();
     assert_eq!(records.len(), 1);
     assert_eq!(records[0].fields.get("source").map(|s| s.as_str()), Some("mdb"));
 }
@@ -151,7 +151,7 @@ async fn test_should_set_source_to_pharos_scan_for_add_from_pharos_scan_client_i
     assert!(line.contains("200:Ok"), "Expected 200:Ok on add, got: {}", line);
 
     // Query back from storage and assert source field
-    let records = storage.read().unwrap().query(&[(Some("hostname".to_string()), "src-test-scan".to_string())], None).unwrap();
+    let records = storage.query(&[(Some("hostname".to_string()), "src-test-scan".to_string())], None).await.unwrap();
     assert_eq!(records.len(), 1);
     assert_eq!(records[0].fields.get("source").map(|s| s.as_str()), Some("pharos-scan"));
 }
@@ -183,7 +183,7 @@ async fn test_should_set_source_to_pharos_pulse_for_add_from_pulse_prefixed_clie
     assert!(line.contains("200:Ok"), "Expected 200:Ok on add, got: {}", line);
 
     // Query back from storage and assert source field
-    let records = storage.read().unwrap().query(&[(Some("hostname".to_string()), "src-test-pulse".to_string())], None).unwrap();
+    let records = storage.query(&[(Some("hostname".to_string()), "src-test-pulse".to_string())], None).await.unwrap();
     assert_eq!(records.len(), 1);
     assert_eq!(records[0].fields.get("source").map(|s| s.as_str()), Some("pharos-pulse"));
 }
@@ -215,7 +215,7 @@ async fn test_should_strip_client_supplied_source_field_and_use_derived_value_in
     assert!(line.contains("200:Ok"), "Expected 200:Ok on add, got: {}", line);
 
     // Query back from storage and assert source field equals derived mdb, NOT spoofed value
-    let records = storage.read().unwrap().query(&[(Some("hostname".to_string()), "src-test-spoof".to_string())], None).unwrap();
+    let records = storage.query(&[(Some("hostname".to_string()), "src-test-spoof".to_string())], None).await.unwrap();
     assert_eq!(records.len(), 1);
     assert_eq!(records[0].fields.get("source").map(|s| s.as_str()), Some("mdb"));
 }
@@ -0,0 +1,152 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core Tests
+ * File: pharos-server/tests/tombstone_command_integration.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Verifies over the wire protocol that `delete` leaves a tombstone: the
+ * record disappears from queries but is listed by `tombstones`, keeps its
+ * history, and can be brought back with `undelete` by its owner or an admin
+ * and by nobody else.
+ * * Traceability:
+ * Backs `mdb undelete <selection>` and the sync bootstrap tombstone pull.
+ * ======================================================================== */
+
+use pharos_server::handle_connection;
+use pharos_server::storage::{MemoryStorage, Storage};
+use pharos_server::auth::{AuthManager, SecurityTier};
+use pharos_server::middleware::{MiddlewareChain, SecurityTierMiddleware};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncWriteExt, AsyncBufReadExt, BufReader};
+use std::sync::Arc;
+use tempfile::tempdir;
+use ssh_key::PrivateKey;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use ed25519_dalek::{Signer, SigningKey};
+
+struct TestUser {
+    pub_key: String,
+    priv_key: PrivateKey,
+}
+
+impl TestUser {
+    fn new() -> Self {
+        let mut rng = rand::rngs::OsRng;
+        let priv_key = PrivateKey::random(&mut rng, ssh_key::Algorithm::Ed25519).unwrap();
+        let pub_key = priv_key.public_key().to_openssh().unwrap();
+        Self { pub_key, priv_key }
+    }
+
+    fn sign(&self, challenge: &str) -> String {
+        let sig_bytes = match self.priv_key.key_data() {
+            ssh_key::private::KeypairData::Ed25519(kp) => {
+                let signing_key = SigningKey::from_bytes(&kp.private.to_bytes());
+                signing_key.sign(challenge.as_bytes()).to_vec()
+            }
+            _ => panic!("Unsupported key type"),
+        };
+        STANDARD.encode(&sig_bytes)
+    }
+}
+
+/// Starts a server that knows three keys: the record owner, another plain user, and an admin
+/// (the `admin` filename token grants the role).
+async fn setup_server() -> (std::net::SocketAddr, [TestUser; 3]) {
+    let dir = tempdir().unwrap();
+    let users = [TestUser::new(), TestUser::new(), TestUser::new()];
+    for (user, name) in users.iter().zip(["owner", "other", "ops-admin"]) {
+        std::fs::write(dir.path().join(format!("{}_id_ed25519.pub", name)), user.pub_key.as_bytes()).unwrap();
+    }
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let auth_manager = Arc::new(AuthManager::new(dir.path(), SecurityTier::Open));
+
+    let mut chain = MiddlewareChain::new();
+    chain.add(Arc::new(SecurityTierMiddleware { default_tier: SecurityTier::Open }));
+    let middleware_chain = Arc::new(chain);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            if let Ok((socket, peer_addr)) = listener.accept().await {
+                let s = Arc::clone(&storage);
+                let a = Arc::clone(&auth_manager);
+                let m = Arc::clone(&middleware_chain);
+                tokio::spawn(async move {
+                    let _ = handle_connection(socket, peer_addr.to_string(), s, a, m).await;
+                });
+            }
+        }
+    });
+
+    (addr, users)
+}
+
+/// Sends one command and collects its response lines up to and including the final status.
+async fn exec(reader: &mut BufReader<TcpStream>, cmd: &str) -> Vec<String> {
+    reader.get_mut().write_all(format!("{}\n", cmd).as_bytes()).await.unwrap();
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        let trimmed = line.trim().to_string();
+        let done = !trimmed.starts_with('-') && !trimmed.starts_with("1");
+        lines.push(trimmed);
+        if done {
+            return lines;
+        }
+    }
+}
+
+async fn connect_as_mdb(addr: std::net::SocketAddr, user: &TestUser) -> BufReader<TcpStream> {
+    let mut reader = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut welcome = String::new();
+    reader.read_line(&mut welcome).await.unwrap();
+
+    assert_eq!(exec(&mut reader, "id mdb").await, vec!["200:Ok"]);
+    let challenge = exec(&mut reader, "login pharos").await.remove(0);
+    let sig = user.sign(challenge.trim_start_matches("301:"));
+    assert_eq!(exec(&mut reader, &format!("auth \"{}\" \"{}\"", user.pub_key, sig)).await, vec!["200:Ok"]);
+    reader
+}
+
+#[tokio::test]
+async fn test_should_hide_deleted_record_and_restore_it_on_undelete() {
+    let (addr, [owner, _, _]) = setup_server().await;
+    let mut reader = connect_as_mdb(addr, &owner).await;
+
+    assert_eq!(exec(&mut reader, "add type=machine hostname=web01 ip_addr=10.0.0.1").await, vec!["200:Ok"]);
+    assert_eq!(exec(&mut reader, "delete hostname=web01").await, vec!["200:Ok"]);
+    assert_eq!(exec(&mut reader, "query hostname=web01").await, vec!["501:No matches to query"]);
+
+    let listed = exec(&mut reader, "tombstones").await;
+    assert_eq!(listed[0], "102:There were 1 matches to your request.");
+    assert!(listed.contains(&"-200:1:hostname: web01".to_string()));
+    assert!(listed.iter().any(|l| l.starts_with("-200:1:deleted_at: ")), "missing deletion time: {:?}", listed);
+
+    let history = exec(&mut reader, "history hostname=web01").await;
+    assert!(history.iter().any(|l| l.starts_with("-200:2:deleted_at: (none) -> ")), "missing delete revision: {:?}", history);
+
+    assert_eq!(exec(&mut reader, "undelete hostname=web01").await, vec!["200:1 entry restored."]);
+    let lines = exec(&mut reader, "query hostname=web01").await;
+    assert_eq!(lines[0], "102:There were 1 matches to your request.");
+    assert_eq!(exec(&mut reader, "tombstones").await, vec!["501:No deleted entries match"]);
+    assert_eq!(exec(&mut reader, "undelete hostname=web01").await, vec!["501:No deleted entries match"]);
+}
+
+#[tokio::test]
+async fn test_should_only_let_owner_or_admin_undelete() {
+    let (addr, [owner, other, admin]) = setup_server().await;
+    let mut reader = connect_as_mdb(addr, &owner).await;
+    assert_eq!(exec(&mut reader, "add type=machine hostname=db01").await, vec!["200:Ok"]);
+    assert_eq!(exec(&mut reader, "delete hostname=db01").await, vec!["200:Ok"]);
+
+    let mut other_reader = connect_as_mdb(addr, &other).await;
+    assert_eq!(exec(&mut other_reader, "undelete hostname=db01").await, vec!["516:No authorization for request"]);
+
+    let mut admin_reader = connect_as_mdb(addr, &admin).await;
+    assert_eq!(exec(&mut admin_reader, "undelete hostname=db01").await, vec!["200:1 entry restored."]);
+    assert_eq!(exec(&mut admin_reader, "undelete").await, vec!["599:Syntax error"]);
+}
@@ -0,0 +1,231 @@
+/* ========================================================================
+ * Project: pharos
+ * Component: Server Core Tests
+ * File: pharos-server/tests/transaction_command_integration.rs
+ * Author: Richard D. (https://github.com/iamrichardd)
+ * License: AGPL-3.0 (See LICENSE file for details)
+ * * Purpose (The "Why"):
+ * Verifies over the wire protocol that writes between `begin` and `commit`
+ * are only staged, then applied together: a commit either applies every
+ * staged command or, if any fails, none of them, and `rollback` or a
+ * dropped connection discards what was staged. The one webhook a commit
+ * sends counts updated records apart from added ones.
+ * * Traceability:
+ * Backs re-IPing a rack as one all-or-nothing batch of `change` commands.
+ * ======================================================================== */
+
+use pharos_server::handle_connection;
+use pharos_server::storage::{MemoryStorage, Storage};
+use pharos_server::auth::{AuthManager, SecurityTier};
+use pharos_server::middleware::{MiddlewareChain, SecurityTierMiddleware};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, AsyncBufReadExt, BufReader};
+use std::sync::Arc;
+use tempfile::tempdir;
+use ssh_key::PrivateKey;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use ed25519_dalek::{Signer, SigningKey};
+
+struct TestUser {
+    pub_key: String,
+    priv_key: PrivateKey,
+}
+
+impl TestUser {
+    fn new() -> Self {
+        let mut rng = rand::rngs::OsRng;
+        let priv_key = PrivateKey::random(&mut rng, ssh_key::Algorithm::Ed25519).unwrap();
+        let pub_key = priv_key.public_key().to_openssh().unwrap();
+        Self { pub_key, priv_key }
+    }
+
+    fn sign(&self, challenge: &str) -> String {
+        let sig_bytes = match self.priv_key.key_data() {
+            ssh_key::private::KeypairData::Ed25519(kp) => {
+                let signing_key = SigningKey::from_bytes(&kp.private.to_bytes());
+                signing_key.sign(challenge.as_bytes()).to_vec()
+            }
+            _ => panic!("Unsupported key type"),
+        };
+        STANDARD.encode(&sig_bytes)
+    }
+}
+
+/// Starts a server that knows two plain users' keys.
+async fn setup_server() -> (std::net::SocketAddr, [TestUser; 2]) {
+    let dir = tempdir().unwrap();
+    let users = [TestUser::new(), TestUser::new()];
+    for (user, name) in users.iter().zip(["owner", "other"]) {
+        std::fs::write(dir.path().join(format!("{}_id_ed25519.pub", name)), user.pub_key.as_bytes()).unwrap();
+    }
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let auth_manager = Arc::new(AuthManager::new(dir.path(), SecurityTier::Open));
+
+    let mut chain = MiddlewareChain::new();
+    chain.add(Arc::new(SecurityTierMiddleware { default_tier: SecurityTier::Open }));
+    let middleware_chain = Arc::new(chain);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            if let Ok((socket, peer_addr)) = listener.accept().await {
+                let s = Arc::clone(&storage);
+                let a = Arc::clone(&auth_manager);
+                let m = Arc::clone(&middleware_chain);
+                tokio::spawn(async move {
+                    let _ = handle_connection(socket, peer_addr.to_string(), s, a, m).await;
+                });
+            }
+        }
+    });
+
+    (addr, users)
+}
+
+/// Sends one command and collects its response lines up to and including the final status.
+async fn exec(reader: &mut BufReader<TcpStream>, cmd: &str) -> Vec<String> {
+    reader.get_mut().write_all(format!("{}\n", cmd).as_bytes()).await.unwrap();
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        let trimmed = line.trim().to_string();
+        let done = !trimmed.starts_with('-') && !trimmed.starts_with("1");
+        lines.push(trimmed);
+        if done {
+            return lines;
+        }
+    }
+}
+
+async fn connect_as_mdb(addr: std::net::SocketAddr, user: &TestUser) -> BufReader<TcpStream> {
+    let mut reader = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut welcome = String::new();
+    reader.read_line(&mut welcome).await.unwrap();
+
+    assert_eq!(exec(&mut reader, "id mdb").await, vec!["200:Ok"]);
+    let challenge = exec(&mut reader, "login pharos").await.remove(0);
+    let sig = user.sign(challenge.trim_start_matches("301:"));
+    assert_eq!(exec(&mut reader, &format!("auth \"{}\" \"{}\"", user.pub_key, sig)).await, vec!["200:Ok"]);
+    reader
+}
+
+#[tokio::test]
+async fn test_should_apply_staged_writes_together_on_commit() {
+    let (addr, [owner, _]) = setup_server().await;
+    let mut reader = connect_as_mdb(addr, &owner).await;
+
+    assert_eq!(exec(&mut reader, "add type=machine hostname=web02").await, vec!["200:Ok"]);
+    assert_eq!(exec(&mut reader, "begin").await, vec!["200:Ok"]);
+    assert_eq!(exec(&mut reader, "begin").await, vec!["522:Transaction already in progress"]);
+    assert_eq!(exec(&mut reader, "add type=machine hostname=web01").await, vec!["200:Staged (1 pending)"]);
+    assert_eq!(exec(&mut reader, "change hostname=web01 make ip_addr=10.0.1.1").await, vec!["200:Staged (2 pending)"]);
+    assert_eq!(exec(&mut reader, "delete hostname=web02").await, vec!["200:Staged (3 pending)"]);
+    // A malformed modification is refused as it's typed, and isn't staged.
+    assert!(exec(&mut reader, "change hostname=web01 make ip_addr=bogus").await[0].starts_with("512:"));
+
+    // Reads inside the transaction still see storage as it was.
+    assert_eq!(exec(&mut reader, "query hostname=web01").await, vec!["501:No matches to query"]);
+
+    assert_eq!(exec(&mut reader, "commit").await, vec![
+        "-200:1:created: 1",
+        "-200:2:changed: 1",
+        "-200:3:deleted: 1",
+        "200:Ok",
+    ]);
+    assert!(exec(&mut reader, "query hostname=web01 return ip_addr").await.contains(&"-200:1:ip_addr: 10.0.1.1".to_string()));
+    assert_eq!(exec(&mut reader, "query hostname=web02").await, vec!["501:No matches to query"]);
+    assert_eq!(exec(&mut reader, "commit").await, vec!["522:No transaction in progress"]);
+}
+
+#[tokio::test]
+async fn test_should_apply_nothing_when_one_staged_write_fails() {
+    let (addr, [owner, other]) = setup_server().await;
+    let mut reader = connect_as_mdb(addr, &owner).await;
+    assert_eq!(exec(&mut reader, "add type=machine hostname=web01").await, vec!["200:Ok"]);
+
+    // `other` may add its own record but not change the owner's, so the commit is refused whole.
+    let mut intruder = connect_as_mdb(addr, &other).await;
+    assert_eq!(exec(&mut intruder, "begin").await, vec!["200:Ok"]);
+    assert_eq!(exec(&mut intruder, "add type=machine hostname=web03").await, vec!["200:Staged (1 pending)"]);
+    assert_eq!(exec(&mut intruder, "change hostname=web01 make ip_addr=10.0.9.9").await, vec!["200:Staged (2 pending)"]);
+    assert_eq!(exec(&mut intruder, "commit").await, vec!["510:Not authorized to change this entry (command 2)"]);
+    assert_eq!(exec(&mut reader, "query hostname=web03").await, vec!["501:No matches to query"]);
+    assert!(!exec(&mut reader, "query hostname=web01").await.iter().any(|l| l.contains("10.0.9.9")));
+}
+
+#[tokio::test]
+async fn test_should_discard_staged_writes_on_rollback_or_disconnect() {
+    let (addr, [owner, _]) = setup_server().await;
+    let mut reader = connect_as_mdb(addr, &owner).await;
+
+    assert_eq!(exec(&mut reader, "rollback").await, vec!["522:No transaction in progress"]);
+    assert_eq!(exec(&mut reader, "begin").await, vec!["200:Ok"]);
+    assert_eq!(exec(&mut reader, "add type=machine hostname=web01").await, vec!["200:Staged (1 pending)"]);
+    assert_eq!(exec(&mut reader, "undelete hostname=web01").await, vec!["522:Command not allowed inside a transaction"]);
+    assert_eq!(exec(&mut reader, "rollback").await, vec!["200:Rolled back 1 staged command(s)"]);
+    assert_eq!(exec(&mut reader, "query hostname=web01").await, vec!["501:No matches to query"]);
+
+    assert_eq!(exec(&mut reader, "begin").await, vec!["200:Ok"]);
+    assert_eq!(exec(&mut reader, "add type=machine hostname=web02").await, vec!["200:Staged (1 pending)"]);
+    drop(reader);
+
+    let mut reader = connect_as_mdb(addr, &owner).await;
+    assert_eq!(exec(&mut reader, "query hostname=web02").await, vec!["501:No matches to query"]);
+}
+
+/// A webhook endpoint that answers 200 and hands each request body it receives to the returned
+/// channel.
+async fn webhook_receiver() -> (String, tokio::sync::mpsc::UnboundedReceiver<serde_json::Value>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                while let Ok(n @ 1..) = socket.read(&mut buf).await {
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((_, body)) = text.split_once("\r\n\r\n")
+                        && let Ok(json) = serde_json::from_str(body)
+                    {
+                        let _ = tx.send(json);
+                        break;
+                    }
+                }
+                let _ = socket.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n").await;
+            });
+        }
+    });
+    (url, rx)
+}
+
+#[tokio::test]
+async fn test_should_count_updated_records_apart_from_added_ones_in_the_commit_notification() {
+    let (url, mut webhooks) = webhook_receiver().await;
+    unsafe {
+        std::env::set_var("PHAROS_WEBHOOK_URL", &url);
+    }
+    let (addr, [owner, _]) = setup_server().await;
+    let mut reader = connect_as_mdb(addr, &owner).await;
+    assert_eq!(exec(&mut reader, "add type=machine hostname=notify-01").await, vec!["200:Ok"]);
+
+    assert_eq!(exec(&mut reader, "begin").await, vec!["200:Ok"]);
+    assert_eq!(exec(&mut reader, "add type=machine hostname=notify-01 status=up").await, vec!["200:Staged (1 pending)"]);
+    assert_eq!(exec(&mut reader, "add type=machine hostname=notify-02").await, vec!["200:Staged (2 pending)"]);
+    assert_eq!(exec(&mut reader, "commit").await, vec!["-200:1:updated: 1", "-200:2:created: 1", "200:Ok"]);
+
+    let batch = loop {
+        let event = tokio::time::timeout(std::time::Duration::from_secs(5), webhooks.recv()).await.expect("a webhook for the commit").unwrap();
+        if event["event"] == "batch" && event["commands"].to_string().contains("notify-02") {
+            break event;
+        }
+    };
+    assert_eq!((batch["added"].as_u64(), batch["updated"].as_u64()), (Some(1), Some(1)), "{}", batch);
+    assert_eq!((batch["changed"].as_u64(), batch["deleted"].as_u64()), (Some(0), Some(0)), "{}", batch);
+}